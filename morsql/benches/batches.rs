@@ -0,0 +1,81 @@
+//! Compares running queries over a table stored as rows with running them
+//! over the same table stored a column at a time, a batch of rows at a
+//! time. Run with `cargo bench`.
+
+extern crate morsql;
+
+use morsql::batch::ColumnarTable;
+use morsql::exec::Config;
+use morsql::functions::FunctionRegistry;
+use morsql::parser::parse_sql;
+use morsql::stats::TableStats;
+use morsql::stream::rows_of;
+use morsql::tables::{TableCell, TableData};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ROWS: i64 = 500_000;
+const RUNS: usize = 5;
+
+/// The fastest of several runs of `f`, which returns the number of result
+/// rows.
+fn time<F: FnMut() -> usize>(mut f: F) -> (Duration, usize) {
+  let mut best = Duration::MAX;
+  let mut rows = 0;
+  for _ in 0..RUNS {
+    let start = Instant::now();
+    rows = black_box(f());
+    best = best.min(start.elapsed());
+  }
+  (best, rows)
+}
+
+fn main() {
+  let names = ["Harry", "Lucas", "Ada", "Grace", "Barbara"];
+  let table = TableData {
+    header: vec!["name".to_owned(), "age".to_owned(), "city".to_owned()],
+    rows: (0..ROWS)
+      .map(|i| {
+        vec![
+          Some(TableCell::CellString(names[i as usize % names.len()].to_owned())),
+          if i % 11 == 0 { None } else { Some(TableCell::CellInt(i % 90)) },
+          Some(TableCell::CellString(format!("city number {}", i % 1000))),
+        ]
+      })
+      .collect(),
+  };
+  let catalog = vec![table];
+  let columnar = ColumnarTable::of_table(&catalog[0]);
+  let stats = vec![TableStats::of(&catalog[0])];
+  let config = Config::default();
+
+  println!("{:<64} {:>12} {:>12} {:>8}", "query", "rows", "batches", "speedup");
+  for sql in [
+    "SELECT name FROM t WHERE 80 < age",
+    "SELECT name, age FROM t WHERE name == \"Harry\" && 18 < age || city == \"city number 7\"",
+    "SELECT count(*) FROM t WHERE NOT (age < 30) && NOT (name == \"Ada\" || name == \"Grace\")",
+    "SELECT upper(name) FROM t WHERE age == 3 && lower(name) == \"lucas\"",
+  ] {
+    let q = parse_sql(sql)
+      .unwrap()
+      .to_indexed(&catalog, &["t".to_owned()], &FunctionRegistry::with_builtins())
+      .unwrap();
+    let (rows, expected) = time(|| {
+      let sources = vec![rows_of(&catalog[0])];
+      q.stream_with(&catalog, &stats, sources, &config).unwrap().count()
+    });
+    let (batches, produced) = time(|| {
+      let sources = vec![&columnar];
+      q.stream_columnar(&catalog, &stats, sources, &config).unwrap().count()
+    });
+    assert_eq!(produced, expected);
+    let per_row = |d: Duration| format!("{:.1} ns/row", d.as_nanos() as f64 / ROWS as f64);
+    println!(
+      "{:<64} {:>12} {:>12} {:>7.1}x",
+      sql,
+      per_row(rows),
+      per_row(batches),
+      rows.as_secs_f64() / batches.as_secs_f64()
+    );
+  }
+}
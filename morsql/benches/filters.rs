@@ -0,0 +1,73 @@
+//! Compares evaluating filters by walking the expression tree for every row
+//! with evaluating them compiled. Run with `cargo bench`.
+
+extern crate morsql;
+
+use morsql::compile::CompiledFilter;
+use morsql::functions::FunctionRegistry;
+use morsql::parser::parse_sql;
+use morsql::stream::Row;
+use morsql::tables::{TableCell, TableData};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ROWS: i64 = 200_000;
+const RUNS: usize = 5;
+
+/// The fastest of several runs of `f`, which returns the number of rows
+/// that matched.
+fn time<F: FnMut() -> usize>(mut f: F) -> (Duration, usize) {
+  let mut best = Duration::MAX;
+  let mut matched = 0;
+  for _ in 0..RUNS {
+    let start = Instant::now();
+    matched = black_box(f());
+    best = best.min(start.elapsed());
+  }
+  (best, matched)
+}
+
+fn main() {
+  let names = ["Harry", "Lucas", "Ada", "Grace", "Barbara"];
+  let catalog = vec![TableData {
+    header: vec!["name".to_owned(), "age".to_owned(), "city".to_owned()],
+    rows: vec![],
+  }];
+  let tuples: Vec<Vec<Row>> = (0..ROWS)
+    .map(|i| {
+      vec![vec![
+        Some(TableCell::CellString(names[i as usize % names.len()].to_owned())),
+        if i % 11 == 0 { None } else { Some(TableCell::CellInt(i % 90)) },
+        Some(TableCell::CellString(format!("city number {}", i % 1000))),
+      ]]
+    })
+    .collect();
+
+  println!("{:<58} {:>12} {:>12} {:>8}", "filter", "interpreted", "compiled", "speedup");
+  for filter in [
+    "name == \"Harry\"",
+    "name == \"Harry\" && 18 < age || city == \"city number 7\"",
+    "NOT (age < 30) && NOT (name == \"Ada\" || name == \"Grace\")",
+    "lower(name) == \"lucas\" && age < 50",
+    "city REGEXP \"number 1[0-9]$\" || age == 3",
+  ] {
+    let text = filter;
+    let filter = parse_sql(&format!("SELECT * FROM t WHERE {}", text))
+      .unwrap()
+      .to_indexed(&catalog, &["t".to_owned()], &FunctionRegistry::with_builtins())
+      .unwrap()
+      .filter;
+    let compiled = CompiledFilter::new(&filter);
+    let (interpreted, expected) = time(|| tuples.iter().filter(|t| filter.valid_row(t)).count());
+    let (fast, matched) = time(|| tuples.iter().filter(|t| compiled.matches(t)).count());
+    assert_eq!(matched, expected);
+    let per_row = |d: Duration| format!("{:.1} ns/row", d.as_nanos() as f64 / ROWS as f64);
+    println!(
+      "{:<58} {:>12} {:>12} {:>7.1}x",
+      text,
+      per_row(interpreted),
+      per_row(fast),
+      interpreted.as_secs_f64() / fast.as_secs_f64()
+    );
+  }
+}
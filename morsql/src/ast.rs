@@ -1,5 +1,25 @@
+use crate::functions::{AggregateUdf, ScalarUdf};
+use regex::Regex;
+use std::sync::Arc;
+
 pub type Ident = String;
 
+/// A range of byte offsets into the query text. Spans only locate errors,
+/// so they are ignored when comparing syntax trees.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
+}
+
+impl PartialEq for Span {
+  fn eq(&self, _other: &Self) -> bool {
+    true
+  }
+}
+
+impl Eq for Span {}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum UnaryOp {
   Not,
@@ -12,6 +32,8 @@ pub enum BinaryOp {
   Eq,
   Lt,
   Like,
+  SimilarTo,
+  Regexp,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -21,13 +43,30 @@ pub enum Filter {
   LitB(bool),
   LitI(i64),
   UnaryOp(UnaryOp, Box<Filter>),
-  BinaryOp(BinaryOp, Box<Filter>, Box<Filter>),
+  /// An operator applied to two operands, with the span from the first to
+  /// the end of the second.
+  BinaryOp(BinaryOp, Box<Filter>, Box<Filter>, Span),
+  /// A call of a function, with the span from its name to the closing
+  /// parenthesis.
+  Call(Ident, Vec<Filter>, Span),
+  /// A placeholder, with its span.
+  Param(Param, Span),
+}
+
+/// A placeholder for a value supplied when a prepared query is executed.
+/// `?` placeholders are numbered in order of appearance by `parse_sql`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Param {
+  Anonymous,
+  Positional(usize),
+  Named(Ident),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct ColumnSelector {
   pub table: Option<Ident>,
   pub field: Ident,
+  pub span: Span,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -38,8 +77,34 @@ pub enum IndexedFilter {
   LitI(i64),
   UnaryOp(UnaryOp, Box<IndexedFilter>),
   BinaryOp(BinaryOp, Box<IndexedFilter>, Box<IndexedFilter>),
+  Call(Arc<ScalarUdf>, Vec<IndexedFilter>),
+  Regex(RegexOp, Pattern, Vec<IndexedFilter>),
+  Param(Param),
+}
+
+/// The regular-expression operations, whose pattern is compiled when the
+/// query is bound. The arguments are the subject string followed by the
+/// optional group of `regexp_extract` or the replacement of
+/// `regexp_replace`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RegexOp {
+  Matches,
+  Extract,
+  Replace,
 }
 
+/// A compiled pattern, compared by its source.
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl PartialEq for Pattern {
+  fn eq(&self, other: &Self) -> bool {
+    self.0.as_str() == other.0.as_str()
+  }
+}
+
+impl Eq for Pattern {}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct IndexedColumnSelector {
   pub table : usize,
@@ -49,19 +114,54 @@ pub struct IndexedColumnSelector {
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Selection {
   Star,
-  Columns(Vec<ColumnSelector>),
+  Columns(Vec<Filter>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IndexedColumn {
+  pub name: Ident,
+  pub expr: IndexedFilter,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IndexedAggregate {
+  pub name: Ident,
+  pub function: Arc<AggregateUdf>,
+  pub args: Vec<IndexedFilter>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum IndexedSelection {
   Star,
-  Columns(Vec<IndexedColumnSelector>),
+  Columns(Vec<IndexedColumn>),
+  Aggregates(Vec<IndexedAggregate>),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Table {
   pub table_name: Ident,
   pub alias: Option<Ident>,
+  pub span: Span,
+}
+
+/// A key of an `ORDER BY` clause.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OrderKey {
+  pub expr: Filter,
+  pub descending: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IndexedOrderKey {
+  pub expr: IndexedFilter,
+  pub descending: bool,
+}
+
+/// A `LIMIT count OFFSET offset` clause.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Limit {
+  pub count: u64,
+  pub offset: u64,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -69,13 +169,79 @@ pub struct Query {
   pub selection: Selection,
   pub tables: Vec<Table>,
   pub filter: Filter,
+  pub order_by: Vec<OrderKey>,
+  pub limit: Option<Limit>,
+}
+
+/// How EXPLAIN shows a plan.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExplainFormat {
+  /// An indented tree, an operator per line.
+  Text,
+  /// A Graphviz graph.
+  Dot,
+}
+
+/// How an index stores the keys of its column.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IndexKind {
+  /// A B+ tree, which finds keys equal to a value or in a range.
+  BTree,
+  /// A hash table, which only finds keys equal to a value, with fewer
+  /// reads than a tree.
+  Hash,
+}
+
+/// A query, or a request to explain one.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Statement {
+  Query(Query),
+  /// `EXPLAIN [ANALYZE] [FORMAT TEXT|DOT] query`: the operators the query
+  /// runs as instead of its result, and with `analyze`, what each did when
+  /// the query was run.
+  Explain {
+    query: Query,
+    analyze: bool,
+    format: ExplainFormat,
+  },
+  /// `CREATE INDEX name ON table [USING BTREE|HASH] (column)`: builds an
+  /// index of the table's rows by the values of the column, which later
+  /// queries may read the rows through.
+  CreateIndex {
+    name: Ident,
+    table: Ident,
+    column: Ident,
+    kind: IndexKind,
+  },
+}
+
+impl Statement {
+  /// The query the statement runs or explains, if it has one.
+  pub fn query(&self) -> Option<&Query> {
+    match self {
+      Statement::Query(query) | Statement::Explain { query, .. } => Some(query),
+      Statement::CreateIndex { .. } => None,
+    }
+  }
+
+  pub fn query_mut(&mut self) -> Option<&mut Query> {
+    match self {
+      Statement::Query(query) | Statement::Explain { query, .. } => Some(query),
+      Statement::CreateIndex { .. } => None,
+    }
+  }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct IndexedQuery {
   pub selection: IndexedSelection,
   pub tables: Vec<Table>,
+  /// For each table in `tables`, its index in the catalog the query was
+  /// bound against.
+  pub sources: Vec<usize>,
   pub filter: IndexedFilter,
+  pub order_by: Vec<IndexedOrderKey>,
+  pub limit: Option<Limit>,
 }
 
 mod display {
@@ -105,11 +271,23 @@ mod display {
           BinaryOp::Eq => "==",
           BinaryOp::Lt => "<",
           BinaryOp::Like => "LIKE",
+          BinaryOp::SimilarTo => "SIMILAR TO",
+          BinaryOp::Regexp => "REGEXP",
         }
       )
     }
   }
 
+  impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+        Param::Anonymous => write!(f, "?"),
+        Param::Positional(n) => write!(f, "${}", n),
+        Param::Named(x) => write!(f, ":{}", x),
+      }
+    }
+  }
+
   impl fmt::Display for Filter {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
       match self {
@@ -118,7 +296,20 @@ mod display {
         Filter::LitB(b) => write!(f, "{}", b),
         Filter::LitI(n) => write!(f, "{}", n),
         Filter::UnaryOp(op, e) => write!(f, "({} {})", op, e),
-        Filter::BinaryOp(op, e1, e2) => write!(f, "({} {} {})", e1, op, e2),
+        Filter::BinaryOp(op, e1, e2, _) => write!(f, "({} {} {})", e1, op, e2),
+        // Rows are counted with `count(*)`, which is parsed as `count()`.
+        Filter::Call(name, args, _) if args.is_empty() && name.eq_ignore_ascii_case("count") => write!(f, "{}(*)", name),
+        Filter::Call(name, args, _) => write!(
+          f,
+          "{}({})",
+          name,
+          args
+            .iter()
+            .map(|a| format!("{}", a))
+            .collect::<Vec<_>>()
+            .join(", ")
+        ),
+        Filter::Param(p, _) => write!(f, "{}", p),
       }
     }
   }
@@ -180,14 +371,74 @@ mod display {
           .collect::<Vec<_>>()
           .join(", "),
         self.filter
-      )
+      )?;
+      if !self.order_by.is_empty() {
+        let keys: Vec<String> = self.order_by.iter().map(|k| format!("{}", k)).collect();
+        write!(f, "\nORDER BY {}", keys.join(", "))?;
+      }
+      if let Some(limit) = &self.limit {
+        write!(f, "\n{}", limit)?;
+      }
+      Ok(())
+    }
+  }
+
+  impl fmt::Display for OrderKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "{}", self.expr)?;
+      if self.descending {
+        write!(f, " DESC")?;
+      }
+      Ok(())
+    }
+  }
+
+  impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "LIMIT {}", self.count)?;
+      if self.offset > 0 {
+        write!(f, " OFFSET {}", self.offset)?;
+      }
+      Ok(())
+    }
+  }
+
+  impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+        Statement::Query(query) => write!(f, "{}", query),
+        Statement::Explain { query, analyze, format } => {
+          write!(f, "EXPLAIN ")?;
+          if *analyze {
+            write!(f, "ANALYZE ")?;
+          }
+          if *format == ExplainFormat::Dot {
+            write!(f, "FORMAT DOT ")?;
+          }
+          write!(f, "{}", query)
+        }
+        Statement::CreateIndex {
+          name,
+          table,
+          column,
+          kind: IndexKind::BTree,
+        } => write!(f, "CREATE INDEX {} ON {}({})", name, table, column),
+        Statement::CreateIndex {
+          name,
+          table,
+          column,
+          kind: IndexKind::Hash,
+        } => write!(f, "CREATE INDEX {} ON {} USING HASH ({})", name, table, column),
+      }
     }
   }
 }
 
 #[cfg(test)]
-mod generators {
+pub(crate) mod generators {
   use super::*;
+  use crate::functions::FunctionRegistry;
+  use crate::tables::TableCell;
   use quickcheck::Arbitrary;
   use quickcheck::Gen;
 
@@ -195,6 +446,11 @@ mod generators {
     *g.choose(&('a'..='z').collect::<Vec<char>>()).unwrap()
   }
 
+  fn gen_ident_char(g: &mut Gen) -> char {
+    *g.choose(&('a'..='z').chain(Some('_')).collect::<Vec<char>>())
+      .unwrap()
+  }
+
   fn gen_list<T, F: Fn(&mut Gen) -> T>(g: &mut Gen, f: F) -> Vec<T> {
     let n = *g.choose(&(0..=g.size()).collect::<Vec<_>>()).unwrap();
     let mut v = vec![];
@@ -208,10 +464,15 @@ mod generators {
     gen_list(g, f).into_iter().collect()
   }
 
+  const KEYWORDS: &[&str] = &[
+    "true", "false", "not", "like", "similar", "regexp", "as", "select", "from", "where", "order",
+    "by", "asc", "desc", "limit", "offset",
+  ];
+
   fn gen_ident(g: &mut Gen) -> String {
     loop {
-      let s = gen_string(g, gen_alpha);
-      if !s.is_empty() {
+      let s = gen_string(g, gen_ident_char);
+      if s.starts_with(|c: char| c.is_alphabetic()) && !KEYWORDS.contains(&s.as_str()) {
         return s;
       }
     }
@@ -221,17 +482,38 @@ mod generators {
     gen_string(g, gen_alpha) // TODO: This should be more interesting
   }
 
+  fn gen_call(g: &mut Gen) -> Filter {
+    let n = g.size();
+    Filter::Call(
+      gen_ident(g),
+      gen_list(&mut Gen::new(n / 2), |_| Filter::arbitrary(&mut Gen::new(n / 2))),
+      Span::default(),
+    )
+  }
+
   fn gen_lit(g: &mut Gen) -> Filter {
-    match g.choose(&[0, 1, 2, 3]).unwrap() {
+    match g.choose(&[0, 1, 2, 3, 4]).unwrap() {
       0 => Filter::Id(ColumnSelector::arbitrary(g)),
       1 => Filter::LitS(gen_string_lit(g)),
       // TODO: Known issue, our parser doesn't handle huge things well
       2 => Filter::LitI(i32::arbitrary(g) as i64),
       3 => Filter::LitB(bool::arbitrary(g)),
+      4 => Filter::Param(Param::arbitrary(g), Span::default()),
       _ => unreachable!(),
     }
   }
 
+  impl Arbitrary for Param {
+    fn arbitrary(g: &mut Gen) -> Self {
+      match g.choose(&[0, 1, 2]).unwrap() {
+        0 => Param::Anonymous,
+        1 => Param::Positional(u16::arbitrary(g) as usize),
+        2 => Param::Named(gen_ident(g)),
+        _ => unreachable!(),
+      }
+    }
+  }
+
   impl Arbitrary for UnaryOp {
     fn arbitrary(_g: &mut Gen) -> Self {
       UnaryOp::Not
@@ -246,6 +528,8 @@ mod generators {
         BinaryOp::Eq,
         BinaryOp::Lt,
         BinaryOp::Like,
+        BinaryOp::SimilarTo,
+        BinaryOp::Regexp,
       ])
       .unwrap()
     }
@@ -257,7 +541,7 @@ mod generators {
       if n <= 1 {
         gen_lit(g)
       } else {
-        match g.choose(&[0, 1, 2, 3, 4, 5]).unwrap() {
+        match g.choose(&[0, 1, 2, 3, 4, 5, 6]).unwrap() {
           0..=3 => gen_lit(g),
           4 => Filter::UnaryOp(
             UnaryOp::arbitrary(g),
@@ -267,7 +551,9 @@ mod generators {
             BinaryOp::arbitrary(g),
             Box::new(Filter::arbitrary(&mut Gen::new(n / 2))),
             Box::new(Filter::arbitrary(&mut Gen::new(n / 2))),
+            Span::default(),
           ),
+          6 => gen_call(g),
           _ => unreachable!(),
         }
       }
@@ -283,6 +569,7 @@ mod generators {
           Some(gen_ident(g))
         },
         field: gen_ident(g),
+        span: Span::default(),
       }
     }
   }
@@ -293,7 +580,7 @@ mod generators {
         Selection::Star
       } else {
         let mut v = Vec::arbitrary(g);
-        v.push(ColumnSelector::arbitrary(g));
+        v.push(Filter::arbitrary(g));
         Selection::Columns(v)
       }
     }
@@ -308,6 +595,7 @@ mod generators {
         } else {
           Some(gen_ident(g))
         },
+        span: Span::default(),
       }
     }
   }
@@ -320,7 +608,66 @@ mod generators {
         selection: Selection::arbitrary(g),
         tables: v,
         filter: Filter::arbitrary(g),
+        order_by: (0..usize::arbitrary(g) % 3)
+          .map(|_| OrderKey {
+            expr: Filter::arbitrary(g),
+            descending: bool::arbitrary(g),
+          })
+          .collect(),
+        limit: Option::<(u32, u16)>::arbitrary(g).map(|(count, offset)| Limit {
+          count: count as u64,
+          offset: offset as u64,
+        }),
+      }
+    }
+  }
+
+  /// Binds a generated expression to a table of three columns, keeping
+  /// the literals few so that they meet the values in the rows.
+  pub(crate) fn bind(f: &Filter, functions: &FunctionRegistry) -> IndexedFilter {
+    match f {
+      Filter::Id(c) => IndexedFilter::Id(IndexedColumnSelector {
+        table: 0,
+        field: c.field.len() % 3,
+      }),
+      Filter::LitS(s) => IndexedFilter::LitS(s.chars().take(1).collect()),
+      Filter::LitB(b) => IndexedFilter::LitB(*b),
+      Filter::LitI(n) => IndexedFilter::LitI(n % 3),
+      Filter::Param(p, _) => IndexedFilter::Param(p.clone()),
+      Filter::UnaryOp(op, e) => IndexedFilter::UnaryOp(*op, Box::new(bind(e, functions))),
+      Filter::BinaryOp(BinaryOp::Regexp | BinaryOp::SimilarTo, e, _, _) => IndexedFilter::Regex(
+        RegexOp::Matches,
+        Pattern(Regex::new("^a").unwrap()),
+        vec![bind(e, functions)],
+      ),
+      Filter::BinaryOp(op, l, r, _) => IndexedFilter::BinaryOp(*op, Box::new(bind(l, functions)), Box::new(bind(r, functions))),
+      Filter::Call(name, args, _) => {
+        let arg = args.first().map_or(IndexedFilter::LitS("ab".to_owned()), |a| bind(a, functions));
+        match name.len() % 4 {
+          3 => IndexedFilter::Regex(RegexOp::Extract, Pattern(Regex::new("(a)(b)?").unwrap()), vec![arg]),
+          n => IndexedFilter::Call(functions.scalar(["length", "upper", "abs"][n]).unwrap().clone(), vec![arg]),
+        }
       }
     }
   }
+
+  /// A row of three cells, each null, a small integer or a short string.
+  #[derive(Debug, Clone)]
+  pub(crate) struct TestRow(pub Vec<Option<TableCell>>);
+
+  impl Arbitrary for TestRow {
+    fn arbitrary(g: &mut Gen) -> Self {
+      let cells = [
+        None,
+        Some(TableCell::CellInt(-1)),
+        Some(TableCell::CellInt(0)),
+        Some(TableCell::CellInt(1)),
+        Some(TableCell::CellInt(2)),
+        Some(TableCell::CellString(String::new())),
+        Some(TableCell::CellString("a".to_owned())),
+        Some(TableCell::CellString("b".to_owned())),
+      ];
+      TestRow((0..3).map(|_| g.choose(&cells).unwrap().clone()).collect())
+    }
+  }
 }
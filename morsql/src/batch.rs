@@ -0,0 +1,744 @@
+//! Columnar, vectorized execution over tables held in memory.
+//!
+//! `TableData` keeps a table as rows of boxed cells. A `ColumnarTable`
+//! keeps each column as a vector of its values, typed when all of them have
+//! the same type, with a bitmap of which of them are not null. Scans over it
+//! work a batch of `BATCH_ROWS` rows at a time: a filter narrows a selection
+//! vector, the positions of the rows of the batch still selected, one
+//! condition at a time, reading only the columns that condition compares;
+//! and a projection gathers the selected values a column at a time. Rows
+//! are only built for the tuples a scan produces.
+//!
+//! Conditions the kernels here do not cover are evaluated compiled on the
+//! selected rows, so a filter selects exactly the rows `valid_row` accepts.
+//!
+//! The command line runs a query this way when every table it reads is a
+//! columnar file, loading them whole.
+
+use crate::ast::{BinaryOp, IndexedFilter, IndexedQuery, IndexedSelection, UnaryOp};
+use crate::compile::CompiledFilter;
+use crate::error::Result;
+use crate::exec::{Config, Execution, Operator, Tuple};
+use crate::stats::{StatsCollector, TableStats};
+use crate::stream::{Row, Rows};
+use crate::tables::{TableCell, TableData};
+use std::collections::VecDeque;
+
+/// The number of rows a scan reads at a time.
+pub const BATCH_ROWS: usize = 1024;
+
+/// A bit per row.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Bitmap {
+  words: Vec<u64>,
+  len: usize,
+}
+
+impl Bitmap {
+  pub fn push(&mut self, bit: bool) {
+    if self.len == self.words.len() * 64 {
+      self.words.push(0);
+    }
+    if bit {
+      self.words[self.len / 64] |= 1 << (self.len % 64);
+    }
+    self.len += 1;
+  }
+
+  pub fn get(&self, i: usize) -> bool {
+    self.words[i / 64] >> (i % 64) & 1 == 1
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+}
+
+/// The values of a column. Null rows hold a placeholder.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Values {
+  Int(Vec<i64>),
+  Str(Vec<String>),
+  /// A column with both integers and strings.
+  Mixed(Vec<TableCell>),
+}
+
+/// A value read in place from a column or a literal.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Value<'a> {
+  Int(i64),
+  Str(&'a str),
+}
+
+impl<'a> Value<'a> {
+  fn of(cell: &'a TableCell) -> Self {
+    match cell {
+      TableCell::CellInt(i) => Value::Int(*i),
+      TableCell::CellString(s) => Value::Str(s),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Column {
+  values: Values,
+  validity: Bitmap,
+}
+
+impl Column {
+  /// A column of the given cells, typed if they all have the same type.
+  pub fn of_cells<'c, I: Iterator<Item = &'c Option<TableCell>> + Clone>(cells: I) -> Self {
+    let mut validity = Bitmap::default();
+    for cell in cells.clone() {
+      validity.push(cell.is_some());
+    }
+    let ints = cells.clone().all(|c| !matches!(c, Some(TableCell::CellString(_))));
+    let strings = cells.clone().all(|c| !matches!(c, Some(TableCell::CellInt(_))));
+    let values = if ints {
+      Values::Int(
+        cells
+          .map(|c| match c {
+            Some(TableCell::CellInt(i)) => *i,
+            _ => 0,
+          })
+          .collect(),
+      )
+    } else if strings {
+      Values::Str(
+        cells
+          .map(|c| match c {
+            Some(TableCell::CellString(s)) => s.clone(),
+            _ => String::new(),
+          })
+          .collect(),
+      )
+    } else {
+      Values::Mixed(cells.map(|c| c.clone().unwrap_or(TableCell::CellInt(0))).collect())
+    };
+    Column { values, validity }
+  }
+
+  pub fn values(&self) -> &Values {
+    &self.values
+  }
+
+  pub fn is_valid(&self, i: usize) -> bool {
+    self.validity.get(i)
+  }
+
+  pub fn len(&self) -> usize {
+    self.validity.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.validity.is_empty()
+  }
+
+  /// The cell in row `i`.
+  pub fn get(&self, i: usize) -> Option<TableCell> {
+    if !self.is_valid(i) {
+      return None;
+    }
+    Some(match &self.values {
+      Values::Int(v) => TableCell::CellInt(v[i]),
+      Values::Str(v) => TableCell::CellString(v[i].clone()),
+      Values::Mixed(v) => v[i].clone(),
+    })
+  }
+
+  fn value(&self, i: usize) -> Option<Value<'_>> {
+    if !self.is_valid(i) {
+      return None;
+    }
+    Some(match &self.values {
+      Values::Int(v) => Value::Int(v[i]),
+      Values::Str(v) => Value::Str(&v[i]),
+      Values::Mixed(v) => Value::of(&v[i]),
+    })
+  }
+}
+
+/// A table stored a column at a time.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ColumnarTable {
+  pub header: Vec<String>,
+  columns: Vec<Column>,
+  rows: usize,
+}
+
+impl ColumnarTable {
+  pub fn of_table(table: &TableData) -> Self {
+    let columns = (0..table.header.len())
+      .map(|field| Column::of_cells(table.rows.iter().map(move |row| &row[field])))
+      .collect();
+    ColumnarTable {
+      header: table.header.clone(),
+      columns,
+      rows: table.rows.len(),
+    }
+  }
+
+  pub fn to_table(&self) -> TableData {
+    TableData {
+      header: self.header.clone(),
+      rows: (0..self.rows).map(|i| self.row(i)).collect(),
+    }
+  }
+
+  pub fn columns(&self) -> &[Column] {
+    &self.columns
+  }
+
+  pub fn len(&self) -> usize {
+    self.rows
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.rows == 0
+  }
+
+  /// Row `i`, built from its columns.
+  pub fn row(&self, i: usize) -> Row {
+    self.columns.iter().map(|c| c.get(i)).collect()
+  }
+
+  /// The rows of the table, a row at a time.
+  pub fn rows(&self) -> Rows<'_> {
+    Box::new((0..self.rows).map(move |i| Ok(self.row(i))))
+  }
+
+  pub fn stats(&self) -> TableStats {
+    let mut collector = StatsCollector::new(self.columns.len());
+    for i in 0..self.rows {
+      collector.add(&self.row(i));
+    }
+    collector.finish()
+  }
+}
+
+impl From<&TableData> for ColumnarTable {
+  fn from(table: &TableData) -> Self {
+    ColumnarTable::of_table(table)
+  }
+}
+
+impl From<&ColumnarTable> for TableData {
+  fn from(table: &ColumnarTable) -> Self {
+    table.to_table()
+  }
+}
+
+/// The positions of the selected rows of a batch, in order.
+pub type Selection = Vec<usize>;
+
+/// A comparison, with a column on the left whenever either side is one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cmp {
+  Eq,
+  Lt,
+  Gt,
+}
+
+impl Cmp {
+  fn holds(self, l: Value, r: Value) -> bool {
+    match (self, l, r) {
+      (Cmp::Eq, l, r) => l == r,
+      (Cmp::Lt, Value::Int(l), Value::Int(r)) => l < r,
+      (Cmp::Gt, Value::Int(l), Value::Int(r)) => l > r,
+      _ => false,
+    }
+  }
+}
+
+enum Operand {
+  Column(usize),
+  Literal(TableCell),
+}
+
+impl Operand {
+  fn of(e: &IndexedFilter) -> Option<Self> {
+    match e {
+      IndexedFilter::Id(id) => Some(Operand::Column(id.field)),
+      IndexedFilter::LitI(i) => Some(Operand::Literal(TableCell::CellInt(*i))),
+      IndexedFilter::LitB(b) => Some(Operand::Literal(TableCell::CellInt(*b as i64))),
+      IndexedFilter::LitS(s) => Some(Operand::Literal(TableCell::CellString(s.clone()))),
+      _ => None,
+    }
+  }
+
+  fn value<'a>(&'a self, table: &'a ColumnarTable, i: usize) -> Option<Value<'a>> {
+    match self {
+      Operand::Column(field) => table.columns[*field].value(i),
+      Operand::Literal(cell) => Some(Value::of(cell)),
+    }
+  }
+}
+
+/// A comparison of two operands, or with `negated` its negation. Either way
+/// it is null when an operand is.
+struct Comparison {
+  cmp: Cmp,
+  left: Operand,
+  right: Operand,
+  negated: bool,
+}
+
+impl Comparison {
+  fn of(e: &IndexedFilter) -> Option<Self> {
+    match e {
+      IndexedFilter::UnaryOp(UnaryOp::Not, e) => Comparison::of(e).map(|c| Comparison {
+        negated: !c.negated,
+        ..c
+      }),
+      IndexedFilter::BinaryOp(op @ (BinaryOp::Eq | BinaryOp::Lt), l, r) => {
+        let (l, r) = (Operand::of(l)?, Operand::of(r)?);
+        let cmp = if *op == BinaryOp::Eq { Cmp::Eq } else { Cmp::Lt };
+        Some(match (l, r) {
+          (l @ Operand::Literal(_), r @ Operand::Column(_)) => Comparison {
+            cmp: if cmp == Cmp::Lt { Cmp::Gt } else { cmp },
+            left: r,
+            right: l,
+            negated: false,
+          },
+          (left, right) => Comparison {
+            cmp,
+            left,
+            right,
+            negated: false,
+          },
+        })
+      }
+      _ => None,
+    }
+  }
+
+  fn test(&self, table: &ColumnarTable, i: usize) -> Option<bool> {
+    let l = self.left.value(table, i)?;
+    let r = self.right.value(table, i)?;
+    Some(self.cmp.holds(l, r) != self.negated)
+  }
+
+  /// Keeps the selected rows satisfying the comparison, reading integer
+  /// columns compared with a literal directly.
+  fn select(&self, table: &ColumnarTable, selection: &mut Selection) {
+    if let (Operand::Column(field), Operand::Literal(TableCell::CellInt(k))) = (&self.left, &self.right) {
+      let column = &table.columns[*field];
+      if let Values::Int(values) = &column.values {
+        let (k, negated) = (*k, self.negated);
+        let mut retain = |holds: &dyn Fn(i64) -> bool| {
+          selection.retain(|&i| column.is_valid(i) && holds(values[i]) != negated)
+        };
+        match self.cmp {
+          Cmp::Eq => retain(&|v| v == k),
+          Cmp::Lt => retain(&|v| v < k),
+          Cmp::Gt => retain(&|v| v > k),
+        }
+        return;
+      }
+    }
+    selection.retain(|&i| self.test(table, i) == Some(true));
+  }
+}
+
+/// One conjunct of a filter.
+enum Kernel {
+  /// Comparisons joined by OR, which is null if any of them is.
+  Any(Vec<Comparison>),
+  /// Any other condition, evaluated on the rows.
+  Rows(CompiledFilter),
+}
+
+impl Kernel {
+  fn of(e: &IndexedFilter) -> Self {
+    fn disjuncts(e: &IndexedFilter, out: &mut Vec<Comparison>) -> Option<()> {
+      match e {
+        IndexedFilter::BinaryOp(BinaryOp::Or, l, r) => {
+          disjuncts(l, out)?;
+          disjuncts(r, out)
+        }
+        e => {
+          out.push(Comparison::of(e)?);
+          Some(())
+        }
+      }
+    }
+    let mut comparisons = vec![];
+    match disjuncts(e, &mut comparisons) {
+      Some(()) => Kernel::Any(comparisons),
+      None => Kernel::Rows(CompiledFilter::new(e)),
+    }
+  }
+}
+
+/// A predicate over the table in one slot, compiled for selecting rows a
+/// batch at a time.
+pub struct BatchFilter {
+  slot: usize,
+  width: usize,
+  kernels: Vec<Kernel>,
+}
+
+impl BatchFilter {
+  /// `width` is the number of tables in the query and `slot` the position
+  /// of the one the predicate reads.
+  pub fn new(predicate: &IndexedFilter, slot: usize, width: usize) -> Self {
+    BatchFilter {
+      slot,
+      width,
+      kernels: predicate.conjuncts().into_iter().map(Kernel::of).collect(),
+    }
+  }
+
+  /// Keeps the selected rows of `table` satisfying the predicate. The
+  /// conjuncts narrow the selection in turn, so each reads only the rows
+  /// the ones before it kept.
+  pub fn select(&self, table: &ColumnarTable, selection: &mut Selection) {
+    for kernel in &self.kernels {
+      match kernel {
+        Kernel::Any(comparisons) => match comparisons.as_slice() {
+          [comparison] => comparison.select(table, selection),
+          _ => selection.retain(|&i| {
+            let mut any = false;
+            for c in comparisons {
+              match c.test(table, i) {
+                Some(holds) => any |= holds,
+                None => return false,
+              }
+            }
+            any
+          }),
+        },
+        Kernel::Rows(filter) => {
+          let mut tuple = vec![vec![]; self.width];
+          selection.retain(|&i| {
+            tuple[self.slot] = table.row(i);
+            filter.matches(&tuple)
+          });
+        }
+      }
+      if selection.is_empty() {
+        break;
+      }
+    }
+  }
+}
+
+enum Output {
+  /// A column of the table, copied.
+  Gather(usize),
+  /// Any other expression, evaluated on the rows.
+  Compute(CompiledFilter),
+}
+
+/// A selection over the table in one slot, computed a batch at a time.
+pub struct BatchProjection {
+  slot: usize,
+  width: usize,
+  outputs: Vec<Output>,
+}
+
+impl BatchProjection {
+  /// The selection must be `Star` or columns; `width` and `slot` are as for
+  /// `BatchFilter`.
+  pub fn new(selection: &IndexedSelection, table: &ColumnarTable, slot: usize, width: usize) -> Self {
+    let outputs = match selection {
+      IndexedSelection::Star => (0..table.columns.len()).map(Output::Gather).collect(),
+      IndexedSelection::Columns(cols) => cols
+        .iter()
+        .map(|col| match &col.expr {
+          IndexedFilter::Id(id) => Output::Gather(id.field),
+          e => Output::Compute(CompiledFilter::new(e)),
+        })
+        .collect(),
+      IndexedSelection::Aggregates(_) => panic!("aggregates do not produce a value per row"),
+    };
+    BatchProjection { slot, width, outputs }
+  }
+
+  /// The result rows for the selected rows of `table`, each output column
+  /// computed for all of them before the next.
+  pub fn project(&self, table: &ColumnarTable, selection: &[usize]) -> Vec<Row> {
+    let mut tuples: Vec<Tuple> = vec![];
+    if self.outputs.iter().any(|o| matches!(o, Output::Compute(_))) {
+      tuples = selection
+        .iter()
+        .map(|&i| {
+          let mut tuple = vec![vec![]; self.width];
+          tuple[self.slot] = table.row(i);
+          tuple
+        })
+        .collect();
+    }
+    let mut rows: Vec<Row> = selection.iter().map(|_| Vec::with_capacity(self.outputs.len())).collect();
+    for output in &self.outputs {
+      match output {
+        Output::Gather(field) => {
+          let column = &table.columns[*field];
+          for (row, &i) in rows.iter_mut().zip(selection) {
+            row.push(column.get(i));
+          }
+        }
+        Output::Compute(e) => {
+          for (row, tuple) in rows.iter_mut().zip(&tuples) {
+            row.push(e.eval(tuple));
+          }
+        }
+      }
+    }
+    rows
+  }
+}
+
+/// Scans a columnar table a batch at a time, filtering and projecting each
+/// batch before producing its tuples.
+pub struct BatchScan<'a> {
+  table: &'a ColumnarTable,
+  slot: usize,
+  width: usize,
+  filter: Option<BatchFilter>,
+  projection: Option<BatchProjection>,
+  /// The first row of the next batch.
+  next_row: usize,
+  ready: VecDeque<Tuple>,
+}
+
+impl<'a> BatchScan<'a> {
+  /// Without a projection, the tuples hold the selected rows in `slot`;
+  /// with one, they hold the result row in a single slot, as `Project`'s
+  /// do.
+  pub fn new(
+    table: &'a ColumnarTable,
+    slot: usize,
+    width: usize,
+    filter: Option<BatchFilter>,
+    projection: Option<BatchProjection>,
+  ) -> Self {
+    BatchScan {
+      table,
+      slot,
+      width,
+      filter,
+      projection,
+      next_row: 0,
+      ready: VecDeque::new(),
+    }
+  }
+
+  fn read_batch(&mut self) {
+    let end = (self.next_row + BATCH_ROWS).min(self.table.len());
+    let mut selection: Selection = (self.next_row..end).collect();
+    self.next_row = end;
+    if let Some(filter) = &self.filter {
+      filter.select(self.table, &mut selection);
+    }
+    match &self.projection {
+      Some(projection) => {
+        let rows = projection.project(self.table, &selection);
+        self.ready.extend(rows.into_iter().map(|row| vec![row]));
+      }
+      None => {
+        for i in selection {
+          let mut tuple = vec![vec![]; self.width];
+          tuple[self.slot] = self.table.row(i);
+          self.ready.push_back(tuple);
+        }
+      }
+    }
+  }
+}
+
+impl Operator for BatchScan<'_> {
+  fn open(&mut self) -> Result<()> {
+    self.next_row = 0;
+    self.ready.clear();
+    Ok(())
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    while self.ready.is_empty() && self.next_row < self.table.len() {
+      self.read_batch();
+    }
+    Ok(self.ready.pop_front())
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.ready.clear();
+    Ok(())
+  }
+}
+
+impl IndexedQuery {
+  /// Runs the query as `run` does, over the tables of the catalog stored a
+  /// column at a time.
+  pub fn run_columnar(&self, tables: &[ColumnarTable]) -> Result<TableData> {
+    let catalog: Vec<TableData> = tables
+      .iter()
+      .map(|t| TableData {
+        header: t.header.clone(),
+        rows: vec![],
+      })
+      .collect();
+    let stats: Vec<TableStats> = tables.iter().map(ColumnarTable::stats).collect();
+    let sources = self.sources.iter().map(|i| &tables[*i]).collect();
+    Ok(TableData {
+      header: self.header(&catalog),
+      rows: self
+        .stream_columnar(&catalog, &stats, sources, &Config::default())?
+        .collect::<Result<_>>()?,
+    })
+  }
+
+  /// Runs the query as `stream_with` does, over tables stored a column at
+  /// a time, given in the order of its FROM clause. Scans filter and
+  /// project their tables a batch at a time.
+  pub fn stream_columnar<'a>(
+    &self,
+    catalog: &[TableData],
+    stats: &[TableStats],
+    sources: Vec<&'a ColumnarTable>,
+    config: &Config,
+  ) -> Result<Rows<'a>> {
+    let root = self.plan(catalog, stats, config).lower_columnar(config, &sources);
+    Ok(Box::new(Execution::new(root)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ast::generators::{bind, TestRow};
+  use crate::ast::Filter;
+  use crate::functions::FunctionRegistry;
+  use crate::parser::parse_sql;
+  use std::fs::File;
+
+  #[test]
+  fn round_trips() {
+    let int = |i| Some(TableCell::CellInt(i));
+    let string = |s: &str| Some(TableCell::CellString(s.to_owned()));
+    let table = TableData {
+      header: vec!["n".to_owned(), "s".to_owned(), "any".to_owned(), "none".to_owned()],
+      rows: (0..200)
+        .map(|i| {
+          vec![
+            if i % 7 == 0 { None } else { int(i) },
+            if i % 5 == 0 { None } else { string(&format!("s{}", i)) },
+            if i % 2 == 0 { int(-i) } else { string("x") },
+            None,
+          ]
+        })
+        .collect(),
+    };
+    let columnar = ColumnarTable::of_table(&table);
+    let types: Vec<&str> = columnar
+      .columns()
+      .iter()
+      .map(|c| match c.values() {
+        Values::Int(_) => "int",
+        Values::Str(_) => "string",
+        Values::Mixed(_) => "mixed",
+      })
+      .collect();
+    assert_eq!(types, vec!["int", "string", "mixed", "int"]);
+    assert!(!columnar.columns()[0].is_valid(7) && columnar.columns()[0].is_valid(8));
+    assert_eq!(columnar.len(), 200);
+    assert_eq!(TableData::from(&columnar), table);
+    assert_eq!(columnar.stats(), TableStats::of(&table));
+  }
+
+  #[quickcheck]
+  fn prop_selects_valid_rows(f: Filter, rows: Vec<TestRow>) -> bool {
+    let e = bind(&f, &FunctionRegistry::with_builtins());
+    let table = TableData {
+      header: vec!["a".to_owned(), "b".to_owned(), "c".to_owned()],
+      rows: rows.into_iter().map(|r| r.0).collect(),
+    };
+    let mut selection: Selection = (0..table.rows.len()).collect();
+    BatchFilter::new(&e, 0, 1).select(&ColumnarTable::of_table(&table), &mut selection);
+    let expected: Selection = (0..table.rows.len())
+      .filter(|i| e.valid_row(&vec![table.rows[*i].clone()]))
+      .collect();
+    selection == expected
+  }
+
+  #[test]
+  fn kernels_select_valid_rows() {
+    let cell = |i: i64, s: &str| match i % 4 {
+      0 => None,
+      1 => Some(TableCell::CellString(s.to_owned())),
+      _ => Some(TableCell::CellInt(i % 5)),
+    };
+    let table = TableData {
+      header: vec!["n".to_owned(), "s".to_owned(), "m".to_owned()],
+      rows: (0..3000)
+        .map(|i| {
+          vec![
+            if i % 6 == 0 { None } else { Some(TableCell::CellInt(i % 7 - 2)) },
+            if i % 5 == 0 { None } else { Some(TableCell::CellString(["a", "b", "c"][i as usize % 3].to_owned())) },
+            cell(i / 3, "b"),
+          ]
+        })
+        .collect(),
+    };
+    let columnar = ColumnarTable::of_table(&table);
+    for predicate in [
+      "n < 3",
+      "3 < n",
+      "n == 2",
+      "NOT (n < 0)",
+      "NOT (2 == n) && NOT NOT (0 < n)",
+      "n < 1 || s == \"b\" || 3 < n",
+      "n == m",
+      "m < 2 || 1 < m",
+      "m == \"b\" || NOT (s == m)",
+      "n < 4 && NOT (s == \"a\") && m == 3",
+      "length(s) == 1 || n",
+    ] {
+      let e = parse_sql(&format!("SELECT * FROM t WHERE {}", predicate))
+        .unwrap()
+        .to_indexed(std::slice::from_ref(&table), &["t".to_owned()], &FunctionRegistry::with_builtins())
+        .unwrap()
+        .filter;
+      let mut selection: Selection = (0..table.rows.len()).collect();
+      BatchFilter::new(&e, 0, 1).select(&columnar, &mut selection);
+      let expected: Selection = (0..table.rows.len())
+        .filter(|i| e.valid_row(&vec![table.rows[*i].clone()]))
+        .collect();
+      assert!(!expected.is_empty(), "{}", predicate);
+      assert_eq!(selection, expected, "{}", predicate);
+    }
+  }
+
+  #[test]
+  fn same_results_as_rows() {
+    let names = vec!["user".to_owned(), "user1".to_owned()];
+    let mut catalog: Vec<TableData> = names
+      .iter()
+      .map(|n| TableData::of_file(&File::open(format!("examples/{}.csv", n)).unwrap()).unwrap())
+      .collect();
+    // Enough rows for several batches.
+    let rows = catalog[0].rows.clone();
+    for _ in 0..BATCH_ROWS * 3 / rows.len() {
+      catalog[0].rows.extend(rows.iter().cloned());
+    }
+    let columnar: Vec<ColumnarTable> = catalog.iter().map(ColumnarTable::of_table).collect();
+    for sql in [
+      "SELECT * FROM user WHERE 18 < age",
+      "SELECT name, id FROM user WHERE name == \"Harry\" || 30 < age && NOT (id == 2)",
+      "SELECT upper(name), age FROM user WHERE length(name) < 5 ORDER BY age DESC LIMIT 7",
+      "SELECT count(*), max(age) FROM user WHERE name REGEXP \"^[A-L]\"",
+      "SELECT u.name, rating FROM user AS u, user1 WHERE u.id == user1.id && 18 < u.age",
+      "SELECT * FROM user WHERE id == 1000",
+    ] {
+      let q = parse_sql(sql)
+        .unwrap()
+        .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+        .unwrap();
+      assert_eq!(q.run_columnar(&columnar).unwrap(), q.run(&catalog).unwrap(), "{}", sql);
+    }
+  }
+}
@@ -0,0 +1,583 @@
+//! A binary columnar format for tables, written whole and read a row group
+//! at a time.
+//!
+//! A file starts with the magic bytes `MORSQL01` and is followed by row
+//! groups of up to [`CHUNK_ROWS`] rows, each stored as one chunk per
+//! column. A chunk is a null bitmap followed by the values of its non-null
+//! rows, encoded by the column's type:
+//!
+//! * `int` columns store zigzag varints,
+//! * `string` columns store a dictionary of the distinct strings in the
+//!   chunk followed by a varint index per value,
+//! * `any` columns store a tag byte per value and then the value.
+//!
+//! The footer holds the schema and, for every chunk, its position, null
+//! count and the minimum and maximum of its values. The file ends with the
+//! footer's offset as 8 little-endian bytes and the magic bytes again.
+
+use crate::error::{Error, Result};
+use crate::functions::CellType;
+use crate::schema::{Column, Schema};
+use crate::tables::{TableCell, TableData};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const MAGIC: &[u8; 8] = b"MORSQL01";
+
+/// The number of rows in a row group.
+pub const CHUNK_ROWS: usize = 65_536;
+
+/// Where a column chunk is stored and what it contains.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ChunkMeta {
+  pub offset: u64,
+  pub len: u64,
+  pub null_count: u64,
+  /// The least and greatest values in the chunk, or `None` if all of them
+  /// are null.
+  pub min_max: Option<(TableCell, TableCell)>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RowGroup {
+  pub rows: u64,
+  pub columns: Vec<ChunkMeta>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Footer {
+  pub schema: Schema,
+  pub row_groups: Vec<RowGroup>,
+}
+
+fn corrupt<S: Into<String>>(message: S) -> Error {
+  Error::data_format(None, message)
+}
+
+pub(crate) fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+  while n >= 0x80 {
+    out.push((n as u8) | 0x80);
+    n >>= 7;
+  }
+  out.push(n as u8);
+}
+
+fn put_int(out: &mut Vec<u8>, n: i64) {
+  put_varint(out, ((n << 1) ^ (n >> 63)) as u64);
+}
+
+pub(crate) fn put_str(out: &mut Vec<u8>, s: &str) {
+  put_varint(out, s.len() as u64);
+  out.extend_from_slice(s.as_bytes());
+}
+
+pub(crate) fn put_cell(out: &mut Vec<u8>, cell: &TableCell) {
+  match cell {
+    TableCell::CellInt(n) => {
+      out.push(0);
+      put_int(out, *n);
+    }
+    TableCell::CellString(s) => {
+      out.push(1);
+      put_str(out, s);
+    }
+  }
+}
+
+/// Reads values back from a byte buffer.
+pub(crate) struct Bytes<'a> {
+  buf: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Bytes<'a> {
+  pub(crate) fn new(buf: &'a [u8]) -> Self {
+    Bytes { buf, pos: 0 }
+  }
+
+  fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+    if self.buf.len() - self.pos < n {
+      return Err(corrupt("the file is truncated"));
+    }
+    let bytes = &self.buf[self.pos..self.pos + n];
+    self.pos += n;
+    Ok(bytes)
+  }
+
+  pub(crate) fn byte(&mut self) -> Result<u8> {
+    Ok(self.take(1)?[0])
+  }
+
+  pub(crate) fn varint(&mut self) -> Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+      let b = self.byte()?;
+      n |= ((b & 0x7f) as u64) << shift;
+      if b < 0x80 {
+        return Ok(n);
+      }
+    }
+    Err(corrupt("a number is too long"))
+  }
+
+  fn int(&mut self) -> Result<i64> {
+    let n = self.varint()?;
+    Ok(((n >> 1) as i64) ^ -((n & 1) as i64))
+  }
+
+  pub(crate) fn usize(&mut self) -> Result<usize> {
+    Ok(self.varint()? as usize)
+  }
+
+  pub(crate) fn str(&mut self) -> Result<String> {
+    let len = self.usize()?;
+    String::from_utf8(self.take(len)?.to_vec()).map_err(|_| corrupt("a string is not valid UTF-8"))
+  }
+
+  pub(crate) fn cell(&mut self) -> Result<TableCell> {
+    match self.byte()? {
+      0 => Ok(TableCell::CellInt(self.int()?)),
+      1 => Ok(TableCell::CellString(self.str()?)),
+      t => Err(corrupt(format!("unknown value tag {}", t))),
+    }
+  }
+}
+
+fn type_tag(ty: CellType) -> u8 {
+  match ty {
+    CellType::Int => 0,
+    CellType::String => 1,
+    CellType::Any => 2,
+  }
+}
+
+fn tag_type(tag: u8) -> Result<CellType> {
+  match tag {
+    0 => Ok(CellType::Int),
+    1 => Ok(CellType::String),
+    2 => Ok(CellType::Any),
+    t => Err(corrupt(format!("unknown column type {}", t))),
+  }
+}
+
+/// The schema that describes `data`: a column is an int or string column
+/// if all its values are, and nullable if any of them is null.
+pub fn schema_of(data: &TableData) -> Schema {
+  let columns = data
+    .header
+    .iter()
+    .enumerate()
+    .map(|(i, name)| {
+      let cells = || data.rows.iter().map(move |row| &row[i]);
+      let ty = if cells().all(|c| CellType::Int.admits(c)) {
+        CellType::Int
+      } else if cells().all(|c| CellType::String.admits(c)) {
+        CellType::String
+      } else {
+        CellType::Any
+      };
+      Column {
+        name: name.clone(),
+        ty,
+        nullable: cells().any(Option::is_none),
+      }
+    })
+    .collect();
+  Schema { columns }
+}
+
+fn encode_chunk(ty: CellType, cells: &[&Option<TableCell>]) -> Vec<u8> {
+  let mut out = vec![0u8; cells.len().div_ceil(8)];
+  for (i, cell) in cells.iter().enumerate() {
+    if cell.is_some() {
+      out[i / 8] |= 1 << (i % 8);
+    }
+  }
+  let values = cells.iter().filter_map(|c| c.as_ref());
+  match ty {
+    CellType::Int => {
+      for v in values {
+        if let TableCell::CellInt(n) = v {
+          put_int(&mut out, *n)
+        }
+      }
+    }
+    CellType::String => {
+      let mut dictionary: Vec<&str> = vec![];
+      let mut indices: HashMap<&str, usize> = HashMap::new();
+      let codes: Vec<usize> = values
+        .map(|v| match v {
+          TableCell::CellString(s) => *indices.entry(s).or_insert_with(|| {
+            dictionary.push(s);
+            dictionary.len() - 1
+          }),
+          TableCell::CellInt(_) => unreachable!("string columns only hold strings"),
+        })
+        .collect();
+      put_varint(&mut out, dictionary.len() as u64);
+      for s in dictionary {
+        put_str(&mut out, s);
+      }
+      for code in codes {
+        put_varint(&mut out, code as u64);
+      }
+    }
+    CellType::Any => {
+      for v in values {
+        put_cell(&mut out, v);
+      }
+    }
+  }
+  out
+}
+
+fn decode_chunk(ty: CellType, rows: usize, buf: &[u8]) -> Result<Vec<Option<TableCell>>> {
+  let mut bytes = Bytes::new(buf);
+  let bitmap = bytes.take(rows.div_ceil(8))?;
+  let present = |i: usize| bitmap[i / 8] & (1 << (i % 8)) != 0;
+  let dictionary = match ty {
+    CellType::String => {
+      let n = bytes.usize()?;
+      (0..n).map(|_| bytes.str()).collect::<Result<Vec<_>>>()?
+    }
+    _ => vec![],
+  };
+  (0..rows)
+    .map(|i| {
+      if !present(i) {
+        return Ok(None);
+      }
+      let cell = match ty {
+        CellType::Int => TableCell::CellInt(bytes.int()?),
+        CellType::String => {
+          let code = bytes.usize()?;
+          let s = dictionary.get(code).ok_or_else(|| corrupt("a string is not in the dictionary"))?;
+          TableCell::CellString(s.clone())
+        }
+        CellType::Any => bytes.cell()?,
+      };
+      Ok(Some(cell))
+    })
+    .collect()
+}
+
+/// Writes `data` in row groups of [`CHUNK_ROWS`] rows.
+pub fn write<W: Write>(data: &TableData, out: W) -> Result<()> {
+  write_chunked(data, out, CHUNK_ROWS)
+}
+
+pub fn write_chunked<W: Write>(data: &TableData, mut out: W, chunk_rows: usize) -> Result<()> {
+  let schema = schema_of(data);
+  out.write_all(MAGIC)?;
+  let mut offset = MAGIC.len() as u64;
+  let mut row_groups = vec![];
+  for rows in data.rows.chunks(chunk_rows.clamp(1, CHUNK_ROWS)) {
+    let mut columns = vec![];
+    for (i, column) in schema.columns.iter().enumerate() {
+      let cells: Vec<&Option<TableCell>> = rows.iter().map(|row| &row[i]).collect();
+      let bytes = encode_chunk(column.ty, &cells);
+      out.write_all(&bytes)?;
+      let values = cells.iter().filter_map(|c| c.as_ref());
+      columns.push(ChunkMeta {
+        offset,
+        len: bytes.len() as u64,
+        null_count: cells.iter().filter(|c| c.is_none()).count() as u64,
+        min_max: values.clone().min().cloned().zip(values.max().cloned()),
+      });
+      offset += bytes.len() as u64;
+    }
+    row_groups.push(RowGroup {
+      rows: rows.len() as u64,
+      columns,
+    });
+  }
+  let mut footer = vec![];
+  put_varint(&mut footer, schema.columns.len() as u64);
+  for column in &schema.columns {
+    put_str(&mut footer, &column.name);
+    footer.push(type_tag(column.ty));
+    footer.push(column.nullable as u8);
+  }
+  put_varint(&mut footer, row_groups.len() as u64);
+  for group in &row_groups {
+    put_varint(&mut footer, group.rows);
+    for chunk in &group.columns {
+      put_varint(&mut footer, chunk.offset);
+      put_varint(&mut footer, chunk.len);
+      put_varint(&mut footer, chunk.null_count);
+      match &chunk.min_max {
+        None => footer.push(0),
+        Some((min, max)) => {
+          footer.push(1);
+          put_cell(&mut footer, min);
+          put_cell(&mut footer, max);
+        }
+      }
+    }
+  }
+  out.write_all(&footer)?;
+  out.write_all(&offset.to_le_bytes())?;
+  out.write_all(MAGIC)?;
+  out.flush()?;
+  Ok(())
+}
+
+/// Reads the schema and chunk statistics without reading the data.
+pub fn read_footer<R: Read + Seek>(input: &mut R) -> Result<Footer> {
+  let mut magic = [0u8; 8];
+  input.seek(SeekFrom::Start(0))?;
+  input.read_exact(&mut magic).map_err(|_| corrupt("the file is too short"))?;
+  if &magic != MAGIC {
+    return Err(corrupt("the file is not in the columnar format"));
+  }
+  let end = input.seek(SeekFrom::End(-16)).map_err(|_| corrupt("the file is too short"))?;
+  let mut tail = [0u8; 16];
+  input.read_exact(&mut tail)?;
+  if &tail[8..] != MAGIC {
+    return Err(corrupt("the file is truncated"));
+  }
+  let mut offset = [0u8; 8];
+  offset.copy_from_slice(&tail[..8]);
+  let offset = u64::from_le_bytes(offset);
+  if offset < MAGIC.len() as u64 || offset > end {
+    return Err(corrupt("the footer offset is out of range"));
+  }
+  input.seek(SeekFrom::Start(offset))?;
+  let mut buf = vec![0u8; (end - offset) as usize];
+  input.read_exact(&mut buf)?;
+
+  let mut bytes = Bytes::new(&buf);
+  let n_columns = bytes.usize()?;
+  let columns = (0..n_columns)
+    .map(|_| {
+      Ok(Column {
+        name: bytes.str()?,
+        ty: tag_type(bytes.byte()?)?,
+        nullable: bytes.byte()? != 0,
+      })
+    })
+    .collect::<Result<Vec<_>>>()?;
+  let n_groups = bytes.usize()?;
+  let row_groups = (0..n_groups)
+    .map(|_| {
+      let rows = bytes.varint()?;
+      if rows > CHUNK_ROWS as u64 {
+        return Err(corrupt("a row group has too many rows"));
+      }
+      let columns = (0..n_columns)
+        .map(|_| {
+          let chunk = ChunkMeta {
+            offset: bytes.varint()?,
+            len: bytes.varint()?,
+            null_count: bytes.varint()?,
+            min_max: match bytes.byte()? {
+              0 => None,
+              _ => Some((bytes.cell()?, bytes.cell()?)),
+            },
+          };
+          // Chunks lie between the magic bytes and the footer, and start
+          // with a bit for each row.
+          let end = chunk.offset.checked_add(chunk.len);
+          if chunk.offset < MAGIC.len() as u64 || end.is_none_or(|end| end > offset) {
+            return Err(corrupt("a chunk is out of range"));
+          }
+          if chunk.len < rows.div_ceil(8) {
+            return Err(corrupt("a chunk is shorter than its row group"));
+          }
+          Ok(chunk)
+        })
+        .collect::<Result<Vec<_>>>()?;
+      Ok(RowGroup { rows, columns })
+    })
+    .collect::<Result<Vec<_>>>()?;
+  Ok(Footer {
+    schema: Schema { columns },
+    row_groups,
+  })
+}
+
+fn read_group<R: Read + Seek>(input: &mut R, schema: &Schema, group: &RowGroup) -> Result<Vec<Vec<Option<TableCell>>>> {
+  let n = group.rows as usize;
+  let mut rows: Vec<Vec<Option<TableCell>>> = (0..n).map(|_| Vec::with_capacity(group.columns.len())).collect();
+  for (column, chunk) in schema.columns.iter().zip(&group.columns) {
+    input.seek(SeekFrom::Start(chunk.offset))?;
+    let mut buf = vec![0u8; chunk.len as usize];
+    input.read_exact(&mut buf).map_err(|_| corrupt("the file is truncated"))?;
+    for (row, cell) in rows.iter_mut().zip(decode_chunk(column.ty, n, &buf)?) {
+      row.push(cell);
+    }
+  }
+  Ok(rows)
+}
+
+/// The rows of a table, read a row group at a time.
+pub struct Scan<R> {
+  pub footer: Footer,
+  input: R,
+  next_group: usize,
+  rows: std::vec::IntoIter<Vec<Option<TableCell>>>,
+}
+
+/// Reads the footer, leaving the rows to be read by iterating over the
+/// scan.
+pub fn scan<R: Read + Seek>(mut input: R) -> Result<Scan<R>> {
+  let footer = read_footer(&mut input)?;
+  Ok(Scan {
+    footer,
+    input,
+    next_group: 0,
+    rows: vec![].into_iter(),
+  })
+}
+
+impl<R: Read + Seek> Iterator for Scan<R> {
+  type Item = Result<Vec<Option<TableCell>>>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      if let Some(row) = self.rows.next() {
+        return Some(Ok(row));
+      }
+      let group = self.footer.row_groups.get(self.next_group)?;
+      self.next_group += 1;
+      match read_group(&mut self.input, &self.footer.schema, group) {
+        Ok(rows) => self.rows = rows.into_iter(),
+        Err(e) => {
+          self.next_group = self.footer.row_groups.len();
+          return Some(Err(e));
+        }
+      }
+    }
+  }
+}
+
+/// Reads a whole table.
+pub fn read<R: Read + Seek>(input: R) -> Result<TableData> {
+  let scan = scan(input)?;
+  Ok(TableData {
+    header: scan.footer.schema.names(),
+    rows: scan.collect::<Result<_>>()?,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn s(x: &str) -> Option<TableCell> {
+    Some(TableCell::CellString(x.to_owned()))
+  }
+
+  fn i(n: i64) -> Option<TableCell> {
+    Some(TableCell::CellInt(n))
+  }
+
+  fn sample() -> TableData {
+    TableData {
+      header: vec!["name".to_owned(), "age".to_owned(), "misc".to_owned()],
+      rows: vec![
+        vec![s("Lucas"), i(24), i(1)],
+        vec![s("Harry"), None, s("x")],
+        vec![s("Lucas"), i(-7), None],
+        vec![s(""), i(i64::MIN), i(i64::MAX)],
+        vec![None, i(0), s("é,\n")],
+      ],
+    }
+  }
+
+  #[test]
+  fn round_trip() {
+    for chunk_rows in [1, 2, 5, 100] {
+      let mut buf = vec![];
+      write_chunked(&sample(), &mut buf, chunk_rows).unwrap();
+      assert_eq!(read(Cursor::new(&buf)).unwrap(), sample());
+    }
+    let empty = TableData {
+      header: vec!["a".to_owned()],
+      rows: vec![],
+    };
+    let mut buf = vec![];
+    write(&empty, &mut buf).unwrap();
+    assert_eq!(read(Cursor::new(&buf)).unwrap(), empty);
+  }
+
+  #[test]
+  fn footer() {
+    let mut buf = vec![];
+    write_chunked(&sample(), &mut buf, 3).unwrap();
+    let footer = read_footer(&mut Cursor::new(&buf)).unwrap();
+    assert_eq!(format!("{}", footer.schema), "name string\nage int\nmisc any\n");
+    assert_eq!(footer.row_groups.len(), 2);
+    assert_eq!(footer.row_groups[0].rows, 3);
+    let age = &footer.row_groups[0].columns[1];
+    assert_eq!(age.null_count, 1);
+    assert_eq!(age.min_max, i(-7).zip(i(24)));
+    assert_eq!(footer.row_groups[1].columns[0].min_max, s("").zip(s("")));
+  }
+
+  #[test]
+  fn smaller_than_csv() {
+    let data = TableData {
+      header: vec!["city".to_owned(), "n".to_owned()],
+      rows: (0..1000)
+        .map(|n| vec![s(["Philadelphia", "New York", "San Jose"][n % 3]), i(n as i64)])
+        .collect(),
+    };
+    let mut buf = vec![];
+    write(&data, &mut buf).unwrap();
+    assert!(buf.len() * 3 < format!("{}", data).len());
+  }
+
+  #[test]
+  fn corrupt_files() {
+    let mut buf = vec![];
+    write(&sample(), &mut buf).unwrap();
+    assert!(read(Cursor::new(&buf[..buf.len() - 3])).is_err());
+    assert!(read(Cursor::new(b"name,age\n")).is_err());
+    let mut flipped = buf.clone();
+    flipped[8] ^= 0xff;
+    assert!(read(Cursor::new(&flipped)).map(|t| t != sample()).unwrap_or(true));
+
+    // A footer claiming huge chunks or row groups is rejected before
+    // anything is allocated for them.
+    let footer = read_footer(&mut Cursor::new(&buf)).unwrap();
+    let mut start = [0u8; 8];
+    start.copy_from_slice(&buf[buf.len() - 16..buf.len() - 8]);
+    let start = u64::from_le_bytes(start) as usize;
+    let chunk = &footer.row_groups[0].columns[0];
+    let rewrite = |f: &dyn Fn(&mut RowGroup)| {
+      let mut group = footer.row_groups[0].clone();
+      f(&mut group);
+      let mut footer_bytes = vec![];
+      put_varint(&mut footer_bytes, footer.schema.columns.len() as u64);
+      for column in &footer.schema.columns {
+        put_str(&mut footer_bytes, &column.name);
+        footer_bytes.push(type_tag(column.ty));
+        footer_bytes.push(column.nullable as u8);
+      }
+      put_varint(&mut footer_bytes, 1);
+      put_varint(&mut footer_bytes, group.rows);
+      for chunk in &group.columns {
+        put_varint(&mut footer_bytes, chunk.offset);
+        put_varint(&mut footer_bytes, chunk.len);
+        put_varint(&mut footer_bytes, chunk.null_count);
+        footer_bytes.push(0);
+      }
+      let mut file = buf[..start].to_vec();
+      file.extend_from_slice(&footer_bytes);
+      file.extend_from_slice(&(start as u64).to_le_bytes());
+      file.extend_from_slice(MAGIC);
+      read(Cursor::new(file))
+    };
+    let message = |f: &dyn Fn(&mut RowGroup)| match rewrite(f) {
+      Err(Error::DataFormat { message, .. }) => message,
+      r => panic!("expected a data error, got {:?}", r.map(|t| t.rows.len())),
+    };
+    assert_eq!(rewrite(&|_| ()).unwrap(), sample());
+    assert_eq!(message(&|g| g.columns[0].len = u64::MAX - 4), "a chunk is out of range");
+    assert_eq!(message(&|g| g.columns[1].offset = start as u64), "a chunk is out of range");
+    assert_eq!(message(&|g| g.rows = u64::MAX), "a row group has too many rows");
+    assert_eq!(message(&|g| g.rows = chunk.len * 8 + 1), "a chunk is shorter than its row group");
+  }
+}
@@ -0,0 +1,208 @@
+//! Compiled expressions. `IndexedFilter::compute_with_row` walks the
+//! expression tree for every row, cloning each cell it reads and each string
+//! literal. Compiling the expression once turns every operator into a
+//! closure over its compiled operands, chosen when the query is planned, and
+//! columns and literals into references: a comparison reads the cells it
+//! compares in place and allocates nothing. Only function calls and the
+//! strings built by regular expressions produce new cells.
+//!
+//! A compiled expression gives exactly the value `compute_with_row` does.
+
+use crate::ast::{BinaryOp, IndexedFilter, RegexOp, UnaryOp};
+use crate::stream::Row;
+use crate::tables::TableCell;
+use regex::Regex;
+use std::borrow::Cow;
+
+type Eval = Box<dyn Fn(&[Row]) -> Option<TableCell> + Send + Sync>;
+
+/// An expression compiled for evaluating on many tuples.
+pub struct CompiledFilter {
+  root: Node,
+}
+
+enum Node {
+  Column(usize, usize),
+  Literal(Option<TableCell>),
+  Computed(Eval),
+}
+
+impl Node {
+  fn eval<'a>(&'a self, t: &'a [Row]) -> Cow<'a, Option<TableCell>> {
+    match self {
+      Node::Column(table, field) => Cow::Borrowed(&t[*table][*field]),
+      Node::Literal(cell) => Cow::Borrowed(cell),
+      Node::Computed(f) => Cow::Owned(f(t)),
+    }
+  }
+}
+
+fn boolean(b: bool) -> Option<TableCell> {
+  Some(TableCell::CellInt(b as i64))
+}
+
+fn truthy(cell: &Option<TableCell>) -> bool {
+  matches!(cell, Some(TableCell::CellInt(i)) if *i != 0)
+}
+
+impl CompiledFilter {
+  pub fn new(e: &IndexedFilter) -> Self {
+    CompiledFilter { root: compile(e) }
+  }
+
+  /// The value of the expression for a tuple.
+  pub fn eval(&self, t: &[Row]) -> Option<TableCell> {
+    self.root.eval(t).into_owned()
+  }
+
+  /// Whether a tuple satisfies the expression, as `valid_row` decides.
+  pub fn matches(&self, t: &[Row]) -> bool {
+    truthy(&self.root.eval(t))
+  }
+}
+
+fn compile(e: &IndexedFilter) -> Node {
+  match e {
+    IndexedFilter::Id(id) => Node::Column(id.table, id.field),
+    IndexedFilter::LitB(b) => Node::Literal(boolean(*b)),
+    IndexedFilter::LitI(i) => Node::Literal(Some(TableCell::CellInt(*i))),
+    IndexedFilter::LitS(s) => Node::Literal(Some(TableCell::CellString(s.clone()))),
+    // Parameters are replaced by their values before a query is run.
+    IndexedFilter::Param(_) => Node::Literal(None),
+    IndexedFilter::UnaryOp(UnaryOp::Not, e) => {
+      let e = compile(e);
+      Node::Computed(Box::new(move |t| match *e.eval(t) {
+        Some(TableCell::CellInt(i)) => boolean(i == 0),
+        _ => None,
+      }))
+    }
+    IndexedFilter::BinaryOp(op, l, r) => binary(*op, compile(l), compile(r)),
+    IndexedFilter::Call(f, args) => {
+      let f = f.clone();
+      let args: Vec<Node> = args.iter().map(compile).collect();
+      Node::Computed(Box::new(move |t| {
+        let cells: Vec<Option<TableCell>> = args.iter().map(|a| a.eval(t).into_owned()).collect();
+        f.call(&cells)
+      }))
+    }
+    IndexedFilter::Regex(op, pattern, args) => regex(*op, pattern.0.clone(), args.iter().map(compile).collect()),
+  }
+}
+
+/// A binary operator, null if either operand is null.
+fn binary(op: BinaryOp, l: Node, r: Node) -> Node {
+  fn strict<F>(l: Node, r: Node, f: F) -> Node
+  where
+    F: Fn(&TableCell, &TableCell) -> bool + Send + Sync + 'static,
+  {
+    Node::Computed(Box::new(move |t| {
+      let l = l.eval(t);
+      let l = l.as_ref().as_ref()?;
+      let r = r.eval(t);
+      let r = r.as_ref().as_ref()?;
+      boolean(f(l, r))
+    }))
+  }
+
+  let nonzero = |c: &TableCell| matches!(c, TableCell::CellInt(i) if *i != 0);
+  match op {
+    BinaryOp::And => strict(l, r, move |a, b| nonzero(a) && nonzero(b)),
+    BinaryOp::Or => strict(l, r, move |a, b| nonzero(a) || nonzero(b)),
+    BinaryOp::Lt => strict(l, r, |a, b| matches!((a, b), (TableCell::CellInt(a), TableCell::CellInt(b)) if a < b)),
+    BinaryOp::Eq => strict(l, r, |a, b| match (a, b) {
+      (TableCell::CellInt(a), TableCell::CellInt(b)) => a == b,
+      (TableCell::CellString(a), TableCell::CellString(b)) => a == b,
+      _ => false,
+    }),
+    BinaryOp::Like | BinaryOp::SimilarTo | BinaryOp::Regexp => strict(l, r, |_, _| false),
+  }
+}
+
+/// A regular-expression operation, null unless its subject is a string.
+fn regex(op: RegexOp, re: Regex, args: Vec<Node>) -> Node {
+  Node::Computed(Box::new(move |t| {
+    let subject = args[0].eval(t);
+    let subject = match subject.as_ref() {
+      Some(TableCell::CellString(s)) => s,
+      _ => return None,
+    };
+    match op {
+      RegexOp::Matches => boolean(re.is_match(subject)),
+      RegexOp::Extract => {
+        // Without an explicit group, extract the first group if there is one.
+        let group = match args.get(1).map(|a| a.eval(t)) {
+          None => (re.captures_len() > 1) as usize,
+          Some(group) => match *group {
+            Some(TableCell::CellInt(i)) if i >= 0 => i as usize,
+            _ => return None,
+          },
+        };
+        re.captures(subject)
+          .and_then(|caps| caps.get(group))
+          .map(|m| TableCell::CellString(m.as_str().to_owned()))
+      }
+      RegexOp::Replace => match args[1].eval(t).as_ref() {
+        Some(TableCell::CellString(replacement)) => {
+          Some(TableCell::CellString(re.replace_all(subject, replacement.as_str()).into_owned()))
+        }
+        _ => None,
+      },
+    }
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ast::generators::{bind, TestRow};
+  use crate::ast::Filter;
+  use crate::functions::FunctionRegistry;
+  use crate::parser::parse_sql;
+  use crate::tables::TableData;
+
+  #[quickcheck]
+  fn prop_same_values_as_interpreter(f: Filter, rows: Vec<TestRow>) -> bool {
+    let e = bind(&f, &FunctionRegistry::with_builtins());
+    let compiled = CompiledFilter::new(&e);
+    rows.iter().all(|row| {
+      let row = vec![row.0.clone()];
+      compiled.eval(&row) == e.compute_with_row(&row)
+    })
+  }
+
+  #[test]
+  fn same_values_as_interpreter() {
+    let catalog = vec![TableData {
+      header: vec!["name".to_owned(), "age".to_owned(), "note".to_owned()],
+      rows: vec![],
+    }];
+    let rows: Vec<Vec<Row>> = vec![
+      vec![Some(TableCell::CellString("Harry".to_owned())), Some(TableCell::CellInt(21)), None],
+      vec![Some(TableCell::CellString("lucas".to_owned())), Some(TableCell::CellInt(0)), Some(TableCell::CellInt(3))],
+      vec![None, Some(TableCell::CellString("x".to_owned())), Some(TableCell::CellString("a-1".to_owned()))],
+    ]
+    .into_iter()
+    .map(|row| vec![row])
+    .collect();
+    for sql in [
+      "SELECT * FROM t WHERE name == \"Harry\" && 18 < age || NOT age",
+      "SELECT * FROM t WHERE NOT (note == 3) || name LIKE \"H%\"",
+      "SELECT * FROM t WHERE upper(name) == \"LUCAS\" && abs(age) < 30",
+      "SELECT * FROM t WHERE name REGEXP \"^[a-z]\" || note SIMILAR TO \"a-%\"",
+      "SELECT * FROM t WHERE regexp_extract(note, \"([a-z])-([0-9])\", 2) == \"1\"",
+      "SELECT * FROM t WHERE regexp_replace(name, \"r+\", \"R\") == regexp_extract(name, \"H.*\")",
+      "SELECT * FROM t WHERE length(name) == 5 && ? == 1",
+    ] {
+      let filter = parse_sql(sql)
+        .unwrap()
+        .to_indexed(&catalog, &["t".to_owned()], &FunctionRegistry::with_builtins())
+        .unwrap()
+        .filter;
+      let compiled = CompiledFilter::new(&filter);
+      for row in &rows {
+        assert_eq!(compiled.eval(row), filter.compute_with_row(row), "{} on {:?}", sql, row);
+        assert_eq!(compiled.matches(row), filter.valid_row(row), "{} on {:?}", sql, row);
+      }
+    }
+  }
+}
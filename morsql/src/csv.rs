@@ -0,0 +1,522 @@
+//! A CSV tokenizer following RFC 4180 by default: fields are separated by
+//! commas, may be enclosed in double quotes, and quoted fields may contain
+//! commas, newlines and quotes doubled as `""`. A [`Dialect`] changes the
+//! separator, quoting and escaping, and how headers, nulls and comments are
+//! written.
+//!
+//! Whitespace around a field is not part of it, so `"Lucas", 24` reads as
+//! the fields `Lucas` and `24`; whitespace inside a field is kept.
+
+use crate::error::{Error, Result};
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+/// How a delimited file is written.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Dialect {
+  pub delimiter: char,
+  /// The character fields may be enclosed in, or `None` if fields are
+  /// never quoted.
+  pub quote: Option<char>,
+  /// A character that makes the next one literal. Without one, a quote
+  /// inside a quoted field is written twice.
+  pub escape: Option<char>,
+  /// Whether the first record names the columns. Otherwise they are named
+  /// `c1`, `c2` and so on.
+  pub has_header: bool,
+  /// An unquoted field that stands for NULL, besides the empty field.
+  pub null: Option<String>,
+  /// Lines starting with this are skipped.
+  pub comment: Option<String>,
+}
+
+impl Default for Dialect {
+  fn default() -> Self {
+    Dialect {
+      delimiter: ',',
+      quote: Some('"'),
+      escape: None,
+      has_header: true,
+      null: None,
+      comment: None,
+    }
+  }
+}
+
+/// Reads a character option, accepting `\t` and `tab` for a tab.
+fn parse_char(key: &str, value: &str) -> Result<char> {
+  match value {
+    "\\t" | "tab" => return Ok('\t'),
+    "space" => return Ok(' '),
+    _ => (),
+  }
+  let mut chars = value.chars();
+  match (chars.next(), chars.next()) {
+    (Some(c), None) => Ok(c),
+    _ => Err(Error::Usage(format!(
+      "{} must be a single character, found `{}`",
+      key, value
+    ))),
+  }
+}
+
+/// Reads an option that may be turned off with `none` or an empty value.
+fn optional(value: &str) -> Option<&str> {
+  match value {
+    "" | "none" => None,
+    v => Some(v),
+  }
+}
+
+impl Dialect {
+  /// The default dialect for a file: tab-separated for `.tsv` files and
+  /// comma-separated otherwise.
+  pub fn for_extension(path: &Path) -> Self {
+    match path.extension().and_then(|e| e.to_str()) {
+      Some("tsv") => Dialect {
+        delimiter: '\t',
+        ..Dialect::default()
+      },
+      _ => Dialect::default(),
+    }
+  }
+
+  /// Sets one option by name, as given on the command line or in a sidecar
+  /// file. The options are `delimiter`, `quote`, `escape`, `header`, `null`
+  /// and `comment`.
+  pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+    let value = value.trim();
+    match key.trim() {
+      "delimiter" => self.delimiter = parse_char("delimiter", value)?,
+      "quote" => self.quote = optional(value).map(|v| parse_char("quote", v)).transpose()?,
+      "escape" => self.escape = optional(value).map(|v| parse_char("escape", v)).transpose()?,
+      "header" => {
+        self.has_header = match value {
+          "true" | "yes" => true,
+          "false" | "no" => false,
+          _ => {
+            return Err(Error::Usage(format!(
+              "header must be true or false, found `{}`",
+              value
+            )))
+          }
+        }
+      }
+      "null" => self.null = optional(value).map(str::to_owned),
+      "comment" => self.comment = optional(value).map(str::to_owned),
+      k => return Err(Error::Usage(format!("unknown dialect option `{}`", k))),
+    }
+    Ok(())
+  }
+
+  /// Applies a sidecar file of `key = value` lines. Blank lines and lines
+  /// starting with `#` are ignored.
+  pub fn apply_sidecar(&mut self, text: &str) -> Result<()> {
+    for (n, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      line
+        .split_once('=')
+        .ok_or_else(|| Error::Usage("expected a line of the form key = value".to_owned()))
+        .and_then(|(key, value)| self.set(key, value))
+        .map_err(|e| Error::data_format(Some(n + 1), e.to_string()))?;
+    }
+    Ok(())
+  }
+
+  /// The dialect of the file at `path`: the default for its extension,
+  /// changed by the sidecar file with the same name and the extension
+  /// `.dialect`, if there is one.
+  pub fn for_file(path: &Path) -> Result<Self> {
+    let mut dialect = Dialect::for_extension(path);
+    let sidecar = path.with_extension("dialect");
+    match fs::read_to_string(&sidecar) {
+      Ok(text) => dialect.apply_sidecar(&text).map_err(|e| match e {
+        Error::DataFormat { line, message, .. } => Error::DataFormat {
+          table: Some(sidecar.display().to_string()),
+          line,
+          message,
+        },
+        e => e,
+      })?,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+      Err(e) => return Err(Error::io(sidecar, e)),
+    }
+    Ok(dialect)
+  }
+
+  /// Whether an unquoted field stands for NULL.
+  pub fn is_null(&self, field: &Field) -> bool {
+    !field.quoted && (field.text.is_empty() || Some(&field.text) == self.null.as_ref())
+  }
+}
+
+/// A field of a record, with whether it was quoted. Quoted fields are
+/// always strings, while unquoted ones may be numbers or empty.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Field {
+  pub text: String,
+  pub quoted: bool,
+}
+
+/// A record with the line it starts on, counting from 1.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Record {
+  pub line: usize,
+  /// The byte offset of the line it starts on in the input.
+  pub offset: u64,
+  pub fields: Vec<Field>,
+}
+
+/// Reads records one at a time. Blank lines and comments are skipped, as is
+/// a byte order mark at the start of the input.
+pub struct Reader<R> {
+  input: R,
+  dialect: Dialect,
+  line: usize,
+  /// The bytes read so far, and the offset of the line in `buf`.
+  offset: u64,
+  line_offset: u64,
+  buf: String,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+  /// Before the first character of a field, skipping whitespace.
+  Start,
+  Unquoted,
+  Quoted,
+  /// After the escape character, in a quoted field or not.
+  Escaped { quoted: bool },
+  /// Just after a quote in a quoted field, which either closes the field
+  /// or, without an escape character, is the first of a doubled quote.
+  AfterQuote,
+  /// After a closed quoted field, where only whitespace may appear before
+  /// the next separator.
+  Closed,
+}
+
+impl Field {
+  fn new() -> Self {
+    Field {
+      text: String::new(),
+      quoted: false,
+    }
+  }
+
+  /// Trims trailing whitespace from an unquoted field, except for the
+  /// first `kept` bytes, which may end in an escaped space.
+  fn finish(mut self, kept: usize) -> Self {
+    if !self.quoted {
+      self.text.truncate(self.text.trim_end().len().max(kept));
+    }
+    self
+  }
+}
+
+impl<R: BufRead> Reader<R> {
+  pub fn new(input: R) -> Self {
+    Reader::with_dialect(input, Dialect::default())
+  }
+
+  pub fn with_dialect(input: R, dialect: Dialect) -> Self {
+    Reader {
+      input,
+      dialect,
+      line: 0,
+      offset: 0,
+      line_offset: 0,
+      buf: String::new(),
+    }
+  }
+
+  /// Numbers the lines of the input as if `lines` lines came before it,
+  /// for reading part of a file.
+  pub fn after_lines(mut self, lines: usize) -> Self {
+    self.line = lines;
+    self
+  }
+
+  fn read_line(&mut self) -> Result<bool> {
+    self.buf.clear();
+    let n = self.input.read_line(&mut self.buf)?;
+    self.line += 1;
+    self.line_offset = self.offset;
+    self.offset += n as u64;
+    if self.line == 1 && self.buf.starts_with('\u{feff}') {
+      self.buf.drain(..'\u{feff}'.len_utf8());
+    }
+    Ok(n > 0)
+  }
+
+  fn skipped(&self) -> bool {
+    self.buf.trim().is_empty()
+      || matches!(&self.dialect.comment, Some(c) if self.buf.starts_with(c.as_str()))
+  }
+
+  /// The next record, or `None` at the end of the input.
+  pub fn next_record(&mut self) -> Result<Option<Record>> {
+    loop {
+      if !self.read_line()? {
+        return Ok(None);
+      }
+      if !self.skipped() {
+        break;
+      }
+    }
+    let start = self.line;
+    let offset = self.line_offset;
+    let mut fields = vec![];
+    let mut field = Field::new();
+    let mut kept = 0;
+    let mut state = State::Start;
+    loop {
+      for c in self.buf.chars() {
+        let (next, action) = step(&self.dialect, state, c).map_err(|e| Error::data_format(Some(self.line), e))?;
+        state = next;
+        match action {
+          Action::Text => field.text.push(c),
+          Action::Escaped => {
+            field.text.push(c);
+            kept = field.text.len();
+          }
+          Action::Open => field.quoted = true,
+          Action::Delimiter => {
+            fields.push(field.finish(kept));
+            field = Field::new();
+            kept = 0;
+          }
+          Action::Skip => (),
+        }
+      }
+      if !state.continues() {
+        break;
+      }
+      if !self.read_line()? {
+        return Err(Error::data_format(Some(start), "a quoted field is never closed"));
+      }
+    }
+    fields.push(field.finish(kept));
+    Ok(Some(Record {
+      line: start,
+      offset,
+      fields,
+    }))
+  }
+}
+
+impl State {
+  /// Whether a record in this state at the end of a line goes on to the
+  /// next, inside a quoted field or after an escape character.
+  fn continues(self) -> bool {
+    matches!(self, State::Quoted | State::Escaped { .. })
+  }
+}
+
+/// What a character of a record does to the field being read.
+#[derive(Clone, Copy)]
+enum Action {
+  /// The character is part of the field's text.
+  Text,
+  /// The character is escaped, and part of the text whatever it is.
+  Escaped,
+  /// The character opens a quoted field.
+  Open,
+  /// The character ends the field.
+  Delimiter,
+  /// The character is not part of the text, such as a closing quote or
+  /// whitespace around a field.
+  Skip,
+}
+
+/// The state after the character `c` of a record in `state` and what the
+/// character does, or why it cannot appear there.
+fn step(dialect: &Dialect, state: State, c: char) -> std::result::Result<(State, Action), String> {
+  let is_quote = |c| Some(c) == dialect.quote;
+  let is_escape = |c| Some(c) == dialect.escape;
+  Ok(match state {
+    State::Escaped { quoted: true } => (State::Quoted, Action::Escaped),
+    State::Escaped { quoted: false } => (State::Unquoted, Action::Escaped),
+    State::Quoted if is_escape(c) => (State::Escaped { quoted: true }, Action::Skip),
+    State::Quoted if is_quote(c) => (State::AfterQuote, Action::Skip),
+    State::Quoted => (State::Quoted, Action::Text),
+    State::AfterQuote if is_quote(c) && dialect.escape.is_none() => (State::Quoted, Action::Text),
+    _ if c == dialect.delimiter => (State::Start, Action::Delimiter),
+    State::Start if is_quote(c) => (State::Quoted, Action::Open),
+    State::Start if c.is_whitespace() => (State::Start, Action::Skip),
+    State::AfterQuote | State::Closed if c.is_whitespace() => (State::Closed, Action::Skip),
+    State::Start | State::Unquoted if is_escape(c) => (State::Escaped { quoted: false }, Action::Skip),
+    State::Unquoted if is_quote(c) => {
+      return Err("a quote may only appear in a field that is enclosed in quotes".to_owned())
+    }
+    State::Start | State::Unquoted => (State::Unquoted, Action::Text),
+    State::AfterQuote | State::Closed => return Err(format!("unexpected `{}` after the closing quote of a field", c)),
+  })
+}
+
+/// The state at the end of a line of a record that began in `state`, or
+/// `None` if the record ends with the line. A malformed record ends where
+/// `next_record` stops reading it.
+fn continue_record(dialect: &Dialect, mut state: State, line: &str) -> Option<State> {
+  for c in line.chars() {
+    state = step(dialect, state, c).ok()?.0;
+  }
+  Some(state).filter(|state| state.continues())
+}
+
+/// Reads the input a piece at a time, each holding whole records, so that
+/// a quoted field spanning lines stays in one piece, and the pieces can be
+/// parsed apart. The first piece holds the first record.
+pub struct Pieces<R> {
+  input: R,
+  dialect: Dialect,
+  size: usize,
+  /// The lines read so far, and whether a record has started.
+  lines: usize,
+  records: bool,
+}
+
+impl<R: BufRead> Pieces<R> {
+  /// Pieces of about `size` bytes, or of one record if that is longer.
+  pub fn new(input: R, dialect: &Dialect, size: usize) -> Self {
+    Pieces {
+      input,
+      dialect: dialect.clone(),
+      size,
+      lines: 0,
+      records: false,
+    }
+  }
+
+  /// The next piece with the number of lines before it, or `None` at the
+  /// end of the input.
+  pub fn next_piece(&mut self) -> Result<Option<(String, usize)>> {
+    let before = self.lines;
+    let mut piece = String::new();
+    // The state within the current record, if one is being read.
+    let mut record: Option<State> = None;
+    while record.is_some() || !self.records || piece.len() < self.size {
+      let start = piece.len();
+      if self.input.read_line(&mut piece)? == 0 {
+        break;
+      }
+      let mut line = &piece[start..];
+      if self.lines == 0 {
+        line = line.strip_prefix('\u{feff}').unwrap_or(line);
+      }
+      self.lines += 1;
+      record = match record {
+        Some(state) => continue_record(&self.dialect, state, line),
+        None if line.trim().is_empty() => None,
+        None if matches!(&self.dialect.comment, Some(c) if line.starts_with(c.as_str())) => None,
+        None => {
+          self.records = true;
+          continue_record(&self.dialect, State::Start, line)
+        }
+      };
+    }
+    Ok(Some((piece, before)).filter(|(piece, _)| !piece.is_empty()))
+  }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+  type Item = Result<Record>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.next_record().transpose()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn records(input: &str) -> Result<Vec<Vec<(String, bool)>>> {
+    records_in(input, Dialect::default())
+  }
+
+  fn records_in(input: &str, dialect: Dialect) -> Result<Vec<Vec<(String, bool)>>> {
+    Reader::with_dialect(input.as_bytes(), dialect)
+      .map(|r| r.map(|r| r.fields.into_iter().map(|f| (f.text, f.quoted)).collect()))
+      .collect()
+  }
+
+  fn field(text: &str, quoted: bool) -> (String, bool) {
+    (text.to_owned(), quoted)
+  }
+
+  #[test]
+  fn quoting() {
+    assert_eq!(
+      records("city, n\n\"New York, NY\" , 3\r\nSan Jose,\n\n\"say \"\"hi\"\"\",\"\"").unwrap(),
+      vec![
+        vec![field("city", false), field("n", false)],
+        vec![field("New York, NY", true), field("3", false)],
+        vec![field("San Jose", false), field("", false)],
+        vec![field("say \"hi\"", true), field("", true)],
+      ]
+    );
+  }
+
+  #[test]
+  fn multi_line_fields() {
+    let mut reader = Reader::new("a,b\n\"one\ntwo\",1\nx,2\n".as_bytes());
+    reader.next_record().unwrap();
+    let record = reader.next_record().unwrap().unwrap();
+    assert_eq!(record.line, 2);
+    assert_eq!(record.fields[0].text, "one\ntwo");
+    assert_eq!(reader.next_record().unwrap().unwrap().line, 4);
+    assert_eq!(reader.next_record().unwrap(), None);
+
+    // Offsets count the bytes of skipped lines and of a byte order mark.
+    let input = "\u{feff}a,b\n\n\"one\ntwo\",1\nx,2\n";
+    let offsets: Vec<(u64, usize)> = Reader::new(input.as_bytes()).map(|r| r.map(|r| (r.offset, r.line)).unwrap()).collect();
+    assert_eq!(offsets, vec![(0, 1), (8, 3), (20, 5)]);
+    let mut reader = Reader::new(&input.as_bytes()[8..]).after_lines(2);
+    let record = reader.next_record().unwrap().unwrap();
+    assert_eq!((record.line, record.fields[0].text.as_str()), (3, "one\ntwo"));
+  }
+
+  #[test]
+  fn malformed() {
+    let line = |input: &str| match records(input) {
+      Err(Error::DataFormat { line, .. }) => line,
+      r => panic!("expected a data error, got {:?}", r),
+    };
+    assert_eq!(line("a\n\"b\" c\n"), Some(2));
+    assert_eq!(line("a\nb\"c\n"), Some(2));
+    assert_eq!(line("a\nb\n\"c,\nd\n"), Some(3));
+  }
+
+  #[test]
+  fn dialects() {
+    let mut dialect = Dialect::default();
+    dialect
+      .apply_sidecar("# a MySQL dump\ndelimiter = tab\nquote = '\nescape = \\\ncomment = --\n")
+      .unwrap();
+    assert_eq!(dialect.delimiter, '\t');
+    assert_eq!(dialect.quote, Some('\''));
+    assert_eq!(
+      records_in("\u{feff}-- users\nO\\'Brien\t'a\\'b, c'\t \\\t\n", dialect).unwrap(),
+      vec![vec![field("O'Brien", false), field("a'b, c", true), field("\t", false)]]
+    );
+
+    let mut dialect = Dialect::default();
+    dialect.set("delimiter", ";").unwrap();
+    dialect.set("quote", "none").unwrap();
+    assert_eq!(
+      records_in("a;\"b\";c d", dialect).unwrap(),
+      vec![vec![field("a", false), field("\"b\"", false), field("c d", false)]]
+    );
+
+    assert!(Dialect::default().set("delimiter", ";;").is_err());
+    assert!(Dialect::default().set("colour", "red").is_err());
+    match Dialect::default().apply_sidecar("header = false\nheader = maybe") {
+      Err(Error::DataFormat { line, .. }) => assert_eq!(line, Some(2)),
+      r => panic!("expected a data error, got {:?}", r),
+    }
+  }
+}
@@ -0,0 +1,194 @@
+//! The error type shared by the parser, binder and executor.
+
+use crate::ast::{Ident, Param, Span};
+use crate::parse_error::ParseError;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A name in a query that does not refer to anything.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum NameError {
+  UnknownTable(Ident),
+  UnknownColumn { table: Option<Ident>, column: Ident },
+  UnknownFunction(Ident),
+  UnboundParameter(Param),
+}
+
+#[derive(Debug)]
+pub enum Error {
+  /// The query text is not valid syntax.
+  Parse(ParseError),
+  /// The query refers to a table, column, function or parameter that does
+  /// not exist.
+  Name { error: NameError, span: Option<Span> },
+  /// An expression is used in a way its type does not allow, such as a call
+  /// with the wrong number of arguments or a pattern that does not compile.
+  Type { message: String, span: Option<Span> },
+  /// Reading or writing a file failed.
+  Io { path: Option<PathBuf>, source: io::Error },
+  /// A data file is malformed.
+  DataFormat {
+    table: Option<String>,
+    line: Option<usize>,
+    message: String,
+  },
+  /// The command line is invalid.
+  Usage(String),
+}
+
+impl Error {
+  pub fn name(error: NameError, span: Option<Span>) -> Self {
+    Error::Name { error, span }
+  }
+
+  pub fn type_error<S: Into<String>>(message: S) -> Self {
+    Error::Type {
+      message: message.into(),
+      span: None,
+    }
+  }
+
+  pub fn data_format<S: Into<String>>(line: Option<usize>, message: S) -> Self {
+    Error::DataFormat {
+      table: None,
+      line,
+      message: message.into(),
+    }
+  }
+
+  pub fn io<P: Into<PathBuf>>(path: P, source: io::Error) -> Self {
+    Error::Io {
+      path: Some(path.into()),
+      source,
+    }
+  }
+
+  /// Attributes a data-format error to the table it occurred in.
+  pub fn in_table(self, name: &str) -> Self {
+    match self {
+      Error::DataFormat {
+        table: None,
+        line,
+        message,
+      } => Error::DataFormat {
+        table: Some(name.to_owned()),
+        line,
+        message,
+      },
+      e => e,
+    }
+  }
+
+  /// Locates a name or type error at the span of the query it occurred in,
+  /// unless it is already located somewhere within it.
+  pub fn at(self, at: Span) -> Self {
+    match self {
+      Error::Name { error, span: None } => Error::Name { error, span: Some(at) },
+      Error::Type { message, span: None } => Error::Type { message, span: Some(at) },
+      e => e,
+    }
+  }
+
+  /// Distinct process exit codes for the command-line tool.
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      Error::Usage(_) => 1,
+      Error::Parse(_) => 2,
+      Error::Name { .. } => 3,
+      Error::Type { .. } => 4,
+      Error::Io { .. } => 5,
+      Error::DataFormat { .. } => 6,
+    }
+  }
+
+  pub fn span(&self) -> Option<Span> {
+    match self {
+      Error::Parse(e) => Some(Span {
+        start: e.offset,
+        end: e.offset,
+      }),
+      Error::Name { span, .. } | Error::Type { span, .. } => *span,
+      _ => None,
+    }
+  }
+
+  /// Renders the error, underlining the part of the query it refers to.
+  pub fn display_with_source(&self, source: &str) -> String {
+    match (self, self.span()) {
+      (Error::Parse(_), _) | (_, None) => format!("{}", self),
+      (_, Some(span)) => format!("{}\n{}", self, span.excerpt(source)),
+    }
+  }
+}
+
+impl fmt::Display for NameError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      NameError::UnknownTable(t) => write!(f, "unknown table {}", t),
+      NameError::UnknownColumn {
+        table: Some(t),
+        column,
+      } => write!(f, "table {} has no column {}", t, column),
+      NameError::UnknownColumn {
+        table: None,
+        column,
+      } => write!(f, "no table has a column {}", column),
+      NameError::UnknownFunction(name) => write!(f, "unknown function {}", name),
+      NameError::UnboundParameter(p) => write!(f, "no value was given for parameter {}", p),
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Parse(e) => write!(f, "{}", e),
+      Error::Name { error, .. } => write!(f, "name error: {}", error),
+      Error::Type { message, .. } => write!(f, "type error: {}", message),
+      Error::Io {
+        path: Some(p),
+        source,
+      } => write!(f, "{}: {}", p.display(), source),
+      Error::Io { path: None, source } => write!(f, "{}", source),
+      Error::DataFormat {
+        table,
+        line,
+        message,
+      } => {
+        write!(f, "data error")?;
+        if let Some(t) = table {
+          write!(f, " in table {}", t)?;
+        }
+        if let Some(l) = line {
+          write!(f, " on line {}", l)?;
+        }
+        write!(f, ": {}", message)
+      }
+      Error::Usage(message) => write!(f, "{}", message),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Io { source, .. } => Some(source),
+      _ => None,
+    }
+  }
+}
+
+impl From<ParseError> for Error {
+  fn from(e: ParseError) -> Self {
+    Error::Parse(e)
+  }
+}
+
+impl From<io::Error> for Error {
+  fn from(source: io::Error) -> Self {
+    Error::Io { path: None, source }
+  }
+}
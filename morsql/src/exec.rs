@@ -0,0 +1,1231 @@
+//! Physical operators. A query runs as a tree of operators, each pulling
+//! tuples from its inputs one at a time.
+//!
+//! A tuple has a slot per table in the query's FROM clause, holding that
+//! table's row, so the column selectors of a bound query find their values
+//! wherever the operators that produced the tuple sit in the tree. Slots of
+//! tables an operator has not seen are empty. `Project` and `Aggregate`
+//! produce tuples with a single slot holding the result row.
+
+use crate::ast::{IndexedAggregate, IndexedFilter, IndexedOrderKey, IndexedSelection};
+use crate::compile::CompiledFilter;
+use crate::error::{Error, Result};
+use crate::functions::AggregateState;
+use crate::index::{KeyRange, Location, Probe, TableIndex};
+use crate::parallel::{self, Morsels, Pool};
+use crate::sort::{self, Sorted, Sorter};
+use crate::stream::{Row, Rows};
+use crate::tables::TableCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub type Tuple = Vec<Row>;
+
+/// Settings for running a query.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Config {
+  /// The bytes of tuples a sort may hold before spilling them to disk.
+  pub memory_budget: usize,
+  /// How every join with equalities between its inputs is run, overriding
+  /// the planner's choice.
+  pub join: Option<JoinAlgorithm>,
+  /// The threads filters, projections, aggregates and joins process their
+  /// input on.
+  pub threads: usize,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      memory_budget: 256 << 20,
+      join: None,
+      threads: 1,
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum JoinAlgorithm {
+  /// Build a hash table of the right input, which must fit in memory.
+  Hash,
+  /// Sort both inputs, spilling to disk if need be, and merge them.
+  Merge,
+}
+
+impl fmt::Display for JoinAlgorithm {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      JoinAlgorithm::Hash => write!(f, "Hash"),
+      JoinAlgorithm::Merge => write!(f, "Merge"),
+    }
+  }
+}
+
+impl FromStr for JoinAlgorithm {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "hash" => Ok(JoinAlgorithm::Hash),
+      "merge" | "sort-merge" => Ok(JoinAlgorithm::Merge),
+      _ => Err(Error::Usage(format!("Unknown join algorithm {}, expected hash or merge", s))),
+    }
+  }
+}
+
+pub trait Operator {
+  /// Prepares the operator and its inputs. Operators that need all of an
+  /// input, such as the build side of a join, read it here.
+  fn open(&mut self) -> Result<()>;
+
+  /// The next tuple, or `None` once there are no more.
+  fn next(&mut self) -> Result<Option<Tuple>>;
+
+  /// Releases the operator's inputs and any tuples it holds.
+  fn close(&mut self) -> Result<()>;
+
+  /// An estimate of the most bytes of tuples the operator has held at once
+  /// since it was opened, not counting its inputs. Operators that hold
+  /// their input, such as joins and sorts, override this.
+  fn peak_memory(&self) -> usize {
+    0
+  }
+}
+
+pub type BoxedOperator<'a> = Box<dyn Operator + 'a>;
+
+/// Combines tuples holding different tables.
+fn merge(left: &[Row], right: &[Row]) -> Tuple {
+  left
+    .iter()
+    .zip(right)
+    .map(|(l, r)| if l.is_empty() { r.clone() } else { l.clone() })
+    .collect()
+}
+
+fn drain(input: &mut BoxedOperator) -> Result<Vec<Tuple>> {
+  let mut tuples = vec![];
+  while let Some(t) = input.next()? {
+    tuples.push(t);
+  }
+  Ok(tuples)
+}
+
+/// Reads the rows of one table into its slot.
+pub struct Scan<'a> {
+  slot: usize,
+  width: usize,
+  rows: Option<Rows<'a>>,
+}
+
+impl<'a> Scan<'a> {
+  /// `width` is the number of tables in the query and `slot` the position
+  /// of this one.
+  pub fn new(rows: Rows<'a>, slot: usize, width: usize) -> Self {
+    Scan {
+      slot,
+      width,
+      rows: Some(rows),
+    }
+  }
+}
+
+impl Operator for Scan<'_> {
+  fn open(&mut self) -> Result<()> {
+    Ok(())
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    let row = match self.rows.as_mut().and_then(|rows| rows.next()) {
+      Some(row) => row?,
+      None => return Ok(None),
+    };
+    let mut tuple = vec![vec![]; self.width];
+    tuple[self.slot] = row;
+    Ok(Some(tuple))
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.rows = None;
+    Ok(())
+  }
+}
+
+pub struct Filter<'a> {
+  input: BoxedOperator<'a>,
+  predicate: Arc<CompiledFilter>,
+  parallel: Option<Morsels>,
+}
+
+impl<'a> Filter<'a> {
+  pub fn new(input: BoxedOperator<'a>, predicate: IndexedFilter) -> Self {
+    Filter {
+      input,
+      predicate: Arc::new(CompiledFilter::new(&predicate)),
+      parallel: None,
+    }
+  }
+
+  /// Evaluates the predicate on the workers of `pool`, if given.
+  pub fn with_pool(mut self, pool: Option<Arc<Pool>>) -> Self {
+    self.parallel = pool.map(Morsels::new);
+    self
+  }
+}
+
+impl Operator for Filter<'_> {
+  fn open(&mut self) -> Result<()> {
+    self.input.open()
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    if let Some(morsels) = &mut self.parallel {
+      let predicate = self.predicate.clone();
+      return morsels.next(&mut self.input, move |t, out| {
+        if predicate.matches(&t) {
+          out.push(t);
+        }
+      });
+    }
+    while let Some(t) = self.input.next()? {
+      if self.predicate.matches(&t) {
+        return Ok(Some(t));
+      }
+    }
+    Ok(None)
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.parallel.iter_mut().for_each(Morsels::clear);
+    self.input.close()
+  }
+}
+
+/// Computes the selected columns. Aggregates are computed by `Aggregate`.
+pub struct Project<'a> {
+  input: BoxedOperator<'a>,
+  selection: Arc<IndexedSelection>,
+  parallel: Option<Morsels>,
+}
+
+impl<'a> Project<'a> {
+  pub fn new(input: BoxedOperator<'a>, selection: IndexedSelection) -> Self {
+    Project {
+      input,
+      selection: Arc::new(selection),
+      parallel: None,
+    }
+  }
+
+  /// Computes the columns on the workers of `pool`, if given.
+  pub fn with_pool(mut self, pool: Option<Arc<Pool>>) -> Self {
+    self.parallel = pool.map(Morsels::new);
+    self
+  }
+}
+
+impl Operator for Project<'_> {
+  fn open(&mut self) -> Result<()> {
+    self.input.open()
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    if let Some(morsels) = &mut self.parallel {
+      let selection = self.selection.clone();
+      return morsels.next(&mut self.input, move |t, out| out.push(vec![selection.to_row(&t)]));
+    }
+    Ok(self.input.next()?.map(|t| vec![self.selection.to_row(&t)]))
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.parallel.iter_mut().for_each(Morsels::clear);
+    self.input.close()
+  }
+}
+
+/// Pairs every left tuple with every right tuple that satisfies the
+/// predicate. The right input is read into memory when the join is opened.
+pub struct NestedLoopJoin<'a> {
+  left: BoxedOperator<'a>,
+  right: BoxedOperator<'a>,
+  predicate: Option<Arc<CompiledFilter>>,
+  right_tuples: Arc<Vec<Tuple>>,
+  current: Option<Tuple>,
+  pos: usize,
+  held: usize,
+  parallel: Option<Morsels>,
+}
+
+impl<'a> NestedLoopJoin<'a> {
+  pub fn new(left: BoxedOperator<'a>, right: BoxedOperator<'a>, predicate: Option<IndexedFilter>) -> Self {
+    NestedLoopJoin {
+      left,
+      right,
+      predicate: predicate.as_ref().map(|p| Arc::new(CompiledFilter::new(p))),
+      right_tuples: Arc::default(),
+      current: None,
+      pos: 0,
+      held: 0,
+      parallel: None,
+    }
+  }
+
+  /// Pairs left tuples with the right input on the workers of `pool`, if
+  /// given.
+  pub fn with_pool(mut self, pool: Option<Arc<Pool>>) -> Self {
+    self.parallel = pool.map(Morsels::new);
+    self
+  }
+}
+
+impl Operator for NestedLoopJoin<'_> {
+  fn open(&mut self) -> Result<()> {
+    self.right.open()?;
+    self.right_tuples = Arc::new(drain(&mut self.right)?);
+    self.right.close()?;
+    self.held = self.right_tuples.iter().map(sort::size_of).sum();
+    if let Some(morsels) = &mut self.parallel {
+      morsels.set_fanout(self.right_tuples.len());
+    }
+    self.left.open()
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    if self.right_tuples.is_empty() {
+      return Ok(None);
+    }
+    if let Some(morsels) = &mut self.parallel {
+      let (right, predicate) = (self.right_tuples.clone(), self.predicate.clone());
+      return morsels.next(&mut self.left, move |left, out| {
+        let joined = right.iter().map(|r| merge(&left, r));
+        out.extend(joined.filter(|t| predicate.as_ref().is_none_or(|p| p.matches(t))));
+      });
+    }
+    loop {
+      let left = match &self.current {
+        Some(left) => left,
+        None => match self.left.next()? {
+          Some(t) => {
+            self.pos = 0;
+            self.current.insert(t)
+          }
+          None => return Ok(None),
+        },
+      };
+      while self.pos < self.right_tuples.len() {
+        let joined = merge(left, &self.right_tuples[self.pos]);
+        self.pos += 1;
+        if self.predicate.as_ref().is_none_or(|p| p.matches(&joined)) {
+          return Ok(Some(joined));
+        }
+      }
+      self.current = None;
+    }
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.right_tuples = Arc::default();
+    self.current = None;
+    self.parallel.iter_mut().for_each(Morsels::clear);
+    self.left.close()
+  }
+
+  fn peak_memory(&self) -> usize {
+    self.held
+  }
+}
+
+/// Joins the tuples whose keys are equal, building a hash table from the
+/// right input when opened and probing it with each left tuple. Tuples
+/// with a null key match nothing. The residual predicate is checked on
+/// each matching pair.
+pub struct HashJoin<'a> {
+  left: BoxedOperator<'a>,
+  right: BoxedOperator<'a>,
+  left_keys: Arc<Vec<IndexedFilter>>,
+  right_keys: Vec<IndexedFilter>,
+  residual: Option<Arc<CompiledFilter>>,
+  table: Arc<HashMap<Vec<TableCell>, Vec<Tuple>>>,
+  current: Option<(Tuple, Vec<TableCell>)>,
+  pos: usize,
+  held: usize,
+  parallel: Option<Morsels>,
+}
+
+impl<'a> HashJoin<'a> {
+  pub fn new(
+    left: BoxedOperator<'a>,
+    right: BoxedOperator<'a>,
+    left_keys: Vec<IndexedFilter>,
+    right_keys: Vec<IndexedFilter>,
+    residual: Option<IndexedFilter>,
+  ) -> Self {
+    HashJoin {
+      left,
+      right,
+      left_keys: Arc::new(left_keys),
+      right_keys,
+      residual: residual.as_ref().map(|p| Arc::new(CompiledFilter::new(p))),
+      table: Arc::default(),
+      current: None,
+      pos: 0,
+      held: 0,
+      parallel: None,
+    }
+  }
+
+  /// Probes the hash table with left tuples on the workers of `pool`, if
+  /// given.
+  pub fn with_pool(mut self, pool: Option<Arc<Pool>>) -> Self {
+    self.parallel = pool.map(Morsels::new);
+    self
+  }
+}
+
+fn key(exprs: &[IndexedFilter], t: &Tuple) -> Option<Vec<TableCell>> {
+  exprs.iter().map(|e| e.compute_with_row(t)).collect()
+}
+
+impl Operator for HashJoin<'_> {
+  fn open(&mut self) -> Result<()> {
+    self.right.open()?;
+    self.held = 0;
+    let mut table: HashMap<_, Vec<Tuple>> = HashMap::new();
+    while let Some(t) = self.right.next()? {
+      if let Some(k) = key(&self.right_keys, &t) {
+        self.held += sort::size_of(&t);
+        table.entry(k).or_default().push(t);
+      }
+    }
+    self.table = Arc::new(table);
+    self.right.close()?;
+    self.left.open()
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    if let Some(morsels) = &mut self.parallel {
+      let (table, keys, residual) = (self.table.clone(), self.left_keys.clone(), self.residual.clone());
+      return morsels.next(&mut self.left, move |left, out| {
+        if let Some(matches) = key(&keys, &left).and_then(|k| table.get(&k)) {
+          let joined = matches.iter().map(|r| merge(&left, r));
+          out.extend(joined.filter(|t| residual.as_ref().is_none_or(|p| p.matches(t))));
+        }
+      });
+    }
+    loop {
+      if let Some((left, k)) = &self.current {
+        let matches = &self.table[k];
+        while self.pos < matches.len() {
+          let joined = merge(left, &matches[self.pos]);
+          self.pos += 1;
+          if self.residual.as_ref().is_none_or(|p| p.matches(&joined)) {
+            return Ok(Some(joined));
+          }
+        }
+        self.current = None;
+      }
+      match self.left.next()? {
+        None => return Ok(None),
+        Some(t) => {
+          if let Some(k) = key(&self.left_keys, &t).filter(|k| self.table.contains_key(k)) {
+            self.pos = 0;
+            self.current = Some((t, k));
+          }
+        }
+      }
+    }
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.table = Arc::default();
+    self.current = None;
+    self.parallel.iter_mut().for_each(Morsels::clear);
+    self.left.close()
+  }
+
+  fn peak_memory(&self) -> usize {
+    self.held
+  }
+}
+
+/// Joins each left tuple with the rows of a table whose indexed column
+/// equals the tuple's key, looking them up in the index instead of reading
+/// the table. The rows go in the table's slot, and each pair is checked
+/// against the predicate. Tuples with a null key match nothing. Only where
+/// the matches of a key start is held; each row is read when its turn comes.
+pub struct IndexNestedLoopJoin<'a> {
+  left: BoxedOperator<'a>,
+  key: Arc<CompiledFilter>,
+  index: Arc<TableIndex>,
+  slot: usize,
+  predicate: Option<Arc<CompiledFilter>>,
+  probe: Option<Probe>,
+  current: Option<(Tuple, std::vec::IntoIter<Location>)>,
+  held: Arc<AtomicUsize>,
+  threads: usize,
+  parallel: Option<Morsels>,
+}
+
+impl<'a> IndexNestedLoopJoin<'a> {
+  pub fn new(
+    left: BoxedOperator<'a>,
+    key: IndexedFilter,
+    index: Arc<TableIndex>,
+    slot: usize,
+    predicate: Option<IndexedFilter>,
+  ) -> Self {
+    IndexNestedLoopJoin {
+      left,
+      key: Arc::new(CompiledFilter::new(&key)),
+      index,
+      slot,
+      predicate: predicate.as_ref().map(|p| Arc::new(CompiledFilter::new(p))),
+      probe: None,
+      current: None,
+      held: Arc::default(),
+      threads: 1,
+      parallel: None,
+    }
+  }
+
+  /// Looks up the matches of left tuples on the workers of `pool`, if
+  /// given, each part of a morsel with an index of its own.
+  pub fn with_pool(mut self, pool: Option<Arc<Pool>>) -> Self {
+    self.threads = pool.as_ref().map_or(1, |pool| pool.threads());
+    self.parallel = pool.map(Morsels::new);
+    self
+  }
+}
+
+/// The left tuple joined with the row at `location`, if the pair satisfies
+/// the predicate. `held` keeps the most bytes a lookup has held: the
+/// locations of the matches left to read and the joined tuple.
+fn fetch_match(
+  probe: &mut Probe,
+  left: &[Row],
+  location: Location,
+  slot: usize,
+  predicate: Option<&CompiledFilter>,
+  (pending, held): (usize, &AtomicUsize),
+) -> Result<Option<Tuple>> {
+  let mut joined = left.to_vec();
+  joined[slot] = probe.fetch(location)?;
+  held.fetch_max(pending * mem::size_of::<Location>() + sort::size_of(&joined), Ordering::Relaxed);
+  Ok(Some(joined).filter(|t| predicate.is_none_or(|p| p.matches(t))))
+}
+
+impl Operator for IndexNestedLoopJoin<'_> {
+  fn open(&mut self) -> Result<()> {
+    self.held.store(0, Ordering::Relaxed);
+    if self.parallel.is_none() {
+      self.probe = Some(Probe::new(self.index.clone())?);
+    }
+    self.left.open()
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    if let Some(morsels) = &mut self.parallel {
+      let (index, key, predicate) = (self.index.clone(), self.key.clone(), self.predicate.clone());
+      let (slot, held) = (self.slot, self.held.clone());
+      return morsels.try_next_parts(&mut self.left, move |part, out| {
+        let mut probe = Probe::new(index.clone())?;
+        for left in part {
+          let locations = match key.eval(&left) {
+            Some(k) => probe.locations(&KeyRange::Equal(k))?,
+            None => continue,
+          };
+          let mut pending = locations.len();
+          for location in locations {
+            pending -= 1;
+            let found = fetch_match(&mut probe, &left, location, slot, predicate.as_deref(), (pending, &held))?;
+            out.extend(found);
+          }
+        }
+        Ok(())
+      });
+    }
+    let probe = match &mut self.probe {
+      Some(probe) => probe,
+      None => return Ok(None),
+    };
+    loop {
+      if let Some((left, locations)) = &mut self.current {
+        while let Some(location) = locations.next() {
+          let pending = (locations.len(), &*self.held);
+          let found = fetch_match(probe, left, location, self.slot, self.predicate.as_deref(), pending)?;
+          if found.is_some() {
+            return Ok(found);
+          }
+        }
+        self.current = None;
+      }
+      match self.left.next()? {
+        None => return Ok(None),
+        Some(t) => {
+          if let Some(k) = self.key.eval(&t) {
+            let locations = probe.locations(&KeyRange::Equal(k))?;
+            self.current = Some((t, locations.into_iter()));
+          }
+        }
+      }
+    }
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.probe = None;
+    self.current = None;
+    self.parallel.iter_mut().for_each(Morsels::clear);
+    self.left.close()
+  }
+
+  /// The most a lookup has held, once for each thread looking up at once.
+  fn peak_memory(&self) -> usize {
+    self.held.load(Ordering::Relaxed) * self.threads
+  }
+}
+
+/// Sorts its input by the keys, reading all of it when opened and spilling
+/// to disk beyond the memory budget. Nulls come first in ascending order.
+pub struct Sort<'a> {
+  input: BoxedOperator<'a>,
+  keys: Vec<IndexedOrderKey>,
+  budget: usize,
+  sorted: Option<Sorted>,
+  held: usize,
+}
+
+impl<'a> Sort<'a> {
+  pub fn new(input: BoxedOperator<'a>, keys: Vec<IndexedOrderKey>, budget: usize) -> Self {
+    Sort {
+      input,
+      keys,
+      budget,
+      sorted: None,
+      held: 0,
+    }
+  }
+}
+
+impl Operator for Sort<'_> {
+  fn open(&mut self) -> Result<()> {
+    self.input.open()?;
+    let mut sorter = Sorter::new(self.keys.clone(), self.budget);
+    while let Some(t) = self.input.next()? {
+      sorter.push(t)?;
+    }
+    self.input.close()?;
+    self.held = sorter.peak_memory();
+    self.sorted = Some(sorter.finish()?);
+    Ok(())
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    match self.sorted.as_mut().and_then(Iterator::next) {
+      Some(sorted) => Ok(Some(sorted?.1)),
+      None => Ok(None),
+    }
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.sorted = None;
+    Ok(())
+  }
+
+  fn peak_memory(&self) -> usize {
+    self.held
+  }
+}
+
+/// Joins the tuples whose keys are equal by sorting both inputs on their
+/// keys, spilling beyond the memory budget, and merging them. Only the
+/// right tuples sharing the current key are held in memory during the
+/// merge. Tuples with a null key match nothing, and the residual predicate
+/// is checked on each matching pair.
+pub struct MergeJoin<'a> {
+  left: BoxedOperator<'a>,
+  right: BoxedOperator<'a>,
+  left_keys: Vec<IndexedFilter>,
+  right_keys: Vec<IndexedFilter>,
+  residual: Option<CompiledFilter>,
+  budget: usize,
+  left_sorted: Option<Sorted>,
+  right_sorted: Option<Sorted>,
+  right_next: Option<(Row, Tuple)>,
+  group: Vec<Tuple>,
+  group_key: Option<Row>,
+  current: Option<Tuple>,
+  pos: usize,
+  sorted_held: usize,
+  group_held: usize,
+}
+
+impl<'a> MergeJoin<'a> {
+  pub fn new(
+    left: BoxedOperator<'a>,
+    right: BoxedOperator<'a>,
+    left_keys: Vec<IndexedFilter>,
+    right_keys: Vec<IndexedFilter>,
+    residual: Option<IndexedFilter>,
+    budget: usize,
+  ) -> Self {
+    MergeJoin {
+      left,
+      right,
+      left_keys,
+      right_keys,
+      residual: residual.as_ref().map(CompiledFilter::new),
+      budget,
+      left_sorted: None,
+      right_sorted: None,
+      right_next: None,
+      group: vec![],
+      group_key: None,
+      current: None,
+      pos: 0,
+      sorted_held: 0,
+      group_held: 0,
+    }
+  }
+}
+
+/// Sorts an input on non-null keys, dropping the tuples with a null key,
+/// and returns the sorted tuples with the most memory the sort held.
+fn sort_on_keys(input: &mut BoxedOperator, keys: &[IndexedFilter], budget: usize) -> Result<(Sorted, usize)> {
+  let order = keys
+    .iter()
+    .map(|k| IndexedOrderKey {
+      expr: k.clone(),
+      descending: false,
+    })
+    .collect();
+  let mut sorter = Sorter::new(order, budget);
+  input.open()?;
+  while let Some(t) = input.next()? {
+    if let Some(k) = key(keys, &t) {
+      sorter.push_keyed(k.into_iter().map(Some).collect(), t)?;
+    }
+  }
+  input.close()?;
+  let held = sorter.peak_memory();
+  Ok((sorter.finish()?, held))
+}
+
+fn next_sorted(sorted: &mut Option<Sorted>) -> Result<Option<(Row, Tuple)>> {
+  sorted.as_mut().and_then(Iterator::next).transpose()
+}
+
+impl Operator for MergeJoin<'_> {
+  fn open(&mut self) -> Result<()> {
+    let (right, right_held) = sort_on_keys(&mut self.right, &self.right_keys, self.budget)?;
+    let (left, left_held) = sort_on_keys(&mut self.left, &self.left_keys, self.budget)?;
+    (self.right_sorted, self.left_sorted) = (Some(right), Some(left));
+    (self.sorted_held, self.group_held) = (right_held + left_held, 0);
+    self.right_next = next_sorted(&mut self.right_sorted)?;
+    Ok(())
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    loop {
+      if let Some(left) = &self.current {
+        while self.pos < self.group.len() {
+          let joined = merge(left, &self.group[self.pos]);
+          self.pos += 1;
+          if self.residual.as_ref().is_none_or(|p| p.matches(&joined)) {
+            return Ok(Some(joined));
+          }
+        }
+        self.current = None;
+      }
+      let (k, left) = match next_sorted(&mut self.left_sorted)? {
+        Some(next) => next,
+        None => return Ok(None),
+      };
+      if self.group_key.as_ref() != Some(&k) {
+        self.group.clear();
+        while self.right_next.as_ref().is_some_and(|(rk, _)| *rk < k) {
+          self.right_next = next_sorted(&mut self.right_sorted)?;
+        }
+        let mut held = 0;
+        while self.right_next.as_ref().is_some_and(|(rk, _)| *rk == k) {
+          let (_, t) = mem::replace(&mut self.right_next, next_sorted(&mut self.right_sorted)?).unwrap();
+          held += sort::size_of(&t);
+          self.group.push(t);
+        }
+        self.group_held = self.group_held.max(held);
+        self.group_key = Some(k);
+      }
+      self.pos = 0;
+      self.current = Some(left);
+    }
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.left_sorted = None;
+    self.right_sorted = None;
+    self.right_next = None;
+    self.group = vec![];
+    self.group_key = None;
+    self.current = None;
+    Ok(())
+  }
+
+  fn peak_memory(&self) -> usize {
+    self.sorted_held + self.group_held
+  }
+}
+
+/// Computes aggregates over its whole input, producing one tuple.
+pub struct Aggregate<'a> {
+  input: BoxedOperator<'a>,
+  aggregates: Arc<Vec<IndexedAggregate>>,
+  done: bool,
+  pool: Option<Arc<Pool>>,
+}
+
+impl<'a> Aggregate<'a> {
+  pub fn new(input: BoxedOperator<'a>, aggregates: Vec<IndexedAggregate>) -> Self {
+    Aggregate {
+      input,
+      aggregates: Arc::new(aggregates),
+      done: false,
+      pool: None,
+    }
+  }
+
+  /// Aggregates parts of the input on the workers of `pool`, if given,
+  /// merging their partial states.
+  pub fn with_pool(mut self, pool: Option<Arc<Pool>>) -> Self {
+    self.pool = pool;
+    self
+  }
+}
+
+fn accumulate(aggregates: &[IndexedAggregate], states: &mut [AggregateState], t: &Tuple) {
+  for (agg, state) in aggregates.iter().zip(states.iter_mut()) {
+    let arg_cells: Vec<_> = agg.args.iter().map(|arg| arg.compute_with_row(t)).collect();
+    agg.function.update(state, &arg_cells);
+  }
+}
+
+impl Operator for Aggregate<'_> {
+  fn open(&mut self) -> Result<()> {
+    self.done = false;
+    self.input.open()
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    if mem::replace(&mut self.done, true) {
+      return Ok(None);
+    }
+    let init = |aggregates: &[IndexedAggregate]| -> Vec<AggregateState> {
+      aggregates.iter().map(|agg| agg.function.init()).collect()
+    };
+    let mut states = init(&self.aggregates);
+    if let Some(pool) = &self.pool {
+      loop {
+        let morsel = parallel::read_morsel(&mut self.input, parallel::MORSEL_ROWS * pool.threads())?;
+        if morsel.is_empty() {
+          break;
+        }
+        let aggregates = self.aggregates.clone();
+        let partials = pool.map_parts(morsel, move |part| {
+          let mut states = init(&aggregates);
+          for t in &part {
+            accumulate(&aggregates, &mut states, t);
+          }
+          states
+        });
+        for partial in partials {
+          for ((agg, state), other) in self.aggregates.iter().zip(states.iter_mut()).zip(partial) {
+            agg.function.merge(state, other);
+          }
+        }
+      }
+    }
+    while let Some(t) = self.input.next()? {
+      accumulate(&self.aggregates, &mut states, &t);
+    }
+    let row = self
+      .aggregates
+      .iter()
+      .zip(states)
+      .map(|(agg, state)| agg.function.finish(state))
+      .collect();
+    Ok(Some(vec![row]))
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.input.close()
+  }
+}
+
+/// Skips `offset` tuples and then passes on at most `count`, reading no
+/// more of its input than that.
+pub struct Limit<'a> {
+  input: BoxedOperator<'a>,
+  count: u64,
+  offset: u64,
+  seen: u64,
+}
+
+impl<'a> Limit<'a> {
+  pub fn new(input: BoxedOperator<'a>, count: u64, offset: u64) -> Self {
+    Limit {
+      input,
+      count,
+      offset,
+      seen: 0,
+    }
+  }
+}
+
+impl Operator for Limit<'_> {
+  fn open(&mut self) -> Result<()> {
+    self.seen = 0;
+    self.input.open()
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    while self.seen < self.offset {
+      if self.input.next()?.is_none() {
+        return Ok(None);
+      }
+      self.seen += 1;
+    }
+    if self.seen - self.offset >= self.count {
+      return Ok(None);
+    }
+    self.seen += 1;
+    self.input.next()
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.input.close()
+  }
+}
+
+/// The tuples of each input in turn, keeping duplicates. Each input is
+/// opened when the one before it is exhausted.
+pub struct Union<'a> {
+  inputs: Vec<BoxedOperator<'a>>,
+  current: usize,
+}
+
+impl<'a> Union<'a> {
+  pub fn new(inputs: Vec<BoxedOperator<'a>>) -> Self {
+    Union { inputs, current: 0 }
+  }
+}
+
+impl Operator for Union<'_> {
+  fn open(&mut self) -> Result<()> {
+    self.current = 0;
+    match self.inputs.first_mut() {
+      Some(input) => input.open(),
+      None => Ok(()),
+    }
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    while let Some(input) = self.inputs.get_mut(self.current) {
+      if let Some(t) = input.next()? {
+        return Ok(Some(t));
+      }
+      input.close()?;
+      self.current += 1;
+      if let Some(input) = self.inputs.get_mut(self.current) {
+        input.open()?;
+      }
+    }
+    Ok(None)
+  }
+
+  fn close(&mut self) -> Result<()> {
+    match self.inputs.get_mut(self.current) {
+      Some(input) => input.close(),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Drives an operator tree, yielding the rows of its tuples. The tree is
+/// opened on the first call to `next` and closed after the last tuple.
+pub struct Execution<'a> {
+  root: BoxedOperator<'a>,
+  state: ExecutionState,
+}
+
+#[derive(PartialEq)]
+enum ExecutionState {
+  Unopened,
+  Open,
+  Closed,
+}
+
+impl<'a> Execution<'a> {
+  pub fn new(root: BoxedOperator<'a>) -> Self {
+    Execution {
+      root,
+      state: ExecutionState::Unopened,
+    }
+  }
+
+  fn step(&mut self) -> Result<Option<Row>> {
+    if self.state == ExecutionState::Unopened {
+      self.state = ExecutionState::Open;
+      self.root.open()?;
+    }
+    match self.root.next()? {
+      Some(t) => Ok(Some(t.concat())),
+      None => {
+        self.state = ExecutionState::Closed;
+        self.root.close()?;
+        Ok(None)
+      }
+    }
+  }
+}
+
+impl Iterator for Execution<'_> {
+  type Item = Result<Row>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.state == ExecutionState::Closed {
+      return None;
+    }
+    let step = self.step();
+    if step.is_err() {
+      self.state = ExecutionState::Closed;
+    }
+    step.transpose()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ast::{BinaryOp, IndexKind, IndexedColumnSelector};
+  use crate::csv::Dialect;
+  use crate::functions::FunctionRegistry;
+  use crate::index::create;
+  use crate::parser::parse_sql;
+  use crate::tables::tests::TempDir;
+  use crate::tables::TableData;
+  use std::cell::Cell;
+
+  fn int(n: i64) -> Option<TableCell> {
+    Some(TableCell::CellInt(n))
+  }
+
+  fn scan<'a>(rows: Vec<Row>, slot: usize, width: usize) -> BoxedOperator<'a> {
+    Box::new(Scan::new(Box::new(rows.into_iter().map(Ok)), slot, width))
+  }
+
+  fn column(table: usize, field: usize) -> IndexedFilter {
+    IndexedFilter::Id(IndexedColumnSelector { table, field })
+  }
+
+  fn run(root: BoxedOperator) -> Vec<Row> {
+    Execution::new(root).collect::<Result<_>>().unwrap()
+  }
+
+  #[test]
+  fn joins() {
+    let left = || scan(vec![vec![int(1), int(10)], vec![int(2), int(20)], vec![None, int(30)]], 0, 2);
+    let right = || scan(vec![vec![int(2), int(7)], vec![int(1), int(8)], vec![int(2), int(9)], vec![None, int(0)]], 1, 2);
+    let equal = IndexedFilter::BinaryOp(BinaryOp::Eq, Box::new(column(0, 0)), Box::new(column(1, 0)));
+
+    let mut nested = run(Box::new(NestedLoopJoin::new(left(), right(), Some(equal))));
+    let mut hashed = run(Box::new(HashJoin::new(left(), right(), vec![column(0, 0)], vec![column(1, 0)], None)));
+    let merged = run(Box::new(MergeJoin::new(left(), right(), vec![column(0, 0)], vec![column(1, 0)], None, 1)));
+    nested.sort();
+    hashed.sort();
+    assert_eq!(merged, hashed);
+    assert_eq!(
+      hashed,
+      vec![
+        vec![int(1), int(10), int(1), int(8)],
+        vec![int(2), int(20), int(2), int(7)],
+        vec![int(2), int(20), int(2), int(9)],
+      ]
+    );
+    assert_eq!(nested, hashed);
+
+    let residual = IndexedFilter::BinaryOp(BinaryOp::Lt, Box::new(column(1, 1)), Box::new(IndexedFilter::LitI(9)));
+    let filtered = run(Box::new(HashJoin::new(left(), right(), vec![column(0, 0)], vec![column(1, 0)], Some(residual.clone()))));
+    assert_eq!(filtered.len(), 2);
+    let filtered = run(Box::new(MergeJoin::new(left(), right(), vec![column(0, 0)], vec![column(1, 0)], Some(residual), 1 << 20)));
+    assert_eq!(filtered.len(), 2);
+
+    assert_eq!(run(Box::new(NestedLoopJoin::new(left(), right(), None))).len(), 12);
+    assert!(run(Box::new(NestedLoopJoin::new(left(), scan(vec![], 1, 2), None))).is_empty());
+  }
+
+  #[test]
+  fn index_joins() {
+    let dir = TempDir::new("exec_join");
+    let table = dir.join("t.csv");
+    std::fs::write(&table, "k,v\n2,7\n1,8\n2,9\n,0\n").unwrap();
+    let dialect = Dialect::default();
+    create(&table, "by_k", "k", IndexKind::Hash, &dialect, None).unwrap();
+    let index = Arc::new(TableIndex::open(&table, "by_k", &dialect, None).unwrap().unwrap());
+    let left = || scan(vec![vec![int(1), int(10)], vec![None, int(30)], vec![int(2), int(20)]], 0, 2);
+
+    let joined = run(Box::new(IndexNestedLoopJoin::new(left(), column(0, 0), index.clone(), 1, None)));
+    assert_eq!(
+      joined,
+      vec![
+        vec![int(1), int(10), int(1), int(8)],
+        vec![int(2), int(20), int(2), int(7)],
+        vec![int(2), int(20), int(2), int(9)],
+      ]
+    );
+
+    let residual = IndexedFilter::BinaryOp(BinaryOp::Lt, Box::new(column(1, 1)), Box::new(IndexedFilter::LitI(9)));
+    let filtered = run(Box::new(IndexNestedLoopJoin::new(left(), column(0, 0), index.clone(), 1, Some(residual))));
+    assert_eq!(filtered, vec![vec![int(1), int(10), int(1), int(8)], vec![int(2), int(20), int(2), int(7)]]);
+
+    let pool = Some(Arc::new(Pool::new(2)));
+    let mut threaded = IndexNestedLoopJoin::new(left(), column(0, 0), index, 1, None).with_pool(pool);
+    threaded.open().unwrap();
+    let mut tuples = vec![];
+    while let Some(t) = threaded.next().unwrap() {
+      tuples.push(t.concat());
+    }
+    assert_eq!(tuples, joined);
+    assert!(threaded.peak_memory() > 0);
+    threaded.close().unwrap();
+  }
+
+  fn query(sql: &str, table: TableData) -> Vec<Row> {
+    let names = vec!["t".to_owned()];
+    let catalog = vec![table];
+    parse_sql(sql)
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+      .unwrap()
+      .run(&catalog)
+      .unwrap()
+      .rows
+  }
+
+  #[test]
+  fn sort_and_limit() {
+    let table = TableData {
+      header: vec!["a".to_owned(), "b".to_owned()],
+      rows: vec![
+        vec![int(2), int(1)],
+        vec![int(1), int(2)],
+        vec![None, int(3)],
+        vec![int(2), int(4)],
+      ],
+    };
+    assert_eq!(
+      query("SELECT b FROM t WHERE TRUE ORDER BY a DESC, b DESC", table.clone()),
+      vec![vec![int(4)], vec![int(1)], vec![int(2)], vec![int(3)]]
+    );
+    assert_eq!(
+      query("SELECT b FROM t WHERE TRUE ORDER BY a, b LIMIT 2 OFFSET 1", table.clone()),
+      vec![vec![int(2)], vec![int(1)]]
+    );
+    assert_eq!(query("SELECT a FROM t WHERE TRUE LIMIT 0", table.clone()), Vec::<Row>::new());
+    assert_eq!(query("SELECT count(*) FROM t WHERE TRUE LIMIT 5", table), vec![vec![int(4)]]);
+  }
+
+  #[test]
+  fn limit_stops_reading() {
+    let read = Cell::new(0);
+    let rows: Rows = Box::new((0..).map(|n| {
+      read.set(read.get() + 1);
+      Ok(vec![int(n)])
+    }));
+    let root = Box::new(Limit::new(Box::new(Scan::new(rows, 0, 1)), 3, 2));
+    assert_eq!(run(root), vec![vec![int(2)], vec![int(3)], vec![int(4)]]);
+    assert_eq!(read.get(), 5);
+  }
+
+  #[test]
+  fn spilling_queries() {
+    let names: Vec<String> = vec!["user".to_owned(), "user1".to_owned()];
+    let catalog: Vec<TableData> = names
+      .iter()
+      .map(|n| TableData::of_file(&std::fs::File::open(format!("examples/{}.csv", n)).unwrap()).unwrap())
+      .collect();
+    let tiny = Config {
+      memory_budget: 1,
+      join: Some(JoinAlgorithm::Merge),
+      ..Config::default()
+    };
+    for sql in [
+      "SELECT name, rating FROM user, user1 WHERE user.id == user1.id ORDER BY rating DESC, name",
+      "SELECT * FROM user AS a, user AS b, user1 WHERE a.id == user1.id && b.age == a.age ORDER BY last_name",
+      "SELECT name FROM user WHERE TRUE ORDER BY age DESC LIMIT 2",
+    ] {
+      let q = parse_sql(sql)
+        .unwrap()
+        .to_indexed(&catalog, &names, &FunctionRegistry::new())
+        .unwrap();
+      let sources = || q.sources.iter().map(|i| crate::stream::rows_of(&catalog[*i])).collect();
+      let expected: Vec<Row> = q.stream(&catalog, sources()).unwrap().collect::<Result<_>>().unwrap();
+      let stats: Vec<_> = catalog.iter().map(crate::stats::TableStats::of).collect();
+      let spilled: Vec<Row> = q
+        .stream_with(&catalog, &stats, sources(), &tiny)
+        .unwrap()
+        .collect::<Result<_>>()
+        .unwrap();
+      assert!(!expected.is_empty(), "{}", sql);
+      assert_eq!(spilled, expected, "{}", sql);
+    }
+  }
+
+  #[test]
+  fn parallel_queries() {
+    let names: Vec<String> = vec!["user".to_owned(), "user1".to_owned()];
+    let mut catalog: Vec<TableData> = names
+      .iter()
+      .map(|n| TableData::of_file(&std::fs::File::open(format!("examples/{}.csv", n)).unwrap()).unwrap())
+      .collect();
+    // Enough rows for several morsels on each thread.
+    let rows = catalog[0].rows.clone();
+    catalog[0].rows = (0..40_000)
+      .map(|i| {
+        let mut row = rows[i % rows.len()].clone();
+        row[1] = Some(TableCell::CellInt(i as i64 % 97));
+        row
+      })
+      .collect();
+    let stats: Vec<_> = catalog.iter().map(crate::stats::TableStats::of).collect();
+    for sql in [
+      "SELECT name, age FROM user WHERE 50 < age && NOT (name == \"Harry\")",
+      "SELECT upper(name), id FROM user WHERE TRUE",
+      "SELECT count(*), sum(age), min(name), max(age) FROM user WHERE age < 90",
+      "SELECT name, rating FROM user, user1 WHERE user.id == user1.id && 10 < age",
+      "SELECT * FROM user, user1 WHERE user.id < user1.id && age == 3",
+      "SELECT name FROM user WHERE 20 < age ORDER BY age DESC LIMIT 5",
+    ] {
+      let q = parse_sql(sql)
+        .unwrap()
+        .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+        .unwrap();
+      let run = |threads: usize| {
+        let sources = q.sources.iter().map(|i| crate::stream::rows_of(&catalog[*i])).collect();
+        let config = Config {
+          threads,
+          ..Config::default()
+        };
+        let mut rows: Vec<Row> = q.stream_with(&catalog, &stats, sources, &config).unwrap().collect::<Result<_>>().unwrap();
+        rows.sort();
+        rows
+      };
+      let serial = run(1);
+      assert!(!serial.is_empty(), "{}", sql);
+      for threads in [2, 4, 7] {
+        assert_eq!(run(threads), serial, "{} on {} threads", sql, threads);
+      }
+    }
+  }
+}
@@ -0,0 +1,345 @@
+//! EXPLAIN: the physical operators a query runs as, described with the
+//! names of the tables and columns they read, and with ANALYZE, what each
+//! of them did while the query ran.
+//!
+//! Profiling wraps every operator in an `Instrumented` operator that counts
+//! the tuples it produces and times the calls into it. The times include
+//! the operator's inputs, as they are pulled from within those calls.
+
+use crate::ast::{ExplainFormat, IndexedQuery};
+use crate::batch::ColumnarTable;
+use crate::error::Result;
+use crate::exec::{BoxedOperator, Config, Execution, Operator, Tuple};
+use crate::stats::TableStats;
+use crate::stream::Rows;
+use crate::tables::TableData;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// What an operator did while a query ran.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct OperatorStats {
+  /// The tuples it produced.
+  pub rows: u64,
+  /// The time spent in the operator and its inputs.
+  pub elapsed: Duration,
+  /// An estimate of the most bytes of tuples it held at once.
+  pub peak_memory: usize,
+}
+
+/// An operator in a profile: its description, the ids of its inputs, and
+/// what it has done so far.
+pub struct ProfileNode {
+  pub label: String,
+  pub inputs: Vec<usize>,
+  stats: Rc<RefCell<OperatorStats>>,
+}
+
+impl ProfileNode {
+  pub(crate) fn new(label: String, inputs: Vec<usize>) -> Self {
+    ProfileNode {
+      label,
+      inputs,
+      stats: Rc::default(),
+    }
+  }
+
+  /// Where an `Instrumented` operator records what this one does.
+  pub(crate) fn recorder(&self) -> Rc<RefCell<OperatorStats>> {
+    self.stats.clone()
+  }
+
+  pub fn stats(&self) -> OperatorStats {
+    *self.stats.borrow()
+  }
+}
+
+/// The tree of operators of a query.
+pub struct Profile {
+  nodes: Vec<ProfileNode>,
+  root: usize,
+}
+
+impl Profile {
+  pub(crate) fn new(nodes: Vec<ProfileNode>, root: usize) -> Self {
+    Profile { nodes, root }
+  }
+
+  /// The operators, each with the ids of its inputs, which are their
+  /// positions here.
+  pub fn nodes(&self) -> &[ProfileNode] {
+    &self.nodes
+  }
+
+  pub fn root(&self) -> &ProfileNode {
+    &self.nodes[self.root]
+  }
+
+  /// The profile as an indented tree, an operator per line with its inputs
+  /// below it, or as a Graphviz graph. With `analyze`, each operator shows
+  /// its statistics.
+  pub fn render(&self, format: ExplainFormat, analyze: bool) -> String {
+    let mut out = String::new();
+    match format {
+      ExplainFormat::Text => self.text(self.root, 0, analyze, &mut out),
+      ExplainFormat::Dot => self.dot(analyze, &mut out),
+    }
+    out
+  }
+
+  fn text(&self, id: usize, depth: usize, analyze: bool, out: &mut String) {
+    let node = &self.nodes[id];
+    out.push_str(&"  ".repeat(depth));
+    out.push_str(&node.label);
+    if analyze {
+      out.push_str(&format!("  ({})", summary(&node.stats())));
+    }
+    out.push('\n');
+    for input in &node.inputs {
+      self.text(*input, depth + 1, analyze, out);
+    }
+  }
+
+  fn dot(&self, analyze: bool, out: &mut String) {
+    out.push_str("digraph plan {\n  node [shape=box];\n");
+    for (id, node) in self.nodes.iter().enumerate() {
+      let mut label = node.label.replace('\\', "\\\\").replace('"', "\\\"");
+      if analyze {
+        label.push_str("\\n");
+        label.push_str(&summary(&node.stats()));
+      }
+      let _ = writeln!(out, "  n{} [label=\"{}\"];", id, label);
+    }
+    for (id, node) in self.nodes.iter().enumerate() {
+      for input in &node.inputs {
+        let _ = writeln!(out, "  n{} -> n{};", id, input);
+      }
+    }
+    out.push_str("}\n");
+  }
+}
+
+fn summary(stats: &OperatorStats) -> String {
+  format!(
+    "rows={} time={:.3}ms memory={}",
+    stats.rows,
+    stats.elapsed.as_secs_f64() * 1000.0,
+    bytes(stats.peak_memory)
+  )
+}
+
+/// A number of bytes in the largest unit that keeps it at least 1.
+fn bytes(n: usize) -> String {
+  const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+  if n < 1024 {
+    return format!("{}B", n);
+  }
+  let mut size = n as f64 / 1024.0;
+  let mut unit = 0;
+  while size >= 1024.0 && unit + 1 < UNITS.len() {
+    size /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1}{}", size, UNITS[unit])
+}
+
+/// Passes on the tuples of its input, recording how many there were, the
+/// time spent producing them and the most memory the input held.
+pub struct Instrumented<'a> {
+  input: BoxedOperator<'a>,
+  stats: Rc<RefCell<OperatorStats>>,
+}
+
+impl<'a> Instrumented<'a> {
+  pub fn new(input: BoxedOperator<'a>, stats: Rc<RefCell<OperatorStats>>) -> Self {
+    Instrumented { input, stats }
+  }
+
+  fn record(&self, start: Instant) {
+    let mut stats = self.stats.borrow_mut();
+    stats.elapsed += start.elapsed();
+    stats.peak_memory = stats.peak_memory.max(self.input.peak_memory());
+  }
+}
+
+impl Operator for Instrumented<'_> {
+  fn open(&mut self) -> Result<()> {
+    let start = Instant::now();
+    let opened = self.input.open();
+    self.record(start);
+    opened
+  }
+
+  fn next(&mut self) -> Result<Option<Tuple>> {
+    let start = Instant::now();
+    let next = self.input.next();
+    self.record(start);
+    if let Ok(Some(_)) = next {
+      self.stats.borrow_mut().rows += 1;
+    }
+    next
+  }
+
+  fn close(&mut self) -> Result<()> {
+    let start = Instant::now();
+    let closed = self.input.close();
+    self.record(start);
+    closed
+  }
+
+  fn peak_memory(&self) -> usize {
+    self.input.peak_memory()
+  }
+}
+
+impl IndexedQuery {
+  /// The operators the query runs as, given the same arguments as
+  /// `stream_with`. With `analyze` the query is run to the end, discarding
+  /// its result, and the profile holds what each operator did.
+  pub fn explain<'a>(
+    &self,
+    catalog: &[TableData],
+    stats: &[TableStats],
+    sources: Vec<Rows<'a>>,
+    config: &Config,
+    analyze: bool,
+  ) -> Result<Profile> {
+    let mut sources: Vec<Option<Rows<'a>>> = sources.into_iter().map(Some).collect();
+    let mut scan = |slot: usize| sources[slot].take().expect("each table is scanned once");
+    let (root, profile) = self.plan(catalog, stats, config).lower_profiled(config, &mut scan);
+    if analyze {
+      for row in Execution::new(root) {
+        row?;
+      }
+    }
+    Ok(profile)
+  }
+
+  /// The operators the query runs as over tables stored a column at a
+  /// time, given the same arguments as `stream_columnar`, profiled as
+  /// `explain` does.
+  pub fn explain_columnar(
+    &self,
+    catalog: &[TableData],
+    stats: &[TableStats],
+    sources: Vec<&ColumnarTable>,
+    config: &Config,
+    analyze: bool,
+  ) -> Result<Profile> {
+    let (root, profile) = self.plan(catalog, stats, config).lower_columnar_profiled(config, &sources);
+    if analyze {
+      for row in Execution::new(root) {
+        row?;
+      }
+    }
+    Ok(profile)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::functions::FunctionRegistry;
+  use crate::parser::parse_sql;
+  use crate::stream::rows_of;
+  use std::fs::File;
+
+  fn explain(sql: &str, format: ExplainFormat, analyze: bool) -> String {
+    let names = vec!["user".to_owned(), "user1".to_owned()];
+    let catalog: Vec<TableData> = names
+      .iter()
+      .map(|n| TableData::of_file(&File::open(format!("examples/{}.csv", n)).unwrap()).unwrap())
+      .collect();
+    let q = parse_sql(sql)
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+      .unwrap();
+    let stats: Vec<TableStats> = catalog.iter().map(TableStats::of).collect();
+    let sources = q.sources.iter().map(|i| rows_of(&catalog[*i])).collect();
+    q.explain(&catalog, &stats, sources, &Config::default(), analyze)
+      .unwrap()
+      .render(format, analyze)
+  }
+
+  const JOIN: &str = "SELECT u.name, rating FROM user AS u, user1 WHERE u.id == user1.id && 18 < u.age ORDER BY rating DESC LIMIT 2";
+
+  #[test]
+  fn operator_tree() {
+    assert_eq!(
+      explain(JOIN, ExplainFormat::Text, false),
+      concat!(
+        "Limit 2\n",
+        "  Project u.name, user1.rating\n",
+        "    Sort user1.rating DESC\n",
+        "      Hash Join (u.id == user1.id)\n",
+        "        Filter (18 < u.age)\n",
+        "          Scan user AS u\n",
+        "        Scan user1\n",
+      )
+    );
+    let dot = explain("SELECT count(*) FROM user WHERE \"Harry\" == name", ExplainFormat::Dot, false);
+    assert_eq!(
+      dot,
+      concat!(
+        "digraph plan {\n",
+        "  node [shape=box];\n",
+        "  n0 [label=\"Scan user\"];\n",
+        "  n1 [label=\"Filter (\\\"Harry\\\" == user.name)\"];\n",
+        "  n2 [label=\"Aggregate count()\"];\n",
+        "  n1 -> n0;\n",
+        "  n2 -> n1;\n",
+        "}\n",
+      )
+    );
+  }
+
+  #[test]
+  fn analyze() {
+    let text = explain(JOIN, ExplainFormat::Text, true);
+    let rows: Vec<(&str, &str)> = text
+      .lines()
+      .map(|line| {
+        let (label, stats) = line.trim().split_once("  (").unwrap();
+        (label, stats.split(' ').next().unwrap())
+      })
+      .collect();
+    let all = TableData::of_file(&File::open("examples/user.csv").unwrap()).unwrap().rows.len();
+    assert_eq!(rows[0], ("Limit 2", "rows=2"));
+    assert_eq!(rows[5], ("Scan user AS u", format!("rows={}", all).as_str()));
+    assert!(text.lines().all(|l| l.contains("time=") && l.contains("memory=")));
+    assert!(!text.lines().nth(3).unwrap().contains("memory=0B"), "{}", text);
+
+    let dot = explain(JOIN, ExplainFormat::Dot, true);
+    assert!(dot.contains("n6 [label=\"Limit 2\\nrows=2 time="), "{}", dot);
+  }
+
+  #[test]
+  fn columnar_scans() {
+    let names = vec!["user".to_owned()];
+    let catalog = vec![TableData::of_file(&File::open("examples/user.csv").unwrap()).unwrap()];
+    let q = parse_sql("SELECT name FROM user WHERE 18 < age")
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+      .unwrap();
+    let table = ColumnarTable::of_table(&catalog[0]);
+    let stats = vec![table.stats()];
+    let profile = q.explain_columnar(&catalog, &stats, vec![&table], &Config::default(), true).unwrap();
+    let text = profile.render(ExplainFormat::Text, true);
+    let expected = q.run(&catalog).unwrap().rows.len();
+    assert!(
+      text.starts_with(&format!("Batch Scan user, Filter (18 < user.age), Project user.name  (rows={} ", expected)),
+      "{}",
+      text
+    );
+  }
+
+  #[test]
+  fn byte_sizes() {
+    assert_eq!(bytes(0), "0B");
+    assert_eq!(bytes(1023), "1023B");
+    assert_eq!(bytes(1536), "1.5KiB");
+    assert_eq!(bytes(3 << 30), "3.0GiB");
+  }
+}
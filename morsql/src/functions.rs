@@ -0,0 +1,515 @@
+//! User-defined scalar and aggregate functions.
+//!
+//! Functions are registered by name in a [`FunctionRegistry`] together with a
+//! [`Signature`]. Binding a query looks calls up in the registry, checks their
+//! arity and argument types and resolves them to shared handles that the
+//! executor invokes per row.
+
+use crate::ast::{IndexedFilter, RegexOp};
+use crate::error::{Error, Result};
+use crate::tables::TableCell;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// The type of a function parameter or result.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CellType {
+  Int,
+  String,
+  Any,
+}
+
+impl CellType {
+  pub fn admits(self, cell: &Option<TableCell>) -> bool {
+    matches!(
+      (self, cell),
+      (_, None)
+        | (CellType::Any, _)
+        | (CellType::Int, Some(TableCell::CellInt(_)))
+        | (CellType::String, Some(TableCell::CellString(_)))
+    )
+  }
+}
+
+impl CellType {
+  /// The type of the values an expression evaluates to, as far as it is
+  /// known before the query runs. Booleans are integers.
+  pub fn of(e: &IndexedFilter) -> CellType {
+    match e {
+      IndexedFilter::LitI(_) | IndexedFilter::LitB(_) | IndexedFilter::UnaryOp(..) | IndexedFilter::BinaryOp(..) => {
+        CellType::Int
+      }
+      IndexedFilter::LitS(_) => CellType::String,
+      IndexedFilter::Call(f, _) => f.signature.returns,
+      IndexedFilter::Regex(RegexOp::Matches, ..) => CellType::Int,
+      IndexedFilter::Regex(..) => CellType::String,
+      IndexedFilter::Id(_) | IndexedFilter::Param(_) => CellType::Any,
+    }
+  }
+
+  /// Whether values of type `other` may be given where this type is
+  /// expected.
+  fn accepts(self, other: CellType) -> bool {
+    self == other || self == CellType::Any || other == CellType::Any
+  }
+}
+
+impl fmt::Display for CellType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{}",
+      match self {
+        CellType::Int => "int",
+        CellType::String => "string",
+        CellType::Any => "any",
+      }
+    )
+  }
+}
+
+/// Parameter and result types of a function. The trailing parameters from
+/// `min_args` onwards may be omitted by the caller.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Signature {
+  pub params: Vec<CellType>,
+  pub min_args: usize,
+  pub returns: CellType,
+}
+
+impl Signature {
+  pub fn new(params: Vec<CellType>, returns: CellType) -> Self {
+    Signature {
+      min_args: params.len(),
+      params,
+      returns,
+    }
+  }
+
+  pub fn optional_from(mut self, min_args: usize) -> Self {
+    self.min_args = min_args;
+    self
+  }
+
+  pub fn check_arity(&self, name: &str, n: usize) -> Result<()> {
+    if n < self.min_args || n > self.params.len() {
+      Err(Error::type_error(format!(
+        "Function {} expects {} arguments but was given {}",
+        name,
+        if self.min_args == self.params.len() {
+          format!("{}", self.min_args)
+        } else {
+          format!("{} to {}", self.min_args, self.params.len())
+        },
+        n
+      )))
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Rejects arguments whose type is known not to match the declared one,
+  /// such as a literal or the result of another function.
+  pub fn check_types(&self, name: &str, args: &[IndexedFilter]) -> Result<()> {
+    for (i, (param, arg)) in self.params.iter().zip(args).enumerate() {
+      let given = CellType::of(arg);
+      if !param.accepts(given) {
+        return Err(Error::type_error(format!(
+          "Function {} expects {} as argument {} but was given {}",
+          name,
+          param,
+          i + 1,
+          given
+        )));
+      }
+    }
+    Ok(())
+  }
+
+  /// Arguments that do not match the declared types make the call NULL,
+  /// just like comparing cells of different types.
+  fn admits(&self, args: &[Option<TableCell>]) -> bool {
+    self.params.iter().zip(args).all(|(t, a)| t.admits(a))
+  }
+}
+
+/// A scalar function over table cells. Any closure of the right shape
+/// implements it.
+pub trait ScalarFunction: Send + Sync {
+  fn call(&self, args: &[Option<TableCell>]) -> Option<TableCell>;
+}
+
+impl<F> ScalarFunction for F
+where
+  F: Fn(&[Option<TableCell>]) -> Option<TableCell> + Send + Sync,
+{
+  fn call(&self, args: &[Option<TableCell>]) -> Option<TableCell> {
+    self(args)
+  }
+}
+
+/// An aggregate function, folding the rows of a query into a single cell.
+/// Partial states can be merged so that aggregation can be split up.
+pub trait Accumulator: Send + Sync + 'static {
+  type State: Send + 'static;
+
+  fn init(&self) -> Self::State;
+  fn update(&self, state: &mut Self::State, args: &[Option<TableCell>]);
+  fn merge(&self, state: &mut Self::State, other: Self::State);
+  fn finish(&self, state: Self::State) -> Option<TableCell>;
+}
+
+/// Object-safe view of an [`Accumulator`], so that accumulators with
+/// different state types can live in the same registry.
+trait DynAccumulator: Send + Sync {
+  fn init(&self) -> Box<dyn Any + Send>;
+  fn update(&self, state: &mut dyn Any, args: &[Option<TableCell>]);
+  fn merge(&self, state: &mut dyn Any, other: Box<dyn Any + Send>);
+  fn finish(&self, state: Box<dyn Any + Send>) -> Option<TableCell>;
+}
+
+impl<A: Accumulator> DynAccumulator for A {
+  fn init(&self) -> Box<dyn Any + Send> {
+    Box::new(Accumulator::init(self))
+  }
+
+  fn update(&self, state: &mut dyn Any, args: &[Option<TableCell>]) {
+    Accumulator::update(self, state.downcast_mut().unwrap(), args)
+  }
+
+  fn merge(&self, state: &mut dyn Any, other: Box<dyn Any + Send>) {
+    Accumulator::merge(self, state.downcast_mut().unwrap(), *other.downcast().unwrap())
+  }
+
+  fn finish(&self, state: Box<dyn Any + Send>) -> Option<TableCell> {
+    Accumulator::finish(self, *state.downcast().unwrap())
+  }
+}
+
+pub struct ScalarUdf {
+  pub name: String,
+  pub signature: Signature,
+  function: Box<dyn ScalarFunction>,
+}
+
+impl ScalarUdf {
+  /// The result of the function, or NULL if the arguments or the result
+  /// do not match the signature.
+  pub fn call(&self, args: &[Option<TableCell>]) -> Option<TableCell> {
+    if !self.signature.admits(args) {
+      return None;
+    }
+    let result = self.function.call(args);
+    if self.signature.returns.admits(&result) {
+      result
+    } else {
+      None
+    }
+  }
+}
+
+pub struct AggregateUdf {
+  pub name: String,
+  pub signature: Signature,
+  accumulator: Box<dyn DynAccumulator>,
+}
+
+/// The running state of one aggregate call.
+pub struct AggregateState(Box<dyn Any + Send>);
+
+impl AggregateUdf {
+  pub fn init(&self) -> AggregateState {
+    AggregateState(self.accumulator.init())
+  }
+
+  pub fn update(&self, state: &mut AggregateState, args: &[Option<TableCell>]) {
+    if self.signature.admits(args) {
+      self.accumulator.update(state.0.as_mut(), args)
+    }
+  }
+
+  pub fn merge(&self, state: &mut AggregateState, other: AggregateState) {
+    self.accumulator.merge(state.0.as_mut(), other.0)
+  }
+
+  /// The result of the aggregate, or NULL if it does not match the
+  /// signature.
+  pub fn finish(&self, state: AggregateState) -> Option<TableCell> {
+    let result = self.accumulator.finish(state.0);
+    if self.signature.returns.admits(&result) {
+      result
+    } else {
+      None
+    }
+  }
+}
+
+// Functions are identified by name; the registry guarantees names are unique.
+macro_rules! udf_identity {
+  ($t : ty) => {
+    impl fmt::Debug for $t {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+      }
+    }
+
+    impl PartialEq for $t {
+      fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+      }
+    }
+
+    impl Eq for $t {}
+  };
+}
+
+udf_identity!(ScalarUdf);
+udf_identity!(AggregateUdf);
+
+/// The functions callable from a query. Names are case-insensitive.
+#[derive(Default, Clone)]
+pub struct FunctionRegistry {
+  scalars: HashMap<String, Arc<ScalarUdf>>,
+  aggregates: HashMap<String, Arc<AggregateUdf>>,
+}
+
+impl FunctionRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// A registry containing the built-in functions.
+  pub fn with_builtins() -> Self {
+    let mut r = Self::new();
+    builtins::register(&mut r);
+    r
+  }
+
+  /// Registers a scalar function, replacing any function of the same name.
+  pub fn register_scalar<F: ScalarFunction + 'static>(
+    &mut self,
+    name: &str,
+    signature: Signature,
+    function: F,
+  ) {
+    let name = name.to_lowercase();
+    self.aggregates.remove(&name);
+    self.scalars.insert(
+      name.clone(),
+      Arc::new(ScalarUdf {
+        name,
+        signature,
+        function: Box::new(function),
+      }),
+    );
+  }
+
+  /// Registers an aggregate function, replacing any function of the same
+  /// name.
+  pub fn register_aggregate<A: Accumulator>(
+    &mut self,
+    name: &str,
+    signature: Signature,
+    accumulator: A,
+  ) {
+    let name = name.to_lowercase();
+    self.scalars.remove(&name);
+    self.aggregates.insert(
+      name.clone(),
+      Arc::new(AggregateUdf {
+        name,
+        signature,
+        accumulator: Box::new(accumulator),
+      }),
+    );
+  }
+
+  pub fn scalar(&self, name: &str) -> Option<&Arc<ScalarUdf>> {
+    self.scalars.get(&name.to_lowercase())
+  }
+
+  pub fn aggregate(&self, name: &str) -> Option<&Arc<AggregateUdf>> {
+    self.aggregates.get(&name.to_lowercase())
+  }
+}
+
+mod builtins {
+  use super::*;
+
+  fn int(args: &[Option<TableCell>], i: usize) -> Option<i64> {
+    match args.get(i) {
+      Some(Some(TableCell::CellInt(n))) => Some(*n),
+      _ => None,
+    }
+  }
+
+  fn string(args: &[Option<TableCell>], i: usize) -> Option<&str> {
+    match args.get(i) {
+      Some(Some(TableCell::CellString(s))) => Some(s),
+      _ => None,
+    }
+  }
+
+  struct Count;
+
+  impl Accumulator for Count {
+    type State = i64;
+
+    fn init(&self) -> i64 {
+      0
+    }
+
+    fn update(&self, state: &mut i64, args: &[Option<TableCell>]) {
+      // `count()` counts rows, `count(e)` counts rows where `e` is not NULL.
+      if args.first().is_none_or(Option::is_some) {
+        *state += 1;
+      }
+    }
+
+    fn merge(&self, state: &mut i64, other: i64) {
+      *state += other;
+    }
+
+    fn finish(&self, state: i64) -> Option<TableCell> {
+      Some(TableCell::CellInt(state))
+    }
+  }
+
+  struct Sum;
+
+  impl Accumulator for Sum {
+    type State = Option<i64>;
+
+    fn init(&self) -> Option<i64> {
+      None
+    }
+
+    fn update(&self, state: &mut Option<i64>, args: &[Option<TableCell>]) {
+      if let Some(n) = int(args, 0) {
+        *state = Some(state.unwrap_or(0).wrapping_add(n));
+      }
+    }
+
+    fn merge(&self, state: &mut Option<i64>, other: Option<i64>) {
+      if let Some(n) = other {
+        *state = Some(state.unwrap_or(0).wrapping_add(n));
+      }
+    }
+
+    fn finish(&self, state: Option<i64>) -> Option<TableCell> {
+      state.map(TableCell::CellInt)
+    }
+  }
+
+  /// `min` and `max`, ordering cells with `TableCell`'s `Ord`.
+  struct Extremum(std::cmp::Ordering);
+
+  impl Extremum {
+    fn pick(&self, state: &mut Option<TableCell>, cell: TableCell) {
+      match state {
+        Some(current) if cell.cmp(current) != self.0 => (),
+        _ => *state = Some(cell),
+      }
+    }
+  }
+
+  impl Accumulator for Extremum {
+    type State = Option<TableCell>;
+
+    fn init(&self) -> Option<TableCell> {
+      None
+    }
+
+    fn update(&self, state: &mut Option<TableCell>, args: &[Option<TableCell>]) {
+      if let Some(Some(cell)) = args.first() {
+        self.pick(state, cell.clone());
+      }
+    }
+
+    fn merge(&self, state: &mut Option<TableCell>, other: Option<TableCell>) {
+      if let Some(cell) = other {
+        self.pick(state, cell);
+      }
+    }
+
+    fn finish(&self, state: Option<TableCell>) -> Option<TableCell> {
+      state
+    }
+  }
+
+  pub fn register(r: &mut FunctionRegistry) {
+    use std::cmp::Ordering;
+    use CellType::*;
+
+    r.register_scalar(
+      "length",
+      Signature::new(vec![String], Int),
+      |args: &[Option<TableCell>]| {
+        string(args, 0).map(|s| TableCell::CellInt(s.chars().count() as i64))
+      },
+    );
+    r.register_scalar(
+      "lower",
+      Signature::new(vec![String], String),
+      |args: &[Option<TableCell>]| string(args, 0).map(|s| TableCell::CellString(s.to_lowercase())),
+    );
+    r.register_scalar(
+      "upper",
+      Signature::new(vec![String], String),
+      |args: &[Option<TableCell>]| string(args, 0).map(|s| TableCell::CellString(s.to_uppercase())),
+    );
+    r.register_scalar(
+      "abs",
+      Signature::new(vec![Int], Int),
+      |args: &[Option<TableCell>]| int(args, 0).map(|n| TableCell::CellInt(n.wrapping_abs())),
+    );
+
+    r.register_aggregate("count", Signature::new(vec![Any], Int).optional_from(0), Count);
+    r.register_aggregate("sum", Signature::new(vec![Int], Int), Sum);
+    r.register_aggregate("min", Signature::new(vec![Any], Any), Extremum(Ordering::Less));
+    r.register_aggregate("max", Signature::new(vec![Any], Any), Extremum(Ordering::Greater));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn closures_register_as_scalars() {
+    let mut r = FunctionRegistry::new();
+    r.register_scalar(
+      "Double",
+      Signature::new(vec![CellType::Int], CellType::Int),
+      |args: &[Option<TableCell>]| match &args[0] {
+        Some(TableCell::CellInt(n)) => Some(TableCell::CellInt(n * 2)),
+        _ => None,
+      },
+    );
+    let f = r.scalar("double").unwrap();
+    assert_eq!(f.call(&[Some(TableCell::CellInt(21))]), Some(TableCell::CellInt(42)));
+    assert_eq!(f.call(&[Some(TableCell::CellString("x".to_owned()))]), None);
+    assert!(f.signature.check_arity("double", 2).is_err());
+  }
+
+  #[test]
+  fn aggregate_states_merge() {
+    let r = FunctionRegistry::with_builtins();
+    let sum = r.aggregate("SUM").unwrap();
+    let mut s1 = sum.init();
+    let mut s2 = sum.init();
+    sum.update(&mut s1, &[Some(TableCell::CellInt(1))]);
+    sum.update(&mut s2, &[Some(TableCell::CellInt(2))]);
+    sum.update(&mut s2, &[None]);
+    sum.merge(&mut s1, s2);
+    assert_eq!(sum.finish(s1), Some(TableCell::CellInt(3)));
+
+    let count = r.aggregate("count").unwrap();
+    let mut c = count.init();
+    count.update(&mut c, &[]);
+    count.update(&mut c, &[]);
+    assert_eq!(count.finish(c), Some(TableCell::CellInt(2)));
+  }
+}
@@ -0,0 +1,108 @@
+//! Hash indexes, which find the records whose key equals a value with a
+//! read of the directory and of one bucket, however many keys there are.
+//!
+//! The file has the framing of [`crate::index`]: the magic bytes, the
+//! buckets as nodes, then the directory and the footer. Each key goes in
+//! the bucket its hash picks, and a bucket is a node holding the entries of
+//! its keys in key order, as a leaf of a tree does. The directory holds
+//! where each bucket starts as 8 little-endian bytes, or 0 for a bucket no
+//! key hashes to. There are about [`BUCKET_KEYS`] keys to a bucket.
+//!
+//! The hash is 64-bit FNV-1a over the encoding of the key in columnar
+//! files, which does not change between runs or builds, as the hashes of
+//! the standard library may.
+
+use crate::columnar::{put_cell, put_varint, Bytes};
+use crate::error::{Error, Result};
+use crate::index::{put_entry, read_entry, KeyRange, Layout, Location, NodeWriter, TableIndex};
+use crate::tables::TableCell;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// The keys a bucket holds on average.
+pub const BUCKET_KEYS: usize = 4;
+
+fn truncated() -> Error {
+  Error::data_format(None, "the file is truncated")
+}
+
+/// The hash of a key.
+fn hash(key: &TableCell) -> u64 {
+  let mut bytes = vec![];
+  put_cell(&mut bytes, key);
+  bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Writes the buckets and directory of the keys, which are in order.
+pub(crate) fn write<W: Write>(keys: &[(TableCell, Vec<Location>)], out: &mut NodeWriter<W>) -> Result<Layout> {
+  let buckets = (keys.len() / BUCKET_KEYS).max(1);
+  let mut members = vec![vec![]; buckets];
+  for entry in keys {
+    members[(hash(&entry.0) % buckets as u64) as usize].push(entry);
+  }
+  let mut directory = Vec::with_capacity(buckets * 8);
+  for entries in members {
+    let offset = if entries.is_empty() {
+      0
+    } else {
+      let mut body = vec![];
+      put_varint(&mut body, entries.len() as u64);
+      for (key, locations) in entries {
+        put_entry(&mut body, key, locations);
+      }
+      out.node(&body)?
+    };
+    directory.extend_from_slice(&offset.to_le_bytes());
+  }
+  let start = out.offset();
+  out.write(&directory)?;
+  Ok(Layout::Hash {
+    buckets: buckets as u64,
+    directory: start,
+  })
+}
+
+/// Where the records with keys from `low` to `high` start, read from
+/// `input`, the file of `index`. Only an equality is answered from a single
+/// bucket; other ranges read them all.
+pub(crate) fn lookup<R: Read + Seek>(
+  index: &TableIndex,
+  input: &mut R,
+  range: &KeyRange,
+  (low, high): (TableCell, TableCell),
+  buckets: u64,
+  directory: u64,
+) -> Result<Vec<Location>> {
+  let starts = match range {
+    KeyRange::Equal(key) => {
+      input.seek(SeekFrom::Start(directory + hash(key) % buckets * 8))?;
+      let mut start = [0u8; 8];
+      input.read_exact(&mut start).map_err(|_| truncated())?;
+      vec![u64::from_le_bytes(start)]
+    }
+    KeyRange::Between { .. } => {
+      input.seek(SeekFrom::Start(directory))?;
+      let mut starts = vec![0u8; buckets as usize * 8];
+      input.read_exact(&mut starts).map_err(|_| truncated())?;
+      starts
+        .chunks(8)
+        .map(|start| {
+          let mut bytes = [0u8; 8];
+          bytes.copy_from_slice(start);
+          u64::from_le_bytes(bytes)
+        })
+        .collect()
+    }
+  };
+  let mut locations = vec![];
+  for start in starts.into_iter().filter(|start| *start != 0) {
+    let body = index.read_node(input, start)?;
+    let mut bytes = Bytes::new(&body);
+    for _ in 0..bytes.usize()? {
+      let (key, found) = read_entry(&mut bytes)?;
+      if low <= key && key <= high {
+        locations.extend(found);
+      }
+    }
+  }
+  Ok(locations)
+}
@@ -0,0 +1,952 @@
+//! Secondary indexes over the columns of delimited files. `CREATE INDEX`
+//! writes an index beside the file it indexes, and the planner reads a
+//! table through one when a selection over it compares the indexed column
+//! with a constant, instead of scanning the whole file (see
+//! [`LogicalPlan::use_indexes`](crate::plan::LogicalPlan::use_indexes)).
+//!
+//! An index is a B+ tree, bulk-loaded from the sorted keys of the column,
+//! or a hash table of them (see [`crate::hash_index`]), and is stored in a
+//! file named after the table and the index, such as `user.by_age.idx` for
+//! the index `by_age` of `user.csv`. The file starts with the magic bytes
+//! `MORIDX02`. In a tree, the leaves follow in key order and then the inner
+//! nodes a level at a time, ending with the root. A node is its length as
+//! 4 little-endian bytes followed by a varint count of its entries:
+//!
+//! * a leaf holds up to [`NODE_KEYS`] distinct keys, each with the byte
+//!   offsets and line numbers of the records holding it, in file order,
+//! * an inner node holds up to [`NODE_KEYS`] children, each with the least
+//!   key under it.
+//!
+//! Null keys are not indexed, as no comparison holds for them. The footer
+//! holds the indexed column, the size and modification time the table had
+//! when the index was built, how its rows were read (see [`format_key`]),
+//! and the kind of index, with where the leaves end and the root starts in
+//! a tree or where the directory of a hash table starts. As in columnar
+//! files, the file ends with the footer's offset as 8 little-endian bytes
+//! and the magic bytes again. An index whose table has since changed size
+//! or modification time, or is now read in another dialect or schema, is
+//! stale and is not used.
+
+use crate::ast::IndexKind;
+use crate::columnar::{put_cell, put_str, put_varint, Bytes};
+use crate::csv::Dialect;
+use crate::error::{Error, NameError, Result};
+use crate::hash_index;
+use crate::schema::Schema;
+use crate::stream::{Row, Rows};
+use crate::tables::{FileScan, TableCell, TableData};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+const MAGIC: &[u8; 8] = b"MORIDX02";
+
+/// The most keys in a leaf and children of an inner node.
+pub const NODE_KEYS: usize = 128;
+
+fn corrupt<S: Into<String>>(message: S) -> Error {
+  Error::data_format(None, message)
+}
+
+/// Where a record starts in its file: the byte offset of its first line,
+/// and that line's number, counting from 1.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct Location {
+  pub offset: u64,
+  pub line: usize,
+}
+
+/// The keys a lookup reads.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum KeyRange {
+  /// The keys equal to the value.
+  Equal(TableCell),
+  /// The integer keys greater than `above` and less than `below`, where
+  /// given, as `<` only holds between integers.
+  Between { above: Option<i64>, below: Option<i64> },
+}
+
+impl KeyRange {
+  /// The least and greatest keys in the range, or `None` if it is empty.
+  fn bounds(&self) -> Option<(TableCell, TableCell)> {
+    match self {
+      KeyRange::Equal(key) => Some((key.clone(), key.clone())),
+      KeyRange::Between { above, below } => {
+        let low = match above {
+          Some(above) => above.checked_add(1)?,
+          None => i64::MIN,
+        };
+        let high = match below {
+          Some(below) => below.checked_sub(1)?,
+          None => i64::MAX,
+        };
+        if low > high {
+          return None;
+        }
+        Some((TableCell::CellInt(low), TableCell::CellInt(high)))
+      }
+    }
+  }
+
+  /// The range as a condition on the column.
+  fn describe(&self, column: &str) -> String {
+    match self {
+      KeyRange::Equal(key) => format!("({} == {})", column, key),
+      KeyRange::Between {
+        above: Some(above),
+        below: Some(below),
+      } => format!("({} < {} && {} < {})", above, column, column, below),
+      KeyRange::Between { above: Some(above), .. } => format!("({} < {})", above, column),
+      KeyRange::Between { below: Some(below), .. } => format!("({} < {})", column, below),
+      KeyRange::Between { .. } => format!("({} is an integer)", column),
+    }
+  }
+}
+
+/// The file holding the index `name` of the table at `table`.
+pub fn index_path(table: &Path, name: &str) -> PathBuf {
+  let stem = table.file_stem().unwrap_or_default().to_string_lossy();
+  table.with_file_name(format!("{}.{}.idx", stem, name))
+}
+
+/// The names of the indexes beside the table at `table`, in order.
+pub fn index_names(table: &Path) -> Result<Vec<String>> {
+  let dir = match table.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  };
+  let prefix = format!("{}.", table.file_stem().unwrap_or_default().to_string_lossy());
+  let mut names = vec![];
+  for entry in fs::read_dir(dir).map_err(|e| Error::io(dir, e))? {
+    let file_name = entry.map_err(|e| Error::io(dir, e))?.file_name();
+    let name = file_name
+      .to_str()
+      .and_then(|f| f.strip_prefix(prefix.as_str()))
+      .and_then(|f| f.strip_suffix(".idx"));
+    match name {
+      Some(name) if !name.is_empty() && !name.contains('.') => names.push(name.to_owned()),
+      _ => (),
+    }
+  }
+  names.sort();
+  Ok(names)
+}
+
+fn table_name(table: &Path) -> String {
+  table.file_stem().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+/// The size and modification time of a file, in nanoseconds since the
+/// epoch, which change when it is written.
+pub(crate) fn version(path: &Path) -> Result<(u64, u64)> {
+  let meta = fs::metadata(path).map_err(|e| Error::io(path, e))?;
+  let modified = meta.modified().map_err(|e| Error::io(path, e))?;
+  let nanos = modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+  Ok((meta.len(), nanos))
+}
+
+/// How the rows of a delimited file are read in `dialect` and checked
+/// against `schema`. What is derived from the rows, such as an index or the
+/// statistics of the file, is only good while this stays the same.
+///
+/// The key has a line per dialect option, `delimiter`, `quote`, `escape`,
+/// `header`, `null` and `comment` in that order, each followed by a space
+/// and its value. Characters and strings are written in double quotes,
+/// escaped as by `str::escape_default`, an option that is not set as
+/// `none`, and `header` as `true` or `false`. Then comes the line `schema`
+/// followed by the schema in the form of a `.schema` file, or `schema none`.
+pub fn format_key(dialect: &Dialect, schema: Option<&Schema>) -> String {
+  let quoted = |s: &str| format!("\"{}\"", s.escape_default());
+  let option = |s: Option<String>| s.map_or_else(|| "none".to_owned(), |s| quoted(&s));
+  let mut key = format!(
+    "delimiter {}\nquote {}\nescape {}\nheader {}\nnull {}\ncomment {}\n",
+    quoted(&dialect.delimiter.to_string()),
+    option(dialect.quote.map(String::from)),
+    option(dialect.escape.map(String::from)),
+    dialect.has_header,
+    option(dialect.null.clone()),
+    option(dialect.comment.clone()),
+  );
+  match schema {
+    Some(schema) => key.push_str(&format!("schema\n{}", schema)),
+    None => key.push_str("schema none\n"),
+  }
+  key
+}
+
+/// Builds the index `name` of the given kind of the rows of the delimited
+/// file at `table` by the values of `column`, replacing any index of that
+/// name.
+pub fn create(
+  table: &Path,
+  name: &str,
+  column: &str,
+  kind: IndexKind,
+  dialect: &Dialect,
+  schema: Option<&Schema>,
+) -> Result<()> {
+  let version = version(table)?;
+  let table_name = table_name(table);
+  let file = File::open(table).map_err(|e| Error::io(table, e))?;
+  let mut scan = TableData::scan_file(file, dialect, schema).map_err(|e| e.in_table(&table_name))?;
+  let field = scan.header.iter().position(|c| c == column).ok_or_else(|| {
+    let error = NameError::UnknownColumn {
+      table: Some(table_name.clone()),
+      column: column.to_owned(),
+    };
+    Error::name(error, None)
+  })?;
+  let mut entries = vec![];
+  while let Some(row) = scan.next_located() {
+    let (offset, line, mut row) = row.map_err(|e| e.in_table(&table_name))?;
+    if let Some(key) = row.swap_remove(field) {
+      entries.push((key, Location { offset, line }));
+    }
+  }
+  // The sort is stable, keeping the records of each key in file order.
+  entries.sort_by(|a, b| a.0.cmp(&b.0));
+  let mut keys: Vec<(TableCell, Vec<Location>)> = vec![];
+  for (key, location) in entries {
+    match keys.last_mut() {
+      Some((last, locations)) if *last == key => locations.push(location),
+      _ => keys.push((key, vec![location])),
+    }
+  }
+  let path = index_path(table, name);
+  let file = File::create(&path).map_err(|e| Error::io(&path, e))?;
+  let format = format_key(dialect, schema);
+  write_index(&keys, column, kind, (version, &format), BufWriter::new(file))
+}
+
+/// Writes nodes, keeping track of where each one starts.
+pub(crate) struct NodeWriter<W> {
+  out: W,
+  offset: u64,
+}
+
+impl<W: Write> NodeWriter<W> {
+  pub(crate) fn write(&mut self, bytes: &[u8]) -> Result<()> {
+    self.out.write_all(bytes)?;
+    self.offset += bytes.len() as u64;
+    Ok(())
+  }
+
+  /// Where the next write starts.
+  pub(crate) fn offset(&self) -> u64 {
+    self.offset
+  }
+
+  pub(crate) fn node(&mut self, body: &[u8]) -> Result<u64> {
+    let offset = self.offset;
+    self.write(&(body.len() as u32).to_le_bytes())?;
+    self.write(body)?;
+    Ok(offset)
+  }
+}
+
+/// Appends a key and the locations of the records holding it.
+pub(crate) fn put_entry(body: &mut Vec<u8>, key: &TableCell, locations: &[Location]) {
+  put_cell(body, key);
+  put_varint(body, locations.len() as u64);
+  // Offsets increase, so each is stored as the step from the last.
+  let mut last = 0;
+  for location in locations {
+    put_varint(body, location.offset - last);
+    put_varint(body, location.line as u64);
+    last = location.offset;
+  }
+}
+
+pub(crate) fn read_entry(bytes: &mut Bytes) -> Result<(TableCell, Vec<Location>)> {
+  let key = bytes.cell()?;
+  let mut last = 0;
+  let locations = (0..bytes.usize()?)
+    .map(|_| {
+      last += bytes.varint()?;
+      Ok(Location {
+        offset: last,
+        line: bytes.usize()?,
+      })
+    })
+    .collect::<Result<_>>()?;
+  Ok((key, locations))
+}
+
+/// How the nodes of an index are arranged.
+pub(crate) enum Layout {
+  /// The leaves of a B+ tree end at `leaves_end`, and the inner nodes rise
+  /// `height` levels above them to the root.
+  BTree { leaves_end: u64, height: u64, root: u64 },
+  /// The directory of a hash index holds where each bucket starts.
+  Hash { buckets: u64, directory: u64 },
+}
+
+fn write_index<W: Write>(
+  keys: &[(TableCell, Vec<Location>)],
+  column: &str,
+  kind: IndexKind,
+  (version, format): ((u64, u64), &str),
+  out: W,
+) -> Result<()> {
+  let mut out = NodeWriter { out, offset: 0 };
+  out.write(MAGIC)?;
+  let layout = match kind {
+    IndexKind::BTree => write_tree(keys, &mut out)?,
+    IndexKind::Hash => hash_index::write(keys, &mut out)?,
+  };
+  let footer = Footer {
+    column: column.to_owned(),
+    version,
+    format: format.to_owned(),
+    keys: keys.len() as u64,
+    layout,
+    end: out.offset,
+  };
+  out.write(&footer.encode())?;
+  out.out.flush()?;
+  Ok(())
+}
+
+fn write_tree<W: Write>(keys: &[(TableCell, Vec<Location>)], out: &mut NodeWriter<W>) -> Result<Layout> {
+  // The least key under each node of the level last written, and where
+  // the node starts.
+  let mut level = vec![];
+  for leaf in keys.chunks(NODE_KEYS) {
+    let mut body = vec![];
+    put_varint(&mut body, leaf.len() as u64);
+    for (key, locations) in leaf {
+      put_entry(&mut body, key, locations);
+    }
+    level.push((&leaf[0].0, out.node(&body)?));
+  }
+  let leaves_end = out.offset;
+  let mut height = 0;
+  while level.len() > 1 {
+    let mut parents = vec![];
+    for children in level.chunks(NODE_KEYS) {
+      let mut body = vec![];
+      put_varint(&mut body, children.len() as u64);
+      for (key, offset) in children {
+        put_cell(&mut body, key);
+        put_varint(&mut body, *offset);
+      }
+      parents.push((children[0].0, out.node(&body)?));
+    }
+    level = parents;
+    height += 1;
+  }
+  Ok(Layout::BTree {
+    leaves_end,
+    height,
+    root: level.first().map_or(0, |(_, offset)| *offset),
+  })
+}
+
+/// What an index holds, read from its footer.
+struct Footer {
+  column: String,
+  version: (u64, u64),
+  format: String,
+  keys: u64,
+  layout: Layout,
+  /// Where the footer starts, which is where the nodes end.
+  end: u64,
+}
+
+impl Footer {
+  /// The footer as it ends the file, followed by its offset and the magic
+  /// bytes.
+  fn encode(&self) -> Vec<u8> {
+    let mut body = vec![];
+    put_str(&mut body, &self.column);
+    put_varint(&mut body, self.version.0);
+    put_varint(&mut body, self.version.1);
+    put_str(&mut body, &self.format);
+    put_varint(&mut body, self.keys);
+    match self.layout {
+      Layout::BTree {
+        leaves_end,
+        height,
+        root,
+      } => {
+        body.push(0);
+        put_varint(&mut body, leaves_end);
+        put_varint(&mut body, height);
+        put_varint(&mut body, root);
+      }
+      Layout::Hash { buckets, directory } => {
+        body.push(1);
+        put_varint(&mut body, buckets);
+        put_varint(&mut body, directory);
+      }
+    }
+    body.extend_from_slice(&self.end.to_le_bytes());
+    body.extend_from_slice(MAGIC);
+    body
+  }
+}
+
+fn read_footer<R: Read + Seek>(input: &mut R) -> Result<Footer> {
+  let mut magic = [0u8; 8];
+  input.read_exact(&mut magic).map_err(|_| corrupt("the file is too short"))?;
+  if &magic != MAGIC {
+    return Err(corrupt("the file is not an index"));
+  }
+  let tail_start = input.seek(SeekFrom::End(-16)).map_err(|_| corrupt("the file is too short"))?;
+  let mut tail = [0u8; 16];
+  input.read_exact(&mut tail)?;
+  if &tail[8..] != MAGIC {
+    return Err(corrupt("the file is truncated"));
+  }
+  let mut offset = [0u8; 8];
+  offset.copy_from_slice(&tail[..8]);
+  let end = u64::from_le_bytes(offset);
+  if end < MAGIC.len() as u64 || end > tail_start {
+    return Err(corrupt("the footer offset is out of range"));
+  }
+  input.seek(SeekFrom::Start(end))?;
+  let mut buf = vec![0u8; (tail_start - end) as usize];
+  input.read_exact(&mut buf)?;
+  let mut bytes = Bytes::new(&buf);
+  let column = bytes.str()?;
+  let version = (bytes.varint()?, bytes.varint()?);
+  let format = bytes.str()?;
+  let keys = bytes.varint()?;
+  let layout = match bytes.byte()? {
+    0 => Layout::BTree {
+      leaves_end: bytes.varint()?,
+      height: bytes.varint()?,
+      root: bytes.varint()?,
+    },
+    1 => Layout::Hash {
+      buckets: bytes.varint()?,
+      directory: bytes.varint()?,
+    },
+    k => return Err(corrupt(format!("unknown index kind {}", k))),
+  };
+  match layout {
+    Layout::BTree { leaves_end, root, .. } if leaves_end > end || root >= end => {
+      return Err(corrupt("the root of the index is out of range"))
+    }
+    Layout::Hash { buckets: 0, .. } => return Err(corrupt("a hash index has no buckets")),
+    Layout::Hash { buckets, directory } => {
+      let directory_end = buckets.checked_mul(8).and_then(|len| directory.checked_add(len));
+      if directory < MAGIC.len() as u64 || directory_end.is_none_or(|directory_end| directory_end > end) {
+        return Err(corrupt("the directory of the index is out of range"));
+      }
+    }
+    _ => (),
+  }
+  Ok(Footer {
+    column,
+    version,
+    format,
+    keys,
+    layout,
+    end,
+  })
+}
+
+/// An index of a delimited file that is up to date with it, with what is
+/// needed to read the rows it finds.
+pub struct TableIndex {
+  pub name: String,
+  /// The position of the indexed column in the table.
+  pub column: usize,
+  path: PathBuf,
+  table: PathBuf,
+  header: Vec<String>,
+  dialect: Dialect,
+  schema: Option<Schema>,
+  footer: Footer,
+}
+
+/// Indexes are told apart by their files.
+impl fmt::Debug for TableIndex {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.path.display())
+  }
+}
+
+impl PartialEq for TableIndex {
+  fn eq(&self, other: &Self) -> bool {
+    self.path == other.path
+  }
+}
+
+impl Eq for TableIndex {}
+
+impl TableIndex {
+  /// Opens the index `name` of the table at `table`, which is read in the
+  /// given dialect and schema, or `None` if the table or how it is read
+  /// has changed since the index was built.
+  pub fn open(table: &Path, name: &str, dialect: &Dialect, schema: Option<&Schema>) -> Result<Option<Self>> {
+    let path = index_path(table, name);
+    let mut input = File::open(&path).map_err(|e| Error::io(&path, e))?;
+    let footer = read_footer(&mut input).map_err(|e| e.in_table(&path.display().to_string()))?;
+    if footer.version != version(table)? || footer.format != format_key(dialect, schema) {
+      return Ok(None);
+    }
+    let file = File::open(table).map_err(|e| Error::io(table, e))?;
+    let header = TableData::scan_file(file, dialect, schema)
+      .map_err(|e| e.in_table(&table_name(table)))?
+      .header;
+    let column = match header.iter().position(|c| *c == footer.column) {
+      Some(column) => column,
+      None => return Ok(None),
+    };
+    Ok(Some(TableIndex {
+      name: name.to_owned(),
+      column,
+      path,
+      table: table.to_owned(),
+      header,
+      dialect: dialect.clone(),
+      schema: schema.cloned(),
+      footer,
+    }))
+  }
+
+  /// The indexes of the table at `table` that are up to date with it, and
+  /// the errors of those that could not be opened, which are left unused
+  /// rather than failing the queries of the table.
+  pub fn open_all(table: &Path, dialect: &Dialect, schema: Option<&Schema>) -> (Vec<Arc<Self>>, Vec<Error>) {
+    let names = match index_names(table) {
+      Ok(names) => names,
+      Err(e) => return (vec![], vec![e]),
+    };
+    let mut indexes = vec![];
+    let mut errors = vec![];
+    for name in names {
+      match TableIndex::open(table, &name, dialect, schema) {
+        Ok(Some(index)) => indexes.push(Arc::new(index)),
+        Ok(None) => (),
+        Err(e) => errors.push(e),
+      }
+    }
+    (indexes, errors)
+  }
+
+  /// The name of the indexed column.
+  pub fn column_name(&self) -> &str {
+    &self.footer.column
+  }
+
+  /// How the index stores its keys.
+  pub fn kind(&self) -> IndexKind {
+    match self.footer.layout {
+      Layout::BTree { .. } => IndexKind::BTree,
+      Layout::Hash { .. } => IndexKind::Hash,
+    }
+  }
+
+  pub(crate) fn read_node<R: Read + Seek>(&self, input: &mut R, offset: u64) -> Result<Vec<u8>> {
+    let truncated = || corrupt("the file is truncated").in_table(&self.path.display().to_string());
+    input.seek(SeekFrom::Start(offset))?;
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len).map_err(|_| truncated())?;
+    let len = u32::from_le_bytes(len) as u64;
+    if offset.saturating_add(4 + len) > self.footer.end {
+      return Err(truncated());
+    }
+    let mut body = vec![0u8; len as usize];
+    input.read_exact(&mut body).map_err(|_| truncated())?;
+    Ok(body)
+  }
+
+  /// Where the records with keys in the range start, in file order, read
+  /// from `input`, the index file.
+  fn lookup<R: Read + Seek>(&self, input: &mut R, range: &KeyRange) -> Result<Vec<Location>> {
+    let (low, high) = match range.bounds() {
+      Some(bounds) if self.footer.keys > 0 => bounds,
+      _ => return Ok(vec![]),
+    };
+    let mut locations = match self.footer.layout {
+      Layout::BTree {
+        leaves_end,
+        height,
+        root,
+      } => self.tree_lookup(input, (low, high), leaves_end, height, root)?,
+      Layout::Hash { buckets, directory } => hash_index::lookup(self, input, range, (low, high), buckets, directory)?,
+    };
+    locations.sort_unstable();
+    Ok(locations)
+  }
+
+  fn tree_lookup<R: Read + Seek>(
+    &self,
+    input: &mut R,
+    (low, high): (TableCell, TableCell),
+    leaves_end: u64,
+    height: u64,
+    root: u64,
+  ) -> Result<Vec<Location>> {
+    let mut offset = root;
+    for _ in 0..height {
+      let body = self.read_node(input, offset)?;
+      let mut bytes = Bytes::new(&body);
+      // The last child whose least key is at most `low`, or else the
+      // first, as the keys before the first are all greater.
+      let mut child = None;
+      for _ in 0..bytes.usize()? {
+        let key = bytes.cell()?;
+        let start = bytes.varint()?;
+        if child.is_some() && key > low {
+          break;
+        }
+        child = Some(start);
+      }
+      offset = child.ok_or_else(|| corrupt("an index node is empty"))?;
+    }
+    let mut locations = vec![];
+    // The leaves follow each other, so the range is read by moving on to
+    // the next until a key is past it.
+    'leaves: while offset < leaves_end {
+      let body = self.read_node(input, offset)?;
+      let mut bytes = Bytes::new(&body);
+      for _ in 0..bytes.usize()? {
+        let (key, found) = read_entry(&mut bytes)?;
+        if key > high {
+          break 'leaves;
+        }
+        if key >= low {
+          locations.extend(found);
+        }
+      }
+      offset += 4 + body.len() as u64;
+    }
+    Ok(locations)
+  }
+}
+
+/// An index and its table, kept open across lookups, such as one for each
+/// row of the outer side of a join.
+pub struct Probe {
+  index: Arc<TableIndex>,
+  nodes: BufReader<File>,
+  table: BufReader<File>,
+}
+
+impl Probe {
+  pub fn new(index: Arc<TableIndex>) -> Result<Self> {
+    let nodes = File::open(&index.path).map_err(|e| Error::io(&index.path, e))?;
+    let table = File::open(&index.table).map_err(|e| Error::io(&index.table, e))?;
+    Ok(Probe {
+      nodes: BufReader::new(nodes),
+      table: BufReader::new(table),
+      index,
+    })
+  }
+
+  /// Where the records with keys in the range start, in file order.
+  pub fn locations(&mut self, range: &KeyRange) -> Result<Vec<Location>> {
+    let index = &self.index;
+    index
+      .lookup(&mut self.nodes, range)
+      .map_err(|e| e.in_table(&index.path.display().to_string()))
+  }
+
+  /// The record starting at the location.
+  pub fn fetch(&mut self, location: Location) -> Result<Row> {
+    let Probe { index, table, .. } = self;
+    let row = (|| {
+      table.seek(SeekFrom::Start(location.offset))?;
+      let lines = location.line.saturating_sub(1);
+      let header = index.header.clone();
+      let mut scan = FileScan::resume(&mut *table, header, &index.dialect, index.schema.as_ref(), lines);
+      match scan.next() {
+        Some(row) => row,
+        None => Err(corrupt("the index refers to a record past the end of the file")),
+      }
+    })();
+    row.map_err(|e| e.in_table(&table_name(&index.table)))
+  }
+}
+
+/// A scan of the rows with keys in a range through an index of their
+/// table.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IndexLookup {
+  pub index: Arc<TableIndex>,
+  pub range: KeyRange,
+}
+
+impl IndexLookup {
+  /// The rows, in the order of the file. The index is read when the first
+  /// row is asked for.
+  pub fn rows(&self) -> Rows<'static> {
+    Box::new(IndexRows {
+      lookup: self.clone(),
+      input: None,
+      done: false,
+    })
+  }
+}
+
+impl fmt::Display for IndexLookup {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} {}", self.index.name, self.range.describe(self.index.column_name()))
+  }
+}
+
+struct IndexRows {
+  lookup: IndexLookup,
+  /// The open index and where the rows left to read start, once looked
+  /// up.
+  input: Option<(Probe, std::vec::IntoIter<Location>)>,
+  done: bool,
+}
+
+impl IndexRows {
+  fn next_row(&mut self) -> Result<Option<Row>> {
+    if self.input.is_none() {
+      let mut probe = Probe::new(self.lookup.index.clone())?;
+      let locations = probe.locations(&self.lookup.range)?;
+      self.input = Some((probe, locations.into_iter()));
+    }
+    let (probe, locations) = self.input.as_mut().unwrap();
+    match locations.next() {
+      Some(location) => probe.fetch(location).map(Some),
+      None => Ok(None),
+    }
+  }
+}
+
+impl Iterator for IndexRows {
+  type Item = Result<Row>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+    let row = self.next_row();
+    self.done = !matches!(row, Ok(Some(_)));
+    row.transpose()
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::exec::Config;
+  use crate::functions::FunctionRegistry;
+  use crate::parser::parse_sql;
+  use crate::stats::TableStats;
+  use crate::stream::rows_of;
+  use crate::tables::tests::TempDir;
+  use std::time::{Duration, SystemTime};
+
+  /// A table keyed by integers, strings and nulls, with a byte order mark,
+  /// blank lines and fields spanning lines.
+  pub(crate) fn write_table(path: &Path, rows: usize) {
+    let mut text = String::from("\u{feff}key,note,n\n");
+    for i in 0..rows {
+      let key = match i % 7 {
+        0 => String::new(),
+        1 => format!("\"k{}\"", i % 50),
+        _ => match (i * 7919) % 40_000 {
+          n if n % 2 == 1 => format!("-{}", n),
+          n => n.to_string(),
+        },
+      };
+      let note = if i % 5 == 0 { "\"two\nlines\"" } else { "x" };
+      text.push_str(&format!("{},{},{}\n", key, note, i));
+      if i % 100 == 0 {
+        text.push('\n');
+      }
+    }
+    fs::write(path, text).unwrap();
+  }
+
+  fn in_range(cell: &Option<TableCell>, range: &KeyRange) -> bool {
+    match (range, cell) {
+      (KeyRange::Equal(key), Some(cell)) => cell == key,
+      (KeyRange::Between { above, below }, Some(TableCell::CellInt(n))) => {
+        above.is_none_or(|a| a < *n) && below.is_none_or(|b| *n < b)
+      }
+      _ => false,
+    }
+  }
+
+  #[test]
+  fn lookups_match_scans() {
+    let dir = TempDir::new("lookups");
+    let table = dir.join("t.csv");
+    write_table(&table, 40_000);
+    let dialect = Dialect::default();
+    create(&table, "by_key", "key", IndexKind::BTree, &dialect, None).unwrap();
+    create(&table, "hash_key", "key", IndexKind::Hash, &dialect, None).unwrap();
+    let tree = Arc::new(TableIndex::open(&table, "by_key", &dialect, None).unwrap().unwrap());
+    assert!(matches!(tree.footer.layout, Layout::BTree { height: 2, .. }));
+    let hash = Arc::new(TableIndex::open(&table, "hash_key", &dialect, None).unwrap().unwrap());
+    assert_eq!(hash.kind(), IndexKind::Hash);
+    let data = TableData::of_file(&File::open(&table).unwrap()).unwrap();
+    let between = |above, below| KeyRange::Between { above, below };
+    let mut found = 0;
+    let ranges = [
+      KeyRange::Equal(TableCell::CellInt(0)),
+      KeyRange::Equal(TableCell::CellInt(-39_999)),
+      KeyRange::Equal(TableCell::CellInt(19_998)),
+      KeyRange::Equal(TableCell::CellInt(123_456)),
+      KeyRange::Equal(TableCell::CellString("k8".to_owned())),
+      KeyRange::Equal(TableCell::CellString("k".to_owned())),
+      between(Some(-20), Some(15)),
+      between(None, Some(-38_000)),
+      between(Some(38_000), None),
+      between(Some(5), Some(6)),
+      between(Some(i64::MAX), None),
+      between(None, Some(i64::MIN)),
+    ];
+    for index in [tree, hash] {
+      for range in &ranges {
+        let expected: Vec<Row> = data.rows.iter().filter(|row| in_range(&row[0], range)).cloned().collect();
+        let lookup = IndexLookup {
+          index: index.clone(),
+          range: range.clone(),
+        };
+        let rows: Vec<Row> = lookup.rows().collect::<Result<_>>().unwrap();
+        assert_eq!(rows, expected, "{} {:?}", index.name, range);
+        found += rows.len();
+      }
+    }
+    assert!(found > 2_000, "{}", found);
+  }
+
+  #[test]
+  fn stale_indexes_are_not_used() {
+    let dir = TempDir::new("stale");
+    let table = dir.join("t.csv");
+    write_table(&table, 20);
+    let dialect = Dialect::default();
+    let open = || TableIndex::open(&table, "by_n", &dialect, None).unwrap();
+    create(&table, "by_n", "n", IndexKind::BTree, &dialect, None).unwrap();
+    assert_eq!(index_names(&table).unwrap(), vec!["by_n".to_owned()]);
+    assert!(open().is_some());
+
+    // Rewriting the file in place changes only its modification time.
+    let file = File::options().write(true).open(&table).unwrap();
+    file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+    assert!(open().is_none());
+    assert!(TableIndex::open_all(&table, &dialect, None).0.is_empty());
+
+    create(&table, "by_n", "n", IndexKind::BTree, &dialect, None).unwrap();
+    assert_eq!(TableIndex::open_all(&table, &dialect, None).0.len(), 1);
+    let mut file = File::options().append(true).open(&table).unwrap();
+    file.write_all(b"1,y,20\n").unwrap();
+    assert!(open().is_none());
+
+    match create(&table, "by_x", "x", IndexKind::Hash, &dialect, None) {
+      Err(Error::Name { .. }) => (),
+      r => panic!("expected a name error, got {:?}", r),
+    }
+    fs::write(index_path(&table, "by_n"), b"MORIDX02 not an index").unwrap();
+    assert!(TableIndex::open(&table, "by_n", &dialect, None).is_err());
+
+    // An index that cannot be read is left unused, and the others still are.
+    create(&table, "by_note", "note", IndexKind::Hash, &dialect, None).unwrap();
+    let (indexes, errors) = TableIndex::open_all(&table, &dialect, None);
+    assert_eq!(indexes.iter().map(|i| i.name.as_str()).collect::<Vec<_>>(), vec!["by_note"]);
+    assert!(matches!(errors[..], [Error::DataFormat { .. }]), "{:?}", errors);
+  }
+
+  #[test]
+  fn corrupt_footers_are_rejected() {
+    let dir = TempDir::new("corrupt");
+    let table = dir.join("t.csv");
+    write_table(&table, 200);
+    let dialect = Dialect::default();
+    // The message opening the index gives once its layout is rewritten.
+    let rewrite = |kind: IndexKind, layout: &dyn Fn(u64) -> Layout| {
+      create(&table, "by_key", "key", kind, &dialect, None).unwrap();
+      let path = index_path(&table, "by_key");
+      let bytes = fs::read(&path).unwrap();
+      let mut footer = read_footer(&mut std::io::Cursor::new(&bytes)).unwrap();
+      footer.layout = layout(footer.end);
+      let mut rewritten = bytes[..footer.end as usize].to_vec();
+      rewritten.extend(footer.encode());
+      fs::write(&path, rewritten).unwrap();
+      match TableIndex::open(&table, "by_key", &dialect, None) {
+        Err(Error::DataFormat { message, .. }) => message,
+        r => panic!("expected a data error, got {:?}", r),
+      }
+    };
+    let hash = |buckets, directory| Layout::Hash { buckets, directory };
+    assert_eq!(rewrite(IndexKind::Hash, &|_| hash(0, 8)), "a hash index has no buckets");
+    let out_of_range: [&dyn Fn(u64) -> Layout; 5] = [
+      &|_| hash(u64::MAX / 4, 8),
+      &|end| hash(1 << 40, end - 8),
+      &|end| hash(2, end - 8),
+      &|_| hash(1, 0),
+      &|_| hash(2, u64::MAX - 8),
+    ];
+    for layout in out_of_range {
+      assert_eq!(rewrite(IndexKind::Hash, layout), "the directory of the index is out of range");
+    }
+    let tree = |end: u64| Layout::BTree {
+      leaves_end: 8,
+      height: 1,
+      root: end,
+    };
+    assert_eq!(rewrite(IndexKind::BTree, &tree), "the root of the index is out of range");
+  }
+
+  #[test]
+  fn indexes_read_in_another_format_are_not_used() {
+    let dir = TempDir::new("format");
+    let table = dir.join("t.csv");
+    fs::write(&table, "name,zip\na,19104\nb,\"19104\"\nc,10001\n").unwrap();
+    let schema_path = table.with_extension("schema");
+    fs::write(&schema_path, "name string\nzip string\n").unwrap();
+    let dialect = Dialect::default();
+    let schema = Schema::for_file(&table).unwrap();
+    create(&table, "by_zip", "zip", IndexKind::BTree, &dialect, schema.as_ref()).unwrap();
+    let open = |dialect: &Dialect, schema: Option<&Schema>| TableIndex::open(&table, "by_zip", dialect, schema).unwrap();
+    assert!(open(&dialect, schema.as_ref()).is_some());
+    let mut nulls = dialect.clone();
+    nulls.set("null", "NA").unwrap();
+    assert!(open(&nulls, schema.as_ref()).is_none());
+
+    // Without the schema, unquoted zip codes read as integers, which the
+    // index of their strings does not hold.
+    fs::remove_file(&schema_path).unwrap();
+    assert!(open(&dialect, None).is_none());
+    let (indexes, errors) = TableIndex::open_all(&table, &dialect, None);
+    assert!(indexes.is_empty() && errors.is_empty());
+    let catalog = vec![TableData::of_file(&File::open(&table).unwrap()).unwrap()];
+    let q = parse_sql("SELECT name FROM t WHERE zip == 19104")
+      .unwrap()
+      .to_indexed(&catalog, &["t".to_owned()], &FunctionRegistry::with_builtins())
+      .unwrap();
+    let mut stats = vec![TableStats::of(&catalog[0])];
+    stats[0].indexes = indexes;
+    let sources = q.sources.iter().map(|i| rows_of(&catalog[*i])).collect();
+    let rows: Vec<Row> = q.stream_with(&catalog, &stats, sources, &Config::default()).unwrap().collect::<Result<_>>().unwrap();
+    assert_eq!(rows, vec![vec![Some(TableCell::CellString("a".to_owned()))]]);
+  }
+
+  #[test]
+  fn format_keys_are_written_out() {
+    let mut dialect = Dialect::default();
+    dialect.set("delimiter", ";").unwrap();
+    dialect.set("null", "\\N").unwrap();
+    let schema = Schema::parse("name string\nage int not null").unwrap();
+    assert_eq!(
+      format_key(&dialect, Some(&schema)),
+      concat!(
+        "delimiter \";\"\n",
+        "quote \"\\\"\"\n",
+        "escape none\n",
+        "header true\n",
+        "null \"\\\\N\"\n",
+        "comment none\n",
+        "schema\n",
+        "name string\n",
+        "age int not null\n",
+      )
+    );
+    assert!(format_key(&Dialect::default(), None).ends_with("comment none\nschema none\n"));
+  }
+}
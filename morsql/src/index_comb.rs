@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+#![allow(clippy::needless_return)]
 
 
 #[derive(Debug)]
@@ -15,8 +16,8 @@ pub fn given_bounds(bounds : Vec<usize>) -> IndexCombinations {
         indices.push(0);
     }
     return IndexCombinations {
-        bounds : bounds,
-        indices : indices,
+        bounds,
+        indices,
         started : false
     };
 }
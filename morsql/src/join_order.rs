@@ -0,0 +1,424 @@
+//! Cost-based join ordering. The tables under a tree of products and joins
+//! are rejoined in the order, and with the algorithm at each step, that
+//! the statistics of the tables make cheapest, found by dynamic programming
+//! over the subsets of the tables.
+//!
+//! Costs count the rows each operator handles. A selection is assumed to
+//! keep `1/d` of its rows for an equality with a column of `d` distinct
+//! values and a third of them for any other condition.
+
+use crate::ast::{BinaryOp, IndexedFilter, UnaryOp};
+use crate::exec::{Config, JoinAlgorithm};
+use crate::plan::{equi_join, LogicalPlan};
+use crate::stats::TableStats;
+use std::collections::HashMap;
+
+/// Regions with more tables than this keep the order they were written in.
+const MAX_TABLES: usize = 12;
+const DEFAULT_SELECTIVITY: f64 = 1.0 / 3.0;
+/// The bytes assumed per column of a tuple held in memory.
+const COLUMN_BYTES: f64 = 32.0;
+
+/// Estimates the number of rows plans produce from the statistics of the
+/// tables they scan.
+pub struct Estimator<'s> {
+  stats: &'s [TableStats],
+  /// For each slot, the catalog index of its table and its number of
+  /// columns.
+  slots: HashMap<usize, (usize, usize)>,
+}
+
+impl<'s> Estimator<'s> {
+  /// `stats` holds the statistics of each table in the catalog.
+  pub fn new(plan: &LogicalPlan, stats: &'s [TableStats]) -> Self {
+    fn scans(plan: &LogicalPlan, slots: &mut HashMap<usize, (usize, usize)>) {
+      if let LogicalPlan::Scan { slot, source, header, .. } = plan {
+        slots.insert(*slot, (*source, header.len()));
+      }
+      plan.inputs().iter().for_each(|p| scans(p, slots));
+    }
+    let mut slots = HashMap::new();
+    scans(plan, &mut slots);
+    Estimator { stats, slots }
+  }
+
+  fn table(&self, slot: usize) -> Option<&TableStats> {
+    self.slots.get(&slot).and_then(|(source, _)| self.stats.get(*source))
+  }
+
+  /// The estimated number of rows of the plan, at least one.
+  pub fn rows(&self, plan: &LogicalPlan) -> f64 {
+    let rows = match plan {
+      LogicalPlan::Scan { slot, .. } => self.table(*slot).map_or(1.0, |t| t.rows as f64),
+      LogicalPlan::Rename { input, .. } | LogicalPlan::Project { input, .. } | LogicalPlan::Sort { input, .. } => {
+        self.rows(input)
+      }
+      LogicalPlan::Select { input, predicate } => self.rows(input) * self.selectivity(predicate),
+      LogicalPlan::Product { left, right } => self.rows(left) * self.rows(right),
+      LogicalPlan::Join {
+        left, right, predicate, ..
+      }
+      | LogicalPlan::IndexJoin {
+        left, right, predicate, ..
+      } => self.rows(left) * self.rows(right) * self.selectivity(predicate),
+      LogicalPlan::Aggregate { .. } => 1.0,
+      LogicalPlan::Limit { input, count, .. } => self.rows(input).min(*count as f64),
+      LogicalPlan::Union { inputs } => inputs.iter().map(|p| self.rows(p)).sum(),
+    };
+    rows.max(1.0)
+  }
+
+  /// The number of columns of the tables scanned under the plan.
+  fn columns(&self, plan: &LogicalPlan) -> usize {
+    plan.slots().iter().map(|s| self.slots.get(s).map_or(1, |(_, n)| *n)).sum()
+  }
+
+  fn distinct(&self, e: &IndexedFilter) -> Option<f64> {
+    match e {
+      IndexedFilter::Id(id) => self
+        .table(id.table)
+        .and_then(|t| t.columns.get(id.field))
+        .map(|c| (c.distinct as f64).max(1.0)),
+      _ => None,
+    }
+  }
+
+  /// The estimated fraction of rows satisfying the predicate.
+  pub fn selectivity(&self, predicate: &IndexedFilter) -> f64 {
+    match predicate {
+      IndexedFilter::LitB(b) => f64::from(u8::from(*b)),
+      IndexedFilter::UnaryOp(UnaryOp::Not, e) => 1.0 - self.selectivity(e),
+      IndexedFilter::BinaryOp(BinaryOp::And, l, r) => self.selectivity(l) * self.selectivity(r),
+      IndexedFilter::BinaryOp(BinaryOp::Or, l, r) => {
+        let (l, r) = (self.selectivity(l), self.selectivity(r));
+        l + r - l * r
+      }
+      IndexedFilter::BinaryOp(BinaryOp::Eq, l, r) => match (self.distinct(l), self.distinct(r)) {
+        (Some(l), Some(r)) => 1.0 / l.max(r),
+        (Some(d), None) | (None, Some(d)) => 1.0 / d,
+        (None, None) => DEFAULT_SELECTIVITY,
+      },
+      _ => DEFAULT_SELECTIVITY,
+    }
+  }
+}
+
+impl LogicalPlan {
+  /// Reorders each tree of products and joins in the plan, choosing the
+  /// algorithm of each join with equalities between its inputs: a hash
+  /// join when the side it builds a table of fits in the memory budget and
+  /// a merge join otherwise. `stats` holds the statistics of each table in
+  /// the catalog.
+  pub fn order_joins(self, stats: &[TableStats], config: &Config) -> LogicalPlan {
+    let estimator = Estimator::new(&self, stats);
+    order(self, &estimator, config)
+  }
+}
+
+fn order(plan: LogicalPlan, estimator: &Estimator, config: &Config) -> LogicalPlan {
+  match plan {
+    plan @ (LogicalPlan::Product { .. } | LogicalPlan::Join { .. }) => {
+      let (mut leaves, mut predicates) = (vec![], vec![]);
+      flatten(plan, &mut leaves, &mut predicates);
+      let leaves = leaves.into_iter().map(|p| order(p, estimator, config)).collect();
+      Region::new(leaves, predicates, estimator, config).best_plan()
+    }
+    plan => plan.map_inputs(|p| order(p, estimator, config)),
+  }
+}
+
+/// Collects the inputs of a tree of products and joins, left to right, and
+/// the conjuncts of their predicates.
+fn flatten(plan: LogicalPlan, leaves: &mut Vec<LogicalPlan>, predicates: &mut Vec<IndexedFilter>) {
+  match plan {
+    LogicalPlan::Product { left, right } => {
+      flatten(*left, leaves, predicates);
+      flatten(*right, leaves, predicates);
+    }
+    LogicalPlan::Join {
+      left, right, predicate, ..
+    } => {
+      predicates.extend(predicate.conjuncts().into_iter().cloned());
+      flatten(*left, leaves, predicates);
+      flatten(*right, leaves, predicates);
+    }
+    leaf => leaves.push(leaf),
+  }
+}
+
+/// A way of joining a subset of the leaves of a region.
+#[derive(Clone)]
+enum Tree {
+  Leaf(usize),
+  Join {
+    left: Box<Tree>,
+    right: Box<Tree>,
+    algorithm: Option<JoinAlgorithm>,
+  },
+}
+
+#[derive(Clone)]
+struct Entry {
+  cost: f64,
+  rows: f64,
+  tree: Tree,
+}
+
+/// The leaves of a tree of products and joins and the predicates between
+/// them, each predicate with the set of leaves it reads as a bit mask.
+struct Region<'e, 's> {
+  leaves: Vec<LogicalPlan>,
+  predicates: Vec<(IndexedFilter, usize)>,
+  slots: Vec<Vec<usize>>,
+  rows: Vec<f64>,
+  columns: Vec<usize>,
+  estimator: &'e Estimator<'s>,
+  config: &'e Config,
+}
+
+impl<'e, 's> Region<'e, 's> {
+  fn new(
+    leaves: Vec<LogicalPlan>,
+    predicates: Vec<IndexedFilter>,
+    estimator: &'e Estimator<'s>,
+    config: &'e Config,
+  ) -> Self {
+    let slots: Vec<Vec<usize>> = leaves.iter().map(LogicalPlan::slots).collect();
+    let all = (1 << leaves.len()) - 1;
+    let predicates = predicates
+      .into_iter()
+      .map(|p| {
+        let mask = p.slots().iter().fold(0, |mask, s| {
+          mask | slots.iter().position(|leaf| leaf.contains(s)).map_or(0, |i| 1 << i)
+        });
+        // A predicate reading none of the tables is checked at the top.
+        (p, if mask == 0 { all } else { mask })
+      })
+      .collect();
+    Region {
+      rows: leaves.iter().map(|p| estimator.rows(p)).collect(),
+      columns: leaves.iter().map(|p| estimator.columns(p)).collect(),
+      leaves,
+      predicates,
+      slots,
+      estimator,
+      config,
+    }
+  }
+
+  /// The predicates to check when joining the leaves in `left` with those
+  /// in `right`: those reading both sides and nothing else.
+  fn between(&self, left: usize, right: usize) -> Vec<&IndexedFilter> {
+    let both = left | right;
+    self
+      .predicates
+      .iter()
+      .filter(|(_, mask)| mask & both == *mask && mask & left != *mask && mask & right != *mask)
+      .map(|(p, _)| p)
+      .collect()
+  }
+
+  fn leaf_slots(&self, mask: usize) -> Vec<usize> {
+    (0..self.leaves.len())
+      .filter(|i| mask & (1 << i) != 0)
+      .flat_map(|i| self.slots[i].iter().copied())
+      .collect()
+  }
+
+  fn leaf(&self, i: usize) -> Entry {
+    Entry {
+      cost: 0.0,
+      rows: self.rows[i],
+      tree: Tree::Leaf(i),
+    }
+  }
+
+  fn join(&self, left: &Entry, left_mask: usize, right: &Entry, right_mask: usize) -> Entry {
+    let predicates = self.between(left_mask, right_mask);
+    let selectivity: f64 = predicates.iter().map(|p| self.estimator.selectivity(p)).product();
+    let rows = (left.rows * right.rows * selectivity).max(1.0);
+    let equi = IndexedFilter::conjunction(predicates.into_iter().cloned().collect()).is_some_and(|p| {
+      !equi_join(&p, &self.leaf_slots(left_mask), &self.leaf_slots(right_mask))
+        .0
+        .is_empty()
+    });
+    let (step, algorithm) = if !equi {
+      (left.rows * right.rows + left.rows + right.rows, None)
+    } else {
+      let columns: usize = (0..self.leaves.len())
+        .filter(|i| right_mask & (1 << i) != 0)
+        .map(|i| self.columns[i])
+        .sum();
+      if right.rows * columns as f64 * COLUMN_BYTES <= self.config.memory_budget as f64 {
+        (left.rows + right.rows, Some(JoinAlgorithm::Hash))
+      } else {
+        let sort = |n: f64| n * n.max(2.0).log2();
+        (sort(left.rows) + sort(right.rows), Some(JoinAlgorithm::Merge))
+      }
+    };
+    Entry {
+      cost: left.cost + right.cost + step + rows,
+      rows,
+      tree: Tree::Join {
+        left: Box::new(left.tree.clone()),
+        right: Box::new(right.tree.clone()),
+        algorithm,
+      },
+    }
+  }
+
+  /// The leaves joined left-deep in the order they were written.
+  fn written_order(&self) -> Entry {
+    let mut entry = self.leaf(0);
+    for i in 1..self.leaves.len() {
+      entry = self.join(&entry, (1 << i) - 1, &self.leaf(i), 1 << i);
+    }
+    entry
+  }
+
+  fn best(&self) -> Entry {
+    let n = self.leaves.len();
+    let written = self.written_order();
+    if n > MAX_TABLES {
+      return written;
+    }
+    let mut best: Vec<Option<Entry>> = vec![None; 1 << n];
+    for i in 0..n {
+      best[1 << i] = Some(self.leaf(i));
+    }
+    for mask in 1..(1usize << n) {
+      if mask.count_ones() < 2 {
+        continue;
+      }
+      let mut left = (mask - 1) & mask;
+      while left > 0 {
+        let right = mask & !left;
+        if let (Some(l), Some(r)) = (&best[left], &best[right]) {
+          let entry = self.join(l, left, r, right);
+          if best[mask].as_ref().is_none_or(|b| entry.cost < b.cost) {
+            best[mask] = Some(entry);
+          }
+        }
+        left = (left - 1) & mask;
+      }
+    }
+    let best = best.pop().flatten().unwrap();
+    // Keep the written order unless another is clearly cheaper.
+    if best.cost < written.cost * (1.0 - 1e-9) {
+      best
+    } else {
+      written
+    }
+  }
+
+  fn best_plan(mut self) -> LogicalPlan {
+    let tree = self.best().tree;
+    let mut leaves: Vec<Option<LogicalPlan>> = self.leaves.drain(..).map(Some).collect();
+    self.build(tree, &mut leaves).0
+  }
+
+  fn build(&self, tree: Tree, leaves: &mut [Option<LogicalPlan>]) -> (LogicalPlan, usize) {
+    match tree {
+      Tree::Leaf(i) => (leaves[i].take().unwrap(), 1 << i),
+      Tree::Join { left, right, algorithm } => {
+        let (left, left_mask) = self.build(*left, leaves);
+        let (right, right_mask) = self.build(*right, leaves);
+        let (left, right) = (Box::new(left), Box::new(right));
+        let predicates = self.between(left_mask, right_mask).into_iter().cloned().collect();
+        let plan = match IndexedFilter::conjunction(predicates) {
+          Some(predicate) => LogicalPlan::Join {
+            left,
+            right,
+            predicate,
+            algorithm,
+          },
+          None => LogicalPlan::Product { left, right },
+        };
+        (plan, left_mask | right_mask)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::functions::FunctionRegistry;
+  use crate::parser::parse_sql;
+  use crate::tables::{TableCell, TableData};
+
+  fn table(columns: &[&str], rows: i64, distinct: &[i64]) -> TableData {
+    TableData {
+      header: columns.iter().map(|c| c.to_string()).collect(),
+      rows: (0..rows)
+        .map(|i| distinct.iter().map(|d| Some(TableCell::CellInt(i % d))).collect())
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn orders_joins_by_size() {
+    // A large fact table joined to two small dimensions, written with the
+    // fact table between them and the dimensions unconnected.
+    let catalog = vec![
+      table(&["id", "name"], 100, &[100, 100]),
+      table(&["a", "b", "v"], 5000, &[100, 200, 5000]),
+      table(&["id", "kind"], 200, &[200, 3]),
+    ];
+    let names: Vec<String> = ["d1", "f", "d2"].iter().map(|n| n.to_string()).collect();
+    let q = parse_sql("SELECT v FROM d2, d1, f WHERE f.a == d1.id && f.b == d2.id && d2.kind == 1")
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::new())
+      .unwrap();
+    let stats: Vec<TableStats> = catalog.iter().map(TableStats::of).collect();
+    let plan = |config: &Config| format!("{}", q.logical_plan(&catalog).optimize(&stats, config));
+    assert_eq!(
+      plan(&Config::default()),
+      concat!(
+        "Project f.v\n",
+        "  Hash Join (f.a == d1.id)\n",
+        "    Hash Join (f.b == d2.id)\n",
+        "      Scan f\n",
+        "      Select (d2.kind == 1)\n",
+        "        Scan d2\n",
+        "    Scan d1\n",
+      )
+    );
+    let tight = Config {
+      memory_budget: 1000,
+      ..Config::default()
+    };
+    assert!(plan(&tight).contains("Merge Join"));
+
+    // The same rows as joining in the written order.
+    let mut scan = |slot: usize| crate::stream::rows_of(&catalog[q.sources[slot]]);
+    let written = q.logical_plan(&catalog).push_down_predicates().lower(&Config::default(), &mut scan);
+    let mut expected: Vec<_> = crate::exec::Execution::new(written).map(Result::unwrap).collect();
+    let mut rows = q.run(&catalog).unwrap().rows;
+    expected.sort();
+    rows.sort();
+    assert_eq!(rows, expected);
+  }
+
+  #[test]
+  fn keeps_written_order_without_statistics() {
+    let catalog = vec![table(&["x"], 0, &[1]), table(&["y"], 0, &[1]), table(&["z"], 0, &[1])];
+    let names: Vec<String> = ["r", "s", "t"].iter().map(|n| n.to_string()).collect();
+    let q = parse_sql("SELECT * FROM r, s, t WHERE x == y && y == z")
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::new())
+      .unwrap();
+    let stats: Vec<TableStats> = catalog.iter().map(TableStats::of).collect();
+    assert_eq!(
+      format!("{}", q.logical_plan(&catalog).optimize(&stats, &Config::default())),
+      concat!(
+        "Project *\n",
+        "  Hash Join (s.y == t.z)\n",
+        "    Hash Join (r.x == s.y)\n",
+        "      Scan r\n",
+        "      Scan s\n",
+        "    Scan t\n",
+      )
+    );
+  }
+}
@@ -0,0 +1,34 @@
+extern crate nom;
+#[cfg(test)]
+extern crate quickcheck;
+#[cfg(test)]
+#[macro_use(quickcheck)]
+extern crate quickcheck_macros;
+extern crate regex;
+
+pub mod ast;
+pub mod batch;
+pub mod columnar;
+pub mod compile;
+pub mod csv;
+pub mod error;
+pub mod exec;
+pub mod explain;
+pub mod functions;
+pub mod hash_index;
+pub mod index;
+pub mod index_comb;
+pub mod join_order;
+pub mod output;
+pub mod parallel;
+pub mod parse_error;
+pub mod parser;
+pub mod plan;
+pub mod prepared;
+pub mod rewrite;
+pub mod schema;
+pub mod simplify;
+pub mod sort;
+pub mod stats;
+pub mod stream;
+pub mod tables;
@@ -1,63 +1,469 @@
-extern crate nom;
-#[cfg(test)]
-extern crate quickcheck;
-#[cfg(test)]
-#[macro_use(quickcheck)]
-extern crate quickcheck_macros;
-extern crate regex;
 #[macro_use]
 extern crate clap;
+extern crate morsql;
 
+use clap::{Arg, ArgMatches, SubCommand};
+use morsql::ast::{IndexKind, Param, Statement};
+use morsql::batch::ColumnarTable;
+use morsql::columnar;
+use morsql::csv::Dialect;
+use morsql::error::{Error, NameError};
+use morsql::exec::Config;
+use morsql::functions::FunctionRegistry;
+use morsql::index::{self, TableIndex};
+use morsql::output::OutputFormat;
+use morsql::parser;
+use morsql::prepared::{Bindings, PreparedStatement};
+use morsql::schema::Schema;
+use morsql::stats::TableStats;
+use morsql::stream::Rows;
+use morsql::tables::{TableCell, TableData};
 use std::env;
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
-use std::path::Path;
-
-mod ast;
-mod index_comb;
-mod parser;
-mod tables;
-
-fn find_files(p: &Path) -> Vec<(File, String)> {
-  fs::read_dir(p)
-    .unwrap()
-    .map(|res| res.unwrap().path())
-    .filter(|e| e.extension() == Some(OsStr::new("csv")))
-    .map(|p| {
-      (
-        File::open(&p).unwrap(),
-        p.file_stem().unwrap().to_str().unwrap().to_string(),
-      )
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// The data files in `p` with their table names.
+fn find_files(p: &Path) -> Result<Vec<(PathBuf, String)>, Error> {
+  let mut files = vec![];
+  for entry in fs::read_dir(p).map_err(|e| Error::io(p, e))? {
+    let path = entry.map_err(|e| Error::io(p, e))?.path();
+    if !matches!(path.extension().and_then(OsStr::to_str), Some("csv" | "tsv" | "mor")) {
+      continue;
+    }
+    let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+    files.push((path, name));
+  }
+  Ok(files)
+}
+
+/// A `--dialect` option: the table it applies to, if not all, and the key
+/// and value.
+type DialectOption<'a> = (Option<&'a str>, &'a str, &'a str);
+
+/// Parses a `--dialect` argument such as `delimiter=;`, which applies to
+/// every table, or `users:header=false`, which applies to one.
+fn parse_dialect_option(arg: &str) -> Result<DialectOption<'_>, Error> {
+  let (target, value) = arg.split_once('=').ok_or_else(|| {
+    Error::Usage(format!(
+      "Expected a dialect option of the form [table:]key=value, found {}",
+      arg
+    ))
+  })?;
+  Ok(match target.split_once(':') {
+    Some((table, key)) => (Some(table), key, value),
+    None => (None, target, value),
+  })
+}
+
+fn is_columnar(path: &Path) -> bool {
+  path.extension() == Some(OsStr::new("mor"))
+}
+
+/// The dialect of a text table, given by its extension and sidecar file and
+/// then by the `--dialect` options, and its schema if it has one.
+fn text_format(path: &Path, name: &str, options: &[DialectOption]) -> Result<(Dialect, Option<Schema>), Error> {
+  let mut dialect = Dialect::for_file(path)?;
+  for (table, key, value) in options {
+    if table.is_none() {
+      dialect.set(key, value)?;
+    }
+  }
+  for (table, key, value) in options {
+    if *table == Some(name) {
+      dialect.set(key, value)?;
+    }
+  }
+  Ok((dialect, Schema::for_file(path)?))
+}
+
+/// Opens a table, reading its header and leaving its rows to be read.
+/// Columnar files carry their own schema; text files are read in the
+/// dialect given by their extension and sidecar file, then by the
+/// `--dialect` options, and checked against their schema file if they have
+/// one. With more than one thread, text files are read a window of pieces
+/// at a time, parsing the pieces of a window in parallel.
+fn open_table(
+  path: &Path,
+  name: &str,
+  options: &[DialectOption],
+  threads: usize,
+) -> Result<(Vec<String>, Rows<'static>), Error> {
+  let file = File::open(path).map_err(|e| Error::io(path, e))?;
+  let name = name.to_owned();
+  if is_columnar(path) {
+    let scan = columnar::scan(BufReader::new(file)).map_err(|e| e.in_table(&name))?;
+    let header = scan.footer.schema.names();
+    return Ok((header, Box::new(scan.map(move |row| row.map_err(|e| e.in_table(&name))))));
+  }
+  let (dialect, schema) = text_format(path, &name, options)?;
+  if threads > 1 {
+    let scan = TableData::scan_file_chunked(file, &dialect, schema.as_ref(), threads).map_err(|e| e.in_table(&name))?;
+    let header = scan.header.clone();
+    return Ok((header, Box::new(scan.map(move |row| row.map_err(|e| e.in_table(&name))))));
+  }
+  let scan = TableData::scan_file(file, &dialect, schema.as_ref()).map_err(|e| e.in_table(&name))?;
+  let header = scan.header.clone();
+  Ok((header, Box::new(scan.map(move |row| row.map_err(|e| e.in_table(&name))))))
+}
+
+fn load_table(path: &Path, name: &str, options: &[DialectOption], threads: usize) -> Result<TableData, Error> {
+  let (header, rows) = open_table(path, name, options, threads)?;
+  Ok(TableData {
+    header,
+    rows: rows.collect::<Result<_, _>>()?,
+  })
+}
+
+/// Reads a columnar file whole, to be queried a batch at a time.
+fn load_columnar(path: &Path, name: &str) -> Result<ColumnarTable, Error> {
+  let file = File::open(path).map_err(|e| Error::io(path, e))?;
+  let data = columnar::read(BufReader::new(file)).map_err(|e| e.in_table(name))?;
+  Ok(ColumnarTable::of_table(&data))
+}
+
+fn dialect_options<'a>(matches: &'a ArgMatches) -> Result<Vec<DialectOption<'a>>, Error> {
+  matches
+    .values_of("dialect")
+    .into_iter()
+    .flatten()
+    .map(parse_dialect_option)
+    .collect()
+}
+
+/// Converts a table between a text format and the columnar format, each
+/// chosen by the file's extension.
+fn convert(matches: &ArgMatches) -> Result<(), Error> {
+  let input = Path::new(matches.value_of("INPUT").unwrap());
+  let output = Path::new(matches.value_of("OUTPUT").unwrap());
+  let name = input.file_stem().unwrap_or_default().to_string_lossy();
+  let data = load_table(input, &name, &dialect_options(matches)?, threads(matches)?)?;
+  let file = BufWriter::new(File::create(output).map_err(|e| Error::io(output, e))?);
+  if is_columnar(output) {
+    columnar::write(&data, file)
+  } else {
+    OutputFormat::Csv.write(&data, file)
+  }
+}
+
+/// Builds an index of a text table in the data directory, written beside
+/// its file.
+fn create_index(
+  files: &[(PathBuf, String)],
+  options: &[DialectOption],
+  name: &str,
+  table: &str,
+  column: &str,
+  kind: IndexKind,
+) -> Result<(), Error> {
+  let (path, _) = files
+    .iter()
+    .find(|(_, n)| n == table)
+    .ok_or_else(|| Error::name(NameError::UnknownTable(table.to_owned()), None))?;
+  if is_columnar(path) {
+    return Err(Error::Usage(format!(
+      "{} is a columnar table, and only CSV and TSV files can be indexed",
+      table
+    )));
+  }
+  let (dialect, schema) = text_format(path, table, options)?;
+  index::create(path, name, column, kind, &dialect, schema.as_ref())
+}
+
+/// Parses a `--param` argument such as `1=42`, `$1=42` or `name="Harry"`.
+/// Values are integers if they parse as one and strings otherwise; quotes
+/// force a string.
+fn parse_param(arg: &str) -> Result<(Param, TableCell), Error> {
+  let (name, value) = arg.split_once('=').ok_or_else(|| {
+    Error::Usage(format!(
+      "Expected a parameter of the form name=value, found {}",
+      arg
+    ))
+  })?;
+  let name = name.trim_start_matches(['$', ':']);
+  let param = match name.parse::<usize>() {
+    Ok(n) => Param::Positional(n),
+    Err(_) => Param::Named(name.to_owned()),
+  };
+  let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+    TableCell::CellString(value[1..value.len() - 1].to_owned())
+  } else {
+    match value.parse::<i64>() {
+      Ok(n) => TableCell::CellInt(n),
+      Err(_) => TableCell::CellString(value.to_owned()),
+    }
+  };
+  Ok((param, value))
+}
+
+/// Where to write results: the `--output` file, or standard output.
+fn output(matches: &ArgMatches) -> Result<Box<dyn Write>, Error> {
+  match matches.value_of("output") {
+    Some(path) => {
+      let file = File::create(path).map_err(|e| Error::io(path, e))?;
+      Ok(Box::new(BufWriter::new(file)))
+    }
+    None => Ok(Box::new(io::stdout())),
+  }
+}
+
+/// Scans a data file and writes a proposed schema for it.
+fn infer_schema(matches: &ArgMatches) -> Result<(), Error> {
+  let path = Path::new(matches.value_of("FILE").unwrap());
+  let mut dialect = Dialect::for_file(path)?;
+  for arg in matches.values_of("dialect").into_iter().flatten() {
+    match parse_dialect_option(arg)? {
+      (None, key, value) => dialect.set(key, value)?,
+      (Some(_), _, _) => return Err(Error::Usage(format!("{} applies to one table, but only one file is read", arg))),
+    }
+  }
+  let file = File::open(path).map_err(|e| Error::io(path, e))?;
+  let schema = Schema::infer(BufReader::new(file), &dialect)?;
+  let mut out = output(matches)?;
+  write!(out, "{}", schema)?;
+  out.flush()?;
+  Ok(())
+}
+
+/// A number of bytes, optionally with a K, M or G suffix.
+fn parse_size(arg: &str) -> Result<usize, Error> {
+  let arg = arg.trim();
+  let (digits, shift) = match arg.char_indices().last() {
+    Some((i, 'k')) | Some((i, 'K')) => (&arg[..i], 10),
+    Some((i, 'm')) | Some((i, 'M')) => (&arg[..i], 20),
+    Some((i, 'g')) | Some((i, 'G')) => (&arg[..i], 30),
+    _ => (arg, 0),
+  };
+  digits
+    .parse::<usize>()
+    .ok()
+    .and_then(|n| n.checked_mul(1 << shift))
+    .ok_or_else(|| Error::Usage(format!("Invalid size {}, expected a number of bytes such as 512K or 64M", arg)))
+}
+
+/// The `--threads` option, 1 if it is not given.
+fn threads(matches: &ArgMatches) -> Result<usize, Error> {
+  match matches.value_of("threads") {
+    None => Ok(1),
+    Some(threads) => match threads.parse::<usize>() {
+      Ok(n) if n > 0 => Ok(n),
+      _ => Err(Error::Usage(format!("Invalid number of threads {}, expected a positive number", threads))),
+    },
+  }
+}
+
+fn config(matches: &ArgMatches) -> Result<Config, Error> {
+  let mut config = Config::default();
+  if let Some(budget) = matches.value_of("memory_budget") {
+    config.memory_budget = parse_size(budget)?;
+  }
+  if let Some(join) = matches.value_of("join") {
+    config.join = Some(join.parse()?);
+  }
+  config.threads = threads(matches)?;
+  Ok(config)
+}
+
+fn run(matches: &ArgMatches, query_text: &str) -> Result<(), Error> {
+  let statement = parser::parse_statement(query_text)?;
+
+  let data_dir = match matches.value_of("data_dir") {
+    Some(d) => PathBuf::from(d),
+    None => env::current_dir()?,
+  };
+  let files = find_files(&data_dir)?;
+  let names: Vec<String> = files.iter().map(|(_, name)| name.clone()).collect();
+  let mut bindings = Bindings::new();
+  for arg in matches.values_of("param").into_iter().flatten() {
+    let (param, value) = parse_param(arg)?;
+    bindings.set(param, value);
+  }
+
+  let options = dialect_options(matches)?;
+  let query = match &statement {
+    Statement::CreateIndex {
+      name,
+      table,
+      column,
+      kind,
+    } => return create_index(&files, &options, name, table, column, *kind),
+    statement => statement.query().unwrap(),
+  };
+
+  // Only the headers are needed to bind the query; the tables it reads are
+  // opened again and streamed.
+  let catalog = files
+    .iter()
+    .map(|(path, name)| {
+      open_table(path, name, &options, 1).map(|(header, _)| TableData { header, rows: vec![] })
     })
-    .collect::<Vec<_>>()
+    .collect::<Result<Vec<_>, _>>()?;
+  let config = config(matches)?;
+  let prepared = PreparedStatement::new(query, &catalog, &names, &FunctionRegistry::with_builtins())?;
+  let query = prepared.bind(&bindings)?;
+
+  // A query reading only columnar files loads them whole and runs over
+  // them a batch at a time. Others stream their tables from their files.
+  let mut stats: Vec<TableStats> = catalog.iter().map(TableStats::of).collect();
+  let batched = !query.sources.is_empty() && query.sources.iter().all(|i| is_columnar(&files[*i].0));
+  let mut tables = vec![];
+  let mut sources = None;
+  if batched {
+    for i in &query.sources {
+      let table = load_columnar(&files[*i].0, &files[*i].1)?;
+      stats[*i] = table.stats();
+      tables.push(table);
+    }
+  } else {
+    sources = Some(
+      query
+        .sources
+        .iter()
+        .map(|i| open_table(&files[*i].0, &files[*i].1, &options, config.threads).map(|(_, rows)| rows))
+        .collect::<Result<Vec<_>, _>>()?,
+    );
+    // Join order only matters with several tables, when the statistics of
+    // each are gathered by reading it once beforehand. With --cache-stats
+    // they are read from the file caching them beside it instead, which is
+    // written when it is missing or out of date.
+    for i in &query.sources {
+      let (path, name) = &files[*i];
+      let format = if is_columnar(path) {
+        None
+      } else {
+        Some(text_format(path, name, &options)?)
+      };
+      let open = || {
+        let (header, rows) = open_table(path, name, &options, config.threads)?;
+        Ok((header.len(), rows))
+      };
+      if query.sources.len() > 1 && matches.is_present("cache_stats") {
+        let key = format
+          .as_ref()
+          .map_or_else(String::new, |(dialect, schema)| index::format_key(dialect, schema.as_ref()));
+        stats[*i] = TableStats::cached(path, &key, open)?;
+      } else if query.sources.len() > 1 {
+        let (width, rows) = open()?;
+        stats[*i] = TableStats::collect(width, rows)?;
+      }
+      if let Some((dialect, schema)) = format {
+        let (indexes, errors) = TableIndex::open_all(path, &dialect, schema.as_ref());
+        for e in errors {
+          eprintln!("warning: an index of {} is not used: {}", name, e);
+        }
+        stats[*i].indexes = indexes;
+      }
+    }
+  }
+  let format: OutputFormat = matches.value_of("output_format").unwrap_or("csv").parse()?;
+  if matches.is_present("show_plan") {
+    eprint!("{}", query.plan(&catalog, &stats, &config));
+  }
+  if let Statement::Explain { analyze, format, .. } = statement {
+    let profile = match sources {
+      Some(sources) => query.explain(&catalog, &stats, sources, &config, analyze)?,
+      None => query.explain_columnar(&catalog, &stats, tables.iter().collect(), &config, analyze)?,
+    };
+    let mut out = output(matches)?;
+    write!(out, "{}", profile.render(format, analyze))?;
+    out.flush()?;
+    return Ok(());
+  }
+  let rows = match sources {
+    Some(sources) => query.stream_with(&catalog, &stats, sources, &config)?,
+    None => query.stream_columnar(&catalog, &stats, tables.iter().collect(), &config)?,
+  };
+  let mut writer = format.writer(output(matches)?, &query.header(&catalog))?;
+  for row in rows {
+    writer.row(&row?)?;
+  }
+  writer.finish()?;
+
+  Ok(())
 }
 
-fn main() -> Result<(), String> {
+fn main() {
+  let dialect_arg = || {
+    Arg::from_usage("--dialect [OPTION]... 'A CSV dialect option, e.g. `delimiter=;`'").number_of_values(1)
+  };
   let matches = clap_app!(morsql =>
       (version: "1.0")
       (author: "Lucas Silver and Harry Goldstein")
       (about: "A small DBMS.")
+      (@setting SubcommandsNegateReqs)
       (@arg INPUT: +required "The query file to run.")
-      (@arg data_dir: -d --data +takes_value "The directory containing the data CSV files.")
+      (@arg data_dir: -d --data +takes_value "The directory containing the data files: CSV, TSV or columnar (.mor).")
+      (@arg param: -p --param +takes_value +multiple number_of_values(1)
+        "A value for a query parameter, e.g. `1=42` or `name=Harry`.")
+      (@arg dialect: --dialect +takes_value +multiple number_of_values(1)
+        "A CSV dialect option for every table, e.g. `delimiter=;`, or for one table, e.g. `users:header=false`. \
+         The options are delimiter, quote, escape, header, null and comment.")
+      (@arg output_format: -f --("output-format") +takes_value
+        "How to write the result: csv (the default), json, ndjson, markdown or pretty.")
+      (@arg output: -o --output +takes_value "A file to write the result to instead of standard output.")
+      (@arg memory_budget: --("memory-budget") +takes_value
+        "The memory a sort may use before spilling to disk, e.g. 64M. The default is 256M.")
+      (@arg join: --join +takes_value
+        "How to join tables on equal columns, hash or merge, instead of choosing for each join by the tables' sizes.")
+      (@arg threads: -t --threads +takes_value
+        "The threads to parse CSV files and evaluate filters, projections, aggregates and joins on. The default is 1.")
+      (@arg show_plan: --("show-plan") "Writes the plan chosen for the query to standard error.")
+      (@arg cache_stats: --("cache-stats")
+        "Reads the statistics used to order joins from a .stats file beside each table, writing it if it is missing \
+         or out of date.")
   )
-  .get_matches();
-
-  let query_file = matches.value_of("INPUT").unwrap();
-  let query_text = fs::read_to_string(query_file).map_err(|e| e.to_string())?;
-  let query = parser::parse_sql(&query_text)?;
-
-  let (files, names) = find_files(
-    matches
-      .value_of("data_dir")
-      .map(|s| Path::new(s))
-      .unwrap_or(env::current_dir().unwrap().as_path()),
+  .subcommand(
+    SubCommand::with_name("infer-schema")
+      .about("Scans a data file and writes a proposed schema for it.")
+      .arg(Arg::from_usage("<FILE> 'The data file to scan.'"))
+      .arg(dialect_arg())
+      .arg(Arg::from_usage("-o --output [FILE] 'A file to write the schema to instead of standard output.'")),
+  )
+  .subcommand(
+    SubCommand::with_name("convert")
+      .about("Converts a table between CSV and the columnar format, chosen by the `.mor` extension.")
+      .arg(Arg::from_usage("<INPUT> 'The table to read.'"))
+      .arg(Arg::from_usage("<OUTPUT> 'The file to write.'"))
+      .arg(Arg::from_usage("-t --threads [N] 'The threads to parse a CSV file on. The default is 1.'"))
+      .arg(dialect_arg()),
   )
-  .into_iter()
-  .unzip();
-  let data = query.run_from_files(&files, &names)?;
+  .get_matches();
 
-  println!("{}", data);
+  let subcommand = match matches.subcommand() {
+    ("infer-schema", Some(sub)) => Some(infer_schema(sub)),
+    ("convert", Some(sub)) => Some(convert(sub)),
+    _ => None,
+  };
+  if let Some(result) = subcommand {
+    if let Err(e) = result {
+      eprintln!("{}", e);
+      process::exit(e.exit_code());
+    }
+    return;
+  }
 
-  Ok(())
+  let query_file = matches.value_of("INPUT").unwrap();
+  let (error, message) = match fs::read_to_string(query_file) {
+    Err(e) => {
+      let e = Error::io(query_file, e);
+      let message = e.to_string();
+      (e, message)
+    }
+    Ok(text) => match run(&matches, &text) {
+      Ok(()) => return,
+      Err(e) if e.span().is_some() => {
+        let message = format!("{}: {}", query_file, e.display_with_source(&text));
+        (e, message)
+      }
+      Err(e) => {
+        let message = e.to_string();
+        (e, message)
+      }
+    },
+  };
+  eprintln!("{}", message);
+  process::exit(error.exit_code());
 }
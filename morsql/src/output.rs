@@ -0,0 +1,290 @@
+//! Writing query results as CSV, JSON, newline-delimited JSON, Markdown or
+//! aligned text.
+
+use crate::error::{Error, Result};
+use crate::tables::{TableCell, TableData};
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OutputFormat {
+  /// RFC 4180 CSV that reads back as the same table: strings are always
+  /// quoted, so that unquoted fields are numbers and empty ones are NULL.
+  Csv,
+  /// An array with an object per row.
+  Json,
+  /// An object per line.
+  Ndjson,
+  /// A GitHub-flavored Markdown table.
+  Markdown,
+  /// Columns padded to line up, for reading in a terminal.
+  Pretty,
+}
+
+impl FromStr for OutputFormat {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "csv" => Ok(OutputFormat::Csv),
+      "json" => Ok(OutputFormat::Json),
+      "ndjson" | "jsonl" => Ok(OutputFormat::Ndjson),
+      "markdown" | "md" => Ok(OutputFormat::Markdown),
+      "pretty" | "table" => Ok(OutputFormat::Pretty),
+      _ => Err(Error::Usage(format!(
+        "Unknown output format {}, expected csv, json, ndjson, markdown or pretty",
+        s
+      ))),
+    }
+  }
+}
+
+pub fn csv_field(cell: &Option<TableCell>) -> String {
+  match cell {
+    None => String::new(),
+    Some(TableCell::CellInt(i)) => i.to_string(),
+    Some(TableCell::CellString(s)) => format!("\"{}\"", s.replace('"', "\"\"")),
+  }
+}
+
+pub fn json_string(s: &str) -> String {
+  let mut out = String::with_capacity(s.len() + 2);
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+fn json_value(cell: &Option<TableCell>) -> String {
+  match cell {
+    None => "null".to_owned(),
+    Some(TableCell::CellInt(i)) => i.to_string(),
+    Some(TableCell::CellString(s)) => json_string(s),
+  }
+}
+
+fn json_object(header: &[String], row: &[Option<TableCell>]) -> String {
+  let members: Vec<String> = header
+    .iter()
+    .zip(row)
+    .map(|(name, cell)| format!("{}:{}", json_string(name), json_value(cell)))
+    .collect();
+  format!("{{{}}}", members.join(","))
+}
+
+fn markdown_text(s: &str) -> String {
+  s.replace('\\', "\\\\")
+    .replace('|', "\\|")
+    .replace("\r\n", "<br>")
+    .replace('\n', "<br>")
+}
+
+fn markdown_cell(cell: &Option<TableCell>) -> String {
+  match cell {
+    None => "NULL".to_owned(),
+    Some(TableCell::CellInt(i)) => i.to_string(),
+    Some(TableCell::CellString(s)) => markdown_text(s),
+  }
+}
+
+/// Strings are shown without quotes, with line breaks escaped so that
+/// every row takes one line.
+fn pretty_cell(cell: &Option<TableCell>) -> String {
+  match cell {
+    None => "NULL".to_owned(),
+    Some(TableCell::CellInt(i)) => i.to_string(),
+    Some(TableCell::CellString(s)) => s.replace('\r', "\\r").replace('\n', "\\n").replace('\t', "\\t"),
+  }
+}
+
+/// Writes a table a row at a time. Formats that align columns hold the rows
+/// until `finish`.
+pub struct RowWriter<W: Write> {
+  format: OutputFormat,
+  out: W,
+  header: Vec<String>,
+  rows: usize,
+  held: Vec<Vec<String>>,
+}
+
+impl OutputFormat {
+  pub fn writer<W: Write>(self, out: W, header: &[String]) -> Result<RowWriter<W>> {
+    let mut writer = RowWriter {
+      format: self,
+      out,
+      header: header.to_vec(),
+      rows: 0,
+      held: vec![],
+    };
+    match self {
+      OutputFormat::Csv => {
+        let names: Vec<String> = header.iter().map(|h| csv_header(h)).collect();
+        writeln!(writer.out, "{}", names.join(","))?
+      }
+      OutputFormat::Json => write!(writer.out, "[")?,
+      OutputFormat::Markdown => {
+        let names: Vec<String> = header.iter().map(|h| markdown_text(h)).collect();
+        writeln!(writer.out, "| {} |", names.join(" | "))?;
+        writeln!(writer.out, "|{}", " --- |".repeat(header.len()))?
+      }
+      OutputFormat::Ndjson | OutputFormat::Pretty => (),
+    }
+    Ok(writer)
+  }
+
+  /// Writes the whole of `data`.
+  pub fn write<W: Write>(self, data: &TableData, out: W) -> Result<()> {
+    let mut writer = self.writer(out, &data.header)?;
+    for row in &data.rows {
+      writer.row(row)?;
+    }
+    writer.finish()?;
+    Ok(())
+  }
+}
+
+/// Header names are quoted only when they would not read back as written.
+fn csv_header(name: &str) -> String {
+  if name.contains([',', '"', '\n', '\r']) || name.trim() != name {
+    format!("\"{}\"", name.replace('"', "\"\""))
+  } else {
+    name.to_owned()
+  }
+}
+
+impl<W: Write> RowWriter<W> {
+  pub fn row(&mut self, row: &[Option<TableCell>]) -> Result<()> {
+    match self.format {
+      OutputFormat::Csv => {
+        let fields: Vec<String> = row.iter().map(csv_field).collect();
+        writeln!(self.out, "{}", fields.join(","))?
+      }
+      OutputFormat::Json => {
+        let sep = if self.rows == 0 { "\n" } else { ",\n" };
+        write!(self.out, "{}  {}", sep, json_object(&self.header, row))?
+      }
+      OutputFormat::Ndjson => writeln!(self.out, "{}", json_object(&self.header, row))?,
+      OutputFormat::Markdown => {
+        let cells: Vec<String> = row.iter().map(markdown_cell).collect();
+        writeln!(self.out, "| {} |", cells.join(" | "))?
+      }
+      OutputFormat::Pretty => self.held.push(row.iter().map(pretty_cell).collect()),
+    }
+    self.rows += 1;
+    Ok(())
+  }
+
+  /// Writes whatever follows the last row and returns the output.
+  pub fn finish(mut self) -> Result<W> {
+    match self.format {
+      OutputFormat::Json if self.rows == 0 => writeln!(self.out, "]")?,
+      OutputFormat::Json => writeln!(self.out, "\n]")?,
+      OutputFormat::Pretty => self.write_pretty()?,
+      _ => (),
+    }
+    self.out.flush()?;
+    Ok(self.out)
+  }
+
+  fn write_pretty(&mut self) -> Result<()> {
+    let header: Vec<String> = self.header.iter().map(|h| pretty_cell(&Some(TableCell::CellString(h.clone())))).collect();
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in &self.held {
+      for (w, cell) in widths.iter_mut().zip(row) {
+        *w = (*w).max(cell.chars().count());
+      }
+    }
+    let line = |cells: &[String]| -> String {
+      let padded: Vec<String> = cells
+        .iter()
+        .zip(&widths)
+        .map(|(c, w)| format!("{}{}", c, " ".repeat(w - c.chars().count())))
+        .collect();
+      padded.join(" | ").trim_end().to_owned()
+    };
+    writeln!(self.out, "{}", line(&header))?;
+    let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+    writeln!(self.out, "{}", rule.join("-+-"))?;
+    for row in &self.held {
+      writeln!(self.out, "{}", line(row))?;
+    }
+    let n = self.held.len();
+    writeln!(self.out, "({} row{})", n, if n == 1 { "" } else { "s" })?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn render(format: OutputFormat) -> String {
+    let data = TableData {
+      header: vec!["name".to_owned(), "age".to_owned()],
+      rows: vec![
+        vec![Some(TableCell::CellString("O\"Brien, \"Pat\"".to_owned())), Some(TableCell::CellInt(24))],
+        vec![Some(TableCell::CellString("a|b\nc".to_owned())), None],
+      ],
+    };
+    let mut out = vec![];
+    format.write(&data, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn formats() {
+    assert_eq!(
+      render(OutputFormat::Csv),
+      "name,age\n\"O\"\"Brien, \"\"Pat\"\"\",24\n\"a|b\nc\",\n"
+    );
+    assert_eq!(
+      render(OutputFormat::Json),
+      "[\n  {\"name\":\"O\\\"Brien, \\\"Pat\\\"\",\"age\":24},\n  {\"name\":\"a|b\\nc\",\"age\":null}\n]\n"
+    );
+    assert_eq!(
+      render(OutputFormat::Ndjson),
+      "{\"name\":\"O\\\"Brien, \\\"Pat\\\"\",\"age\":24}\n{\"name\":\"a|b\\nc\",\"age\":null}\n"
+    );
+    assert_eq!(
+      render(OutputFormat::Markdown),
+      "| name | age |\n| --- | --- |\n| O\"Brien, \"Pat\" | 24 |\n| a\\|b<br>c | NULL |\n"
+    );
+    assert_eq!(
+      render(OutputFormat::Pretty),
+      concat!(
+        "name           | age\n",
+        "---------------+-----\n",
+        "O\"Brien, \"Pat\" | 24\n",
+        "a|b\\nc         | NULL\n",
+        "(2 rows)\n"
+      )
+    );
+  }
+
+  #[test]
+  fn csv_reads_back() {
+    let data = TableData {
+      header: vec!["s".to_owned(), "n".to_owned()],
+      rows: vec![
+        vec![Some(TableCell::CellString("x, \"y\"\nz".to_owned())), None],
+        vec![Some(TableCell::CellString("".to_owned())), Some(TableCell::CellInt(-3))],
+      ],
+    };
+    let path = std::env::temp_dir().join("morsql_output.csv");
+    OutputFormat::Csv
+      .write(&data, std::fs::File::create(&path).unwrap())
+      .unwrap();
+    let read = TableData::of_file(&std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(read, data);
+  }
+}
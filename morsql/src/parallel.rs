@@ -0,0 +1,275 @@
+//! Parallel execution. Operators that do work for each tuple of an input,
+//! such as filters, projections, aggregates and the probe side of joins,
+//! can run on several threads: they read a morsel of tuples from their
+//! input, split it into a contiguous part per thread, hand the parts to the
+//! workers of a [`Pool`] the query starts once, and put the results of the
+//! parts back together in order. Reading the input stays serial, and
+//! a query produces the same rows in the same order however many threads it
+//! runs on.
+
+use crate::error::Result;
+use crate::exec::{BoxedOperator, Tuple};
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// The number of tuples a morsel holds for each thread.
+pub const MORSEL_ROWS: usize = 4096;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Worker threads, started once for a query and shared by its operators,
+/// which are given work over a channel. The workers stop when the pool is
+/// dropped.
+pub struct Pool {
+  threads: usize,
+  jobs: Option<Sender<Job>>,
+  workers: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+  pub fn new(threads: usize) -> Self {
+    let (jobs, queue) = mpsc::channel::<Job>();
+    let queue = Arc::new(Mutex::new(queue));
+    let workers = (0..threads)
+      .map(|_| {
+        let queue = queue.clone();
+        thread::spawn(move || loop {
+          // The lock is released as soon as a job is taken.
+          let job = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+          match job {
+            Ok(job) => job(),
+            Err(_) => return,
+          }
+        })
+      })
+      .collect();
+    Pool {
+      threads,
+      jobs: Some(jobs),
+      workers,
+    }
+  }
+
+  pub fn threads(&self) -> usize {
+    self.threads
+  }
+
+  /// Splits `items` into at most as many contiguous parts of nearly equal
+  /// length as there are workers and calls `f` on each on a worker,
+  /// returning the results in the order of the parts. A panic in `f` is
+  /// raised again here once every part is done.
+  pub fn map_parts<T, R, F>(&self, items: Vec<T>, f: F) -> Vec<R>
+  where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(Vec<T>) -> R + Send + Sync + 'static,
+  {
+    if self.threads <= 1 || items.len() <= 1 {
+      return vec![f(items)];
+    }
+    let parts = split(items, self.threads);
+    let n = parts.len();
+    let f = Arc::new(f);
+    let (done, results): (Sender<_>, Receiver<_>) = mpsc::channel();
+    for (i, part) in parts.into_iter().enumerate() {
+      let (f, done) = (f.clone(), done.clone());
+      self.submit(Box::new(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(part)));
+        let _ = done.send((i, result));
+      }));
+    }
+    let mut out: Vec<Option<R>> = (0..n).map(|_| None).collect();
+    let mut panicked = None;
+    for _ in 0..n {
+      match results.recv().expect("a worker stopped") {
+        (i, Ok(result)) => out[i] = Some(result),
+        (_, Err(panic)) => panicked = Some(panic),
+      }
+    }
+    if let Some(panic) = panicked {
+      panic::resume_unwind(panic);
+    }
+    out.into_iter().map(Option::unwrap).collect()
+  }
+
+  /// Calls `f` on each item, on the workers, and returns what the calls
+  /// pushed, in the order of the items.
+  pub fn flat_map<T, U, F>(&self, items: Vec<T>, f: F) -> Vec<U>
+  where
+    T: Send + 'static,
+    U: Send + 'static,
+    F: Fn(T, &mut Vec<U>) + Send + Sync + 'static,
+  {
+    let parts = self.map_parts(items, move |part| {
+      let mut out = vec![];
+      for item in part {
+        f(item, &mut out);
+      }
+      out
+    });
+    parts.into_iter().flatten().collect()
+  }
+
+  fn submit(&self, job: Job) {
+    let jobs = self.jobs.as_ref().expect("the pool is running");
+    jobs.send(job).expect("the workers are running");
+  }
+}
+
+impl Drop for Pool {
+  fn drop(&mut self) {
+    self.jobs = None;
+    for worker in self.workers.drain(..) {
+      let _ = worker.join();
+    }
+  }
+}
+
+/// Splits `items` into at most `n` contiguous parts of nearly equal length.
+fn split<T>(mut items: Vec<T>, n: usize) -> Vec<Vec<T>> {
+  let len = items.len().div_ceil(n.max(1)).max(1);
+  let mut parts = vec![];
+  while items.len() > len {
+    let rest = items.split_off(len);
+    parts.push(items);
+    items = rest;
+  }
+  parts.push(items);
+  parts
+}
+
+/// Up to `n` tuples of an input.
+pub fn read_morsel(input: &mut BoxedOperator, n: usize) -> Result<Vec<Tuple>> {
+  let mut morsel = Vec::with_capacity(n.min(MORSEL_ROWS));
+  while morsel.len() < n {
+    match input.next()? {
+      Some(t) => morsel.push(t),
+      None => break,
+    }
+  }
+  Ok(morsel)
+}
+
+/// The tuples an operator has produced from a morsel of its input and not
+/// yet passed on.
+pub struct Morsels {
+  pool: Arc<Pool>,
+  len: usize,
+  ready: VecDeque<Tuple>,
+}
+
+impl Morsels {
+  /// Morsels of `MORSEL_ROWS` tuples per worker of the pool.
+  pub fn new(pool: Arc<Pool>) -> Self {
+    Morsels {
+      len: MORSEL_ROWS * pool.threads(),
+      pool,
+      ready: VecDeque::new(),
+    }
+  }
+
+  /// Shrinks the morsels of an operator producing up to `fanout` tuples
+  /// for each one it reads, so the tuples produced from a morsel number
+  /// about `MORSEL_ROWS` per worker.
+  pub fn set_fanout(&mut self, fanout: usize) {
+    let threads = self.pool.threads();
+    self.len = (MORSEL_ROWS * threads / fanout.max(1)).max(threads);
+  }
+
+  /// The next tuple `f` produces from the tuples of `input`, reading and
+  /// processing another morsel on the pool once those from the last are
+  /// passed on.
+  pub fn next<F>(&mut self, input: &mut BoxedOperator, f: F) -> Result<Option<Tuple>>
+  where
+    F: Fn(Tuple, &mut Vec<Tuple>) + Send + Sync + 'static,
+  {
+    if self.ready.is_empty() {
+      let f = Arc::new(f);
+      while self.ready.is_empty() {
+        let morsel = read_morsel(input, self.len)?;
+        if morsel.is_empty() {
+          return Ok(None);
+        }
+        let f = f.clone();
+        self.ready.extend(self.pool.flat_map(morsel, move |t, out| f(t, out)));
+      }
+    }
+    Ok(self.ready.pop_front())
+  }
+
+  /// Like `next`, but `f` is given a whole part of a morsel at once, so it
+  /// can set up what it needs once for each part, and may fail.
+  pub fn try_next_parts<F>(&mut self, input: &mut BoxedOperator, f: F) -> Result<Option<Tuple>>
+  where
+    F: Fn(Vec<Tuple>, &mut Vec<Tuple>) -> Result<()> + Send + Sync + 'static,
+  {
+    if self.ready.is_empty() {
+      let f = Arc::new(f);
+      while self.ready.is_empty() {
+        let morsel = read_morsel(input, self.len)?;
+        if morsel.is_empty() {
+          return Ok(None);
+        }
+        let f = f.clone();
+        let parts = self.pool.map_parts(morsel, move |part| {
+          let mut out = vec![];
+          f(part, &mut out).map(|()| out)
+        });
+        for part in parts {
+          self.ready.extend(part?);
+        }
+      }
+    }
+    Ok(self.ready.pop_front())
+  }
+
+  pub fn clear(&mut self) {
+    self.ready.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parts_keep_their_order() {
+    for threads in 1..6 {
+      let pool = Pool::new(threads);
+      for n in 0..20 {
+        let items: Vec<usize> = (0..n).collect();
+        let doubled = pool.flat_map(items.clone(), |i, out| {
+          if i % 3 != 0 {
+            out.push(i);
+            out.push(i);
+          }
+        });
+        let expected: Vec<usize> = items.iter().filter(|i| *i % 3 != 0).flat_map(|i| vec![*i, *i]).collect();
+        assert_eq!(doubled, expected);
+        let parts = pool.map_parts(items, |part| part.len());
+        assert!(parts.len() <= threads);
+        assert_eq!(parts.iter().sum::<usize>(), n);
+      }
+    }
+  }
+
+  #[test]
+  fn workers_are_reused() {
+    let pool = Pool::new(3);
+    let mut workers = std::collections::HashSet::new();
+    for _ in 0..50 {
+      workers.extend(pool.map_parts((0..9).collect(), |_: Vec<i32>| thread::current().id()));
+    }
+    assert!(workers.len() <= 3, "{:?}", workers);
+    assert!(!workers.contains(&thread::current().id()));
+
+    let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+      pool.map_parts(vec![1, 2, 3], |part: Vec<i32>| assert!(!part.contains(&2)))
+    }));
+    assert!(panicked.is_err());
+    assert_eq!(pool.map_parts(vec![1, 2, 3], |part: Vec<i32>| part.len()).len(), 3);
+  }
+}
@@ -0,0 +1,252 @@
+//! Syntax errors for `parse_sql`, pointing at the offending line and column
+//! with the constructs the parser would have accepted there.
+
+use crate::ast::Span;
+use nom::error::{ContextError, ErrorKind, FromExternalError};
+use nom::InputLength;
+use std::fmt;
+
+/// Something the parser expected: either a `context(...)` label or a
+/// specific character.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+pub enum Label {
+  Context(&'static str),
+  Char(char),
+}
+
+/// The nom error type used by the parser. Instead of a trace of every
+/// parser that failed, it keeps the furthest position reached and what
+/// could have appeared there, merging the expectations of alternatives that
+/// failed at the same position.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expected<I> {
+  pub input: I,
+  pub labels: Vec<Label>,
+  /// Whether the input was recognised but is invalid, such as a literal
+  /// out of range, so the constructs around it keep these labels.
+  pub invalid: bool,
+}
+
+impl<I> Expected<I> {
+  fn new(input: I, labels: Vec<Label>) -> Self {
+    Expected {
+      input,
+      labels,
+      invalid: false,
+    }
+  }
+
+  /// An error for input that has the form of `label` but is not valid.
+  pub fn invalid(input: I, label: Label) -> Self {
+    Expected {
+      input,
+      labels: vec![label],
+      invalid: true,
+    }
+  }
+}
+
+impl<I: InputLength> nom::error::ParseError<I> for Expected<I> {
+  fn from_error_kind(input: I, _kind: ErrorKind) -> Self {
+    Expected::new(input, vec![])
+  }
+
+  fn append(_input: I, _kind: ErrorKind, other: Self) -> Self {
+    other
+  }
+
+  fn from_char(input: I, c: char) -> Self {
+    Expected::new(input, vec![Label::Char(c)])
+  }
+
+  fn or(mut self, other: Self) -> Self {
+    match self.input.input_len().cmp(&other.input.input_len()) {
+      std::cmp::Ordering::Less => self,
+      std::cmp::Ordering::Greater => other,
+      std::cmp::Ordering::Equal => {
+        for l in other.labels {
+          if !self.labels.contains(&l) {
+            self.labels.push(l);
+          }
+        }
+        self
+      }
+    }
+  }
+}
+
+impl<I, E> FromExternalError<I, E> for Expected<I> {
+  fn from_external_error(input: I, _kind: ErrorKind, _e: E) -> Self {
+    Expected::new(input, vec![])
+  }
+}
+
+impl<I: InputLength> ContextError<I> for Expected<I> {
+  fn add_context(input: I, ctx: &'static str, other: Self) -> Self {
+    // A construct that failed on its first token was expected as a whole;
+    // one that failed further in reports what it expected at that point.
+    if other.input.input_len() == input.input_len() && !other.invalid {
+      Expected::new(other.input, vec![Label::Context(ctx)])
+    } else {
+      other
+    }
+  }
+}
+
+/// A syntax error in a query.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseError {
+  /// Line and column of the error, both counting from 1.
+  pub line: usize,
+  pub column: usize,
+  /// Byte offset of the error in the query.
+  pub offset: usize,
+  /// The text of the line containing the error.
+  pub source_line: String,
+  /// Descriptions of what could have appeared at the error.
+  pub expected: Vec<String>,
+  /// What was found instead, or `None` at the end of the query.
+  pub found: Option<String>,
+  pub hint: Option<String>,
+}
+
+fn describe(label: Label) -> String {
+  match label {
+    Label::Char(c) => format!("`{}`", c),
+    Label::Context(ctx) => match ctx {
+      "ident" => "an identifier".to_owned(),
+      "column_selector" => "a column".to_owned(),
+      "string" => "a string".to_owned(),
+      "bool" => "a boolean".to_owned(),
+      "param" => "a parameter".to_owned(),
+      "count" => "a number".to_owned(),
+      "int" => "a 64-bit integer".to_owned(),
+      "table" => "a table".to_owned(),
+      "selection" => "`*` or a list of expressions".to_owned(),
+      "factor" | "comparison" | "term" | "expression" => "an expression".to_owned(),
+      "operator" => "an operator".to_owned(),
+      "end" => "the end of the query".to_owned(),
+      keyword => format!("`{}`", keyword),
+    },
+  }
+}
+
+/// Suggestions for common mistakes, based on the text at the error.
+fn hint(rest: &str) -> Option<String> {
+  let word: String = rest
+    .chars()
+    .take_while(|c| c.is_alphanumeric() || *c == '_')
+    .collect::<String>()
+    .to_uppercase();
+  let hint = if rest.starts_with("!=") || rest.starts_with("<>") {
+    "there is no inequality operator, write `NOT (a == b)`"
+  } else if rest.starts_with('=') {
+    "did you mean `==`?"
+  } else if rest.starts_with('>') {
+    "there is no `>`, swap the operands and use `<`"
+  } else if rest.starts_with('\'') {
+    "strings are written with double quotes"
+  } else if word == "AND" {
+    "did you mean `&&`?"
+  } else if word == "OR" {
+    "did you mean `||`?"
+  } else if rest.trim().is_empty() {
+    "the query may be incomplete"
+  } else {
+    return None;
+  };
+  Some(hint.to_owned())
+}
+
+/// Line, column and text of the line at `offset` in `source`.
+fn locate(source: &str, offset: usize) -> (usize, usize, &str) {
+  let before = &source[..offset];
+  let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+  let line_end = source[offset..].find('\n').map_or(source.len(), |i| offset + i);
+  (
+    before.matches('\n').count() + 1,
+    before[line_start..].chars().count() + 1,
+    &source[line_start..line_end],
+  )
+}
+
+/// The lines pointing at a location, as printed under an error message.
+fn excerpt(line: usize, column: usize, source_line: &str, width: usize) -> String {
+  let gutter = " ".repeat(line.to_string().len());
+  format!(
+    "{g} --> line {l}, column {c}\n{g} |\n{l} | {s}\n{g} | {pad}{caret}",
+    g = gutter,
+    l = line,
+    c = column,
+    s = source_line,
+    pad = " ".repeat(column - 1),
+    caret = "^".repeat(width.max(1)),
+  )
+}
+
+impl Span {
+  /// The part of `source` covered by the span, underlined.
+  pub fn excerpt(&self, source: &str) -> String {
+    let (line, column, source_line) = locate(source, self.start.min(source.len()));
+    let width = source[self.start.min(source.len())..self.end.min(source.len())]
+      .chars()
+      .take_while(|c| *c != '\n')
+      .count();
+    excerpt(line, column, source_line, width)
+  }
+}
+
+impl ParseError {
+  /// Builds the error for a failure at `rest`, the unparsed suffix of
+  /// `source`.
+  pub fn new(source: &str, rest: &str, labels: &[Label]) -> Self {
+    let offset = source.len() - rest.len();
+    let (line, column, source_line) = locate(source, offset);
+    let mut expected: Vec<String> = vec![];
+    for l in labels {
+      let d = describe(*l);
+      if !expected.contains(&d) {
+        expected.push(d);
+      }
+    }
+    let found = rest
+      .split_whitespace()
+      .next()
+      .map(|t| t.chars().take(20).collect());
+    ParseError {
+      line,
+      column,
+      offset,
+      source_line: source_line.to_owned(),
+      expected,
+      found,
+      hint: hint(rest),
+    }
+  }
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "syntax error: ")?;
+    match self.expected.len() {
+      0 => write!(f, "unexpected input")?,
+      1 => write!(f, "expected {}", self.expected[0])?,
+      n => write!(
+        f,
+        "expected {} or {}",
+        self.expected[..n - 1].join(", "),
+        self.expected[n - 1]
+      )?,
+    }
+    match &self.found {
+      Some(t) => writeln!(f, ", found `{}`", t)?,
+      None => writeln!(f, ", found the end of the query")?,
+    }
+    write!(f, "{}", excerpt(self.line, self.column, &self.source_line, 1))?;
+    if let Some(h) = &self.hint {
+      let gutter = " ".repeat(self.line.to_string().len());
+      write!(f, "\n{} = hint: {}", gutter, h)?;
+    }
+    Ok(())
+  }
+}
@@ -1,50 +1,220 @@
 use crate::ast::*;
+use crate::parse_error::{Expected, Label, ParseError};
 use nom::branch::alt;
 use nom::bytes::complete::escaped;
 use nom::bytes::complete::is_not;
 use nom::bytes::complete::{tag, tag_no_case};
 use nom::character::complete::*;
+use nom::combinator::cut;
 use nom::combinator::map;
+use nom::combinator::map_res;
+use nom::combinator::not;
 use nom::combinator::opt;
-use nom::error::{context, VerboseError};
+use nom::combinator::recognize;
+use nom::combinator::value;
+use nom::combinator::verify;
+use nom::error::context;
 use nom::multi::fold_many0;
+use nom::multi::many0;
+use nom::multi::separated_list0;
 use nom::multi::separated_list1;
 use nom::sequence::pair;
+use nom::sequence::preceded;
 use nom::sequence::terminated;
 use nom::sequence::{delimited, tuple};
 use nom::IResult;
 
-pub fn parse_sql(input: &str) -> Result<Query, String> {
-  match query(input) {
-    Ok((_, q)) => Ok(q),
-    Err(p) => Err(format!("parsing failed: {}", p)),
+pub fn parse_sql(input: &str) -> Result<Query, ParseError> {
+  let mut q = parse_all(input, query)?;
+  locate_spans(&mut q, input.len());
+  number_placeholders(&mut q, input)?;
+  Ok(q)
+}
+
+/// Parses a query, a query prefixed with `EXPLAIN`, or `CREATE INDEX`.
+pub fn parse_statement(input: &str) -> Result<Statement, ParseError> {
+  let mut s = parse_all(input, statement)?;
+  if let Some(q) = s.query_mut() {
+    locate_spans(q, input.len());
+    number_placeholders(q, input)?;
+  }
+  Ok(s)
+}
+
+fn parse_all<'a, O>(input: &'a str, p: impl FnMut(&'a str) -> Res<&'a str, O>) -> Result<O, ParseError> {
+  match terminated(p, multispace0)(input) {
+    Ok(("", o)) => Ok(o),
+    // The filter can only be followed by another operator.
+    Ok((rest, _)) => Err(ParseError::new(
+      input,
+      rest,
+      &[Label::Context("operator"), Label::Context("end")],
+    )),
+    Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+      Err(ParseError::new(input, e.input, &e.labels))
+    }
+    Err(nom::Err::Incomplete(_)) => Err(ParseError::new(input, "", &[])),
+  }
+}
+
+/// Replaces each `?` with a positional parameter, counting from 1 in the
+/// order they appear in the query. A query with both `?` and `$n`
+/// placeholders is rejected, as the numbers given to the `?`s would collide
+/// with those written out; the error points at the first placeholder of the
+/// other kind than the query's first. Runs after `locate_spans`.
+fn number_placeholders(q: &mut Query, input: &str) -> Result<(), ParseError> {
+  struct Numbering<'a> {
+    input: &'a str,
+    next: usize,
+    anonymous: Option<bool>,
+  }
+
+  impl Numbering<'_> {
+    fn go(&mut self, f: &mut Filter) -> Result<(), ParseError> {
+      match f {
+        Filter::Param(p, span) => {
+          let anonymous = match p {
+            Param::Anonymous => true,
+            Param::Positional(_) => false,
+            Param::Named(_) => return Ok(()),
+          };
+          match self.anonymous {
+            Some(first) if first != anonymous => {
+              let mut e = ParseError::new(self.input, &self.input[span.start..], &[]);
+              e.expected = vec![if first { "`?`" } else { "`$n`" }.to_owned()];
+              e.hint = Some("`?` and `$n` parameters cannot be mixed in one query".to_owned());
+              return Err(e);
+            }
+            _ => self.anonymous = Some(anonymous),
+          }
+          if anonymous {
+            self.next += 1;
+            *p = Param::Positional(self.next);
+          }
+          Ok(())
+        }
+        Filter::UnaryOp(_, e) => self.go(e),
+        Filter::BinaryOp(_, e1, e2, _) => {
+          self.go(e1)?;
+          self.go(e2)
+        }
+        Filter::Call(_, args, _) => args.iter_mut().try_for_each(|a| self.go(a)),
+        _ => Ok(()),
+      }
+    }
+  }
+
+  let mut numbering = Numbering {
+    input,
+    next: 0,
+    anonymous: None,
+  };
+  if let Selection::Columns(cols) = &mut q.selection {
+    cols.iter_mut().try_for_each(|c| numbering.go(c))?;
+  }
+  numbering.go(&mut q.filter)?;
+  q.order_by.iter_mut().try_for_each(|k| numbering.go(&mut k.expr))
+}
+
+/// Converts the spans recorded by `spanned` into offsets from the start of
+/// the query.
+fn locate_spans(q: &mut Query, len: usize) {
+  fn locate(span: &mut Span, len: usize) {
+    *span = Span {
+      start: len - span.start,
+      end: len - span.end,
+    };
+  }
+
+  fn go(f: &mut Filter, len: usize) {
+    match f {
+      Filter::Id(c) => locate(&mut c.span, len),
+      Filter::Param(_, span) => locate(span, len),
+      Filter::UnaryOp(_, e) => go(e, len),
+      Filter::BinaryOp(_, e1, e2, span) => {
+        locate(span, len);
+        go(e1, len);
+        go(e2, len);
+      }
+      Filter::Call(_, args, span) => {
+        locate(span, len);
+        args.iter_mut().for_each(|a| go(a, len))
+      }
+      _ => (),
+    }
+  }
+
+  if let Selection::Columns(cols) = &mut q.selection {
+    cols.iter_mut().for_each(|c| go(c, len));
   }
+  q.tables.iter_mut().for_each(|t| locate(&mut t.span, len));
+  go(&mut q.filter, len);
+  q.order_by.iter_mut().for_each(|k| go(&mut k.expr, len));
 }
 
-type Res<T, U> = IResult<T, U, VerboseError<T>>;
+type Res<T, U> = IResult<T, U, Expected<T>>;
+
+/// Runs `p` and also returns the span of input it consumed. Parsers only
+/// see the rest of the query, so the span is recorded as lengths of the
+/// remaining input until `locate_spans` fixes it up.
+fn spanned<'a, O>(
+  mut p: impl FnMut(&'a str) -> Res<&'a str, O>,
+) -> impl FnMut(&'a str) -> Res<&'a str, (O, Span)> {
+  move |input: &'a str| {
+    let (rest, o) = p(input)?;
+    let span = Span {
+      start: input.len(),
+      end: rest.len(),
+    };
+    Ok((rest, (o, span)))
+  }
+}
+
+/// A case-insensitive keyword that is not the prefix of a longer identifier.
+fn keyword<'a>(k: &'static str) -> impl FnMut(&'a str) -> Res<&'a str, &'a str> {
+  terminated(tag_no_case(k), not(alt((alphanumeric1, tag("_")))))
+}
 
 fn ident(input: &str) -> Res<&str, String> {
   context(
     "ident",
-    map(tuple((alpha1, alphanumeric0)), |(x, y): (&str, &str)| {
-      format!("{}{}", x, y)
-    }),
+    map(
+      recognize(pair(alpha1, many0(alt((alphanumeric1, tag("_")))))),
+      String::from,
+    ),
   )(input)
 }
 
+/// An integer literal. One too large for 64 bits is an error at the literal
+/// rather than a column or another expression.
 fn p_int(input: &str) -> Res<&str, i64> {
-  let (input, s) = opt(tag("-"))(input)?;
-  let (input, ds) = digit1(input)?;
-  let n = ds.parse::<i64>().expect("failed to parse difits");
-  Ok((input, if s.is_some() { -n } else { n }))
+  let (rest, ds) = recognize(pair(opt(tag("-")), digit1))(input)?;
+  match ds.parse::<i64>() {
+    Ok(n) => Ok((rest, n)),
+    Err(_) => Err(nom::Err::Failure(Expected::invalid(input, Label::Context("int")))),
+  }
 }
 
 fn p_bool(input: &str) -> Res<&str, bool> {
   context(
     "bool",
     alt((
-      map(tag_no_case("TRUE"), |_| true),
-      map(tag_no_case("FALSE"), |_| false),
+      map(keyword("TRUE"), |_| true),
+      map(keyword("FALSE"), |_| false),
+    )),
+  )(input)
+}
+
+fn param(input: &str) -> Res<&str, Param> {
+  context(
+    "param",
+    alt((
+      map(char('?'), |_| Param::Anonymous),
+      map(
+        preceded(char('$'), cut(context("count", map_res(digit1, str::parse::<usize>)))),
+        Param::Positional,
+      ),
+      map(preceded(char(':'), ident), Param::Named),
     )),
   )(input)
 }
@@ -66,9 +236,28 @@ fn p_string(input: &str) -> Res<&str, String> {
   )(input)
 }
 
+fn call(input: &str) -> Res<&str, Filter> {
+  let start = input.len();
+  let (input, name) = ident(input)?;
+  let (input, _) = terminated(char('('), space0)(input)?;
+  // An identifier followed by a parenthesis can only be a call. Only
+  // `count(*)` takes a `*`, counting rows as `count()` does.
+  let star = name.eq_ignore_ascii_case("count");
+  let (input, args) = cut(alt((
+    map(verify(terminated(tag("*"), space0), |_: &str| star), |_| vec![]),
+    separated_list0(terminated(tag(","), space0), terminated(expression, space0)),
+  )))(input)?;
+  let (input, _) = cut(char(')'))(input)?;
+  let span = Span {
+    start,
+    end: input.len(),
+  };
+  Ok((input, Filter::Call(name, args, span)))
+}
+
 fn factor(input: &str) -> Res<&str, Filter> {
-  fn not(i: &str) -> Res<&str, Filter> {
-    let (i, _) = tag_no_case("NOT")(i)?;
+  fn negation(i: &str) -> Res<&str, Filter> {
+    let (i, _) = keyword("NOT")(i)?;
     let (i, _) = space0(i)?;
     let (i, f) = factor(i)?;
     Ok((i, Filter::UnaryOp(UnaryOp::Not, Box::new(f))))
@@ -78,35 +267,73 @@ fn factor(input: &str) -> Res<&str, Filter> {
     "factor",
     alt((
       delimited(char('('), expression, char(')')),
-      not,
+      negation,
       map(p_int, Filter::LitI),
       map(p_bool, Filter::LitB),
       map(p_string, Filter::LitS),
+      map(spanned(param), |(p, span)| Filter::Param(p, span)),
+      call,
       map(column_selector, Filter::Id),
     )),
   )(input)
 }
 
-fn term(input: &str) -> Res<&str, Filter> {
+/// An operator applied to `left`, which starts at `start`, and `right`,
+/// which spans `span`. Operators associate to the left, so every operator
+/// of a chain starts where the chain does.
+fn binary(op: BinaryOp, left: Filter, right: Filter, start: usize, span: Span) -> Filter {
+  let span = Span { start, end: span.end };
+  Filter::BinaryOp(op, Box::new(left), Box::new(right), span)
+}
+
+fn comparison(input: &str) -> Res<&str, Filter> {
+  let start = input.len();
   let (input, init) = factor(input)?;
   let (input, _) = space0(input)?;
 
   context(
-    "term",
+    "comparison",
     fold_many0(
       pair(
         alt((
-          terminated(alt((tag("&&"), tag("=="), tag("<"))), space0),
-          terminated(tag_no_case("LIKE"), space0),
+          terminated(alt((tag("=="), tag("<"), tag("~"))), space0),
+          terminated(keyword("LIKE"), space0),
+          terminated(keyword("REGEXP"), space0),
+          map(
+            tuple((keyword("SIMILAR"), space1, keyword("TO"), space0)),
+            |_| "similar to",
+          ),
         )),
-        factor,
+        cut(terminated(spanned(factor), space0)),
       ),
       init,
-      |acc: Filter, (op, f): (&str, Filter)| match op.to_lowercase().as_str() {
-        "&&" => Filter::BinaryOp(BinaryOp::And, Box::new(acc), Box::new(f)),
-        "==" => Filter::BinaryOp(BinaryOp::Eq, Box::new(acc), Box::new(f)),
-        "<" => Filter::BinaryOp(BinaryOp::Lt, Box::new(acc), Box::new(f)),
-        "like" => Filter::BinaryOp(BinaryOp::Like, Box::new(acc), Box::new(f)),
+      move |acc: Filter, (op, (f, span)): (&str, (Filter, Span))| {
+        let op = match op.to_lowercase().as_str() {
+          "==" => BinaryOp::Eq,
+          "<" => BinaryOp::Lt,
+          "like" => BinaryOp::Like,
+          "similar to" => BinaryOp::SimilarTo,
+          "regexp" | "~" => BinaryOp::Regexp,
+          _ => panic!("invalid comparison op"),
+        };
+        binary(op, acc, f, start, span)
+      },
+    ),
+  )(input)
+}
+
+fn term(input: &str) -> Res<&str, Filter> {
+  let start = input.len();
+  let (input, init) = comparison(input)?;
+  let (input, _) = space0(input)?;
+
+  context(
+    "term",
+    fold_many0(
+      pair(terminated(tag("&&"), space0), cut(terminated(spanned(comparison), space0))),
+      init,
+      move |acc: Filter, (op, (f, span)): (&str, (Filter, Span))| match op {
+        "&&" => binary(BinaryOp::And, acc, f, start, span),
         _ => panic!("invalid term op"),
       },
     ),
@@ -114,16 +341,17 @@ fn term(input: &str) -> Res<&str, Filter> {
 }
 
 fn expression(input: &str) -> Res<&str, Filter> {
+  let start = input.len();
   let (input, init) = term(input)?;
   let (input, _) = space0(input)?;
 
   context(
     "expression",
     fold_many0(
-      pair(terminated(tag("||"), space0), term),
+      pair(terminated(tag("||"), space0), cut(terminated(spanned(term), space0))),
       init,
-      |acc: Filter, (op, f): (&str, Filter)| match op {
-        "||" => Filter::BinaryOp(BinaryOp::Or, Box::new(acc), Box::new(f)),
+      move |acc: Filter, (op, (f, span)): (&str, (Filter, Span))| match op {
+        "||" => binary(BinaryOp::Or, acc, f, start, span),
         _ => panic!("invalid factor op"),
       },
     ),
@@ -134,15 +362,18 @@ fn column_selector(input: &str) -> Res<&str, ColumnSelector> {
   context(
     "column_selector",
     alt((
-      map(tuple((terminated(ident, tag(".")), ident)), |(x, y)| {
-        ColumnSelector {
+      map(
+        spanned(tuple((terminated(ident, tag(".")), ident))),
+        |((x, y), span)| ColumnSelector {
           table: Some(x),
           field: y,
-        }
-      }),
-      map(ident, |x| ColumnSelector {
+          span,
+        },
+      ),
+      map(spanned(ident), |(x, span)| ColumnSelector {
         table: None,
         field: x,
+        span,
       }),
     )),
   )(input)
@@ -154,7 +385,7 @@ fn selection(input: &str) -> Res<&str, Selection> {
     alt((
       map(tag("*"), |_| Selection::Star),
       map(
-        separated_list1(terminated(tag(","), space0), column_selector),
+        separated_list1(terminated(tag(","), space0), expression),
         Selection::Columns,
       ),
     )),
@@ -166,35 +397,42 @@ fn table(input: &str) -> Res<&str, Table> {
     "table",
     alt((
       map(
-        tuple((
+        spanned(tuple((
           terminated(ident, delimited(space0, tag_no_case("AS"), space0)),
           ident,
-        )),
-        |(x, y)| Table {
+        ))),
+        |((x, y), span)| Table {
           table_name: x,
           alias: Some(y),
+          span,
         },
       ),
-      map(ident, |x| Table {
+      map(spanned(ident), |(x, span)| Table {
         table_name: x,
         alias: None,
+        span,
       }),
     )),
   )(input)
 }
 
 fn query(input: &str) -> Res<&str, Query> {
-  let (input, _) = tag_no_case("SELECT")(input)?;
-  let (input, _) = multispace1(input)?;
+  // Keywords cannot run into the identifiers around them, so the
+  // whitespace separating clauses is optional here.
+  let (input, _) = multispace0(input)?;
+  let (input, _) = context("SELECT", keyword("SELECT"))(input)?;
+  let (input, _) = multispace0(input)?;
   let (input, selection) = selection(input)?;
-  let (input, _) = multispace1(input)?;
-  let (input, _) = tag_no_case("FROM")(input)?;
-  let (input, _) = multispace1(input)?;
+  let (input, _) = multispace0(input)?;
+  let (input, _) = context("FROM", keyword("FROM"))(input)?;
+  let (input, _) = multispace0(input)?;
   let (input, tables) = separated_list1(terminated(tag(","), space0), table)(input)?;
-  let (input, _) = multispace1(input)?;
-  let (input, _) = tag_no_case("WHERE")(input)?;
-  let (input, _) = space1(input)?;
+  let (input, _) = multispace0(input)?;
+  let (input, _) = context("WHERE", keyword("WHERE"))(input)?;
+  let (input, _) = multispace0(input)?;
   let (input, filter) = expression(input)?;
+  let (input, order_by) = opt(preceded(multispace0, order_by))(input)?;
+  let (input, limit) = opt(preceded(multispace0, limit))(input)?;
 
   Ok((
     input,
@@ -202,6 +440,130 @@ fn query(input: &str) -> Res<&str, Query> {
       selection,
       tables,
       filter,
+      order_by: order_by.unwrap_or_default(),
+      limit,
+    },
+  ))
+}
+
+fn explain_format(input: &str) -> Res<&str, ExplainFormat> {
+  let (input, _) = keyword("FORMAT")(input)?;
+  let (input, _) = multispace0(input)?;
+  cut(context(
+    "TEXT or DOT",
+    alt((
+      value(ExplainFormat::Text, keyword("TEXT")),
+      value(ExplainFormat::Dot, keyword("DOT")),
+    )),
+  ))(input)
+}
+
+/// The rest of a `CREATE INDEX` statement, after `CREATE`.
+fn create_index(input: &str) -> Res<&str, Statement> {
+  let (input, _) = multispace0(input)?;
+  let (input, _) = cut(context("INDEX", keyword("INDEX")))(input)?;
+  let (input, _) = multispace0(input)?;
+  let (input, name) = cut(ident)(input)?;
+  let (input, _) = multispace0(input)?;
+  let (input, _) = cut(context("ON", keyword("ON")))(input)?;
+  let (input, _) = multispace0(input)?;
+  let (input, table) = cut(ident)(input)?;
+  let (input, _) = multispace0(input)?;
+  let (input, kind) = opt(terminated(index_kind, multispace0))(input)?;
+  let (input, column) = cut(delimited(
+    terminated(tag("("), multispace0),
+    ident,
+    preceded(multispace0, tag(")")),
+  ))(input)?;
+  Ok((
+    input,
+    Statement::CreateIndex {
+      name,
+      table,
+      column,
+      kind: kind.unwrap_or(IndexKind::BTree),
+    },
+  ))
+}
+
+fn index_kind(input: &str) -> Res<&str, IndexKind> {
+  let (input, _) = keyword("USING")(input)?;
+  let (input, _) = multispace0(input)?;
+  cut(context(
+    "BTREE or HASH",
+    alt((
+      value(IndexKind::BTree, keyword("BTREE")),
+      value(IndexKind::Hash, keyword("HASH")),
+    )),
+  ))(input)
+}
+
+fn statement(input: &str) -> Res<&str, Statement> {
+  let (input, _) = multispace0(input)?;
+  if let (input, Some(_)) = opt(keyword("CREATE"))(input)? {
+    return create_index(input);
+  }
+  let (input, explain) = opt(keyword("EXPLAIN"))(input)?;
+  if explain.is_none() {
+    return map(query, Statement::Query)(input);
+  }
+  let (input, _) = multispace0(input)?;
+  let (input, analyze) = opt(terminated(keyword("ANALYZE"), multispace0))(input)?;
+  let (input, format) = opt(terminated(explain_format, multispace0))(input)?;
+  let (input, query) = query(input)?;
+  Ok((
+    input,
+    Statement::Explain {
+      query,
+      analyze: analyze.is_some(),
+      format: format.unwrap_or(ExplainFormat::Text),
+    },
+  ))
+}
+
+fn order_key(input: &str) -> Res<&str, OrderKey> {
+  let (input, expr) = expression(input)?;
+  let (input, direction) = opt(preceded(
+    multispace0,
+    alt((keyword("ASC"), keyword("DESC"))),
+  ))(input)?;
+  Ok((
+    input,
+    OrderKey {
+      expr,
+      descending: direction.is_some_and(|d| d.eq_ignore_ascii_case("DESC")),
+    },
+  ))
+}
+
+fn order_by(input: &str) -> Res<&str, Vec<OrderKey>> {
+  let (input, _) = context("ORDER BY", keyword("ORDER"))(input)?;
+  let (input, _) = multispace0(input)?;
+  let (input, _) = cut(context("BY", keyword("BY")))(input)?;
+  let (input, _) = multispace0(input)?;
+  cut(separated_list1(
+    delimited(multispace0, tag(","), multispace0),
+    order_key,
+  ))(input)
+}
+
+fn count(input: &str) -> Res<&str, u64> {
+  context("count", map_res(digit1, str::parse::<u64>))(input)
+}
+
+fn limit(input: &str) -> Res<&str, Limit> {
+  let (input, _) = context("LIMIT", keyword("LIMIT"))(input)?;
+  let (input, _) = multispace0(input)?;
+  let (input, n) = cut(count)(input)?;
+  let (input, offset) = opt(preceded(
+    delimited(multispace0, keyword("OFFSET"), multispace0),
+    cut(count),
+  ))(input)?;
+  Ok((
+    input,
+    Limit {
+      count: n,
+      offset: offset.unwrap_or(0),
     },
   ))
 }
@@ -221,6 +583,12 @@ mod tests {
     assert_eq!(p_string("\"\""), Ok(("", "".to_owned())));
   }
 
+  #[test]
+  fn comparisons_bind_tighter_than_and() {
+    let q = parse_sql("SELECT * FROM t WHERE x == 1 && y < 2 || z == 3").unwrap();
+    assert_eq!(format!("{}", q), "SELECT *\nFROM t\nWHERE (((x == 1) && (y < 2)) || (z == 3))");
+  }
+
   #[test]
   fn whole_query() {
     assert_eq!(
@@ -229,32 +597,240 @@ mod tests {
         "",
         Query {
           selection: Selection::Columns(vec![
-            ColumnSelector {
+            Filter::Id(ColumnSelector {
               table: None,
-              field: "name".to_owned()
-            },
-            ColumnSelector {
+              field: "name".to_owned(),
+              span: Span::default(),
+            }),
+            Filter::Id(ColumnSelector {
               table: None,
-              field: "id".to_owned()
-            },
+              field: "id".to_owned(),
+              span: Span::default(),
+            }),
           ]),
           tables: vec![Table {
             table_name: "users".to_owned(),
-            alias: None
+            alias: None,
+            span: Span::default(),
           }],
           filter: Filter::BinaryOp(
             BinaryOp::Eq,
             Box::new(Filter::Id(ColumnSelector {
               table: None,
-              field: "name".to_owned()
+              field: "name".to_owned(),
+              span: Span::default(),
             })),
-            Box::new(Filter::LitS("Harry".to_owned()))
-          )
+            Box::new(Filter::LitS("Harry".to_owned())),
+            Span::default()
+          ),
+          order_by: vec![],
+          limit: None,
         }
       ))
     )
   }
 
+  #[test]
+  fn order_and_limit() {
+    let (rest, q) = query("SELECT x FROM t WHERE TRUE ORDER BY x DESC, y asc LIMIT 10 OFFSET 5").unwrap();
+    assert_eq!(rest, "");
+    assert_eq!(
+      q.order_by.iter().map(|k| k.descending).collect::<Vec<_>>(),
+      vec![true, false]
+    );
+    assert_eq!(q.limit, Some(Limit { count: 10, offset: 5 }));
+    assert_eq!(
+      format!("{}", q),
+      "SELECT x\nFROM t\nWHERE true\nORDER BY x DESC, y\nLIMIT 10 OFFSET 5"
+    );
+    assert_eq!(query("SELECT x FROM t WHERE TRUE LIMIT 3").unwrap().1.limit, Some(Limit { count: 3, offset: 0 }));
+    assert!(parse_sql("SELECT x FROM t WHERE TRUE ORDER x").is_err());
+    assert!(parse_sql("SELECT x FROM t WHERE TRUE LIMIT many").is_err());
+  }
+
+  #[test]
+  fn explain() {
+    let q = parse_sql("SELECT x FROM t WHERE x == ?").unwrap();
+    assert_eq!(parse_statement("SELECT x FROM t WHERE x == ?"), Ok(Statement::Query(q.clone())));
+    let s = parse_statement("explain\nSELECT x FROM t WHERE x == ?").unwrap();
+    assert_eq!(
+      s,
+      Statement::Explain {
+        query: q.clone(),
+        analyze: false,
+        format: ExplainFormat::Text,
+      }
+    );
+    let s = parse_statement("EXPLAIN ANALYZE FORMAT DOT SELECT x FROM t WHERE x == ?").unwrap();
+    assert_eq!(
+      s,
+      Statement::Explain {
+        query: q,
+        analyze: true,
+        format: ExplainFormat::Dot,
+      }
+    );
+    assert_eq!(
+      format!("{}", s),
+      "EXPLAIN ANALYZE FORMAT DOT SELECT x\nFROM t\nWHERE (x == $1)"
+    );
+    assert_eq!(parse_statement(&format!("{}", s)), Ok(s));
+    assert!(parse_statement("EXPLAIN FORMAT JSON SELECT x FROM t WHERE TRUE").is_err());
+    assert!(parse_statement("EXPLAINSELECT x FROM t WHERE TRUE").is_err());
+    let e = parse_statement("EXPLAIN SELECT * FROM user WHERE x ==").unwrap_err();
+    assert_eq!(e.offset, 37);
+  }
+
+  #[test]
+  fn create_index() {
+    let s = parse_statement("create index by_age\nON user ( age )").unwrap();
+    assert_eq!(
+      s,
+      Statement::CreateIndex {
+        name: "by_age".to_owned(),
+        table: "user".to_owned(),
+        column: "age".to_owned(),
+        kind: IndexKind::BTree,
+      }
+    );
+    assert_eq!(s.query(), None);
+    assert_eq!(format!("{}", s), "CREATE INDEX by_age ON user(age)");
+    assert_eq!(parse_statement(&format!("{}", s)), Ok(s));
+    let s = parse_statement("CREATE INDEX by_id ON user USING hash(id)").unwrap();
+    assert_eq!(format!("{}", s), "CREATE INDEX by_id ON user USING HASH (id)");
+    assert_eq!(parse_statement(&format!("{}", s)), Ok(s));
+    assert_eq!(
+      parse_statement("CREATE INDEX by_id ON user USING btree (id)").unwrap().to_string(),
+      "CREATE INDEX by_id ON user(id)"
+    );
+    assert!(parse_statement("CREATE INDEX by_id ON user USING GIST (id)").is_err());
+    assert!(parse_statement("CREATE INDEX by_age ON user").is_err());
+    assert!(parse_statement("CREATE INDEX by_age ON user(age, name)").is_err());
+    assert_eq!(parse_statement("CREATE TABLE t").unwrap_err().offset, 7);
+  }
+
+  #[test]
+  fn calls() {
+    let x = Filter::Id(ColumnSelector {
+      table: None,
+      field: "x".to_owned(),
+      span: Span::default(),
+    });
+    assert_eq!(
+      expression("f(x, 1) == g()"),
+      Ok((
+        "",
+        Filter::BinaryOp(
+          BinaryOp::Eq,
+          Box::new(Filter::Call(
+            "f".to_owned(),
+            vec![x.clone(), Filter::LitI(1)],
+            Span::default()
+          )),
+          Box::new(Filter::Call("g".to_owned(), vec![], Span::default())),
+          Span::default()
+        )
+      ))
+    );
+    assert_eq!(
+      query("SELECT count(*), max(x) FROM t WHERE TRUE").map(|(_, q)| q.selection),
+      Ok(Selection::Columns(vec![
+        Filter::Call("count".to_owned(), vec![], Span::default()),
+        Filter::Call("max".to_owned(), vec![x], Span::default()),
+      ]))
+    );
+  }
+
+  #[test]
+  fn regex_ops() {
+    let x = Filter::Id(ColumnSelector {
+      table: None,
+      field: "x".to_owned(),
+      span: Span::default(),
+    });
+    let regexp = Filter::BinaryOp(
+      BinaryOp::Regexp,
+      Box::new(x.clone()),
+      Box::new(Filter::LitS("a+".to_owned())),
+      Span::default(),
+    );
+    assert_eq!(expression("x ~ \"a+\""), Ok(("", regexp.clone())));
+    assert_eq!(expression("x regexp \"a+\""), Ok(("", regexp)));
+    assert_eq!(
+      expression("x SIMILAR  TO \"%a\""),
+      Ok((
+        "",
+        Filter::BinaryOp(
+          BinaryOp::SimilarTo,
+          Box::new(x),
+          Box::new(Filter::LitS("%a".to_owned())),
+          Span::default()
+        )
+      ))
+    );
+  }
+
+  #[test]
+  fn placeholders() {
+    let q = parse_sql("SELECT ?, x\nFROM t\nWHERE x == ? && y == \"$1?\" || z == :name").unwrap();
+    assert_eq!(
+      format!("{}", q),
+      "SELECT $1, x\nFROM t\nWHERE (((x == $2) && (y == \"$1?\")) || (z == :name))"
+    );
+    let q = parse_sql("SELECT * FROM t WHERE x == $2 && y == $1 && z == :name").unwrap();
+    assert_eq!(format!("{}", q), "SELECT *\nFROM t\nWHERE (((x == $2) && (y == $1)) && (z == :name))");
+
+    let e = parse_sql("SELECT * FROM t WHERE id == ? || id == $1").unwrap_err();
+    assert_eq!(e.column, 40);
+    assert_eq!(e.expected, vec!["`?`".to_owned()]);
+    assert_eq!(e.found, Some("$1".to_owned()));
+    assert!(e.hint.unwrap().contains("cannot be mixed"));
+    let e = parse_statement("EXPLAIN SELECT $1, ? FROM t WHERE x == 1").unwrap_err();
+    assert_eq!((e.column, e.found), (20, Some("?".to_owned())));
+    let e = parse_sql("SELECT * FROM t WHERE age < $99999999999999999999999").unwrap_err();
+    assert_eq!((e.column, e.expected), (30, vec!["a number".to_owned()]));
+  }
+
+  #[test]
+  fn error_positions() {
+    let e = parse_sql("SELECT *\nFROM user\nWHERE name = \"Harry\"").unwrap_err();
+    assert_eq!((e.line, e.column), (3, 12));
+    assert_eq!(e.source_line, "WHERE name = \"Harry\"");
+    assert_eq!(e.found, Some("=".to_owned()));
+    assert_eq!(e.hint, Some("did you mean `==`?".to_owned()));
+    assert_eq!(
+      format!("{}", e),
+      concat!(
+        "syntax error: expected an operator or the end of the query, found `=`\n",
+        "  --> line 3, column 12\n",
+        "  |\n",
+        "3 | WHERE name = \"Harry\"\n",
+        "  |            ^\n",
+        "  = hint: did you mean `==`?"
+      )
+    );
+
+    let e = parse_sql("SELECT * FROM user WHERE x == ").unwrap_err();
+    assert_eq!(e.column, 31);
+    assert_eq!(e.expected, vec!["an expression".to_owned()]);
+    assert_eq!(e.found, None);
+
+    let e = parse_sql("SELECT * user WHERE x").unwrap_err();
+    assert_eq!(e.expected, vec!["`FROM`".to_owned()]);
+    assert_eq!(e.found, Some("user".to_owned()));
+
+    let e = parse_sql("SELECT * FROM user WHERE f(x,").unwrap_err();
+    assert_eq!(e.expected, vec!["`)`".to_owned()]);
+    assert_eq!(e.found, Some(",".to_owned()));
+
+    let e = parse_sql("SELECT * FROM user WHERE x < 99999999999999999999").unwrap_err();
+    assert_eq!((e.column, e.expected), (30, vec!["a 64-bit integer".to_owned()]));
+    assert_eq!(expression("-9223372036854775808"), Ok(("", Filter::LitI(i64::MIN))));
+
+    let e = parse_sql("SELECT * FROM user WHERE x == 1 AND y").unwrap_err();
+    assert_eq!(e.hint, Some("did you mean `&&`?".to_owned()));
+  }
+
   #[test]
   fn filter_not() {
     assert_eq!(
@@ -267,13 +843,16 @@ mod tests {
             UnaryOp::Not,
             Box::new(Filter::Id(ColumnSelector {
               table: None,
-              field: "x".to_owned()
+              field: "x".to_owned(),
+              span: Span::default(),
             }))
           )),
           Box::new(Filter::Id(ColumnSelector {
             table: None,
-            field: "y".to_owned()
-          }))
+            field: "y".to_owned(),
+            span: Span::default(),
+          })),
+          Span::default()
         )
       ))
     );
@@ -284,21 +863,24 @@ mod tests {
     let x = Filter::Id(ColumnSelector {
       table: None,
       field: "x".to_owned(),
+      span: Span::default(),
     });
     let y = Filter::Id(ColumnSelector {
       table: None,
       field: "y".to_owned(),
+      span: Span::default(),
     });
     let z = Filter::Id(ColumnSelector {
       table: None,
       field: "z".to_owned(),
+      span: Span::default(),
     });
 
     assert_eq!(
       expression("x||y"),
       Ok((
         "",
-        Filter::BinaryOp(BinaryOp::Or, Box::new(x.clone()), Box::new(y.clone()))
+        Filter::BinaryOp(BinaryOp::Or, Box::new(x.clone()), Box::new(y.clone()), Span::default())
       ))
     );
 
@@ -312,8 +894,10 @@ mod tests {
           Box::new(Filter::BinaryOp(
             BinaryOp::And,
             Box::new(y.clone()),
-            Box::new(z.clone())
-          ))
+            Box::new(z.clone()),
+            Span::default()
+          )),
+          Span::default()
         )
       ))
     );
@@ -327,9 +911,11 @@ mod tests {
           Box::new(Filter::BinaryOp(
             BinaryOp::And,
             Box::new(x.clone()),
-            Box::new(y.clone())
+            Box::new(y.clone()),
+            Span::default()
           )),
-          Box::new(z.clone())
+          Box::new(z.clone()),
+          Span::default()
         )
       ))
     );
@@ -343,9 +929,11 @@ mod tests {
           Box::new(Filter::BinaryOp(
             BinaryOp::And,
             Box::new(x.clone()),
-            Box::new(y.clone())
+            Box::new(y.clone()),
+            Span::default()
           )),
-          Box::new(z.clone())
+          Box::new(z.clone()),
+          Span::default()
         )
       ))
     );
@@ -357,7 +945,8 @@ mod tests {
         Filter::BinaryOp(
           BinaryOp::And,
           Box::new(x),
-          Box::new(Filter::BinaryOp(BinaryOp::And, Box::new(y), Box::new(z))),
+          Box::new(Filter::BinaryOp(BinaryOp::And, Box::new(y), Box::new(z), Span::default())),
+          Span::default(),
         )
       ))
     );
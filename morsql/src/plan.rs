@@ -0,0 +1,907 @@
+//! Logical plans: a bound query as a tree of relational-algebra operators,
+//! which rewrites can reshape before it is lowered to the physical
+//! operators of `exec`.
+//!
+//! The plan of a query multiplies its tables together, selects the rows
+//! satisfying the WHERE clause, sorts them, and then projects or aggregates
+//! them and applies the limit. Expressions keep the column selectors of the
+//! bound query: a column is found by the position of its table in the FROM
+//! clause, its slot, wherever it sits in the tree. So `Project` and
+//! `Aggregate`, which replace the slots with a result row, are only ever
+//! followed by `Limit`.
+
+use crate::ast::{BinaryOp, IndexedAggregate, IndexedFilter, IndexedOrderKey, IndexedQuery, IndexedSelection, Ident, RegexOp};
+use crate::batch::{BatchFilter, BatchProjection, BatchScan, ColumnarTable};
+use crate::exec::{self, BoxedOperator, Config, JoinAlgorithm};
+use crate::explain::{Instrumented, Profile, ProfileNode};
+use crate::index::{IndexLookup, TableIndex};
+use crate::parallel::Pool;
+use crate::stream::Rows;
+use crate::tables::TableData;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LogicalPlan {
+  /// The rows of the table at `source` in the catalog, in slot `slot`:
+  /// all of them, or with an index lookup, those it finds.
+  Scan {
+    table: Ident,
+    source: usize,
+    slot: usize,
+    header: Vec<String>,
+    index: Option<IndexLookup>,
+  },
+  /// Its input, known by another name.
+  Rename { input: Box<LogicalPlan>, alias: Ident },
+  /// The rows of its input satisfying the predicate.
+  Select {
+    input: Box<LogicalPlan>,
+    predicate: IndexedFilter,
+  },
+  /// Every pair of rows of its inputs.
+  Product {
+    left: Box<LogicalPlan>,
+    right: Box<LogicalPlan>,
+  },
+  /// The pairs of rows of its inputs satisfying the predicate. The
+  /// algorithm, if chosen, is used for equalities between the inputs.
+  Join {
+    left: Box<LogicalPlan>,
+    right: Box<LogicalPlan>,
+    predicate: IndexedFilter,
+    algorithm: Option<JoinAlgorithm>,
+  },
+  /// The pairs of rows of its inputs satisfying the predicate, found by
+  /// looking up the value of `key` for each left row in `index` of the
+  /// table scanned on the right, under any renaming and selection, instead
+  /// of reading that table.
+  IndexJoin {
+    left: Box<LogicalPlan>,
+    right: Box<LogicalPlan>,
+    key: IndexedFilter,
+    index: Arc<TableIndex>,
+    predicate: IndexedFilter,
+  },
+  Project {
+    input: Box<LogicalPlan>,
+    selection: IndexedSelection,
+  },
+  /// A single row of aggregates over all of its input.
+  Aggregate {
+    input: Box<LogicalPlan>,
+    aggregates: Vec<IndexedAggregate>,
+  },
+  Sort {
+    input: Box<LogicalPlan>,
+    keys: Vec<IndexedOrderKey>,
+  },
+  Limit {
+    input: Box<LogicalPlan>,
+    count: u64,
+    offset: u64,
+  },
+  /// The rows of each input in turn, keeping duplicates. The inputs
+  /// produce the same slots. With no inputs it is the empty relation.
+  Union { inputs: Vec<LogicalPlan> },
+}
+
+impl IndexedQuery {
+  /// The logical plan of the query. `catalog` is the list of tables the
+  /// query was bound against; only their headers are used.
+  pub fn logical_plan(&self, catalog: &[TableData]) -> LogicalPlan {
+    let mut relations = self.tables.iter().zip(&self.sources).enumerate().map(|(slot, (table, source))| {
+      let scan = LogicalPlan::Scan {
+        table: table.table_name.clone(),
+        source: *source,
+        slot,
+        header: catalog[*source].header.clone(),
+        index: None,
+      };
+      match &table.alias {
+        Some(alias) => LogicalPlan::Rename {
+          input: Box::new(scan),
+          alias: alias.clone(),
+        },
+        None => scan,
+      }
+    });
+    let first = relations.next().unwrap_or(LogicalPlan::Union { inputs: vec![] });
+    let mut plan = relations.fold(first, |left, right| LogicalPlan::Product {
+      left: Box::new(left),
+      right: Box::new(right),
+    });
+    if self.filter != IndexedFilter::LitB(true) {
+      plan = LogicalPlan::Select {
+        input: Box::new(plan),
+        predicate: self.filter.clone(),
+      };
+    }
+    plan = match &self.selection {
+      IndexedSelection::Aggregates(aggregates) => LogicalPlan::Aggregate {
+        input: Box::new(plan),
+        aggregates: aggregates.clone(),
+      },
+      selection => {
+        if !self.order_by.is_empty() {
+          plan = LogicalPlan::Sort {
+            input: Box::new(plan),
+            keys: self.order_by.clone(),
+          };
+        }
+        LogicalPlan::Project {
+          input: Box::new(plan),
+          selection: selection.clone(),
+        }
+      }
+    };
+    if let Some(limit) = self.limit {
+      plan = LogicalPlan::Limit {
+        input: Box::new(plan),
+        count: limit.count,
+        offset: limit.offset,
+      };
+    }
+    plan
+  }
+}
+
+impl LogicalPlan {
+  pub fn inputs(&self) -> Vec<&LogicalPlan> {
+    match self {
+      LogicalPlan::Scan { .. } => vec![],
+      LogicalPlan::Rename { input, .. }
+      | LogicalPlan::Select { input, .. }
+      | LogicalPlan::Project { input, .. }
+      | LogicalPlan::Aggregate { input, .. }
+      | LogicalPlan::Sort { input, .. }
+      | LogicalPlan::Limit { input, .. } => vec![input],
+      LogicalPlan::Product { left, right }
+      | LogicalPlan::Join { left, right, .. }
+      | LogicalPlan::IndexJoin { left, right, .. } => vec![left, right],
+      LogicalPlan::Union { inputs } => inputs.iter().collect(),
+    }
+  }
+
+  /// The plan with `f` applied to each of its inputs.
+  pub fn map_inputs<F: FnMut(LogicalPlan) -> LogicalPlan>(self, mut f: F) -> LogicalPlan {
+    match self {
+      scan @ LogicalPlan::Scan { .. } => scan,
+      LogicalPlan::Rename { input, alias } => LogicalPlan::Rename {
+        input: Box::new(f(*input)),
+        alias,
+      },
+      LogicalPlan::Select { input, predicate } => LogicalPlan::Select {
+        input: Box::new(f(*input)),
+        predicate,
+      },
+      LogicalPlan::Product { left, right } => LogicalPlan::Product {
+        left: Box::new(f(*left)),
+        right: Box::new(f(*right)),
+      },
+      LogicalPlan::Join {
+        left,
+        right,
+        predicate,
+        algorithm,
+      } => LogicalPlan::Join {
+        left: Box::new(f(*left)),
+        right: Box::new(f(*right)),
+        predicate,
+        algorithm,
+      },
+      LogicalPlan::IndexJoin {
+        left,
+        right,
+        key,
+        index,
+        predicate,
+      } => LogicalPlan::IndexJoin {
+        left: Box::new(f(*left)),
+        right: Box::new(f(*right)),
+        key,
+        index,
+        predicate,
+      },
+      LogicalPlan::Project { input, selection } => LogicalPlan::Project {
+        input: Box::new(f(*input)),
+        selection,
+      },
+      LogicalPlan::Aggregate { input, aggregates } => LogicalPlan::Aggregate {
+        input: Box::new(f(*input)),
+        aggregates,
+      },
+      LogicalPlan::Sort { input, keys } => LogicalPlan::Sort {
+        input: Box::new(f(*input)),
+        keys,
+      },
+      LogicalPlan::Limit { input, count, offset } => LogicalPlan::Limit {
+        input: Box::new(f(*input)),
+        count,
+        offset,
+      },
+      LogicalPlan::Union { inputs } => LogicalPlan::Union {
+        inputs: inputs.into_iter().map(f).collect(),
+      },
+    }
+  }
+
+  /// The slots of the tables scanned under this node.
+  pub fn slots(&self) -> Vec<usize> {
+    match self {
+      LogicalPlan::Scan { slot, .. } => vec![*slot],
+      plan => {
+        let mut slots: Vec<usize> = plan.inputs().iter().flat_map(|p| p.slots()).collect();
+        slots.sort_unstable();
+        slots.dedup();
+        slots
+      }
+    }
+  }
+
+  /// The physical operators for the plan. `scan` is called with the slot
+  /// of each table scanned, in the order they appear in the tree, for the
+  /// rows of that table. A product under a selection is lowered with the
+  /// selection as its join predicate.
+  pub fn lower<'a>(self, config: &Config, scan: &mut dyn FnMut(usize) -> Rows<'a>) -> BoxedOperator<'a> {
+    let width = self.slots().last().map_or(0, |s| s + 1);
+    let mut lowering = Lowering {
+      width,
+      config,
+      scan,
+      columnar: None,
+      pool: pool(config),
+      profile: None,
+    };
+    lowering.lower(self).0
+  }
+
+  /// Lowers the plan as `lower` does, over tables stored a column at a
+  /// time, given by slot. Each table is scanned a batch at a time, with the
+  /// selection and projection directly over it, if any, applied to the
+  /// batches.
+  pub fn lower_columnar<'a>(self, config: &Config, tables: &[&'a ColumnarTable]) -> BoxedOperator<'a> {
+    let width = self.slots().last().map_or(0, |s| s + 1);
+    let mut scan = |slot: usize| tables[slot].rows();
+    let mut lowering = Lowering {
+      width,
+      config,
+      scan: &mut scan,
+      columnar: Some(tables),
+      pool: pool(config),
+      profile: None,
+    };
+    lowering.lower(self).0
+  }
+
+  /// Lowers the plan as `lower` does, with each operator wrapped to record
+  /// what it does as it runs, and the tree of operators with their records.
+  pub fn lower_profiled<'a>(self, config: &Config, scan: &mut dyn FnMut(usize) -> Rows<'a>) -> (BoxedOperator<'a>, Profile) {
+    self.profiled(config, scan, None)
+  }
+
+  /// Lowers the plan as `lower_columnar` does, recording what each
+  /// operator does as `lower_profiled` does.
+  pub fn lower_columnar_profiled<'a>(self, config: &Config, tables: &[&'a ColumnarTable]) -> (BoxedOperator<'a>, Profile) {
+    let mut scan = |slot: usize| tables[slot].rows();
+    self.profiled(config, &mut scan, Some(tables))
+  }
+
+  fn profiled<'a>(
+    self,
+    config: &Config,
+    scan: &mut dyn FnMut(usize) -> Rows<'a>,
+    columnar: Option<&[&'a ColumnarTable]>,
+  ) -> (BoxedOperator<'a>, Profile) {
+    let width = self.slots().last().map_or(0, |s| s + 1);
+    let mut scope = vec![];
+    self.scope(&mut scope);
+    let mut lowering = Lowering {
+      width,
+      config,
+      scan,
+      columnar,
+      pool: pool(config),
+      profile: Some((Names(scope), vec![])),
+    };
+    let (root, id) = lowering.lower(self);
+    let (_, nodes) = lowering.profile.unwrap();
+    (root, Profile::new(nodes, id))
+  }
+
+  /// The name and columns of the table in each slot, for printing.
+  fn scope(&self, scope: &mut Vec<Option<(String, Vec<String>)>>) {
+    match self {
+      LogicalPlan::Scan { table, slot, header, .. } => {
+        if scope.len() <= *slot {
+          scope.resize(slot + 1, None);
+        }
+        scope[*slot] = Some((table.clone(), header.clone()));
+      }
+      LogicalPlan::Rename { input, alias } => {
+        input.scope(scope);
+        for slot in input.slots() {
+          if let Some((name, _)) = &mut scope[slot] {
+            *name = alias.clone();
+          }
+        }
+      }
+      plan => plan.inputs().iter().for_each(|p| p.scope(scope)),
+    }
+  }
+}
+
+/// The workers the operators of a query share, when it runs on several
+/// threads.
+fn pool(config: &Config) -> Option<Arc<Pool>> {
+  (config.threads > 1).then(|| Arc::new(Pool::new(config.threads)))
+}
+
+/// The state of lowering a plan: what every operator needs to be built,
+/// the tables by slot when they are stored a column at a time, the workers
+/// of the query, and when profiling, the names to describe the operators
+/// with and the nodes of the profile so far.
+struct Lowering<'s, 'a> {
+  width: usize,
+  config: &'s Config,
+  scan: &'s mut dyn FnMut(usize) -> Rows<'a>,
+  columnar: Option<&'s [&'a ColumnarTable]>,
+  pool: Option<Arc<Pool>>,
+  profile: Option<(Names, Vec<ProfileNode>)>,
+}
+
+impl<'a> Lowering<'_, 'a> {
+  /// The operators for the plan, and the id of its root in the profile.
+  fn lower(&mut self, plan: LogicalPlan) -> (BoxedOperator<'a>, usize) {
+    let plan = match self.columnar {
+      Some(tables) => match self.batch_scan(plan, tables) {
+        Ok(lowered) => return lowered,
+        Err(plan) => plan,
+      },
+      None => plan,
+    };
+    let label = self.describe(|names| names.node(&plan));
+    match plan {
+      LogicalPlan::Scan { table, slot, index, .. } => {
+        let label = self.describe(|names| match &index {
+          Some(lookup) => format!("Index {} USING {}", names.scan(&table, slot), lookup),
+          None => names.scan(&table, slot),
+        });
+        let rows = match index {
+          Some(lookup) => lookup.rows(),
+          None => (self.scan)(slot),
+        };
+        self.add(Box::new(exec::Scan::new(rows, slot, self.width)), label, vec![])
+      }
+      LogicalPlan::Rename { input, .. } => self.lower(*input),
+      LogicalPlan::Select { input, predicate } => match *input {
+        LogicalPlan::Product { left, right } => self.join(*left, *right, Some(predicate), None),
+        input => {
+          let label = self.describe(|names| format!("Filter {}", names.expr(&predicate)));
+          let (input, id) = self.lower(input);
+          self.add(Box::new(exec::Filter::new(input, predicate).with_pool(self.pool.clone())), label, vec![id])
+        }
+      },
+      LogicalPlan::Product { left, right } => self.join(*left, *right, None, None),
+      LogicalPlan::Join {
+        left,
+        right,
+        predicate,
+        algorithm,
+      } => self.join(*left, *right, Some(predicate), algorithm),
+      LogicalPlan::IndexJoin {
+        left,
+        right,
+        key,
+        index,
+        predicate,
+      } => {
+        let label = self.describe(|names| {
+          format!("Index Nested Loop Join {} USING {}", names.expr(&predicate), index.name)
+        });
+        // The right side is read through the index, so its selections are
+        // checked along with the predicate.
+        let mut predicates = vec![predicate];
+        let mut right = *right;
+        let slot = loop {
+          match right {
+            LogicalPlan::Scan { slot, .. } => break slot,
+            LogicalPlan::Rename { input, .. } => right = *input,
+            LogicalPlan::Select { input, predicate } => {
+              predicates.push(predicate);
+              right = *input;
+            }
+            plan => unreachable!("an index join reads a table, not {:?}", plan),
+          }
+        };
+        let predicate = IndexedFilter::conjunction(predicates);
+        let (left, id) = self.lower(*left);
+        let join = exec::IndexNestedLoopJoin::new(left, key, index, slot, predicate).with_pool(self.pool.clone());
+        self.add(Box::new(join), label, vec![id])
+      }
+      LogicalPlan::Project { input, selection } => {
+        let (input, id) = self.lower(*input);
+        self.add(Box::new(exec::Project::new(input, selection).with_pool(self.pool.clone())), label, vec![id])
+      }
+      LogicalPlan::Aggregate { input, aggregates } => {
+        let (input, id) = self.lower(*input);
+        self.add(Box::new(exec::Aggregate::new(input, aggregates).with_pool(self.pool.clone())), label, vec![id])
+      }
+      LogicalPlan::Sort { input, keys } => {
+        let (input, id) = self.lower(*input);
+        let sort = exec::Sort::new(input, keys, self.config.memory_budget);
+        self.add(Box::new(sort), label, vec![id])
+      }
+      LogicalPlan::Limit { input, count, offset } => {
+        let (input, id) = self.lower(*input);
+        self.add(Box::new(exec::Limit::new(input, count, offset)), label, vec![id])
+      }
+      LogicalPlan::Union { inputs } => {
+        let (inputs, ids) = inputs.into_iter().map(|p| self.lower(p)).unzip();
+        self.add(Box::new(exec::Union::new(inputs)), label, ids)
+      }
+    }
+  }
+
+  /// A batch scan for a scan of a columnar table, with the selection and
+  /// projection over it if there are any, or else the plan unchanged.
+  fn batch_scan(&mut self, plan: LogicalPlan, tables: &[&'a ColumnarTable]) -> Result<(BoxedOperator<'a>, usize), LogicalPlan> {
+    fn scanned(plan: &LogicalPlan) -> Option<(&Ident, usize, Option<&IndexedFilter>)> {
+      match plan {
+        LogicalPlan::Scan { table, slot, index: None, .. } => Some((table, *slot, None)),
+        LogicalPlan::Rename { input, .. } => scanned(input),
+        LogicalPlan::Select { input, predicate } => match scanned(input)? {
+          (table, slot, None) => Some((table, slot, Some(predicate))),
+          _ => None,
+        },
+        _ => None,
+      }
+    }
+    let (input, selection) = match &plan {
+      LogicalPlan::Project { input, selection } => (&**input, Some(selection)),
+      plan => (plan, None),
+    };
+    let (table, slot, predicate) = match scanned(input) {
+      Some(scanned) => scanned,
+      None => return Err(plan),
+    };
+    let label = self.describe(|names| {
+      let mut label = format!("Batch {}", names.scan(table, slot));
+      if let Some(predicate) = predicate {
+        label.push_str(&format!(", Filter {}", names.expr(predicate)));
+      }
+      if selection.is_some() {
+        label.push_str(&format!(", {}", names.node(&plan)));
+      }
+      label
+    });
+    let filter = predicate.map(|p| BatchFilter::new(p, slot, self.width));
+    let projection = selection.map(|s| BatchProjection::new(s, tables[slot], slot, self.width));
+    let scan = BatchScan::new(tables[slot], slot, self.width, filter, projection);
+    Ok(self.add(Box::new(scan), label, vec![]))
+  }
+
+  /// A join of the two plans, by hashing or sorting when the predicate has
+  /// equalities between the two sides and by nested loops otherwise.
+  fn join(
+    &mut self,
+    left: LogicalPlan,
+    right: LogicalPlan,
+    predicate: Option<IndexedFilter>,
+    algorithm: Option<JoinAlgorithm>,
+  ) -> (BoxedOperator<'a>, usize) {
+    let (left_slots, right_slots) = (left.slots(), right.slots());
+    let (left, left_id) = self.lower(left);
+    let (right, right_id) = self.lower(right);
+    let ids = vec![left_id, right_id];
+    let pool = self.pool.clone();
+    let predicate = match predicate {
+      Some(predicate) => predicate,
+      None => {
+        let label = self.describe(|_| "Nested Loop Join".to_owned());
+        let join = exec::NestedLoopJoin::new(left, right, None).with_pool(pool);
+        return self.add(Box::new(join), label, ids);
+      }
+    };
+    let (keys, residual) = equi_join(&predicate, &left_slots, &right_slots);
+    if keys.is_empty() {
+      let label = self.describe(|names| format!("Nested Loop Join {}", names.expr(&predicate)));
+      let join = exec::NestedLoopJoin::new(left, right, Some(predicate)).with_pool(pool);
+      return self.add(Box::new(join), label, ids);
+    }
+    let (left_keys, right_keys) = keys.into_iter().unzip();
+    let algorithm = self.config.join.or(algorithm).unwrap_or(JoinAlgorithm::Hash);
+    let label = self.describe(|names| format!("{} Join {}", algorithm, names.expr(&predicate)));
+    let join: BoxedOperator<'a> = match algorithm {
+      JoinAlgorithm::Hash => Box::new(exec::HashJoin::new(left, right, left_keys, right_keys, residual).with_pool(pool)),
+      JoinAlgorithm::Merge => Box::new(exec::MergeJoin::new(
+        left,
+        right,
+        left_keys,
+        right_keys,
+        residual,
+        self.config.memory_budget,
+      )),
+    };
+    self.add(join, label, ids)
+  }
+
+  /// The description of an operator for the profile, if there is one.
+  fn describe<F: FnOnce(&Names) -> String>(&self, f: F) -> Option<String> {
+    self.profile.as_ref().map(|(names, _)| f(names))
+  }
+
+  /// Adds an operator with the given inputs to the profile, if there is
+  /// one, and wraps it to record what it does.
+  fn add(&mut self, op: BoxedOperator<'a>, label: Option<String>, inputs: Vec<usize>) -> (BoxedOperator<'a>, usize) {
+    match (&mut self.profile, label) {
+      (Some((_, nodes)), Some(label)) => {
+        let node = ProfileNode::new(label, inputs);
+        let op = Box::new(Instrumented::new(op, node.recorder()));
+        nodes.push(node);
+        (op, nodes.len() - 1)
+      }
+      _ => (op, 0),
+    }
+  }
+}
+
+/// Splits a join predicate into the pairs of expressions that must be
+/// equal, the first of each over the tables in `left` and the second over
+/// those in `right`, and the conjunction of the remaining conditions.
+pub fn equi_join(
+  predicate: &IndexedFilter,
+  left: &[usize],
+  right: &[usize],
+) -> (Vec<(IndexedFilter, IndexedFilter)>, Option<IndexedFilter>) {
+  let within = |e: &IndexedFilter, side: &[usize]| {
+    let slots = e.slots();
+    !slots.is_empty() && slots.iter().all(|s| side.contains(s))
+  };
+  let mut keys = vec![];
+  let mut rest = vec![];
+  for term in predicate.conjuncts() {
+    match term {
+      IndexedFilter::BinaryOp(BinaryOp::Eq, l, r) if within(l, left) && within(r, right) => {
+        keys.push(((**l).clone(), (**r).clone()))
+      }
+      IndexedFilter::BinaryOp(BinaryOp::Eq, l, r) if within(r, left) && within(l, right) => {
+        keys.push(((**r).clone(), (**l).clone()))
+      }
+      term => rest.push(term.clone()),
+    }
+  }
+  (keys, IndexedFilter::conjunction(rest))
+}
+
+impl IndexedFilter {
+  /// The terms of the expression if it is a chain of `&&`, or else the
+  /// expression itself. A row satisfies the expression exactly when it
+  /// satisfies every term.
+  pub fn conjuncts(&self) -> Vec<&IndexedFilter> {
+    match self {
+      IndexedFilter::BinaryOp(BinaryOp::And, l, r) => {
+        let mut terms = l.conjuncts();
+        terms.extend(r.conjuncts());
+        terms
+      }
+      e => vec![e],
+    }
+  }
+
+  /// The terms joined by `&&`, or `None` if there are none.
+  pub fn conjunction(terms: Vec<IndexedFilter>) -> Option<IndexedFilter> {
+    terms
+      .into_iter()
+      .reduce(|l, r| IndexedFilter::BinaryOp(BinaryOp::And, Box::new(l), Box::new(r)))
+  }
+
+  /// The slots of the tables whose columns the expression refers to.
+  pub fn slots(&self) -> Vec<usize> {
+    let mut slots = match self {
+      IndexedFilter::Id(id) => vec![id.table],
+      IndexedFilter::LitS(_) | IndexedFilter::LitB(_) | IndexedFilter::LitI(_) | IndexedFilter::Param(_) => vec![],
+      IndexedFilter::UnaryOp(_, e) => e.slots(),
+      IndexedFilter::BinaryOp(_, l, r) => [l.slots(), r.slots()].concat(),
+      IndexedFilter::Call(_, args) | IndexedFilter::Regex(_, _, args) => args.iter().flat_map(|a| a.slots()).collect(),
+    };
+    slots.sort_unstable();
+    slots.dedup();
+    slots
+  }
+}
+
+/// Writes bound expressions with the names of the columns they refer to.
+struct Names(Vec<Option<(String, Vec<String>)>>);
+
+impl Names {
+  fn scan(&self, table: &str, slot: usize) -> String {
+    match self.0.get(slot) {
+      Some(Some((name, _))) if name != table => format!("Scan {} AS {}", table, name),
+      _ => format!("Scan {}", table),
+    }
+  }
+
+  fn expr(&self, e: &IndexedFilter) -> String {
+    let list = |args: &[IndexedFilter]| args.iter().map(|a| self.expr(a)).collect::<Vec<_>>().join(", ");
+    match e {
+      IndexedFilter::Id(id) => match self.0.get(id.table) {
+        Some(Some((table, header))) => format!("{}.{}", table, header[id.field]),
+        _ => format!("#{}.{}", id.table, id.field),
+      },
+      IndexedFilter::LitS(s) => format!(r#""{}""#, s),
+      IndexedFilter::LitB(b) => format!("{}", b),
+      IndexedFilter::LitI(n) => format!("{}", n),
+      IndexedFilter::UnaryOp(op, e) => format!("({} {})", op, self.expr(e)),
+      IndexedFilter::BinaryOp(op, l, r) => format!("({} {} {})", self.expr(l), op, self.expr(r)),
+      IndexedFilter::Call(f, args) => format!("{}({})", f.name, list(args)),
+      IndexedFilter::Regex(RegexOp::Matches, pattern, args) => {
+        format!(r#"({} REGEXP "{}")"#, self.expr(&args[0]), pattern.0.as_str())
+      }
+      IndexedFilter::Regex(op, pattern, args) => format!(
+        r#"{}({}, "{}"{})"#,
+        if *op == RegexOp::Extract { "regexp_extract" } else { "regexp_replace" },
+        self.expr(&args[0]),
+        pattern.0.as_str(),
+        args[1..].iter().map(|a| format!(", {}", self.expr(a))).collect::<String>()
+      ),
+      IndexedFilter::Param(p) => format!("{}", p),
+    }
+  }
+
+  fn node(&self, plan: &LogicalPlan) -> String {
+    match plan {
+      LogicalPlan::Scan { table, index: None, .. } => format!("Scan {}", table),
+      LogicalPlan::Scan {
+        table, index: Some(lookup), ..
+      } => format!("Index Scan {} USING {}", table, lookup),
+      LogicalPlan::Rename { alias, .. } => format!("Rename {}", alias),
+      LogicalPlan::Select { predicate, .. } => format!("Select {}", self.expr(predicate)),
+      LogicalPlan::Product { .. } => "Product".to_owned(),
+      LogicalPlan::Join {
+        predicate,
+        algorithm: None,
+        ..
+      } => format!("Join {}", self.expr(predicate)),
+      LogicalPlan::Join {
+        predicate,
+        algorithm: Some(algorithm),
+        ..
+      } => format!("{} Join {}", algorithm, self.expr(predicate)),
+      LogicalPlan::IndexJoin { predicate, index, .. } => format!("Index Join {} USING {}", self.expr(predicate), index.name),
+      LogicalPlan::Project {
+        selection: IndexedSelection::Columns(columns),
+        ..
+      } => {
+        let columns: Vec<String> = columns.iter().map(|c| self.expr(&c.expr)).collect();
+        format!("Project {}", columns.join(", "))
+      }
+      LogicalPlan::Project { .. } => "Project *".to_owned(),
+      LogicalPlan::Aggregate { aggregates, .. } => {
+        let aggregates: Vec<String> = aggregates
+          .iter()
+          .map(|a| {
+            let args: Vec<String> = a.args.iter().map(|e| self.expr(e)).collect();
+            format!("{}({})", a.function.name, args.join(", "))
+          })
+          .collect();
+        format!("Aggregate {}", aggregates.join(", "))
+      }
+      LogicalPlan::Sort { keys, .. } => {
+        let keys: Vec<String> = keys
+          .iter()
+          .map(|k| format!("{}{}", self.expr(&k.expr), if k.descending { " DESC" } else { "" }))
+          .collect();
+        format!("Sort {}", keys.join(", "))
+      }
+      LogicalPlan::Limit { count, offset: 0, .. } => format!("Limit {}", count),
+      LogicalPlan::Limit { count, offset, .. } => format!("Limit {} OFFSET {}", count, offset),
+      LogicalPlan::Union { inputs } if inputs.is_empty() => "Empty".to_owned(),
+      LogicalPlan::Union { .. } => "Union".to_owned(),
+    }
+  }
+
+  fn tree(&self, plan: &LogicalPlan, depth: usize, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "{}{}", "  ".repeat(depth), self.node(plan))?;
+    plan.inputs().iter().try_for_each(|p| self.tree(p, depth + 1, f))
+  }
+}
+
+/// The plan as an indented tree, a node per line with its inputs below it.
+impl fmt::Display for LogicalPlan {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut scope = vec![];
+    self.scope(&mut scope);
+    Names(scope).tree(self, 0, f)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::functions::FunctionRegistry;
+  use crate::parser::parse_sql;
+  use crate::stream::{rows_of, Row};
+  use crate::tables::TableCell;
+
+  fn catalog() -> (Vec<TableData>, Vec<String>) {
+    let int = |n| Some(TableCell::CellInt(n));
+    let catalog = vec![
+      TableData {
+        header: vec!["name".to_owned(), "id".to_owned()],
+        rows: vec![
+          vec![Some(TableCell::CellString("Lucas".to_owned())), int(0)],
+          vec![Some(TableCell::CellString("Harry".to_owned())), int(1)],
+        ],
+      },
+      TableData {
+        header: vec!["id".to_owned(), "score".to_owned()],
+        rows: vec![vec![int(1), int(90)], vec![int(1), int(70)], vec![int(0), int(80)]],
+      },
+    ];
+    (catalog, vec!["users".to_owned(), "scores".to_owned()])
+  }
+
+  #[test]
+  fn pretty_print() {
+    let (catalog, names) = catalog();
+    let q = parse_sql(
+      "SELECT u.name, score FROM users AS u, scores WHERE u.id == scores.id && 75 < score ORDER BY score DESC LIMIT 2",
+    )
+    .unwrap()
+    .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+    .unwrap();
+    assert_eq!(
+      format!("{}", q.logical_plan(&catalog)),
+      concat!(
+        "Limit 2\n",
+        "  Project u.name, scores.score\n",
+        "    Sort scores.score DESC\n",
+        "      Select ((u.id == scores.id) && (75 < scores.score))\n",
+        "        Product\n",
+        "          Rename u\n",
+        "            Scan users\n",
+        "          Scan scores\n",
+      )
+    );
+    let q = parse_sql("SELECT count(*), max(score) FROM scores WHERE TRUE")
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+      .unwrap();
+    assert_eq!(
+      format!("{}", q.logical_plan(&catalog)),
+      "Aggregate count(), max(scores.score)\n  Scan scores\n"
+    );
+  }
+
+  #[test]
+  fn lowering() {
+    let (catalog, names) = catalog();
+    let q = parse_sql("SELECT name, score FROM users, scores WHERE users.id == scores.id ORDER BY score")
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::new())
+      .unwrap();
+    let rows = |plan: LogicalPlan| -> Vec<Row> {
+      let mut scan = |slot: usize| rows_of(&catalog[q.sources[slot]]);
+      exec::Execution::new(plan.lower(&Config::default(), &mut scan)).collect::<crate::error::Result<_>>().unwrap()
+    };
+    let plan = q.logical_plan(&catalog);
+    assert_eq!(rows(plan.clone()), q.run(&catalog).unwrap().rows);
+
+    // The same query with the selection made a join predicate.
+    let joined = match plan {
+      LogicalPlan::Project { input, selection } => match *input {
+        LogicalPlan::Sort { input, keys } => match *input {
+          LogicalPlan::Select { input, predicate } => match *input {
+            LogicalPlan::Product { left, right } => LogicalPlan::Project {
+              input: Box::new(LogicalPlan::Sort {
+                input: Box::new(LogicalPlan::Join {
+                  left,
+                  right,
+                  predicate,
+                  algorithm: None,
+                }),
+                keys,
+              }),
+              selection,
+            },
+            p => panic!("unexpected plan {}", p),
+          },
+          p => panic!("unexpected plan {}", p),
+        },
+        p => panic!("unexpected plan {}", p),
+      },
+      p => panic!("unexpected plan {}", p),
+    };
+    assert_eq!(rows(joined), q.run(&catalog).unwrap().rows);
+
+    let scan = LogicalPlan::Scan {
+      table: "scores".to_owned(),
+      source: 1,
+      slot: 1,
+      header: catalog[1].header.clone(),
+      index: None,
+    };
+    let union = LogicalPlan::Union {
+      inputs: vec![scan.clone(), LogicalPlan::Union { inputs: vec![] }, scan],
+    };
+    assert_eq!(rows(union).len(), 6);
+  }
+
+  #[test]
+  fn equi_joins() {
+    let (catalog, names) = catalog();
+    let bind = |sql: &str| {
+      parse_sql(sql)
+        .unwrap()
+        .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+        .unwrap()
+        .filter
+    };
+    let names = Names(vec![
+      Some(("a".to_owned(), vec!["x".to_owned(), "y".to_owned()])),
+      Some(("b".to_owned(), vec!["x".to_owned(), "y".to_owned()])),
+    ]);
+    let split = |sql: &str| {
+      let (keys, residual) = equi_join(&bind(sql), &[0], &[1]);
+      let keys: Vec<String> = keys.iter().map(|(l, r)| format!("{} = {}", names.expr(l), names.expr(r))).collect();
+      (keys, residual.map(|r| names.expr(&r)))
+    };
+    assert_eq!(
+      split("SELECT * FROM users, scores WHERE scores.id == users.id && name == \"x\" && abs(users.id) == score"),
+      (
+        vec!["a.y = b.x".to_owned(), "abs(a.y) = b.y".to_owned()],
+        Some("(a.x == \"x\")".to_owned())
+      )
+    );
+    assert_eq!(
+      split("SELECT * FROM users, scores WHERE users.id == scores.id || score == 1"),
+      (vec![], Some("((a.y == b.x) || (b.y == 1))".to_owned()))
+    );
+    assert_eq!(split("SELECT * FROM users, scores WHERE users.id == 1"), (vec![], Some("(a.y == 1)".to_owned())));
+  }
+
+  #[test]
+  fn hash_joins_match_nested_loops() {
+    let cell = |i: i64| match i % 5 {
+      0 => None,
+      1 => Some(TableCell::CellString((i % 3).to_string())),
+      _ => Some(TableCell::CellInt(i % 7)),
+    };
+    let table = |header: &[&str], n: i64, seed: i64| TableData {
+      header: header.iter().map(|h| h.to_string()).collect(),
+      rows: (0..n).map(|i| vec![cell(i * seed), cell(i + seed), Some(TableCell::CellInt(i))]).collect(),
+    };
+    let catalog = vec![table(&["a", "b", "n"], 40, 3), table(&["c", "d", "m"], 30, 7), table(&["e", "f", "k"], 20, 11)];
+    let names = vec!["r".to_owned(), "s".to_owned(), "t".to_owned()];
+    for sql in [
+      "SELECT * FROM r, s WHERE a == c",
+      "SELECT * FROM r, s WHERE d == b && a == c && n < m",
+      "SELECT * FROM r, s, t WHERE a == c && d == e && (b == f || m < k)",
+      "SELECT n, m FROM r, s WHERE a == c || b == d",
+    ] {
+      let q = parse_sql(sql)
+        .unwrap()
+        .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+        .unwrap();
+      let mut hashed = q.run(&catalog).unwrap().rows;
+      let mut nested = TableData::join_table(&|row| q.filter.valid_row(row), &catalog[..q.sources.len()], &q.selection).rows;
+      hashed.sort();
+      nested.sort();
+      assert!(!nested.is_empty(), "{}", sql);
+      assert_eq!(hashed, nested, "{}", sql);
+    }
+
+    let big = |name: &str| TableData {
+      header: vec![name.to_owned()],
+      rows: (0..50_000).map(|i| vec![Some(TableCell::CellInt(i))]).collect(),
+    };
+    let catalog = vec![big("x"), big("y")];
+    let names = vec!["p".to_owned(), "q".to_owned()];
+    let q = parse_sql("SELECT count(*) FROM p, q WHERE x == y")
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+      .unwrap();
+    assert_eq!(q.run(&catalog).unwrap().rows, vec![vec![Some(TableCell::CellInt(50_000))]]);
+  }
+}
@@ -0,0 +1,231 @@
+//! Prepared queries with placeholders (`?`, `$1` or `:name`) whose values
+//! are supplied each time the query is executed.
+
+use crate::ast::*;
+use crate::error::{Error, NameError, Result};
+use crate::functions::FunctionRegistry;
+use crate::tables::{TableCell, TableData};
+use std::collections::HashMap;
+
+/// Values for the parameters of a prepared query.
+#[derive(Debug, Default, Clone)]
+pub struct Bindings(HashMap<Param, TableCell>);
+
+impl Bindings {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn set(&mut self, param: Param, value: TableCell) {
+    self.0.insert(param, value);
+  }
+
+  /// Binds `$n`, or the `n`th `?`, counting from 1.
+  pub fn positional(mut self, n: usize, value: TableCell) -> Self {
+    self.set(Param::Positional(n), value);
+    self
+  }
+
+  pub fn named(mut self, name: &str, value: TableCell) -> Self {
+    self.set(Param::Named(name.to_owned()), value);
+    self
+  }
+
+  fn get(&self, param: &Param) -> Result<&TableCell> {
+    self
+      .0
+      .get(param)
+      .ok_or_else(|| Error::name(NameError::UnboundParameter(param.clone()), None))
+  }
+}
+
+/// A query that has been resolved against its tables once and can be run
+/// repeatedly with different parameter values.
+#[derive(Debug, Clone)]
+pub struct PreparedStatement {
+  query: IndexedQuery,
+  params: Vec<Param>,
+}
+
+impl PreparedStatement {
+  pub fn new(
+    query: &Query,
+    tables: &[TableData],
+    names: &[String],
+    functions: &FunctionRegistry,
+  ) -> Result<Self> {
+    let query = query.to_indexed(tables, names, functions)?;
+    let params = query.params();
+    Ok(PreparedStatement { query, params })
+  }
+
+  /// The distinct parameters of the query, in order of first appearance.
+  pub fn params(&self) -> &[Param] {
+    &self.params
+  }
+
+  /// The query with every parameter replaced by its value.
+  pub fn bind(&self, values: &Bindings) -> Result<IndexedQuery> {
+    let selection = match &self.query.selection {
+      IndexedSelection::Star => IndexedSelection::Star,
+      IndexedSelection::Columns(cols) => IndexedSelection::Columns(
+        cols
+          .iter()
+          .map(|c| {
+            Ok(IndexedColumn {
+              name: c.name.clone(),
+              expr: c.expr.bind(values)?,
+            })
+          })
+          .collect::<Result<_>>()?,
+      ),
+      IndexedSelection::Aggregates(aggs) => IndexedSelection::Aggregates(
+        aggs
+          .iter()
+          .map(|a| {
+            Ok(IndexedAggregate {
+              name: a.name.clone(),
+              function: a.function.clone(),
+              args: bind_all(&a.args, values)?,
+            })
+          })
+          .collect::<Result<_>>()?,
+      ),
+    };
+    Ok(IndexedQuery {
+      selection,
+      tables: self.query.tables.clone(),
+      sources: self.query.sources.clone(),
+      filter: self.query.filter.bind(values)?,
+      order_by: self
+        .query
+        .order_by
+        .iter()
+        .map(|k| {
+          Ok(IndexedOrderKey {
+            expr: k.expr.bind(values)?,
+            descending: k.descending,
+          })
+        })
+        .collect::<Result<_>>()?,
+      limit: self.query.limit,
+    })
+  }
+
+  pub fn execute(&self, tables: &[TableData], values: &Bindings) -> Result<TableData> {
+    self.bind(values)?.run(tables)
+  }
+}
+
+fn bind_all(fs: &[IndexedFilter], values: &Bindings) -> Result<Vec<IndexedFilter>> {
+  fs.iter().map(|f| f.bind(values)).collect()
+}
+
+impl IndexedFilter {
+  fn bind(&self, values: &Bindings) -> Result<IndexedFilter> {
+    Ok(match self {
+      IndexedFilter::Param(p) => match values.get(p)? {
+        TableCell::CellInt(n) => IndexedFilter::LitI(*n),
+        TableCell::CellString(s) => IndexedFilter::LitS(s.clone()),
+      },
+      IndexedFilter::UnaryOp(op, e) => IndexedFilter::UnaryOp(*op, Box::new(e.bind(values)?)),
+      IndexedFilter::BinaryOp(op, e1, e2) => {
+        IndexedFilter::BinaryOp(*op, Box::new(e1.bind(values)?), Box::new(e2.bind(values)?))
+      }
+      IndexedFilter::Call(f, args) => IndexedFilter::Call(f.clone(), bind_all(args, values)?),
+      IndexedFilter::Regex(op, p, args) => IndexedFilter::Regex(*op, p.clone(), bind_all(args, values)?),
+      IndexedFilter::Id(_) | IndexedFilter::LitS(_) | IndexedFilter::LitB(_) | IndexedFilter::LitI(_) => {
+        self.clone()
+      }
+    })
+  }
+
+  fn collect_params(&self, params: &mut Vec<Param>) {
+    match self {
+      IndexedFilter::Param(p) => {
+        if !params.contains(p) {
+          params.push(p.clone())
+        }
+      }
+      IndexedFilter::UnaryOp(_, e) => e.collect_params(params),
+      IndexedFilter::BinaryOp(_, e1, e2) => {
+        e1.collect_params(params);
+        e2.collect_params(params);
+      }
+      IndexedFilter::Call(_, args) | IndexedFilter::Regex(_, _, args) => {
+        args.iter().for_each(|a| a.collect_params(params))
+      }
+      IndexedFilter::Id(_) | IndexedFilter::LitS(_) | IndexedFilter::LitB(_) | IndexedFilter::LitI(_) => (),
+    }
+  }
+}
+
+impl IndexedQuery {
+  /// The distinct parameters of the query, in order of first appearance.
+  pub fn params(&self) -> Vec<Param> {
+    let mut params = vec![];
+    match &self.selection {
+      IndexedSelection::Star => (),
+      IndexedSelection::Columns(cols) => cols.iter().for_each(|c| c.expr.collect_params(&mut params)),
+      IndexedSelection::Aggregates(aggs) => aggs
+        .iter()
+        .flat_map(|a| &a.args)
+        .for_each(|e| e.collect_params(&mut params)),
+    }
+    self.filter.collect_params(&mut params);
+    self.order_by.iter().for_each(|k| k.expr.collect_params(&mut params));
+    params
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::parse_sql;
+
+  #[test]
+  fn execute_with_bindings() {
+    let table = TableData {
+      header: vec!["name".to_owned(), "age".to_owned()],
+      rows: vec![
+        vec![Some(TableCell::CellString("Lucas".to_owned())), Some(TableCell::CellInt(24))],
+        vec![Some(TableCell::CellString("O\"Brien".to_owned())), Some(TableCell::CellInt(25))],
+      ],
+    };
+    let tables = vec![table];
+    let names = vec!["user".to_owned()];
+    let q = parse_sql("SELECT name\nFROM user\nWHERE name == ? || age == :age").unwrap();
+    let functions = FunctionRegistry::new();
+    let stmt = PreparedStatement::new(&q, &tables, &names, &functions).unwrap();
+    assert_eq!(
+      stmt.params(),
+      &[Param::Positional(1), Param::Named("age".to_owned())]
+    );
+    assert!(matches!(
+      q.run(&tables, &names, &functions),
+      Err(Error::Name {
+        error: NameError::UnboundParameter(Param::Positional(1)),
+        ..
+      })
+    ));
+
+    let run = |b: Bindings| stmt.execute(&tables, &b).map(|t| t.rows).ok();
+    assert_eq!(
+      run(
+        Bindings::new()
+          .positional(1, TableCell::CellString("O\"Brien".to_owned()))
+          .named("age", TableCell::CellInt(0))
+      ),
+      Some(vec![vec![Some(TableCell::CellString("O\"Brien".to_owned()))]])
+    );
+    assert_eq!(
+      run(
+        Bindings::new()
+          .positional(1, TableCell::CellString("".to_owned()))
+          .named("age", TableCell::CellInt(24))
+      ),
+      Some(vec![vec![Some(TableCell::CellString("Lucas".to_owned()))]])
+    );
+    assert!(run(Bindings::new().positional(1, TableCell::CellInt(0))).is_none());
+  }
+}
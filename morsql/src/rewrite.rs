@@ -0,0 +1,525 @@
+//! Rewrites of logical plans that keep their results but make them cheaper
+//! to run.
+
+use crate::ast::{BinaryOp, IndexKind, IndexedFilter};
+use crate::exec::Config;
+use crate::index::{IndexLookup, KeyRange, TableIndex};
+use crate::join_order::Estimator;
+use crate::plan::{equi_join, LogicalPlan};
+use crate::stats::TableStats;
+use crate::tables::TableCell;
+use std::sync::Arc;
+
+/// The rows a lookup is taken to cost as much as reading, for its seeks.
+/// An index join is chosen when its outer side has fewer rows than the
+/// inner table divided by this.
+pub const PROBE_ROWS: f64 = 16.0;
+
+impl LogicalPlan {
+  /// The plan with every rewrite applied. `stats` holds the statistics of
+  /// each table in the catalog.
+  pub fn optimize(self, stats: &[TableStats], config: &Config) -> LogicalPlan {
+    self
+      .simplify_predicates()
+      .push_down_predicates()
+      .order_joins(stats, config)
+      .use_indexes(stats, config)
+  }
+
+  /// Simplifies the predicates of selections and joins. A selection every
+  /// row satisfies is removed, and one no row can satisfy replaces its input
+  /// with an empty relation, so none of the tables under it are read.
+  pub fn simplify_predicates(self) -> LogicalPlan {
+    match self {
+      LogicalPlan::Select { input, predicate } => match predicate.simplify_predicate() {
+        IndexedFilter::LitB(true) => input.simplify_predicates(),
+        IndexedFilter::LitB(false) => LogicalPlan::Union { inputs: vec![] },
+        predicate => LogicalPlan::Select {
+          input: Box::new(input.simplify_predicates()),
+          predicate,
+        },
+      },
+      LogicalPlan::Join {
+        left,
+        right,
+        predicate,
+        algorithm,
+      } => match predicate.simplify_predicate() {
+        IndexedFilter::LitB(true) => LogicalPlan::Product {
+          left: Box::new(left.simplify_predicates()),
+          right: Box::new(right.simplify_predicates()),
+        },
+        IndexedFilter::LitB(false) => LogicalPlan::Union { inputs: vec![] },
+        predicate => LogicalPlan::Join {
+          left: Box::new(left.simplify_predicates()),
+          right: Box::new(right.simplify_predicates()),
+          predicate,
+          algorithm,
+        },
+      },
+      plan => plan.map_inputs(LogicalPlan::simplify_predicates),
+    }
+  }
+
+  /// Moves each conjunct of a selection as close to the tables it reads as
+  /// it can go: a conjunct over one table filters that table before it is
+  /// joined, and a conjunct over several becomes the predicate of the
+  /// lowest product that brings them together, making it a join.
+  pub fn push_down_predicates(self) -> LogicalPlan {
+    self.push_down(vec![])
+  }
+
+  fn push_down(self, predicates: Vec<IndexedFilter>) -> LogicalPlan {
+    match self {
+      LogicalPlan::Select { input, predicate } => {
+        let mut predicates = predicates;
+        predicates.extend(predicate.conjuncts().into_iter().cloned());
+        input.push_down(predicates)
+      }
+      LogicalPlan::Product { left, right } => join(*left, *right, predicates),
+      LogicalPlan::Join { left, right, predicate, .. } | LogicalPlan::IndexJoin { left, right, predicate, .. } => {
+        let mut predicates = predicates;
+        predicates.extend(predicate.conjuncts().into_iter().cloned());
+        join(*left, *right, predicates)
+      }
+      LogicalPlan::Rename { input, alias } => LogicalPlan::Rename {
+        input: Box::new(input.push_down(predicates)),
+        alias,
+      },
+      LogicalPlan::Sort { input, keys } => LogicalPlan::Sort {
+        input: Box::new(input.push_down(predicates)),
+        keys,
+      },
+      LogicalPlan::Union { inputs } => LogicalPlan::Union {
+        inputs: inputs.into_iter().map(|p| p.push_down(predicates.clone())).collect(),
+      },
+      // The tuples above these no longer hold the tables' rows, or depend
+      // on how many rows there are, so nothing passes through them.
+      LogicalPlan::Project { input, selection } => select(
+        LogicalPlan::Project {
+          input: Box::new(input.push_down(vec![])),
+          selection,
+        },
+        predicates,
+      ),
+      LogicalPlan::Aggregate { input, aggregates } => select(
+        LogicalPlan::Aggregate {
+          input: Box::new(input.push_down(vec![])),
+          aggregates,
+        },
+        predicates,
+      ),
+      LogicalPlan::Limit { input, count, offset } => select(
+        LogicalPlan::Limit {
+          input: Box::new(input.push_down(vec![])),
+          count,
+          offset,
+        },
+        predicates,
+      ),
+      scan @ LogicalPlan::Scan { .. } => select(scan, predicates),
+    }
+  }
+
+  /// Reads the table under each selection through one of its indexes if
+  /// the selection compares the indexed column with a constant, preferring
+  /// an equality to `<` comparisons. The selection stays, checking its
+  /// whole predicate on the rows the index finds. A hash index is only
+  /// used for an equality.
+  ///
+  /// A join of a table with an index of a column it equates with the
+  /// other side becomes an index join when that side is small enough that
+  /// looking up each of its rows reads less than the whole table, unless
+  /// `config` sets the join algorithm. `stats` holds the statistics of each
+  /// table in the catalog, with its indexes.
+  pub fn use_indexes(self, stats: &[TableStats], config: &Config) -> LogicalPlan {
+    match self {
+      LogicalPlan::Select { input, predicate } => {
+        let input = match *input {
+          LogicalPlan::Rename { input, alias } => LogicalPlan::Rename {
+            input: Box::new(index_scan(*input, &predicate, stats, config)),
+            alias,
+          },
+          input => index_scan(input, &predicate, stats, config),
+        };
+        LogicalPlan::Select {
+          input: Box::new(input),
+          predicate,
+        }
+      }
+      LogicalPlan::Join {
+        left,
+        right,
+        predicate,
+        algorithm,
+      } if config.join.is_none() => match index_join(&left, &right, &predicate, stats) {
+        Some((swap, key, index)) => {
+          let (outer, inner) = if swap { (right, left) } else { (left, right) };
+          LogicalPlan::IndexJoin {
+            left: Box::new(outer.use_indexes(stats, config)),
+            right: inner,
+            key,
+            index,
+            predicate,
+          }
+        }
+        None => LogicalPlan::Join {
+          left,
+          right,
+          predicate,
+          algorithm,
+        }
+        .map_inputs(|p| p.use_indexes(stats, config)),
+      },
+      plan => plan.map_inputs(|p| p.use_indexes(stats, config)),
+    }
+  }
+}
+
+fn select(input: LogicalPlan, predicates: Vec<IndexedFilter>) -> LogicalPlan {
+  match IndexedFilter::conjunction(predicates) {
+    Some(predicate) => LogicalPlan::Select {
+      input: Box::new(input),
+      predicate,
+    },
+    None => input,
+  }
+}
+
+/// Sends each predicate to the side of the product holding all the tables
+/// it reads, and joins on those reading both. Predicates reading no table
+/// go left.
+fn join(left: LogicalPlan, right: LogicalPlan, predicates: Vec<IndexedFilter>) -> LogicalPlan {
+  let (left_slots, right_slots) = (left.slots(), right.slots());
+  let (mut to_left, mut to_right, mut here) = (vec![], vec![], vec![]);
+  for p in predicates {
+    let slots = p.slots();
+    if slots.iter().all(|s| left_slots.contains(s)) {
+      to_left.push(p);
+    } else if slots.iter().all(|s| right_slots.contains(s)) {
+      to_right.push(p);
+    } else {
+      here.push(p);
+    }
+  }
+  let left = Box::new(left.push_down(to_left));
+  let right = Box::new(right.push_down(to_right));
+  match IndexedFilter::conjunction(here) {
+    Some(predicate) => LogicalPlan::Join {
+      left,
+      right,
+      predicate,
+      algorithm: None,
+    },
+    None => LogicalPlan::Product { left, right },
+  }
+}
+
+/// A scan reading through the index that suits the predicate best, if
+/// there is one, or any other plan with its inputs rewritten.
+fn index_scan(plan: LogicalPlan, predicate: &IndexedFilter, stats: &[TableStats], config: &Config) -> LogicalPlan {
+  match plan {
+    LogicalPlan::Scan {
+      table,
+      source,
+      slot,
+      header,
+      index: None,
+    } => {
+      let indexes = stats.get(source).map_or(&[][..], |s| &s.indexes[..]);
+      let index = indexes
+        .iter()
+        .filter_map(|index| {
+          key_range(predicate, slot, index.column).map(|range| IndexLookup {
+            index: index.clone(),
+            range,
+          })
+        })
+        .filter(|lookup| lookup.index.kind() == IndexKind::BTree || matches!(lookup.range, KeyRange::Equal(_)))
+        .min_by_key(|lookup| !matches!(lookup.range, KeyRange::Equal(_)));
+      LogicalPlan::Scan {
+        table,
+        source,
+        slot,
+        header,
+        index,
+      }
+    }
+    plan => plan.use_indexes(stats, config),
+  }
+}
+
+/// How a join could look up the rows of one side in an index, if either
+/// side is a table with an index of a column the predicate equates with
+/// the other side, and that side is small enough: whether the inner side
+/// is the left one, the key over the outer side, and the index. Of two
+/// such sides, the one with the smaller outer side is chosen, and of two
+/// indexes, a hash index.
+fn index_join(
+  left: &LogicalPlan,
+  right: &LogicalPlan,
+  predicate: &IndexedFilter,
+  stats: &[TableStats],
+) -> Option<(bool, IndexedFilter, Arc<TableIndex>)> {
+  let candidate = |outer: &LogicalPlan, inner: &LogicalPlan| {
+    let (source, slot) = scanned(inner)?;
+    let table = stats.get(source)?;
+    let (keys, _) = equi_join(predicate, &outer.slots(), &[slot]);
+    let (key, index) = keys
+      .into_iter()
+      .flat_map(|(key, column)| {
+        table.indexes.iter().filter_map(move |index| match &column {
+          IndexedFilter::Id(id) if id.field == index.column => Some((key.clone(), index.clone())),
+          _ => None,
+        })
+      })
+      .min_by_key(|(_, index)| index.kind() != IndexKind::Hash)?;
+    let outer_rows = Estimator::new(outer, stats).rows(outer);
+    (outer_rows * PROBE_ROWS < table.rows as f64).then_some((outer_rows, key, index))
+  };
+  let join = candidate(left, right).map(|(rows, key, index)| (rows, false, key, index));
+  let swapped = candidate(right, left).map(|(rows, key, index)| (rows, true, key, index));
+  let (_, swap, key, index) = match (join, swapped) {
+    (Some(join), Some(swapped)) if swapped.0 < join.0 => swapped,
+    (Some(join), _) => join,
+    (None, swapped) => swapped?,
+  };
+  Some((swap, key, index))
+}
+
+/// The catalog index and slot of the table a plan reads, if it reads only
+/// that table, under any renaming and selection, and without an index.
+fn scanned(plan: &LogicalPlan) -> Option<(usize, usize)> {
+  match plan {
+    LogicalPlan::Scan {
+      source,
+      slot,
+      index: None,
+      ..
+    } => Some((*source, *slot)),
+    LogicalPlan::Rename { input, .. } | LogicalPlan::Select { input, .. } => scanned(input),
+    _ => None,
+  }
+}
+
+/// The keys of a column of the table in `slot` that the conjuncts of the
+/// predicate limit it to, if any of them does.
+fn key_range(predicate: &IndexedFilter, slot: usize, column: usize) -> Option<KeyRange> {
+  let is_column = |e: &IndexedFilter| matches!(e, IndexedFilter::Id(id) if id.table == slot && id.field == column);
+  let (mut above, mut below): (Option<i64>, Option<i64>) = (None, None);
+  for term in predicate.conjuncts() {
+    if let IndexedFilter::BinaryOp(op, l, r) = term {
+      match (op, literal(l), literal(r)) {
+        (BinaryOp::Eq, None, Some(key)) if is_column(l) => return Some(KeyRange::Equal(key)),
+        (BinaryOp::Eq, Some(key), None) if is_column(r) => return Some(KeyRange::Equal(key)),
+        (BinaryOp::Lt, None, Some(TableCell::CellInt(n))) if is_column(l) => {
+          below = Some(below.map_or(n, |b| b.min(n)))
+        }
+        (BinaryOp::Lt, Some(TableCell::CellInt(n)), None) if is_column(r) => {
+          above = Some(above.map_or(n, |a| a.max(n)))
+        }
+        _ => (),
+      }
+    }
+  }
+  if above.is_none() && below.is_none() {
+    return None;
+  }
+  Some(KeyRange::Between { above, below })
+}
+
+/// The value of a constant, as an expression evaluates it.
+fn literal(e: &IndexedFilter) -> Option<TableCell> {
+  match e {
+    IndexedFilter::LitI(n) => Some(TableCell::CellInt(*n)),
+    IndexedFilter::LitB(b) => Some(TableCell::CellInt(*b as i64)),
+    IndexedFilter::LitS(s) => Some(TableCell::CellString(s.clone())),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ast::IndexKind;
+  use crate::csv::Dialect;
+  use crate::error::Result;
+  use crate::exec::{Config, JoinAlgorithm};
+  use crate::functions::FunctionRegistry;
+  use crate::index::tests::write_table;
+  use crate::index::{create, TableIndex};
+  use crate::parser::parse_sql;
+  use crate::stats::TableStats;
+  use crate::stream::{rows_of, Row, Rows};
+  use crate::tables::tests::TempDir;
+  use crate::tables::{TableCell, TableData};
+  use std::cell::Cell;
+  use std::fs::File;
+
+  fn catalog() -> (Vec<TableData>, Vec<String>) {
+    let table = |header: &[&str], n: i64| TableData {
+      header: header.iter().map(|h| h.to_string()).collect(),
+      rows: (0..n)
+        .map(|i| {
+          vec![
+            Some(TableCell::CellInt(i % 4)),
+            if i % 5 == 0 { None } else { Some(TableCell::CellString(format!("s{}", i % 3))) },
+            Some(TableCell::CellInt(i)),
+          ]
+        })
+        .collect(),
+    };
+    (
+      vec![table(&["a", "b", "n"], 12), table(&["c", "d", "m"], 9), table(&["e", "f", "k"], 7)],
+      vec!["r".to_owned(), "s".to_owned(), "t".to_owned()],
+    )
+  }
+
+  #[test]
+  fn pushed_down_plan() {
+    let (catalog, names) = catalog();
+    let q = parse_sql("SELECT n FROM r, s, t WHERE b == \"s1\" && a == c && TRUE && (d == f || k < 3) && 2 < m ORDER BY n")
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::new())
+      .unwrap();
+    assert_eq!(
+      format!("{}", q.logical_plan(&catalog).push_down_predicates()),
+      concat!(
+        "Project r.n\n",
+        "  Sort r.n\n",
+        "    Join ((s.d == t.f) || (t.k < 3))\n",
+        "      Join (r.a == s.c)\n",
+        "        Select ((r.b == \"s1\") && true)\n",
+        "          Scan r\n",
+        "        Select (2 < s.m)\n",
+        "          Scan s\n",
+        "      Scan t\n",
+      )
+    );
+  }
+
+  #[test]
+  fn same_results_as_naive_join() {
+    let (catalog, names) = catalog();
+    for sql in [
+      "SELECT * FROM r, s WHERE b == \"s1\"",
+      "SELECT * FROM r, s, t WHERE b == \"s1\" && a == c && (d == f || k < 3) && 2 < m",
+      "SELECT * FROM r, s, t WHERE a == e && n < 6 && (b == d || c == e)",
+      "SELECT * FROM r AS x, r AS y WHERE x.a == y.a && x.n < y.n && NOT (y.b == \"s2\")",
+      "SELECT n, k FROM r, t WHERE a == e || n == k",
+      "SELECT * FROM r, s WHERE FALSE",
+    ] {
+      let q = parse_sql(sql)
+        .unwrap()
+        .to_indexed(&catalog, &names, &FunctionRegistry::new())
+        .unwrap();
+      let tables: Vec<&TableData> = q.sources.iter().map(|i| &catalog[*i]).collect();
+      let mut naive = TableData::join_table(&|row| q.filter.valid_row(row), &tables, &q.selection).rows;
+      let mut pushed = q.run(&catalog).unwrap().rows;
+      naive.sort();
+      pushed.sort();
+      assert_eq!(pushed, naive, "{}", sql);
+    }
+  }
+
+  #[test]
+  fn contradictions_read_nothing() {
+    let (catalog, names) = catalog();
+    let q = parse_sql("SELECT count(*) FROM r, s WHERE a == c && (NOT NOT (n == 1) && TRUE) && n == 2")
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+      .unwrap();
+    let plan = q.logical_plan(&catalog).simplify_predicates();
+    assert_eq!(format!("{}", plan), "Aggregate count()\n  Empty\n");
+    let read = Cell::new(0);
+    let sources = q
+      .sources
+      .iter()
+      .map(|i| -> Rows {
+        Box::new(catalog[*i].rows.iter().map(|row| {
+          read.set(read.get() + 1);
+          Ok(row.clone())
+        }))
+      })
+      .collect();
+    let rows: Vec<Row> = q.stream(&catalog, sources).unwrap().collect::<Result<_>>().unwrap();
+    assert_eq!(rows, vec![vec![Some(TableCell::CellInt(0))]]);
+    assert_eq!(read.get(), 0);
+  }
+
+  #[test]
+  fn planner_reads_through_indexes() {
+    let dir = TempDir::new("planner");
+    let table = dir.join("t.csv");
+    write_table(&table, 2_000);
+    let dialect = Dialect::default();
+    create(&table, "by_key", "key", IndexKind::BTree, &dialect, None).unwrap();
+    create(&table, "by_n", "n", IndexKind::BTree, &dialect, None).unwrap();
+    let catalog = vec![TableData::of_file(&File::open(&table).unwrap()).unwrap()];
+    let names = vec!["t".to_owned()];
+    let mut stats = vec![TableStats::of(&catalog[0])];
+    stats[0].indexes = TableIndex::open_all(&table, &dialect, None).0;
+    for (sql, scan) in [
+      ("SELECT n FROM t WHERE key == 15838 && n < 1000", "Index Scan t USING by_key (key == 15838)"),
+      ("SELECT n, note FROM t WHERE 100 < n && n < 200 && note == \"x\"", "Index Scan t USING by_n (100 < n && n < 200)"),
+      ("SELECT count(*) FROM t AS a WHERE \"k3\" == a.key", "Index Scan t USING by_key (key == \"k3\")"),
+      ("SELECT a.n, b.n FROM t AS a, t AS b WHERE a.key == b.key && b.n < 10", "Index Scan t USING by_n (n < 10)"),
+      ("SELECT n FROM t WHERE key == 15838 || n < 5", "Scan t"),
+    ] {
+      let q = parse_sql(sql)
+        .unwrap()
+        .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+        .unwrap();
+      let config = Config::default();
+      let plan = format!("{}", q.plan(&catalog, &stats, &config));
+      assert!(plan.lines().any(|l| l.trim() == scan), "{}\n{}", sql, plan);
+      let sources = q.sources.iter().map(|i| rows_of(&catalog[*i])).collect();
+      let mut indexed: Vec<Row> = q.stream_with(&catalog, &stats, sources, &config).unwrap().collect::<Result<_>>().unwrap();
+      let mut scanned = q.run(&catalog).unwrap().rows;
+      indexed.sort();
+      scanned.sort();
+      assert!(!scanned.is_empty(), "{}", sql);
+      assert_eq!(indexed, scanned, "{}", sql);
+    }
+  }
+
+  #[test]
+  fn small_sides_join_through_indexes() {
+    let dir = TempDir::new("joins");
+    let table = dir.join("t.csv");
+    write_table(&table, 2_000);
+    let dialect = Dialect::default();
+    create(&table, "by_key", "key", IndexKind::BTree, &dialect, None).unwrap();
+    create(&table, "hash_key", "key", IndexKind::Hash, &dialect, None).unwrap();
+    let catalog = vec![TableData::of_file(&File::open(&table).unwrap()).unwrap()];
+    let names = vec!["t".to_owned()];
+    let mut stats = vec![TableStats::of(&catalog[0])];
+    stats[0].indexes = TableIndex::open_all(&table, &dialect, None).0;
+    let hash_join = Config {
+      join: Some(JoinAlgorithm::Hash),
+      ..Config::default()
+    };
+    for (sql, config, join) in [
+      ("SELECT a.n, b.n FROM t AS a, t AS b WHERE a.n == 2 && a.key == b.key", Config::default(), true),
+      ("SELECT a.n, b.note FROM t AS a, t AS b WHERE b.key == a.key && b.n == 3 && a.n < 1000", Config::default(), true),
+      ("SELECT count(*) FROM t AS a, t AS b WHERE a.n == 8 && a.key == b.key", Config::default(), true),
+      ("SELECT a.n, b.n FROM t AS a, t AS b WHERE a.n == 2 && a.key == b.key", hash_join, false),
+      ("SELECT a.n, b.n FROM t AS a, t AS b WHERE a.n < 1000 && a.key == b.key", Config::default(), false),
+      ("SELECT a.n, b.n FROM t AS a, t AS b WHERE a.n == 2 && a.n == b.n", Config::default(), false),
+    ] {
+      let q = parse_sql(sql)
+        .unwrap()
+        .to_indexed(&catalog, &names, &FunctionRegistry::with_builtins())
+        .unwrap();
+      let plan = format!("{}", q.plan(&catalog, &stats, &config));
+      let index_join = plan.lines().any(|l| l.trim().starts_with("Index Join") && l.ends_with("USING hash_key"));
+      assert_eq!(index_join, join, "{}\n{}", sql, plan);
+      let sources = q.sources.iter().map(|i| rows_of(&catalog[*i])).collect();
+      let mut joined: Vec<Row> = q.stream_with(&catalog, &stats, sources, &config).unwrap().collect::<Result<_>>().unwrap();
+      let mut scanned = q.run(&catalog).unwrap().rows;
+      joined.sort();
+      scanned.sort();
+      assert!(!scanned.is_empty(), "{}", sql);
+      assert_eq!(joined, scanned, "{}", sql);
+    }
+  }
+}
@@ -0,0 +1,217 @@
+//! Table schemas: the name, type and nullability of each column, read from
+//! a `.schema` file next to the data file and enforced when it is loaded.
+//!
+//! A schema file has a line per column, such as `age int not null`. The
+//! types are `int`, `string` and `any`, the last keeping the loader's
+//! per-cell inference. Blank lines and lines starting with `#` are ignored.
+
+use crate::csv::{self, Dialect, Field};
+use crate::error::{Error, Result};
+use crate::functions::CellType;
+use crate::tables::TableCell;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Column {
+  pub name: String,
+  pub ty: CellType,
+  pub nullable: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Schema {
+  pub columns: Vec<Column>,
+}
+
+impl Schema {
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut columns = vec![];
+    for (n, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let error = |message: String| Error::data_format(Some(n + 1), message);
+      let words: Vec<&str> = line.split_whitespace().collect();
+      let ty = match words.get(1).map(|w| w.to_ascii_lowercase()).as_deref() {
+        Some("int") | Some("integer") => CellType::Int,
+        Some("string") | Some("text") => CellType::String,
+        Some("any") => CellType::Any,
+        Some(t) => return Err(error(format!("unknown type {}, expected int, string or any", t))),
+        None => return Err(error(format!("column {} has no type", words[0]))),
+      };
+      let rest: Vec<String> = words[2..].iter().map(|w| w.to_ascii_lowercase()).collect();
+      let nullable = match rest.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] | ["null"] => true,
+        ["not", "null"] => false,
+        _ => return Err(error(format!("expected `null` or `not null` after the type, found `{}`", words[2..].join(" ")))),
+      };
+      if columns.iter().any(|c: &Column| c.name == words[0]) {
+        return Err(error(format!("column {} is listed twice", words[0])));
+      }
+      columns.push(Column {
+        name: words[0].to_owned(),
+        ty,
+        nullable,
+      });
+    }
+    Ok(Schema { columns })
+  }
+
+  /// The schema in the `.schema` file with the same name as `path`, if
+  /// there is one.
+  pub fn for_file(path: &Path) -> Result<Option<Self>> {
+    let schema_path = path.with_extension("schema");
+    match fs::read_to_string(&schema_path) {
+      Ok(text) => Schema::parse(&text).map(Some).map_err(|e| match e {
+        Error::DataFormat { line, message, .. } => Error::DataFormat {
+          table: Some(schema_path.display().to_string()),
+          line,
+          message,
+        },
+        e => e,
+      }),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(Error::io(schema_path, e)),
+    }
+  }
+
+  pub fn names(&self) -> Vec<String> {
+    self.columns.iter().map(|c| c.name.clone()).collect()
+  }
+
+  /// Checks that a file's header lists the schema's columns in order.
+  pub fn check_header(&self, header: &[String]) -> Result<()> {
+    if header != self.names().as_slice() {
+      return Err(Error::data_format(
+        Some(1),
+        format!(
+          "the header lists the columns {} but the schema lists {}",
+          header.join(", "),
+          self.names().join(", ")
+        ),
+      ));
+    }
+    Ok(())
+  }
+
+  /// The cell for `field` in column `i`, converted to the column's type.
+  /// A string column keeps the text of a number as written.
+  pub fn cell(&self, i: usize, field: Field, dialect: &Dialect) -> std::result::Result<Option<TableCell>, String> {
+    let column = &self.columns[i];
+    if dialect.is_null(&field) {
+      if !column.nullable {
+        return Err(format!("column {} may not be null", column.name));
+      }
+      return Ok(None);
+    }
+    match column.ty {
+      CellType::Any => Ok(TableCell::of_field(field)),
+      CellType::String => Ok(Some(TableCell::CellString(field.text))),
+      CellType::Int => match field.text.trim().parse::<i64>() {
+        Ok(n) => Ok(Some(TableCell::CellInt(n))),
+        Err(_) => Err(format!(
+          "column {} is an int but the value is `{}`",
+          column.name, field.text
+        )),
+      },
+    }
+  }
+
+  /// Proposes a schema for a file by reading all of it. A column is an int
+  /// if every value in it is an unquoted integer written without leading
+  /// zeros, and is nullable if any value is null.
+  pub fn infer<R: BufRead>(input: R, dialect: &Dialect) -> Result<Self> {
+    let mut reader = csv::Reader::with_dialect(input, dialect.clone());
+    let mut columns: Vec<Column> = vec![];
+    let mut first = true;
+    while let Some(record) = reader.next_record()? {
+      if first {
+        first = false;
+        columns = record
+          .fields
+          .iter()
+          .enumerate()
+          .map(|(i, f)| Column {
+            name: if dialect.has_header { f.text.clone() } else { format!("c{}", i + 1) },
+            ty: CellType::Int,
+            nullable: false,
+          })
+          .collect();
+        if dialect.has_header {
+          continue;
+        }
+      }
+      if record.fields.len() != columns.len() {
+        return Err(Error::data_format(
+          Some(record.line),
+          format!("expected {} fields but found {}", columns.len(), record.fields.len()),
+        ));
+      }
+      for (column, field) in columns.iter_mut().zip(&record.fields) {
+        if dialect.is_null(field) {
+          column.nullable = true;
+        } else if field.quoted || field.text.parse::<i64>().map_or(true, |n| n.to_string() != field.text) {
+          column.ty = CellType::String;
+        }
+      }
+    }
+    Ok(Schema { columns })
+  }
+}
+
+/// The schema in the format of a schema file.
+impl fmt::Display for Schema {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for c in &self.columns {
+      write!(f, "{} {}", c.name, c.ty)?;
+      if !c.nullable {
+        write!(f, " not null")?;
+      }
+      writeln!(f)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_and_print() {
+    let text = "# users\nname string not null\n\nage INT null\nid any\n";
+    let schema = Schema::parse(text).unwrap();
+    assert_eq!(
+      schema.columns[0],
+      Column {
+        name: "name".to_owned(),
+        ty: CellType::String,
+        nullable: false
+      }
+    );
+    assert_eq!(format!("{}", schema), "name string not null\nage int\nid any\n");
+    assert_eq!(Schema::parse(&format!("{}", schema)).unwrap(), schema);
+
+    let line = |text: &str| match Schema::parse(text) {
+      Err(Error::DataFormat { line, .. }) => line,
+      r => panic!("expected a data error, got {:?}", r),
+    };
+    assert_eq!(line("a int\nb float"), Some(2));
+    assert_eq!(line("a int\n\nb int maybe"), Some(3));
+    assert_eq!(line("a int\na string"), Some(2));
+  }
+
+  #[test]
+  fn infer() {
+    let input = "name, zip, age\n\"Lucas\", 02134, 24\n\"Harry\", \"19104\",\n";
+    let schema = Schema::infer(input.as_bytes(), &Dialect::default()).unwrap();
+    assert_eq!(
+      format!("{}", schema),
+      "name string not null\nzip string not null\nage int\n"
+    );
+  }
+}
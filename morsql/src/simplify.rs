@@ -0,0 +1,329 @@
+//! Simplification of bound expressions: folding constants, removing double
+//! negations, pushing negations through `&&` and `||`, and finding
+//! conjunctions that no row can satisfy.
+//!
+//! Expressions are strict in nulls: any operator with a null operand is
+//! null, so `TRUE || x` is null where `x` is, and `NOT` of a string is null
+//! too. A rewrite is only made where it cannot change the result on any row,
+//! which usually needs the operands known to be non-null or integers.
+//! `simplify` keeps the value of an expression; `simplify_predicate` keeps
+//! only whether a row satisfies it, where null and false are alike, which
+//! allows more. Functions are assumed to give the same result for the same
+//! arguments, so calls with constant arguments are folded.
+
+use crate::ast::{BinaryOp, IndexedFilter, RegexOp, UnaryOp};
+use crate::tables::TableCell;
+
+impl IndexedFilter {
+  /// An expression with the same value as this one on every row.
+  pub fn simplify(&self) -> IndexedFilter {
+    let e = match self {
+      IndexedFilter::UnaryOp(op, e) => IndexedFilter::UnaryOp(*op, Box::new(e.simplify())),
+      IndexedFilter::BinaryOp(op, l, r) => IndexedFilter::BinaryOp(*op, Box::new(l.simplify()), Box::new(r.simplify())),
+      IndexedFilter::Call(f, args) => IndexedFilter::Call(f.clone(), args.iter().map(IndexedFilter::simplify).collect()),
+      IndexedFilter::Regex(op, pattern, args) => {
+        IndexedFilter::Regex(*op, pattern.clone(), args.iter().map(IndexedFilter::simplify).collect())
+      }
+      e => return e.clone(),
+    };
+    if e.is_constant() {
+      return e.fold();
+    }
+    match e {
+      IndexedFilter::UnaryOp(UnaryOp::Not, e) => match *e {
+        IndexedFilter::UnaryOp(UnaryOp::Not, e) if e.is_boolean() => *e,
+        // De Morgan's laws, which hold for integer operands.
+        IndexedFilter::BinaryOp(op @ (BinaryOp::And | BinaryOp::Or), l, r) if l.is_int() && r.is_int() => {
+          let dual = if op == BinaryOp::And { BinaryOp::Or } else { BinaryOp::And };
+          IndexedFilter::BinaryOp(dual, Box::new(not(*l)), Box::new(not(*r))).simplify()
+        }
+        e => not(e),
+      },
+      IndexedFilter::BinaryOp(op @ (BinaryOp::And | BinaryOp::Or), l, r) => {
+        let (l, r) = (*l, *r);
+        match (op, l.truth(), r.truth()) {
+          // The constant decides the result, unless the other side is null.
+          (BinaryOp::And, Some(false), _) if r.never_null() => IndexedFilter::LitB(false),
+          (BinaryOp::And, _, Some(false)) if l.never_null() => IndexedFilter::LitB(false),
+          (BinaryOp::Or, Some(true), _) if r.never_null() => IndexedFilter::LitB(true),
+          (BinaryOp::Or, _, Some(true)) if l.never_null() => IndexedFilter::LitB(true),
+          // The constant leaves the result to the other side.
+          (BinaryOp::And, Some(true), _) | (BinaryOp::Or, Some(false), _) if r.is_boolean() => r,
+          (BinaryOp::And, _, Some(true)) | (BinaryOp::Or, _, Some(false)) if l.is_boolean() => l,
+          _ => IndexedFilter::BinaryOp(op, Box::new(l), Box::new(r)),
+        }
+      }
+      e => e,
+    }
+  }
+
+  /// An expression satisfied by exactly the rows that satisfy this one:
+  /// `LitB(true)` if every row does and `LitB(false)` if none can.
+  pub fn simplify_predicate(&self) -> IndexedFilter {
+    self.simplify().predicate()
+  }
+
+  /// The predicate rewrites of an already simplified expression.
+  fn predicate(self) -> IndexedFilter {
+    if self.is_constant() {
+      return IndexedFilter::LitB(self.valid_row(&vec![]));
+    }
+    match self {
+      e @ IndexedFilter::BinaryOp(BinaryOp::And, _, _) => {
+        let terms: Vec<IndexedFilter> = e.conjuncts().into_iter().map(|t| t.clone().predicate()).collect();
+        conjoin(terms)
+      }
+      IndexedFilter::UnaryOp(UnaryOp::Not, e) => match *e {
+        IndexedFilter::UnaryOp(UnaryOp::Not, e) => e.predicate(),
+        e => not(e),
+      },
+      IndexedFilter::BinaryOp(BinaryOp::Or, l, r) => match (l.truth(), r.truth()) {
+        (Some(false), _) => r.predicate(),
+        (_, Some(false)) => l.predicate(),
+        _ => IndexedFilter::BinaryOp(BinaryOp::Or, l, r),
+      },
+      // Only integers can be less than anything.
+      IndexedFilter::BinaryOp(BinaryOp::Lt, l, r)
+        if matches!(*l, IndexedFilter::LitS(_)) || matches!(*r, IndexedFilter::LitS(_)) =>
+      {
+        IndexedFilter::LitB(false)
+      }
+      e => e,
+    }
+  }
+
+  /// Whether the expression reads nothing from the row.
+  fn is_constant(&self) -> bool {
+    match self {
+      IndexedFilter::Id(_) | IndexedFilter::Param(_) => false,
+      IndexedFilter::LitS(_) | IndexedFilter::LitB(_) | IndexedFilter::LitI(_) => true,
+      IndexedFilter::UnaryOp(_, e) => e.is_constant(),
+      IndexedFilter::BinaryOp(_, l, r) => l.is_constant() && r.is_constant(),
+      IndexedFilter::Call(_, args) | IndexedFilter::Regex(_, _, args) => args.iter().all(IndexedFilter::is_constant),
+    }
+  }
+
+  /// The constant expression as a literal, or as it is if it is null, which
+  /// no literal can express.
+  fn fold(self) -> IndexedFilter {
+    match self.compute_with_row(&vec![]) {
+      Some(TableCell::CellInt(n)) if self.is_boolean() => IndexedFilter::LitB(n != 0),
+      Some(TableCell::CellInt(n)) => IndexedFilter::LitI(n),
+      Some(TableCell::CellString(s)) => IndexedFilter::LitS(s),
+      None => self,
+    }
+  }
+
+  /// Whether a literal satisfies a predicate, or `None` for expressions
+  /// that are not literals.
+  fn truth(&self) -> Option<bool> {
+    match self {
+      IndexedFilter::LitB(b) => Some(*b),
+      IndexedFilter::LitI(n) => Some(*n != 0),
+      IndexedFilter::LitS(_) => Some(false),
+      _ => None,
+    }
+  }
+
+  /// Whether the expression is never null.
+  fn never_null(&self) -> bool {
+    match self {
+      IndexedFilter::LitS(_) | IndexedFilter::LitB(_) | IndexedFilter::LitI(_) => true,
+      IndexedFilter::UnaryOp(UnaryOp::Not, e) => e.never_null() && e.is_int(),
+      IndexedFilter::BinaryOp(_, l, r) => l.never_null() && r.never_null(),
+      _ => false,
+    }
+  }
+
+  /// Whether the expression is never a string.
+  fn is_int(&self) -> bool {
+    match self {
+      IndexedFilter::LitB(_) | IndexedFilter::LitI(_) | IndexedFilter::UnaryOp(..) | IndexedFilter::BinaryOp(..) => true,
+      IndexedFilter::Regex(op, _, _) => *op == RegexOp::Matches,
+      _ => false,
+    }
+  }
+
+  /// Whether the expression is always 0, 1 or null.
+  fn is_boolean(&self) -> bool {
+    match self {
+      IndexedFilter::LitI(n) => *n == 0 || *n == 1,
+      e => e.is_int() && !matches!(e, IndexedFilter::LitI(_)),
+    }
+  }
+}
+
+fn not(e: IndexedFilter) -> IndexedFilter {
+  IndexedFilter::UnaryOp(UnaryOp::Not, Box::new(e))
+}
+
+/// The conjunction of simplified predicates, or `LitB(false)` if the terms
+/// contradict each other.
+fn conjoin(terms: Vec<IndexedFilter>) -> IndexedFilter {
+  let mut rest = vec![];
+  for term in terms {
+    match term {
+      IndexedFilter::LitB(true) => (),
+      IndexedFilter::LitB(false) => return IndexedFilter::LitB(false),
+      // Flatten the conjunctions simplifying the terms produced.
+      IndexedFilter::BinaryOp(BinaryOp::And, _, _) => rest.extend(term.conjuncts().into_iter().cloned()),
+      term => rest.push(term),
+    }
+  }
+  if contradictory(&rest) {
+    return IndexedFilter::LitB(false);
+  }
+  IndexedFilter::conjunction(rest).unwrap_or(IndexedFilter::LitB(true))
+}
+
+/// The values an expression can take to satisfy a conjunction, from the
+/// terms comparing it with a literal.
+#[derive(Default)]
+struct Range {
+  equal: Vec<TableCell>,
+  /// Exclusive integer bounds.
+  above: Option<i64>,
+  below: Option<i64>,
+}
+
+/// Whether no row can satisfy all of the terms: one is the negation of
+/// another, or an expression is compared with literals that no single value
+/// matches, as in `x == 1 && x == 2` or `x < 3 && 5 < x`.
+fn contradictory(terms: &[IndexedFilter]) -> bool {
+  // A term and its negation need a value both non-zero and zero.
+  let negated = terms.iter().any(|t| match t {
+    IndexedFilter::UnaryOp(UnaryOp::Not, e) => terms.contains(e),
+    _ => false,
+  });
+  if negated {
+    return true;
+  }
+  let mut ranges: Vec<(&IndexedFilter, Range)> = vec![];
+  for term in terms {
+    let (op, l, r) = match term {
+      IndexedFilter::BinaryOp(op @ (BinaryOp::Eq | BinaryOp::Lt), l, r) => (*op, &**l, &**r),
+      _ => continue,
+    };
+    let (e, value, e_on_left) = match (literal(l), literal(r)) {
+      (None, Some(v)) => (l, v, true),
+      (Some(v), None) => (r, v, false),
+      _ => continue,
+    };
+    let range = match ranges.iter().position(|(x, _)| *x == e) {
+      Some(i) => &mut ranges[i].1,
+      None => {
+        ranges.push((e, Range::default()));
+        &mut ranges.last_mut().unwrap().1
+      }
+    };
+    match (op, value) {
+      (BinaryOp::Eq, v) => range.equal.push(v),
+      (_, TableCell::CellInt(n)) if e_on_left => range.below = Some(range.below.map_or(n, |b| b.min(n))),
+      (_, TableCell::CellInt(n)) => range.above = Some(range.above.map_or(n, |a| a.max(n))),
+      (_, TableCell::CellString(_)) => return true,
+    }
+  }
+  ranges.iter().any(|(_, r)| r.is_empty())
+}
+
+fn literal(e: &IndexedFilter) -> Option<TableCell> {
+  match e {
+    IndexedFilter::LitI(n) => Some(TableCell::CellInt(*n)),
+    IndexedFilter::LitB(b) => Some(TableCell::CellInt(*b as i64)),
+    IndexedFilter::LitS(s) => Some(TableCell::CellString(s.clone())),
+    _ => None,
+  }
+}
+
+impl Range {
+  fn is_empty(&self) -> bool {
+    let (above, below) = (self.above.map(i128::from), self.below.map(i128::from));
+    let outside = |v: &TableCell| match v {
+      TableCell::CellInt(n) => above.is_some_and(|a| i128::from(*n) <= a) || below.is_some_and(|b| i128::from(*n) >= b),
+      // Strings are neither less nor greater than anything.
+      TableCell::CellString(_) => above.is_some() || below.is_some(),
+    };
+    self.equal.windows(2).any(|w| w[0] != w[1])
+      || self.equal.iter().any(outside)
+      || matches!((above, below), (Some(a), Some(b)) if b - a < 2)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ast::generators::{bind, TestRow};
+  use crate::ast::Filter;
+  use crate::functions::FunctionRegistry;
+  use crate::parser::parse_sql;
+  use crate::tables::TableData;
+
+  #[quickcheck]
+  fn prop_simplify_keeps_values(f: Filter, rows: Vec<TestRow>) -> bool {
+    let e = bind(&f, &FunctionRegistry::with_builtins());
+    let simple = e.simplify();
+    rows.iter().all(|row| {
+      let row = vec![row.0.clone()];
+      simple.compute_with_row(&row) == e.compute_with_row(&row)
+    })
+  }
+
+  #[quickcheck]
+  fn prop_simplify_predicate_keeps_rows(f: Filter, g: Filter, rows: Vec<TestRow>) -> bool {
+    let functions = FunctionRegistry::with_builtins();
+    // Conjunctions give the contradictions something to find.
+    let e = IndexedFilter::BinaryOp(BinaryOp::And, Box::new(bind(&f, &functions)), Box::new(bind(&g, &functions)));
+    let simple = e.simplify_predicate();
+    rows.iter().all(|row| {
+      let row = vec![row.0.clone()];
+      simple.valid_row(&row) == e.valid_row(&row)
+    })
+  }
+
+  fn simplified(filter: &str) -> String {
+    let catalog = vec![TableData {
+      header: vec!["x".to_owned(), "y".to_owned(), "s".to_owned()],
+      rows: vec![],
+    }];
+    let mut q = parse_sql(&format!("SELECT * FROM t WHERE {}", filter))
+      .unwrap()
+      .to_indexed(&catalog, &["t".to_owned()], &FunctionRegistry::with_builtins())
+      .unwrap();
+    q.filter = q.filter.simplify_predicate();
+    let plan = format!("{}", q.logical_plan(&catalog));
+    let select = plan.lines().find_map(|l| l.trim().strip_prefix("Select "));
+    select.unwrap_or("true").to_owned()
+  }
+
+  #[test]
+  fn simplifies() {
+    assert_eq!(simplified("TRUE && x == 1 || FALSE"), "(t.x == 1)");
+    assert_eq!(simplified("NOT NOT (x == 1)"), "(t.x == 1)");
+    assert_eq!(simplified("NOT NOT x"), "t.x");
+    assert_eq!(simplified("NOT (x == 1 && NOT (y < 2))"), "((NOT (t.x == 1)) || (t.y < 2))");
+    assert_eq!(simplified("NOT (x == 1 || s == \"a\")"), "((NOT (t.x == 1)) && (NOT (t.s == \"a\")))");
+    assert_eq!(simplified("abs(0) == 0 && length(\"ab\") == 2 && x == 1"), "(t.x == 1)");
+    // Nulls keep these: with x null, the first is never satisfied.
+    assert_eq!(simplified("TRUE || x"), "(true || t.x)");
+    assert_eq!(simplified("NOT (x && y)"), "(NOT (t.x && t.y))");
+  }
+
+  #[test]
+  fn finds_contradictions() {
+    for filter in [
+      "x == 1 && x == 2",
+      "x == 1 && y == 3 && 1 == x && x == \"1\"",
+      "x < 3 && 5 < x",
+      "2 < x && x < 3",
+      "x == 7 && x < 3",
+      "s == \"a\" && 0 < s",
+      "x == 1 && NOT (x == 1)",
+      "FALSE && x",
+      "y == 1 && (x == 1 && (3 < y || FALSE))",
+    ] {
+      assert_eq!(simplified(filter), "false", "{}", filter);
+    }
+    assert_eq!(simplified("2 < x && x < 4"), "((2 < t.x) && (t.x < 4))");
+    assert_eq!(simplified("x == 1 || x == 2"), "((t.x == 1) || (t.x == 2))");
+  }
+}
@@ -0,0 +1,413 @@
+//! External merge sort. Tuples are buffered until they exceed a memory
+//! budget, when the buffer is sorted and written to a temporary file as a
+//! run. The output merges the runs with whatever is still buffered. The
+//! sort is stable: tuples with equal keys come out in the order they were
+//! pushed.
+//!
+//! A run holds at least [`MIN_RUN_TUPLES`] tuples, however small the
+//! budget, and at most [`MERGE_FAN_IN`] sources are merged at once, so the
+//! files open stay bounded. With more runs than that, groups of them are
+//! first merged into longer runs.
+
+use crate::ast::IndexedOrderKey;
+use crate::columnar::{put_cell, put_varint, Bytes};
+use crate::error::{Error, Result};
+use crate::exec::Tuple;
+use crate::stream::Row;
+use crate::tables::TableCell;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::vec;
+
+/// The fewest tuples written to a run, so that a tiny budget does not make
+/// a run of each tuple.
+pub const MIN_RUN_TUPLES: usize = 1024;
+
+/// The most sources merged at once.
+pub const MERGE_FAN_IN: usize = 64;
+
+/// Compares sort keys, each ascending with nulls first unless descending.
+pub fn compare(descending: &[bool], a: &Row, b: &Row) -> Ordering {
+  descending
+    .iter()
+    .zip(a.iter().zip(b))
+    .map(|(desc, (x, y))| if *desc { y.cmp(x) } else { x.cmp(y) })
+    .find(|o| o.is_ne())
+    .unwrap_or(Ordering::Equal)
+}
+
+/// An estimate of the memory a tuple takes.
+pub fn size_of(t: &Tuple) -> usize {
+  let cells: usize = t
+    .iter()
+    .flatten()
+    .map(|c| match c {
+      Some(TableCell::CellString(s)) => s.len(),
+      _ => 0,
+    })
+    .sum();
+  mem::size_of::<Tuple>()
+    + t.len() * mem::size_of::<Row>()
+    + t.iter().map(Vec::len).sum::<usize>() * mem::size_of::<Option<TableCell>>()
+    + cells
+}
+
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// A sorted run in a temporary file, removed when dropped.
+struct Run {
+  path: PathBuf,
+}
+
+impl Run {
+  fn write<I: IntoIterator<Item = Result<(Row, Tuple)>>>(tuples: I) -> Result<Run> {
+    let path = std::env::temp_dir().join(format!(
+      "morsql-{}-{}.run",
+      process::id(),
+      RUNS.fetch_add(1, AtomicOrdering::Relaxed)
+    ));
+    let run = Run { path };
+    let mut out = BufWriter::new(File::create(&run.path).map_err(|e| Error::io(&run.path, e))?);
+    let mut buf = vec![];
+    for sorted in tuples {
+      let (key, tuple) = sorted?;
+      buf.clear();
+      put_row(&mut buf, &key);
+      put_varint(&mut buf, tuple.len() as u64);
+      for row in &tuple {
+        put_row(&mut buf, row);
+      }
+      out.write_all(&(buf.len() as u32).to_le_bytes())?;
+      out.write_all(&buf)?;
+    }
+    out.flush()?;
+    Ok(run)
+  }
+
+  fn read(&self) -> Result<RunReader> {
+    let file = File::open(&self.path).map_err(|e| Error::io(&self.path, e))?;
+    Ok(RunReader {
+      input: BufReader::new(file),
+      buf: vec![],
+    })
+  }
+}
+
+impl Drop for Run {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.path);
+  }
+}
+
+fn put_row(out: &mut Vec<u8>, row: &Row) {
+  put_varint(out, row.len() as u64);
+  for cell in row {
+    match cell {
+      None => out.push(0),
+      Some(cell) => {
+        out.push(1);
+        put_cell(out, cell);
+      }
+    }
+  }
+}
+
+fn read_row(bytes: &mut Bytes) -> Result<Row> {
+  let len = bytes.usize()?;
+  (0..len)
+    .map(|_| match bytes.byte()? {
+      0 => Ok(None),
+      _ => bytes.cell().map(Some),
+    })
+    .collect()
+}
+
+struct RunReader {
+  input: BufReader<File>,
+  buf: Vec<u8>,
+}
+
+impl RunReader {
+  fn next(&mut self) -> Result<Option<(Row, Tuple)>> {
+    let mut len = [0; 4];
+    match self.input.read_exact(&mut len) {
+      Ok(()) => (),
+      Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+      Err(e) => return Err(e.into()),
+    }
+    self.buf.resize(u32::from_le_bytes(len) as usize, 0);
+    self.input.read_exact(&mut self.buf)?;
+    let mut bytes = Bytes::new(&self.buf);
+    let key = read_row(&mut bytes)?;
+    let slots = bytes.usize()?;
+    let tuple = (0..slots).map(|_| read_row(&mut bytes)).collect::<Result<_>>()?;
+    Ok(Some((key, tuple)))
+  }
+}
+
+/// Sorts tuples by keys, spilling to disk once the tuples held take more
+/// than `budget` bytes.
+pub struct Sorter {
+  keys: Vec<IndexedOrderKey>,
+  descending: Arc<[bool]>,
+  budget: usize,
+  fan_in: usize,
+  buffer: Vec<(Row, Tuple)>,
+  buffered: usize,
+  peak: usize,
+  runs: Vec<Run>,
+}
+
+impl Sorter {
+  pub fn new(keys: Vec<IndexedOrderKey>, budget: usize) -> Self {
+    Sorter {
+      descending: keys.iter().map(|k| k.descending).collect(),
+      keys,
+      budget,
+      fan_in: MERGE_FAN_IN,
+      buffer: vec![],
+      buffered: 0,
+      peak: 0,
+      runs: vec![],
+    }
+  }
+
+  /// Merges at most `fan_in` sources at once, instead of [`MERGE_FAN_IN`].
+  pub fn with_fan_in(mut self, fan_in: usize) -> Self {
+    self.fan_in = fan_in.max(2);
+    self
+  }
+
+  pub fn push(&mut self, tuple: Tuple) -> Result<()> {
+    let key = self.keys.iter().map(|k| k.expr.compute_with_row(&tuple)).collect();
+    self.push_keyed(key, tuple)
+  }
+
+  /// Adds a tuple whose key has already been computed.
+  pub fn push_keyed(&mut self, key: Row, tuple: Tuple) -> Result<()> {
+    self.buffered += size_of(&tuple) + key.len() * mem::size_of::<Option<TableCell>>();
+    self.buffer.push((key, tuple));
+    self.peak = self.peak.max(self.buffered);
+    if self.buffered > self.budget && self.buffer.len() >= MIN_RUN_TUPLES {
+      self.spill()?;
+    }
+    Ok(())
+  }
+
+  fn spill(&mut self) -> Result<()> {
+    self.sort_buffer();
+    self.runs.push(Run::write(self.buffer.drain(..).map(Ok))?);
+    self.buffered = 0;
+    Ok(())
+  }
+
+  fn sort_buffer(&mut self) {
+    let descending = &self.descending;
+    self.buffer.sort_by(|(a, _), (b, _)| compare(descending, a, b));
+  }
+
+  /// The number of runs written to disk so far.
+  pub fn runs(&self) -> usize {
+    self.runs.len()
+  }
+
+  /// The most bytes of tuples held in memory at once so far.
+  pub fn peak_memory(&self) -> usize {
+    self.peak
+  }
+
+  /// The tuples in order, with their keys.
+  pub fn finish(mut self) -> Result<Sorted> {
+    self.sort_buffer();
+    // The buffer is merged with the runs, so there is room for one fewer.
+    while self.runs.len() >= self.fan_in {
+      self.merge_runs()?;
+    }
+    let mut sources = self
+      .runs
+      .iter()
+      .map(|run| run.read().map(Source::Run))
+      .collect::<Result<Vec<_>>>()?;
+    sources.push(Source::Memory(mem::take(&mut self.buffer).into_iter()));
+    Ok(Sorted {
+      merge: Merge::new(self.descending, sources)?,
+      _runs: self.runs,
+    })
+  }
+
+  /// Merges each group of `fan_in` consecutive runs into one, which keeps
+  /// the merge stable.
+  fn merge_runs(&mut self) -> Result<()> {
+    let mut merged = vec![];
+    let mut runs = mem::take(&mut self.runs).into_iter().peekable();
+    while runs.peek().is_some() {
+      let group: Vec<Run> = runs.by_ref().take(self.fan_in).collect();
+      if group.len() == 1 {
+        merged.extend(group);
+        continue;
+      }
+      let sources = group.iter().map(|run| run.read().map(Source::Run)).collect::<Result<_>>()?;
+      let mut merge = Merge::new(self.descending.clone(), sources)?;
+      merged.push(Run::write(std::iter::from_fn(|| merge.next().transpose()))?);
+    }
+    self.runs = merged;
+    Ok(())
+  }
+}
+
+enum Source {
+  Memory(vec::IntoIter<(Row, Tuple)>),
+  Run(RunReader),
+}
+
+impl Source {
+  fn next(&mut self) -> Result<Option<(Row, Tuple)>> {
+    match self {
+      Source::Memory(tuples) => Ok(tuples.next()),
+      Source::Run(reader) => reader.next(),
+    }
+  }
+}
+
+/// The next tuple of a source being merged.
+struct Head {
+  key: Row,
+  tuple: Tuple,
+  source: usize,
+  descending: Arc<[bool]>,
+}
+
+/// Heads are ordered so that the greatest is the least key, and of equal
+/// keys, the one from the earliest source, as `BinaryHeap` pops the
+/// greatest.
+impl Ord for Head {
+  fn cmp(&self, other: &Self) -> Ordering {
+    compare(&self.descending, &other.key, &self.key).then(other.source.cmp(&self.source))
+  }
+}
+
+impl PartialOrd for Head {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl PartialEq for Head {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other).is_eq()
+  }
+}
+
+impl Eq for Head {}
+
+/// Merges sorted sources a tuple at a time.
+struct Merge {
+  descending: Arc<[bool]>,
+  sources: Vec<Source>,
+  heads: BinaryHeap<Head>,
+}
+
+impl Merge {
+  fn new(descending: Arc<[bool]>, sources: Vec<Source>) -> Result<Self> {
+    let mut merge = Merge {
+      descending,
+      heads: BinaryHeap::with_capacity(sources.len()),
+      sources,
+    };
+    for source in 0..merge.sources.len() {
+      merge.refill(source)?;
+    }
+    Ok(merge)
+  }
+
+  fn refill(&mut self, source: usize) -> Result<()> {
+    if let Some((key, tuple)) = self.sources[source].next()? {
+      self.heads.push(Head {
+        key,
+        tuple,
+        source,
+        descending: self.descending.clone(),
+      });
+    }
+    Ok(())
+  }
+
+  fn next(&mut self) -> Result<Option<(Row, Tuple)>> {
+    match self.heads.pop() {
+      None => Ok(None),
+      Some(head) => {
+        self.refill(head.source)?;
+        Ok(Some((head.key, head.tuple)))
+      }
+    }
+  }
+}
+
+/// The output of a `Sorter`: the keys and tuples in order, merged from its
+/// runs a tuple at a time.
+pub struct Sorted {
+  merge: Merge,
+  _runs: Vec<Run>,
+}
+
+impl Iterator for Sorted {
+  type Item = Result<(Row, Tuple)>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.merge.next().transpose()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ast::{IndexedColumnSelector, IndexedFilter};
+  use crate::tables::TableData;
+
+  #[test]
+  fn spills_and_merges() {
+    let mut tables = vec![];
+    for file in ["examples/user.csv", "examples/user1.csv", "examples/quoted.csv"] {
+      tables.push(TableData::of_file(&File::open(file).unwrap()).unwrap());
+    }
+    let tuples: Vec<Tuple> = tables
+      .iter()
+      .flat_map(|t| t.rows.iter().cycle().take(2_000))
+      .map(|row| vec![row.clone()])
+      .collect();
+    let key = |field, descending| IndexedOrderKey {
+      expr: IndexedFilter::Id(IndexedColumnSelector { table: 0, field }),
+      descending,
+    };
+    let sort = |budget, fan_in| {
+      let mut sorter = Sorter::new(vec![key(1, false), key(0, true)], budget).with_fan_in(fan_in);
+      for t in &tuples {
+        sorter.push(t.clone()).unwrap();
+      }
+      let runs = sorter.runs();
+      let sorted: Vec<Tuple> = sorter.finish().unwrap().map(|r| r.unwrap().1).collect();
+      (runs, sorted)
+    };
+    let (runs, in_memory) = sort(usize::MAX, MERGE_FAN_IN);
+    assert_eq!(runs, 0);
+    let mut expected = tuples.clone();
+    expected.sort_by(|a, b| a[0][1].cmp(&b[0][1]).then(b[0][0].cmp(&a[0][0])));
+    assert_eq!(in_memory, expected);
+    // However small the budget, runs hold enough tuples to keep their
+    // number down, and more runs than can be merged at once are merged in
+    // passes.
+    for (budget, fan_in) in [(1, MERGE_FAN_IN), (1, 2), (1, 3), (200, 4), (100_000, 2)] {
+      let (runs, spilled) = sort(budget, fan_in);
+      assert!(runs > 1 && runs <= tuples.len() / MIN_RUN_TUPLES, "{}", runs);
+      assert_eq!(spilled, expected, "{} {}", budget, fan_in);
+    }
+    assert!(expected.iter().any(|t| t[0].iter().any(|c| matches!(c, Some(TableCell::CellString(s)) if s.contains('\n')))));
+  }
+}
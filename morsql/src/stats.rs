@@ -0,0 +1,279 @@
+//! Table statistics for the planner: the number of rows of a table and an
+//! estimate of the number of distinct values in each column.
+//!
+//! Distinct values are counted with a k-minimum-values sketch, which keeps
+//! the `SKETCH_SIZE` smallest hashes seen. The count is exact for columns
+//! with fewer distinct values than that, and within a few percent above, in
+//! constant memory however large the table is.
+//!
+//! The statistics of a table file can be cached beside it, as `user.stats`
+//! for `user.csv`, so that it is only read for them again once it changes;
+//! the CLI does so when given `--cache-stats`. The file starts with the
+//! magic bytes `MORSTA01`, then holds the size and modification time the
+//! table had, how its rows were read (see
+//! [`format_key`](crate::index::format_key)), its number of rows and, for
+//! each column, its distinct values and nulls, all as varints but for the
+//! format, a string.
+
+use crate::columnar::{put_str, put_varint, Bytes};
+use crate::error::{Error, Result};
+use crate::index::{version, TableIndex};
+use crate::stream::{Row, Rows};
+use crate::tables::{TableCell, TableData};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const SKETCH_SIZE: usize = 1024;
+
+const MAGIC: &[u8; 8] = b"MORSTA01";
+
+/// The file caching the statistics of the table at `table`.
+pub fn stats_path(table: &Path) -> PathBuf {
+  let stem = table.file_stem().unwrap_or_default().to_string_lossy();
+  table.with_file_name(format!("{}.stats", stem))
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ColumnStats {
+  pub distinct: u64,
+  pub nulls: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TableStats {
+  pub rows: u64,
+  pub columns: Vec<ColumnStats>,
+  /// The indexes of the table that are up to date with it, which the
+  /// planner may read its rows through.
+  pub indexes: Vec<Arc<TableIndex>>,
+}
+
+impl TableStats {
+  pub fn of(table: &TableData) -> Self {
+    let mut collector = StatsCollector::new(table.header.len());
+    for row in &table.rows {
+      collector.add(row);
+    }
+    collector.finish()
+  }
+
+  /// The statistics of a stream of rows with `width` columns, read to the
+  /// end.
+  pub fn collect<I: Iterator<Item = Result<Row>>>(width: usize, rows: I) -> Result<Self> {
+    let mut collector = StatsCollector::new(width);
+    for row in rows {
+      collector.add(&row?);
+    }
+    Ok(collector.finish())
+  }
+
+  /// The statistics of the table at `table`, whose rows are read in the
+  /// format `format`, from the file caching them if neither has changed
+  /// since it was written. Otherwise they are collected from the width and
+  /// rows `open` gives, and cached.
+  pub fn cached<F>(table: &Path, format: &str, open: F) -> Result<Self>
+  where
+    F: FnOnce() -> Result<(usize, Rows<'static>)>,
+  {
+    let version = version(table)?;
+    let path = stats_path(table);
+    // An unreadable or damaged cache is only a cache miss.
+    let cached = fs::read(&path).ok().and_then(|buf| read_cache(&buf, version, format).ok().flatten());
+    if let Some(stats) = cached {
+      return Ok(stats);
+    }
+    let (width, rows) = open()?;
+    let stats = TableStats::collect(width, rows)?;
+    fs::write(&path, write_cache(&stats, version, format)).map_err(|e| Error::io(&path, e))?;
+    Ok(stats)
+  }
+}
+
+fn write_cache(stats: &TableStats, version: (u64, u64), format: &str) -> Vec<u8> {
+  let mut out = MAGIC.to_vec();
+  put_varint(&mut out, version.0);
+  put_varint(&mut out, version.1);
+  put_str(&mut out, format);
+  put_varint(&mut out, stats.rows);
+  put_varint(&mut out, stats.columns.len() as u64);
+  for column in &stats.columns {
+    put_varint(&mut out, column.distinct);
+    put_varint(&mut out, column.nulls);
+  }
+  out
+}
+
+/// The statistics cached in `buf`, or `None` if they are of another
+/// version or format of the table.
+fn read_cache(buf: &[u8], version: (u64, u64), format: &str) -> Result<Option<TableStats>> {
+  if buf.get(..MAGIC.len()) != Some(&MAGIC[..]) {
+    return Ok(None);
+  }
+  let mut bytes = Bytes::new(&buf[MAGIC.len()..]);
+  if (bytes.varint()?, bytes.varint()?) != version || bytes.str()? != format {
+    return Ok(None);
+  }
+  let rows = bytes.varint()?;
+  let columns = (0..bytes.varint()?)
+    .map(|_| {
+      Ok(ColumnStats {
+        distinct: bytes.varint()?,
+        nulls: bytes.varint()?,
+      })
+    })
+    .collect::<Result<_>>()?;
+  Ok(Some(TableStats {
+    rows,
+    columns,
+    indexes: vec![],
+  }))
+}
+
+/// The hashes of the distinct values of a column with the smallest hashes.
+struct Sketch {
+  smallest: BTreeSet<u64>,
+}
+
+impl Sketch {
+  fn add(&mut self, cell: &TableCell) {
+    let mut hasher = DefaultHasher::new();
+    cell.hash(&mut hasher);
+    let hash = hasher.finish();
+    if self.smallest.len() < SKETCH_SIZE {
+      self.smallest.insert(hash);
+    } else if hash < *self.smallest.iter().next_back().unwrap() && self.smallest.insert(hash) {
+      self.smallest.pop_last();
+    }
+  }
+
+  fn estimate(&self) -> u64 {
+    if self.smallest.len() < SKETCH_SIZE {
+      return self.smallest.len() as u64;
+    }
+    // The k-th smallest of n uniform hashes is near k/n of the range.
+    let kth = *self.smallest.iter().next_back().unwrap() as f64 / u64::MAX as f64;
+    ((SKETCH_SIZE - 1) as f64 / kth) as u64
+  }
+}
+
+/// Gathers statistics a row at a time.
+pub struct StatsCollector {
+  rows: u64,
+  sketches: Vec<Sketch>,
+  nulls: Vec<u64>,
+}
+
+impl StatsCollector {
+  pub fn new(width: usize) -> Self {
+    StatsCollector {
+      rows: 0,
+      sketches: (0..width).map(|_| Sketch { smallest: BTreeSet::new() }).collect(),
+      nulls: vec![0; width],
+    }
+  }
+
+  pub fn add(&mut self, row: &[Option<TableCell>]) {
+    self.rows += 1;
+    for ((sketch, nulls), cell) in self.sketches.iter_mut().zip(&mut self.nulls).zip(row) {
+      match cell {
+        Some(cell) => sketch.add(cell),
+        None => *nulls += 1,
+      }
+    }
+  }
+
+  pub fn finish(self) -> TableStats {
+    TableStats {
+      rows: self.rows,
+      columns: self
+        .sketches
+        .iter()
+        .zip(self.nulls)
+        .map(|(sketch, nulls)| ColumnStats {
+          distinct: sketch.estimate(),
+          nulls,
+        })
+        .collect(),
+      indexes: vec![],
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts() {
+    let table = TableData {
+      header: vec!["id".to_owned(), "group".to_owned(), "note".to_owned()],
+      rows: (0..100_000)
+        .map(|i| {
+          vec![
+            Some(TableCell::CellInt(i)),
+            Some(TableCell::CellString(format!("g{}", i % 10))),
+            if i % 4 == 0 { None } else { Some(TableCell::CellInt(i % 500)) },
+          ]
+        })
+        .collect(),
+    };
+    let stats = TableStats::of(&table);
+    assert_eq!(stats.rows, 100_000);
+    assert_eq!(stats.columns[1], ColumnStats { distinct: 10, nulls: 0 });
+    assert_eq!(stats.columns[2], ColumnStats { distinct: 375, nulls: 25_000 });
+    let distinct = stats.columns[0].distinct as f64;
+    assert!((distinct - 100_000.0).abs() < 10_000.0, "{}", distinct);
+  }
+
+  #[test]
+  fn caches_beside_the_table() {
+    use std::cell::Cell;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    let dir = crate::tables::tests::TempDir::new("stats_cache");
+    let table = dir.join("t.csv");
+    fs::write(&table, "a,b\n1,x\n2,\n").unwrap();
+    let reads = Cell::new(0);
+    let stats = |format: &str| {
+      TableStats::cached(&table, format, || {
+        reads.set(reads.get() + 1);
+        let data = TableData::of_file(&File::open(&table).unwrap())?;
+        Ok((data.header.len(), Box::new(data.rows.into_iter().map(Ok)) as Rows))
+      })
+      .unwrap()
+    };
+    let first = stats("csv");
+    assert_eq!(first.rows, 2);
+    assert_eq!(first.columns[1], ColumnStats { distinct: 1, nulls: 1 });
+    assert_eq!(stats("csv"), first);
+    assert_eq!(reads.get(), 1);
+
+    // A table read another way, or changed since, is read again.
+    stats("tsv");
+    assert_eq!(reads.get(), 2);
+    let file = File::options().write(true).open(&table).unwrap();
+    file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+    stats("tsv");
+    assert_eq!(reads.get(), 3);
+    stats("tsv");
+    assert_eq!(reads.get(), 3);
+
+    fs::write(stats_path(&table), b"MORSTA01\xff").unwrap();
+    assert_eq!(stats("tsv"), first);
+    assert_eq!(reads.get(), 4);
+
+    // A cache that cannot be written is an error.
+    fs::remove_file(stats_path(&table)).unwrap();
+    fs::create_dir(stats_path(&table)).unwrap();
+    let unwritable = TableStats::cached(&table, "tsv", || {
+      let data = TableData::of_file(&File::open(&table).unwrap())?;
+      Ok((data.header.len(), Box::new(data.rows.into_iter().map(Ok)) as Rows))
+    });
+    assert!(unwritable.is_err());
+  }
+}
@@ -0,0 +1,96 @@
+//! Pull-based execution: a query reads its first table a row at a time and
+//! yields result rows as they are produced, so a query over one table runs
+//! in constant memory however large the table is. The other tables of a
+//! join are held in memory.
+
+use crate::ast::IndexedQuery;
+use crate::error::Result;
+use crate::exec::{Config, Execution};
+use crate::plan::LogicalPlan;
+use crate::stats::TableStats;
+use crate::tables::{TableCell, TableData};
+
+pub type Row = Vec<Option<TableCell>>;
+
+/// A stream of rows, any of which may fail to be read.
+pub type Rows<'a> = Box<dyn Iterator<Item = Result<Row>> + 'a>;
+
+/// The rows of an in-memory table.
+pub fn rows_of(table: &TableData) -> Rows<'_> {
+  Box::new(table.rows.iter().cloned().map(Ok))
+}
+
+impl IndexedQuery {
+  /// The column names of the result. `catalog` is the list of tables the
+  /// query was bound against; only their headers are used.
+  pub fn header(&self, catalog: &[TableData]) -> Vec<String> {
+    self
+      .selection
+      .new_header(self.sources.iter().map(|i| catalog[*i].header.clone()).collect())
+  }
+
+  /// Runs the query over the rows of its tables, given in the order of its
+  /// FROM clause, optimizing and lowering its logical plan and pulling rows
+  /// through the operators as the result is read. Sorts and aggregates read
+  /// all their input first. `catalog` is as for `header`.
+  /// The joins are ordered using statistics of the rows in `catalog`.
+  pub fn stream<'a>(&self, catalog: &[TableData], sources: Vec<Rows<'a>>) -> Result<Rows<'a>> {
+    let stats: Vec<TableStats> = catalog.iter().map(TableStats::of).collect();
+    self.stream_with(catalog, &stats, sources, &Config::default())
+  }
+
+  /// The optimized logical plan of the query. `stats` holds the statistics
+  /// of each table in the catalog.
+  pub fn plan(&self, catalog: &[TableData], stats: &[TableStats], config: &Config) -> LogicalPlan {
+    self.logical_plan(catalog).optimize(stats, config)
+  }
+
+  pub fn stream_with<'a>(
+    &self,
+    catalog: &[TableData],
+    stats: &[TableStats],
+    sources: Vec<Rows<'a>>,
+    config: &Config,
+  ) -> Result<Rows<'a>> {
+    let mut sources: Vec<Option<Rows<'a>>> = sources.into_iter().map(Some).collect();
+    let mut scan = |slot: usize| sources[slot].take().expect("each table is scanned once");
+    Ok(Box::new(Execution::new(self.plan(catalog, stats, config).lower(config, &mut scan))))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::Error;
+  use crate::functions::FunctionRegistry;
+  use crate::parser::parse_sql;
+  use std::cell::Cell;
+
+  #[test]
+  fn rows_are_pulled_lazily() {
+    let names = vec!["t".to_owned()];
+    let catalog = vec![TableData {
+      header: vec!["n".to_owned()],
+      rows: vec![],
+    }];
+    let q = parse_sql("SELECT n\nFROM t\nWHERE 3 < n")
+      .unwrap()
+      .to_indexed(&catalog, &names, &FunctionRegistry::new())
+      .unwrap();
+    let read = Cell::new(0);
+    let source: Rows = Box::new((0..).map(|n| {
+      read.set(read.get() + 1);
+      Ok(vec![Some(TableCell::CellInt(n))])
+    }));
+    let first: Vec<Row> = q.stream(&catalog, vec![source]).unwrap().take(2).collect::<Result<_>>().unwrap();
+    assert_eq!(
+      first,
+      vec![vec![Some(TableCell::CellInt(4))], vec![Some(TableCell::CellInt(5))]]
+    );
+    assert_eq!(read.get(), 6);
+
+    let failing: Rows = Box::new(vec![Ok(vec![Some(TableCell::CellInt(9))]), Err(Error::data_format(Some(2), "bad"))].into_iter());
+    let results: Vec<_> = q.stream(&catalog, vec![failing]).unwrap().collect();
+    assert!(results[0].is_ok() && results[1].is_err());
+  }
+}
@@ -1,18 +1,22 @@
-#![allow(dead_code)]
-#![allow(unused_variables)]
 use crate::index_comb::*;
 use crate::ast::*;
-use std::io::BufReader;
-use std::io::prelude::*;
+use crate::functions::*;
+use crate::csv;
+use crate::parallel::Pool;
+use crate::output::OutputFormat;
+use crate::schema::Schema;
+use crate::stream;
+use crate::error::{Error, NameError, Result};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Cursor, Read};
 use regex::Regex;
 use std::fs::File;
-use std::io::Error;
-use either::Either;
+use std::borrow::Borrow;
 use std::fmt;
 
 
 
-#[derive(Debug,PartialEq, Eq, Clone)]
+#[derive(Debug,PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub enum TableCell{
     CellInt(i64),
     CellString(String)
@@ -30,6 +34,22 @@ pub struct TableData{
     pub rows : Vec<Vec<Option<TableCell>>>
 }
 
+impl TableCell {
+    //quoted fields are strings, and empty unquoted fields are null
+    pub fn of_field(field : csv::Field) -> Option<TableCell> {
+        if field.quoted {
+            return Some(TableCell::CellString(field.text));
+        }
+        if field.text.is_empty() {
+            return None;
+        }
+        match field.text.parse::<i64>() {
+            Ok(i) => Some(TableCell::CellInt(i)),
+            Err(_) => Some(TableCell::CellString(field.text))
+        }
+    }
+}
+
 impl fmt::Display for TableCell {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -39,100 +59,388 @@ impl fmt::Display for TableCell {
     }
 }
 
+//the rows of a delimited file, read one record at a time
+pub struct FileScan<R : BufRead> {
+    pub header : Vec<String>,
+    reader : std::iter::Peekable<csv::Reader<R>>,
+    dialect : csv::Dialect,
+    schema : Option<Schema>
+}
+
+impl<R : BufRead> FileScan<R> {
+    fn row(&self, record : csv::Record) -> Result<Vec<Option<TableCell>>> {
+        if record.fields.len() != self.header.len() {
+            return Err(Error::data_format(Some(record.line),
+                format!("expected {} fields but found {}", self.header.len(), record.fields.len())));
+        }
+        let line = record.line;
+        record.fields.into_iter().enumerate().map(|(i, field)| {
+            match &self.schema {
+                Some(schema) => schema.cell(i, field, &self.dialect).map_err(|e| Error::data_format(Some(line), e)),
+                None if self.dialect.is_null(&field) => Ok(None),
+                None => Ok(TableCell::of_field(field))
+            }
+        }).collect()
+    }
+}
+
+impl<R : BufRead> FileScan<R> {
+    //the rows of input that starts at a record after the given number of lines of a file with this
+    //header, such as a piece of the file or the rest of it after seeking to a record's offset
+    pub fn resume(input : R, header : Vec<String>, dialect : &csv::Dialect, schema : Option<&Schema>, lines : usize) -> Self {
+        FileScan{
+            header,
+            reader : csv::Reader::with_dialect(input, dialect.clone()).after_lines(lines).peekable(),
+            dialect : dialect.clone(),
+            schema : schema.cloned()
+        }
+    }
+
+    //the next row with the byte offset and line its record starts at
+    pub fn next_located(&mut self) -> Option<Result<(u64, usize, stream::Row)>> {
+        let record = self.reader.next()?;
+        Some(record.and_then(|record| {
+            let (offset, line) = (record.offset, record.line);
+            self.row(record).map(|row| (offset, line, row))
+        }))
+    }
+}
+
+impl<R : BufRead> Iterator for FileScan<R> {
+    type Item = Result<Vec<Option<TableCell>>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.reader.next()?;
+        Some(record.and_then(|record| self.row(record)))
+    }
+}
+
+//the bytes a piece of a file holds when its pieces are parsed on several threads
+pub const PIECE_BYTES : usize = 1 << 20;
+
+//a piece of whole records of a file, read into memory
+type Piece = FileScan<BufReader<Cursor<Vec<u8>>>>;
+
+//the rows of a delimited file, read a window of pieces of whole records at a time and parsing a
+//piece on each worker, so that only the window is held in memory
+pub struct ChunkedScan<R : BufRead> {
+    pub header : Vec<String>,
+    pieces : csv::Pieces<R>,
+    dialect : csv::Dialect,
+    schema : Option<Schema>,
+    pool : Pool,
+    //the first piece, read with the header
+    first : Option<Piece>,
+    rows : VecDeque<Result<stream::Row>>,
+    done : bool
+}
+
+//the rows of a piece, up to and including the first error
+fn parse_piece(scan : Piece) -> Vec<Result<stream::Row>> {
+    let mut rows = Vec::new();
+    for row in scan {
+        let failed = row.is_err();
+        rows.push(row);
+        if failed {
+            break;
+        }
+    }
+    rows
+}
+
+impl<R : BufRead> ChunkedScan<R> {
+    //parses the next window of pieces, a piece for each worker
+    fn read_window(&mut self) -> Result<()> {
+        let mut scans : Vec<Piece> = self.first.take().into_iter().collect();
+        while scans.len() < self.pool.threads() {
+            match self.pieces.next_piece()? {
+                Some((text, lines)) => {
+                    let piece = BufReader::new(Cursor::new(text.into_bytes()));
+                    scans.push(FileScan::resume(piece, self.header.clone(), &self.dialect, self.schema.as_ref(), lines));
+                },
+                None => break
+            }
+        }
+        if scans.is_empty() {
+            self.done = true;
+            return Ok(());
+        }
+        let parsed = self.pool.map_parts(scans, |scans| scans.into_iter().map(parse_piece).collect::<Vec<_>>());
+        self.rows.extend(parsed.into_iter().flatten().flatten());
+        Ok(())
+    }
+}
+
+impl<R : BufRead> Iterator for ChunkedScan<R> {
+    type Item = Result<stream::Row>;
+
+    //stops after the first error, as the rows of later pieces were parsed past it
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.rows.is_empty() && !self.done {
+            if let Err(e) = self.read_window() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        let row = self.rows.pop_front()?;
+        if row.is_err() {
+            self.done = true;
+            self.rows.clear();
+        }
+        Some(row)
+    }
+}
+
+//the table as CSV
 impl fmt::Display for TableData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let data = self.rows.iter().map(|r| r.iter().map(|c| {
-            match c {
-                Some(x) => format!("{}", x),
-                None => "".to_string(),
-            }
-        }).collect::<Vec<_>>().join(",")).collect::<Vec<_>>().join("\n");
-        write!(f, "{}\n{}", self.header.join(","), data)
+        let mut out = vec![];
+        OutputFormat::Csv.write(self, &mut out).map_err(|_| fmt::Error)?;
+        write!(f, "{}", String::from_utf8_lossy(&out).trim_end_matches('\n'))
     }
 }
 
 
 impl ColumnSelector {
-    pub fn to_indexed(&self, tables : &Vec<TableData>, names : &Vec<String>) -> Result<IndexedColumnSelector, String>{
+    //resolves the column against the tables in scope, which are known by names
+    pub fn to_indexed(&self, tables : &[TableData], names : &[String]) -> Result<IndexedColumnSelector>{
         let table_index : usize;
         let column_index : usize;
+        let unknown = || Error::name(NameError::UnknownColumn{ table : self.table.clone(), column : self.field.clone() }, Some(self.span));
         match &self.table {
             Some(name) => {
                 table_index = names.iter().position(|n| *n == *name)
-                    .ok_or(format!("Specified table {} was not found for {}", name, self))?;
+                    .ok_or_else(|| Error::name(NameError::UnknownTable(name.clone()), Some(self.span)))?;
                 
                 column_index = tables[table_index].header.iter().position(|id| *id == self.field)
-                    .ok_or(format!("Specified column {} was not found for in table {} for {}", self.field, name, self))?;
+                    .ok_or_else(unknown)?;
 
             },
             None => {
                 let mut column_index_opt : Option<usize> = None;
                 table_index = tables.iter().position( |table| {
                     column_index_opt = table.header.iter().position(|id| *id == self.field);
-                    return matches!(column_index_opt, Some(x));
-                }).ok_or(format!("Specified column {} was not found for in tables {:?} for {}", self.field, names, self))?;
-                column_index = column_index_opt.ok_or("".to_string())?;
+                    column_index_opt.is_some()
+                }).ok_or_else(unknown)?;
+                column_index = column_index_opt.ok_or_else(unknown)?;
             }
         } 
-        return Ok(IndexedColumnSelector{
+        Ok(IndexedColumnSelector{
             table : table_index,
             field : column_index
-        });
+        })
     }
 }
 
 impl Filter {
-    pub fn to_indexed(&self, tables : &Vec<TableData>, names : &Vec<String>) -> Result<IndexedFilter, String>{
+    pub fn to_indexed(&self, tables : &[TableData], names : &[String], functions : &FunctionRegistry) -> Result<IndexedFilter>{
+        //errors without a place in the query are placed at the innermost expression around them
+        self.bind(tables, names, functions).map_err(|e| match self.span() {
+            Some(span) => e.at(span),
+            None => e
+        })
+    }
+
+    fn bind(&self, tables : &[TableData], names : &[String], functions : &FunctionRegistry) -> Result<IndexedFilter>{
         match self {
             Filter::Id(id) => {
                 let indexed_id : IndexedColumnSelector = id.to_indexed(tables,names)?;
-                return Ok(IndexedFilter::Id(indexed_id));
+                Ok(IndexedFilter::Id(indexed_id))
             },
             Filter::UnaryOp(uop, filter) => {
-                let indexed_filter : IndexedFilter = filter.to_indexed(tables,names)?;
-                return Ok(IndexedFilter::UnaryOp(*uop, Box::new(indexed_filter)));
+                let indexed_filter : IndexedFilter = filter.to_indexed(tables,names,functions)?;
+                Ok(IndexedFilter::UnaryOp(*uop, Box::new(indexed_filter)))
+            },
+            Filter::BinaryOp(bop @ (BinaryOp::SimilarTo | BinaryOp::Regexp), subject, pattern, _) => {
+                let pattern = match &**pattern {
+                    Filter::LitS(p) if *bop == BinaryOp::SimilarTo => Pattern::similar_to(p)?,
+                    Filter::LitS(p) => Pattern::regexp(p)?,
+                    _ => return Err(Error::type_error(format!("the pattern of {} must be a string literal", self)))
+                };
+                Ok(IndexedFilter::Regex(RegexOp::Matches, pattern, vec![subject.to_indexed(tables,names,functions)?]))
             },
-            Filter::BinaryOp(bop, filterl, filterr) => {
-                let indexed_filterl = filterl.to_indexed(tables,names)?;
-                let indexed_filterr = filterr.to_indexed(tables,names)?;
-                return Ok(IndexedFilter::BinaryOp(*bop, Box::new(indexed_filterl), Box::new(indexed_filterr)));
+            Filter::BinaryOp(bop, filterl, filterr, _) => {
+                let indexed_filterl = filterl.to_indexed(tables,names,functions)?;
+                let indexed_filterr = filterr.to_indexed(tables,names,functions)?;
+                Ok(IndexedFilter::BinaryOp(*bop, Box::new(indexed_filterl), Box::new(indexed_filterr)))
             }
+            Filter::Call(name, args, _) if REGEX_FUNCTIONS.iter().any(|(f, _)| name.eq_ignore_ascii_case(f)) => {
+                let (_, op) = REGEX_FUNCTIONS.iter().find(|(f, _)| name.eq_ignore_ascii_case(f)).unwrap();
+                let arity_ok = match op {
+                    RegexOp::Extract => args.len() == 2 || args.len() == 3,
+                    _ => args.len() == 3
+                };
+                if !arity_ok {
+                    return Err(Error::type_error(format!("wrong number of arguments to {} in {}", name, self)));
+                }
+                let pattern = match &args[1] {
+                    Filter::LitS(p) => Pattern::regexp(p)?,
+                    _ => return Err(Error::type_error(format!("the pattern of {} must be a string literal", self)))
+                };
+                let mut indexed_args = vec![args[0].to_indexed(tables,names,functions)?];
+                for arg in &args[2..] {
+                    indexed_args.push(arg.to_indexed(tables,names,functions)?);
+                }
+                Ok(IndexedFilter::Regex(*op, pattern, indexed_args))
+            },
+            Filter::Call(name, args, span) => {
+                let function = match functions.scalar(name) {
+                    Some(f) => f.clone(),
+                    None if functions.aggregate(name).is_some() =>
+                        return Err(Error::type_error(format!("aggregate function {} can only be used directly in the selection, in {}", name, self))),
+                    None => return Err(Error::name(NameError::UnknownFunction(name.clone()), None))
+                };
+                function.signature.check_arity(name, args.len())?;
+                let indexed_args : Vec<_> = args.iter().map(|arg| arg.to_indexed(tables,names,functions)).collect::<Result<_>>()?;
+                function.signature.check_types(name, &indexed_args).map_err(|e| e.at(*span))?;
+                Ok(IndexedFilter::Call(function, indexed_args))
+            }
+
+            Filter::Param(p, _) => Ok(IndexedFilter::Param(p.clone())),
+            Filter::LitB(b) => Ok(IndexedFilter::LitB(*b)),
+            Filter::LitS(s) => Ok(IndexedFilter::LitS(s.clone())),
+            Filter::LitI(i) => Ok(IndexedFilter::LitI(*i))
+        }
+    }
 
-            Filter::LitB(b) => return Ok(IndexedFilter::LitB(*b)),
-            Filter::LitS(s) => return Ok(IndexedFilter::LitS(s.clone())),
-            Filter::LitI(i) => return Ok(IndexedFilter::LitI(*i))
+    fn is_aggregate(&self, functions : &FunctionRegistry) -> bool {
+        matches!(self, Filter::Call(name, _, _) if functions.aggregate(name).is_some())
+    }
+
+    //the part of the query the expression was parsed from, for expressions that record it
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Filter::Id(id) => Some(id.span),
+            Filter::BinaryOp(_, _, _, span) | Filter::Call(_, _, span) => Some(*span),
+            _ => None
+        }
+    }
+
+    //the header name of a selected expression
+    fn column_name(&self) -> String {
+        match self {
+            Filter::Id(id) => id.field.clone(),
+            _ => format!("{}", self)
         }
     }
 }
 
-impl Query {
-    pub fn to_indexed(self, tables : &Vec<TableData>, names : &Vec<String>) -> Result<IndexedQuery, String>{
-        let indexed_filter = self.filter.to_indexed(tables,names)?;
-        let indexed_selection : Result<IndexedSelection,String>;
-         match self.selection {
-            Selection::Star => indexed_selection = Ok(IndexedSelection::Star),
-            Selection::Columns(v) =>{ 
-                let cols : Result<Vec<_>,_> = v.iter().map(|col| {col.to_indexed(tables,names)   }).collect(); 
-                indexed_selection = Ok(IndexedSelection::Columns(cols?));
-        } 
+const REGEX_FUNCTIONS : [(&str, RegexOp); 2] = [("regexp_extract", RegexOp::Extract), ("regexp_replace", RegexOp::Replace)];
+
+impl Pattern {
+    //REGEXP patterns match anywhere in the string
+    pub fn regexp(pattern : &str) -> Result<Pattern> {
+        Regex::new(pattern).map(Pattern).map_err(|e| Error::type_error(format!("invalid pattern \"{}\": {}", pattern, e)))
+    }
+
+    //SIMILAR TO patterns are regexes that must match the whole string, with % and _ as in LIKE
+    pub fn similar_to(pattern : &str) -> Result<Pattern> {
+        let mut translated = String::from("^(?:");
+        let mut escaped = false;
+        for c in pattern.chars() {
+            match c {
+                _ if escaped => {
+                    translated.push_str(&regex::escape(&c.to_string()));
+                    escaped = false;
+                },
+                '\\' => escaped = true,
+                '%' => translated.push_str(".*"),
+                '_' => translated.push('.'),
+                '.' | '^' | '$' => translated.push_str(&regex::escape(&c.to_string())),
+                _ => translated.push(c)
+            }
         }
-        return Ok (IndexedQuery{
-            filter : indexed_filter,
-            tables : self.tables,
-            selection : indexed_selection?
+        translated.push_str(")$");
+        Regex::new(&translated).map(Pattern).map_err(|e| Error::type_error(format!("invalid pattern \"{}\": {}", pattern, e)))
+    }
+}
+
+impl Selection {
+    pub fn to_indexed(&self, tables : &[TableData], names : &[String], functions : &FunctionRegistry) -> Result<IndexedSelection>{
+        match self {
+            Selection::Star => Ok(IndexedSelection::Star),
+            Selection::Columns(cols) if cols.iter().any(|col| col.is_aggregate(functions)) => {
+                let aggs : Result<Vec<_>> = cols.iter().map(|col| {
+                    match col {
+                        Filter::Call(name, args, span) if col.is_aggregate(functions) => {
+                            let function = functions.aggregate(name).unwrap().clone();
+                            function.signature.check_arity(name, args.len()).map_err(|e| e.at(*span))?;
+                            let indexed_args : Vec<_> = args.iter().map(|arg| arg.to_indexed(tables,names,functions)).collect::<Result<_>>()?;
+                            function.signature.check_types(name, &indexed_args).map_err(|e| e.at(*span))?;
+                            Ok(IndexedAggregate{
+                                name : col.column_name(),
+                                function,
+                                args : indexed_args
+                            })
+                        },
+                        _ => {
+                            let error = Error::type_error(format!("selected column {} must be an aggregate, since other selected columns are", col));
+                            Err(match col.span() {
+                                Some(span) => error.at(span),
+                                None => error
+                            })
+                        }
+                    }
+                }).collect();
+                Ok(IndexedSelection::Aggregates(aggs?))
+            },
+            Selection::Columns(cols) => {
+                let indexed_cols : Result<Vec<_>> = cols.iter().map(|col| {
+                    Ok(IndexedColumn{
+                        name : col.column_name(),
+                        expr : col.to_indexed(tables,names,functions)?
+                    })
+                }).collect();
+                Ok(IndexedSelection::Columns(indexed_cols?))
+            }
+        }
+    }
+}
+
+impl Table {
+    //the name the table's columns are qualified with in the query
+    pub fn scope_name(&self) -> &Ident {
+        self.alias.as_ref().unwrap_or(&self.table_name)
+    }
+}
+
+impl Query {
+    //tables and names are every table that the query may refer to
+    pub fn to_indexed(&self, tables : &[TableData], names : &[String], functions : &FunctionRegistry) -> Result<IndexedQuery>{
+        let sources : Result<Vec<usize>> = self.tables.iter().map(|table| {
+            names.iter().position(|n| *n == table.table_name)
+                .ok_or_else(|| Error::name(NameError::UnknownTable(table.table_name.clone()), Some(table.span)))
+        }).collect();
+        let sources = sources?;
+        //only the headers are needed to resolve columns
+        let scope : Vec<TableData> = sources.iter().map(|i| TableData{ header : tables[*i].header.clone(), rows : vec![] }).collect();
+        let scope_names : Vec<String> = self.tables.iter().map(|table| table.scope_name().clone()).collect();
+        Ok (IndexedQuery{
+            filter : self.filter.to_indexed(&scope,&scope_names,functions)?,
+            tables : self.tables.clone(),
+            sources,
+            selection : self.selection.to_indexed(&scope,&scope_names,functions)?,
+            order_by : self.order_by.iter().map(|key| {
+                Ok(IndexedOrderKey{
+                    expr : key.expr.to_indexed(&scope,&scope_names,functions)?,
+                    descending : key.descending
+                })
+            }).collect::<Result<_>>()?,
+            limit : self.limit
         })
     }
 
-    pub fn run(self, tables : &Vec<TableData>, names : &Vec<String>) -> Result<TableData, String>{
-        let query : IndexedQuery = self.to_indexed(tables,names)?;
-        return query.run(tables);
+    pub fn run(&self, tables : &[TableData], names : &[String], functions : &FunctionRegistry) -> Result<TableData>{
+        let query : IndexedQuery = self.to_indexed(tables,names,functions)?;
+        if let Some(param) = query.params().first() {
+            return Err(Error::name(NameError::UnboundParameter(param.clone()), None));
+        }
+        query.run(tables)
     }
 
-    pub fn run_from_files(self, files : &Vec<File>, names : &Vec<String>) -> Result<TableData, String>{
-        let tables_res : Result<Vec<TableData>, Either<Error,String>> = files.iter().map(|f| {TableData::of_file(f).map_err(|err| Either::Left(err))})
-            .collect();
-        let tables = tables_res.map_err(|e| e.to_string())?;
-        self.run(&tables, &names)
+    pub fn run_from_files(&self, files : &[File], names : &[String], functions : &FunctionRegistry) -> Result<TableData>{
+        let tables = TableData::of_files(files, names)?;
+        self.run(&tables, names, functions)
     }
 
 }
@@ -142,9 +450,9 @@ impl IndexedFilter {
         match uop {
             UnaryOp::Not => self.compute_with_row(row).and_then(|tc|{
                 match tc {
-                    TableCell::CellInt(i) => return tc_bool!(i == 0)
+                    TableCell::CellInt(i) => tc_bool!(i == 0)
                     ,
-                    TableCell::CellString(s) => return None
+                    TableCell::CellString(_) => None
                 } 
             })
         }
@@ -152,21 +460,21 @@ impl IndexedFilter {
 
     pub fn compute_with_row_bop(&self, row : &Vec<Vec<Option<TableCell>>>, bop : BinaryOp, 
                 filterl : &IndexedFilter, filterr : &IndexedFilter) -> Option<TableCell> {
-        return filterl.compute_with_row(row).and_then(|tcl|{ filterr.compute_with_row(row).and_then(|tcr| {
+        filterl.compute_with_row(row).and_then(|tcl|{ filterr.compute_with_row(row).and_then(|tcr| {
             match bop {
                 BinaryOp::And => {
-                    return tc_bool!( matches!((tcl, tcr), (TableCell::CellInt(l), TableCell::CellInt(r)) if l != 0 && r != 0 ))
+                    tc_bool!( matches!((tcl, tcr), (TableCell::CellInt(l), TableCell::CellInt(r)) if l != 0 && r != 0 ))
                 },
                 BinaryOp::Or => {
-                    return tc_bool!(matches!(tcl, TableCell::CellInt(l) if l != 0) || matches!(tcr, TableCell::CellInt(r) if r != 0)) 
+                    tc_bool!(matches!(tcl, TableCell::CellInt(l) if l != 0) || matches!(tcr, TableCell::CellInt(r) if r != 0)) 
                 },
                 BinaryOp::Lt => {
-                    return tc_bool!(matches!((tcl, tcr), (TableCell::CellInt(l), TableCell::CellInt(r)) if l < r ))
+                    tc_bool!(matches!((tcl, tcr), (TableCell::CellInt(l), TableCell::CellInt(r)) if l < r ))
                 },
                 BinaryOp::Eq => 
-                    return tc_bool!(matches!((&tcl, &tcr), (TableCell::CellInt(l), TableCell::CellInt(r)) if l == r) || 
+                    tc_bool!(matches!((&tcl, &tcr), (TableCell::CellInt(l), TableCell::CellInt(r)) if l == r) || 
                                     matches!((tcl, tcr), (TableCell::CellString(l),TableCell::CellString(r)) if l == r )),
-                _ => return tc_bool!(false)
+                _ => tc_bool!(false)
                 
 
                 }
@@ -179,130 +487,232 @@ impl IndexedFilter {
 
     pub fn compute_with_row(&self, row : &Vec<Vec<Option<TableCell>>>) -> Option<TableCell> {
         match self  {
-            IndexedFilter::Id(ind) => return row[ind.table][ind.field].clone(),
-            IndexedFilter::LitB(b) => return tc_bool!(*b),
-            IndexedFilter::LitI(i) => return Some(TableCell::CellInt(*i)),
-            IndexedFilter::LitS(s) => return Some(TableCell::CellString(s.clone())),
+            IndexedFilter::Id(ind) => row[ind.table][ind.field].clone(),
+            IndexedFilter::LitB(b) => tc_bool!(*b),
+            IndexedFilter::LitI(i) => Some(TableCell::CellInt(*i)),
+            IndexedFilter::LitS(s) => Some(TableCell::CellString(s.clone())),
             IndexedFilter::UnaryOp(uop, filter) => filter.compute_with_row_uop(row,*uop),
-            IndexedFilter::BinaryOp(bop, filterl, filterr)  => self.compute_with_row_bop(row, *bop, filterl, filterr)
+            IndexedFilter::BinaryOp(bop, filterl, filterr)  => self.compute_with_row_bop(row, *bop, filterl, filterr),
+            IndexedFilter::Call(function, args) => {
+                let arg_cells : Vec<_> = args.iter().map(|arg| arg.compute_with_row(row)).collect();
+                function.call(&arg_cells)
+            },
+            IndexedFilter::Regex(op, pattern, args) => self.compute_with_row_regex(row, *op, &pattern.0, args),
+            //parameters are replaced by their values before a prepared query is run
+            IndexedFilter::Param(_) => None
+        }
+    }
+
+    fn compute_with_row_regex(&self, row : &Vec<Vec<Option<TableCell>>>, op : RegexOp, re : &Regex, args : &[IndexedFilter]) -> Option<TableCell> {
+        let subject = match args[0].compute_with_row(row) {
+            Some(TableCell::CellString(s)) => s,
+            _ => return None
+        };
+        match op {
+            RegexOp::Matches => tc_bool!(re.is_match(&subject)),
+            RegexOp::Extract => {
+                //without an explicit group, extract the first group if there is one
+                let group = match args.get(1).map(|arg| arg.compute_with_row(row)) {
+                    None => if re.captures_len() > 1 {1} else {0},
+                    Some(Some(TableCell::CellInt(i))) if i >= 0 => i as usize,
+                    Some(_) => return None
+                };
+                re.captures(&subject).and_then(|caps| caps.get(group))
+                    .map(|m| TableCell::CellString(m.as_str().to_string()))
+            },
+            RegexOp::Replace => match args[1].compute_with_row(row) {
+                Some(TableCell::CellString(replacement)) =>
+                    Some(TableCell::CellString(re.replace_all(&subject, replacement.as_str()).into_owned())),
+                _ => None
+            }
 
         }
     }
 
     pub fn valid_row(&self, row : &Vec<Vec<Option<TableCell>>>) -> bool{
         let tc_opt = self.compute_with_row(row);
-        return matches!(tc_opt, Some(TableCell::CellInt(i)) if i != 0);
+        matches!(tc_opt, Some(TableCell::CellInt(i)) if i != 0)
     }
 }
 
 impl IndexedQuery {
 
-    pub fn run(&self, tables : &Vec<TableData>) -> Result<TableData,String>{
-        return Ok(TableData::join_table(&|row| {return self.filter.valid_row(row)}, tables, &self.selection));         
+    //tables is the catalog the query was bound against
+    pub fn run(&self, tables : &[TableData]) -> Result<TableData>{
+        let sources = self.sources.iter().map(|i| stream::rows_of(&tables[*i])).collect();
+        return Ok(TableData{
+            header : self.header(tables),
+            rows : self.stream(tables, sources)?.collect::<Result<_>>()?
+        });
     }
 }
 
 impl IndexedSelection {
     pub fn to_row(&self, row_vec : &Vec<Vec<Option<TableCell>>>) -> Vec<Option<TableCell>> {
         match self {
-            IndexedSelection::Star => return row_vec.concat(),
+            IndexedSelection::Star => row_vec.concat(),
             IndexedSelection::Columns(cols) => {
-                let mut new_row = Vec::new();
-                for col_sel in cols {
-                    new_row.push(row_vec[col_sel.table][col_sel.field].clone());
-                }
-                return new_row;
-            }
-
+                cols.iter().map(|col| col.expr.compute_with_row(row_vec)).collect()
+            },
+            IndexedSelection::Aggregates(_) => panic!("aggregates do not produce a value per row")
         }
     }
     
     pub fn new_header(&self, old_header : Vec<Vec<String>>) -> Vec<String>{
         match self {
-            IndexedSelection::Star => return old_header.concat(),
-            IndexedSelection::Columns(cols) => {
-                let mut new_header = Vec::new();
-                for col_sel in cols {
-                    new_header.push(old_header[col_sel.table][col_sel.field].clone());
-                }
-                return new_header;
-            }
+            IndexedSelection::Star => old_header.concat(),
+            IndexedSelection::Columns(cols) => cols.iter().map(|col| col.name.clone()).collect(),
+            IndexedSelection::Aggregates(aggs) => aggs.iter().map(|agg| agg.name.clone()).collect()
         }
 
     }
 }
 
 impl TableData {
-    pub fn of_file(file : &File) -> Result<Self, Error>{
-        let mut header : Option<Vec<String>> = None;
-        let mut rows : Vec<Vec<Option<TableCell>>> = Vec::new();
-        let re = Regex::new("\".*\"").unwrap();
-        let reader = BufReader :: new(file);
-        for line_res in reader.lines(){
-            let line = line_res.unwrap();
-            if line.is_empty(){
-                continue;
-            }
-            let cell_strings = line.split(',').map(|st|  { 
-                let mut st_mut = st.to_string();
-                st_mut.retain(|c| !c.is_whitespace() );
-                return st_mut;
-            
-            }).collect();
-            if header == None{
-                header = Some(cell_strings);
-            } else{
-                let cells = cell_strings.iter().map(|cell|{
-                    match re.captures_iter(cell).nth(0) {
-                        None => cell.parse::<i64>().ok().map(|n| TableCell::CellInt(n)),
-                        Some(_) => Some (TableCell::CellString(cell[1..cell.len() - 1].to_string()))
-                    }
-                }).collect();
-                rows.push(cells);
-            }
+    //names are used to attribute errors to the file they occurred in
+    pub fn of_files(files : &[File], names : &[String]) -> Result<Vec<Self>>{
+        files.iter().zip(names).map(|(f, name)| TableData::of_file(f).map_err(|e| e.in_table(name)))
+            .collect()
+    }
+
+    pub fn of_file(file : &File) -> Result<Self>{
+        TableData::of_file_with(file, &csv::Dialect::default(), None)
+    }
+
+    //without a schema, the type of each cell is inferred on its own
+    pub fn of_file_with(file : &File, dialect : &csv::Dialect, schema : Option<&Schema>) -> Result<Self>{
+        let scan = TableData::scan_file(file, dialect, schema)?;
+        Ok(TableData{
+            header : scan.header.clone(),
+            rows : scan.collect::<Result<_>>()?
+        })
+    }
+
+    //splits the file at record boundaries and parses a piece on each thread, reading the same
+    //header and rows with the same types as of_file_with
+    pub fn of_file_chunked<R : Read>(file : R, dialect : &csv::Dialect, schema : Option<&Schema>, threads : usize) -> Result<Self>{
+        let scan = TableData::scan_file_chunked(file, dialect, schema, threads)?;
+        Ok(TableData{
+            header : scan.header.clone(),
+            rows : scan.collect::<Result<_>>()?
+        })
+    }
+
+    //reads the header as scan_file does, leaving the rows to be read a window of pieces of
+    //PIECE_BYTES at a time, parsed on `threads` threads
+    pub fn scan_file_chunked<R : Read>(file : R, dialect : &csv::Dialect, schema : Option<&Schema>, threads : usize) -> Result<ChunkedScan<BufReader<R>>>{
+        TableData::scan_file_pieces(file, dialect, schema, threads, PIECE_BYTES)
+    }
+
+    fn scan_file_pieces<R : Read>(file : R, dialect : &csv::Dialect, schema : Option<&Schema>, threads : usize, piece_bytes : usize) -> Result<ChunkedScan<BufReader<R>>>{
+        let mut pieces = csv::Pieces::new(BufReader::new(file), dialect, piece_bytes);
+        //the first piece holds the header
+        let (text, _) = pieces.next_piece()?.unwrap_or_default();
+        let first = TableData::scan_file(Cursor::new(text.into_bytes()), dialect, schema)?;
+        Ok(ChunkedScan{
+            header : first.header.clone(),
+            pieces,
+            dialect : dialect.clone(),
+            schema : schema.cloned(),
+            pool : Pool::new(threads),
+            first : Some(first),
+            rows : VecDeque::new(),
+            done : false
+        })
+    }
+
+    //reads the header, leaving the rows to be read by iterating over the scan
+    pub fn scan_file<R : Read>(file : R, dialect : &csv::Dialect, schema : Option<&Schema>) -> Result<FileScan<BufReader<R>>>{
+        let mut reader = csv::Reader::with_dialect(BufReader::new(file), dialect.clone()).peekable();
+        let header : Vec<String> = match (reader.peek(), schema) {
+            (Some(Err(_)), _) => return Err(reader.next().unwrap().unwrap_err()),
+            (_, Some(schema)) if !dialect.has_header => schema.names(),
+            (None, _) => return Err(Error::data_format(None, "the file has no header")),
+            (Some(Ok(record)), None) if !dialect.has_header => (1..=record.fields.len()).map(|i| format!("c{}", i)).collect(),
+            (Some(Ok(_)), _) => reader.next().unwrap()?.fields.into_iter().map(|field| field.text).collect()
+        };
+        if let Some(schema) = schema {
+            schema.check_header(&header)?;
         }
-        return Ok(TableData{
-            header : header.unwrap(),
-            rows : rows
-        });
-        
+        Ok(FileScan{
+            header,
+            reader,
+            dialect : dialect.clone(),
+            schema : schema.cloned()
+        })
     }
 
-    //maybe refactor into function that takes a filter and a row
-    pub fn join_table<F>(valid_row :&F, tables : &Vec<TableData>, sel : &IndexedSelection) -> TableData
-    where F : Fn(&Vec<Vec<Option<TableCell>>>) -> bool
+    //calls on_row with every combination of rows of the tables that satisfies valid_row
+    pub fn join_rows<T, F, G>(valid_row :&F, tables : &[T], mut on_row : G)
+    where T : Borrow<TableData>,
+          F : Fn(&Vec<Vec<Option<TableCell>>>) -> bool,
+          G : FnMut(Vec<Vec<Option<TableCell>>>)
     {
         let n_tables = tables.len();
-        let table_contents : Vec<_> = tables.iter().map(|table| &table.rows).collect();
-        let table_headers : Vec<_>= tables.iter().map(|table| table.header.clone()).collect();
+        let table_contents : Vec<_> = tables.iter().map(|table| &table.borrow().rows).collect();
         let bounds : Vec<usize> = table_contents.iter().map(|table| table.len()).collect();
-        //fix the header 
-        let new_header : Vec<String> = sel.new_header(table_headers);
-        let mut new_rows : Vec<Vec<Option<TableCell>>> = Vec::with_capacity(n_tables);
+        if bounds.contains(&0) {
+            return;
+        }
         for indices in given_bounds(bounds){
             let mut current_proposed_row : Vec<Vec<Option<TableCell>>> = Vec::with_capacity(n_tables);
             for (table_index, row_index) in indices.iter().enumerate(){
                 current_proposed_row.push(table_contents[table_index][*row_index].clone());
             }
             if valid_row(&current_proposed_row){
-                //replace this concatenation with new selection stuff
-                // indexedselection -> Vec<Vec<Option<TableCell>>> -> Vec<Option<TableCell>>
-                let new_row = sel.to_row(&current_proposed_row);
-                new_rows.push(new_row);
+                on_row(current_proposed_row);
             }
         }
-        return TableData{
+    }
+
+    pub fn join_table<T, F>(valid_row :&F, tables : &[T], sel : &IndexedSelection) -> TableData
+    where T : Borrow<TableData>,
+          F : Fn(&Vec<Vec<Option<TableCell>>>) -> bool
+    {
+        let table_headers : Vec<_>= tables.iter().map(|table| table.borrow().header.clone()).collect();
+        //fix the header 
+        let new_header : Vec<String> = sel.new_header(table_headers);
+        let mut new_rows : Vec<Vec<Option<TableCell>>> = Vec::new();
+        TableData::join_rows(valid_row, tables, |row| new_rows.push(sel.to_row(&row)));
+        TableData{
             header : new_header,
             rows : new_rows
-        };
+        }
     } 
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use crate::tables::*;
     use std::env::current_dir;
     use std::path::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static TEMP_DIRS : AtomicUsize = AtomicUsize::new(0);
+
+    //a directory of a test's own for the files it writes, removed when dropped
+    pub(crate) struct TempDir {
+        pub path : PathBuf
+    }
+
+    impl TempDir {
+        pub(crate) fn new(test : &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("morsql-{}-{}-{}", std::process::id(), TEMP_DIRS.fetch_add(1, Ordering::Relaxed), test));
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir { path }
+        }
+
+        pub(crate) fn join<P : AsRef<Path>>(&self, file : P) -> PathBuf {
+            self.path.join(file)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
     #[test]
     fn load_file_test1(){
         let test_header = vec!["name".to_string(), "age".to_string(), "id".to_string()];
@@ -320,23 +730,143 @@ mod tests {
         assert_eq!(testtable, filetable);
     }
 
+    #[test]
+    fn load_quoted_file(){
+        let filetable = TableData::of_file(&File::open("examples/quoted.csv").unwrap()).unwrap();
+        let s = |st : &str| Some(TableCell::CellString(st.to_string()));
+        assert_eq!(filetable, TableData {
+            header : vec!["name".to_string(), "city".to_string(), "visits".to_string()],
+            rows : vec![
+                vec![s("Lucas"), s("New York, NY"), Some(TableCell::CellInt(3))],
+                vec![s("Harry \"H\" Goldstein"), s("Philadelphia\nPA"), None],
+                vec![s("Hal"), s("San Jose"), Some(TableCell::CellInt(1))],
+            ]
+        });
+    }
+
+    #[test]
+    fn load_chunked_file(){
+        let sequential = |text : &str, dialect : &csv::Dialect| -> Result<TableData> {
+            let scan = TableData::scan_file(text.as_bytes(), dialect, None)?;
+            Ok(TableData{ header : scan.header.clone(), rows : scan.collect::<Result<_>>()? })
+        };
+        let chunked = |text : &str, dialect : &csv::Dialect, threads, piece_bytes| -> Result<TableData> {
+            let scan = TableData::scan_file_pieces(text.as_bytes(), dialect, None, threads, piece_bytes)?;
+            Ok(TableData{ header : scan.header.clone(), rows : scan.collect::<Result<_>>()? })
+        };
+        let pieces = || (1..6).flat_map(|threads| vec![1, 10, 100, 1000, 100_000].into_iter().map(move |bytes| (threads, bytes)));
+        let mut text = String::from("\u{feff}name, note, n\n");
+        for i in 0..60 {
+            text.push_str(&format!("\"Lucas {}\", \"two\nlines, \"\"quoted\"\"\n\", {}\n", i, i));
+            text.push_str(if i % 3 == 0 { "\n  \n" } else { "" });
+            text.push_str(&format!("Harry, \"\", 00{}\n\"\"  ,  plain {} , \n", i, i));
+        }
+        let mut escaped = csv::Dialect::default();
+        escaped.apply_sidecar("delimiter = tab\nquote = '\nescape = \\\ncomment = --\n").unwrap();
+        let mut dump = String::from("-- users\nname\tnote\n");
+        for i in 0..60 {
+            dump.push_str(&format!("O\\'Brien\t'a\\'b,\n-- not a comment {}'\n-- a comment\n\\\t{}\t' x '\n", i, i));
+        }
+        let mut headerless = csv::Dialect::default();
+        headerless.set("header", "false").unwrap();
+        for (text, dialect) in [(&text, csv::Dialect::default()), (&dump, escaped), (&text, headerless)] {
+            let expected = sequential(text, &dialect).unwrap();
+            assert!(expected.rows.len() >= 120);
+            for (threads, piece_bytes) in pieces() {
+                assert_eq!(chunked(text, &dialect, threads, piece_bytes).unwrap(), expected);
+            }
+            assert_eq!(TableData::of_file_chunked(text.as_bytes(), &dialect, None, 4).unwrap(), expected);
+        }
+
+        let malformed = format!("{}\"never, closed\n{}", text, text);
+        let line = |r : Result<TableData>| match r {
+            Err(Error::DataFormat { line, .. }) => line,
+            r => panic!("expected a data error, got {:?}", r)
+        };
+        let expected = line(sequential(&malformed, &csv::Dialect::default()));
+        assert!(expected.is_some());
+        for (threads, piece_bytes) in pieces() {
+            assert_eq!(line(chunked(&malformed, &csv::Dialect::default(), threads, piece_bytes)), expected);
+        }
+    }
+
+    #[test]
+    fn load_ragged_file(){
+        let dir = TempDir::new("ragged");
+        let path = dir.join("ragged.csv");
+        std::fs::write(&path, "name, age\n\"Lucas\", 24\n\"Harry\"\n").unwrap();
+        let res = TableData::of_files(&[File::open(&path).unwrap()], &["ragged".to_string()]);
+        match res {
+            Err(Error::DataFormat { table, line, .. }) => {
+                assert_eq!(table.as_deref(), Some("ragged"));
+                assert_eq!(line, Some(3));
+            },
+            r => panic!("expected a data error, got {:?}", r)
+        }
+    }
+
+    #[test]
+    fn load_headerless_file(){
+        let dir = TempDir::new("headerless");
+        let path = dir.join("headerless.csv");
+        std::fs::write(&path, "Lucas|24\nHarry|\\N\n").unwrap();
+        let mut dialect = csv::Dialect::default();
+        dialect.set("delimiter", "|").unwrap();
+        dialect.set("header", "false").unwrap();
+        dialect.set("null", "\\N").unwrap();
+        let filetable = TableData::of_file_with(&File::open(&path).unwrap(), &dialect, None).unwrap();
+        assert_eq!(filetable, TableData {
+            header : vec!["c1".to_string(), "c2".to_string()],
+            rows : vec![
+                vec![Some(TableCell::CellString("Lucas".to_string())), Some(TableCell::CellInt(24))],
+                vec![Some(TableCell::CellString("Harry".to_string())), None],
+            ]
+        });
+    }
+
+    #[test]
+    fn load_with_schema(){
+        let dir = TempDir::new("schema");
+        let path = dir.join("schema.csv");
+        std::fs::write(&path, "zip, n\n02134, \"7\"\n19104,\n").unwrap();
+        let schema = Schema::parse("zip string not null\nn int").unwrap();
+        let dialect = csv::Dialect::default();
+        let filetable = TableData::of_file_with(&File::open(&path).unwrap(), &dialect, Some(&schema)).unwrap();
+        assert_eq!(filetable.rows, vec![
+            vec![Some(TableCell::CellString("02134".to_string())), Some(TableCell::CellInt(7))],
+            vec![Some(TableCell::CellString("19104".to_string())), None],
+        ]);
+
+        let line = |schema : &str| match TableData::of_file_with(&File::open(&path).unwrap(), &dialect, Some(&Schema::parse(schema).unwrap())) {
+            Err(Error::DataFormat { line, .. }) => line,
+            r => panic!("expected a data error, got {:?}", r)
+        };
+        assert_eq!(line("zip int\nn int not null"), Some(3));
+        assert_eq!(line("zip string\nn string\nm int"), Some(1));
+        assert_eq!(line("zip\tint\nm int"), Some(1));
+    }
+
     #[test]
     fn to_indexed_test(){
         let test_sel1 = ColumnSelector {
             table : Some ("t1".to_string()),
-            field : "f1".to_string()
+            field : "f1".to_string(),
+            span : Span::default()
         };
         let test_sel2 = ColumnSelector {
             table : Some ("t2".to_string()),
-            field : "f2".to_string()
+            field : "f2".to_string(),
+            span : Span::default()
         };
         let test_sel3 = ColumnSelector {
             table : None,
-            field : "f1".to_string()
+            field : "f1".to_string(),
+            span : Span::default()
         };
         let test_sel4 = ColumnSelector {
             table : None,
-            field : "f0".to_string()
+            field : "f0".to_string(),
+            span : Span::default()
         };
 
         let test_table1 = TableData{
@@ -349,14 +879,14 @@ mod tests {
         };
         let test_tables1 = vec![test_table1.clone(), test_table2.clone()];
         let test_tables2 = vec![test_table1.clone()];
-        let test_index1 = test_sel1.to_indexed(&test_tables1, &vec!["t1".to_string(), "t2".to_string()]);
-        let test_index2 = test_sel2.to_indexed(&test_tables1, &vec!["t1".to_string(), "t2".to_string()]);
-        let test_index3 = test_sel3.to_indexed(&test_tables2, &vec!["t1".to_string()]);
-        let test_index4 = test_sel4.to_indexed(&test_tables1, &vec!["t1".to_string(), "t2".to_string()]);
-        assert_eq!(test_index1,Ok(IndexedColumnSelector{table : 0, field : 0}));
-        assert_eq!(test_index2,Ok(IndexedColumnSelector{table : 1, field : 0}));
-        assert_eq!(test_index3, Ok(IndexedColumnSelector{table : 0, field : 0}));
-        assert_eq!(true, matches!(test_index4, Err(s)));
+        let test_index1 = test_sel1.to_indexed(&test_tables1, &["t1".to_string(), "t2".to_string()]);
+        let test_index2 = test_sel2.to_indexed(&test_tables1, &["t1".to_string(), "t2".to_string()]);
+        let test_index3 = test_sel3.to_indexed(&test_tables2, &["t1".to_string()]);
+        let test_index4 = test_sel4.to_indexed(&test_tables1, &["t1".to_string(), "t2".to_string()]);
+        assert_eq!(test_index1.ok(),Some(IndexedColumnSelector{table : 0, field : 0}));
+        assert_eq!(test_index2.ok(),Some(IndexedColumnSelector{table : 1, field : 0}));
+        assert_eq!(test_index3.ok(), Some(IndexedColumnSelector{table : 0, field : 0}));
+        assert!(test_index4.is_err());
     }
 
     #[test]
@@ -378,32 +908,183 @@ mod tests {
             rows : vec![row21.clone(), row22.clone(), row23.clone()]
         };
 
-        let res_table1 = TableData::join_table( &|vec| vec[0][1] == vec[1][1] , &(vec![testtable1.clone(), testtable2.clone()]),&IndexedSelection::Star);
-        assert_eq!(res_table1.header, vec![test_header1.clone(), test_header2.clone()].concat());
+        let res_table1 = TableData::join_table( &|vec| vec[0][1] == vec[1][1] , &[testtable1.clone(), testtable2.clone()],&IndexedSelection::Star);
+        assert_eq!(res_table1.header, [test_header1.clone(), test_header2.clone()].concat());
         assert_eq!(res_table1.rows.len(), 3);
         //check rows
-        assert_ne!(res_table1.rows.iter().find(| row | **row == vec![row11.clone(), row21.clone()].concat()), None );
-        assert_ne!(res_table1.rows.iter().find(| row | **row == vec![row12.clone(), row22.clone()].concat()), None );
-        assert_ne!(res_table1.rows.iter().find(| row | **row == vec![row13.clone(), row23.clone()].concat()), None );
-        assert_eq!(res_table1.rows.iter().find(| row | **row == vec![row11.clone(), row22.clone()].concat()), None );
-        let res_table2 = TableData::join_table( &|vec| true , &(vec![testtable1.clone(), testtable2.clone()]),&IndexedSelection::Star); 
-        assert_eq!(res_table2.header, vec![test_header1.clone(), test_header2.clone()].concat());
+        assert_ne!(res_table1.rows.iter().find(| row | **row == [row11.clone(), row21.clone()].concat()), None );
+        assert_ne!(res_table1.rows.iter().find(| row | **row == [row12.clone(), row22.clone()].concat()), None );
+        assert_ne!(res_table1.rows.iter().find(| row | **row == [row13.clone(), row23.clone()].concat()), None );
+        assert_eq!(res_table1.rows.iter().find(| row | **row == [row11.clone(), row22.clone()].concat()), None );
+        let res_table2 = TableData::join_table( &|_vec| true , &[testtable1.clone(), testtable2.clone()],&IndexedSelection::Star); 
+        assert_eq!(res_table2.header, [test_header1.clone(), test_header2.clone()].concat());
         assert_eq!(res_table2.rows.len(),9);
-        assert_ne!(res_table2.rows.iter().find(| row | **row == vec![row11.clone(), row21.clone()].concat()), None );
-        assert_ne!(res_table2.rows.iter().find(| row | **row == vec![row12.clone(), row21.clone()].concat()), None );
-        assert_ne!(res_table2.rows.iter().find(| row | **row == vec![row13.clone(), row21.clone()].concat()), None );
+        assert_ne!(res_table2.rows.iter().find(| row | **row == [row11.clone(), row21.clone()].concat()), None );
+        assert_ne!(res_table2.rows.iter().find(| row | **row == [row12.clone(), row21.clone()].concat()), None );
+        assert_ne!(res_table2.rows.iter().find(| row | **row == [row13.clone(), row21.clone()].concat()), None );
 
-        assert_ne!(res_table2.rows.iter().find(| row | **row == vec![row11.clone(), row22.clone()].concat()), None );
-        assert_ne!(res_table2.rows.iter().find(| row | **row == vec![row12.clone(), row22.clone()].concat()), None );
-        assert_ne!(res_table2.rows.iter().find(| row | **row == vec![row13.clone(), row22.clone()].concat()), None );
+        assert_ne!(res_table2.rows.iter().find(| row | **row == [row11.clone(), row22.clone()].concat()), None );
+        assert_ne!(res_table2.rows.iter().find(| row | **row == [row12.clone(), row22.clone()].concat()), None );
+        assert_ne!(res_table2.rows.iter().find(| row | **row == [row13.clone(), row22.clone()].concat()), None );
 
-        assert_ne!(res_table2.rows.iter().find(| row | **row == vec![row11.clone(), row23.clone()].concat()), None );
-        assert_ne!(res_table2.rows.iter().find(| row | **row == vec![row12.clone(), row23.clone()].concat()), None );
-        assert_ne!(res_table2.rows.iter().find(| row | **row == vec![row13.clone(), row23.clone()].concat()), None );
+        assert_ne!(res_table2.rows.iter().find(| row | **row == [row11.clone(), row23.clone()].concat()), None );
+        assert_ne!(res_table2.rows.iter().find(| row | **row == [row12.clone(), row23.clone()].concat()), None );
+        assert_ne!(res_table2.rows.iter().find(| row | **row == [row13.clone(), row23.clone()].concat()), None );
         //the tables appear to be right
-        let res_table3 = TableData::join_table( &|vec| false , &(vec![testtable1.clone(), testtable2.clone()]), &IndexedSelection::Star); 
-        assert_eq!(res_table3.header, vec![test_header1.clone(), test_header2.clone()].concat());
+        let res_table3 = TableData::join_table( &|_vec| false , &[testtable1.clone(), testtable2.clone()], &IndexedSelection::Star); 
+        assert_eq!(res_table3.header, [test_header1.clone(), test_header2.clone()].concat());
         assert_eq!(res_table3.rows.len(),0);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn udf_test(){
+        let mut functions = FunctionRegistry::with_builtins();
+        functions.register_scalar("initial", Signature::new(vec![CellType::String], CellType::String), |args : &[Option<TableCell>]| {
+            match &args[0] {
+                Some(TableCell::CellString(s)) => s.chars().next().map(|c| TableCell::CellString(c.to_string())),
+                _ => None
+            }
+        });
+        let table = TableData {
+            header : vec!["name".to_string(), "age".to_string()],
+            rows : vec![
+                vec![Some(TableCell::CellString("Lucas".to_string())), Some(TableCell::CellInt(24))],
+                vec![Some(TableCell::CellString("Harry".to_string())), Some(TableCell::CellInt(25))],
+                vec![Some(TableCell::CellString("Hal".to_string())), None],
+            ]
+        };
+        let tables = vec![table];
+        let names = vec!["user".to_string()];
+
+        let query = crate::parser::parse_sql("SELECT name, initial(name)\nFROM user\nWHERE initial(name) == \"H\"").unwrap();
+        let res = query.run(&tables, &names, &functions).unwrap();
+        assert_eq!(res.header, vec!["name".to_string(), "initial(name)".to_string()]);
+        assert_eq!(res.rows, vec![
+            vec![Some(TableCell::CellString("Harry".to_string())), Some(TableCell::CellString("H".to_string()))],
+            vec![Some(TableCell::CellString("Hal".to_string())), Some(TableCell::CellString("H".to_string()))],
+        ]);
+
+        let query = crate::parser::parse_sql("SELECT count(*), count(age), sum(age), max(name)\nFROM user\nWHERE TRUE").unwrap();
+        let res = query.run(&tables, &names, &functions).unwrap();
+        assert_eq!(res.rows, vec![vec![Some(TableCell::CellInt(3)), Some(TableCell::CellInt(2)), Some(TableCell::CellInt(49)), Some(TableCell::CellString("Lucas".to_string()))]]);
+        assert_eq!(res.header[0], "count(*)");
+
+        //arguments of the wrong type and results other than declared are rejected
+        functions.register_scalar("liar", Signature::new(vec![CellType::Any], CellType::Int), |args : &[Option<TableCell>]| args[0].clone());
+        let query = crate::parser::parse_sql("SELECT liar(name), liar(age)\nFROM user\nWHERE TRUE").unwrap();
+        assert_eq!(query.run(&tables, &names, &functions).unwrap().rows[0], vec![None, Some(TableCell::CellInt(24))]);
+        for sql in ["SELECT initial(abs(age))\nFROM user\nWHERE TRUE", "SELECT name\nFROM user\nWHERE length(3) == 1", "SELECT sum(initial(name))\nFROM user\nWHERE TRUE"] {
+            let query = crate::parser::parse_sql(sql).unwrap();
+            assert!(query.run(&tables, &names, &functions).is_err(), "{}", sql);
+        }
+        assert!(crate::parser::parse_sql("SELECT length(*)\nFROM user\nWHERE TRUE").is_err());
+
+        let mixed = crate::parser::parse_sql("SELECT name, count(*)\nFROM user\nWHERE TRUE").unwrap();
+        assert!(mixed.run(&tables, &names, &functions).is_err());
+        let unknown = crate::parser::parse_sql("SELECT name\nFROM user\nWHERE nope(name)").unwrap();
+        assert!(unknown.run(&tables, &names, &functions).is_err());
+    }
+
+    #[test]
+    fn regex_test(){
+        let functions = FunctionRegistry::with_builtins();
+        let table = TableData {
+            header : vec!["name".to_string(), "email".to_string()],
+            rows : vec![
+                vec![Some(TableCell::CellString("Lucas".to_string())), Some(TableCell::CellString("lucas@upenn.edu".to_string()))],
+                vec![Some(TableCell::CellString("Harry".to_string())), Some(TableCell::CellString("harry@cis.upenn.edu".to_string()))],
+                vec![Some(TableCell::CellString("Hal".to_string())), None],
+            ]
+        };
+        let tables = vec![table];
+        let names = vec!["user".to_string()];
+        let run = |q : &str| crate::parser::parse_sql(q).map_err(Error::from).and_then(|q| q.run(&tables, &names, &functions));
+
+        let res = run("SELECT name\nFROM user\nWHERE name ~ \"rr\"").unwrap();
+        assert_eq!(res.rows, vec![vec![Some(TableCell::CellString("Harry".to_string()))]]);
+        let res = run("SELECT name\nFROM user\nWHERE name SIMILAR TO \"H(a|e)%\"").unwrap();
+        assert_eq!(res.rows.len(), 2);
+        //SIMILAR TO must match the whole string
+        let res = run("SELECT name\nFROM user\nWHERE name SIMILAR TO \"a\"").unwrap();
+        assert_eq!(res.rows.len(), 0);
+
+        let res = run("SELECT regexp_extract(email, \"@(.*)[.]edu\"), regexp_replace(name, \"[aeiou]\", \"_\")\nFROM user\nWHERE name REGEXP \"^[HL]\"").unwrap();
+        assert_eq!(res.rows, vec![
+            vec![Some(TableCell::CellString("upenn".to_string())), Some(TableCell::CellString("L_c_s".to_string()))],
+            vec![Some(TableCell::CellString("cis.upenn".to_string())), Some(TableCell::CellString("H_rry".to_string()))],
+            vec![None, Some(TableCell::CellString("H_l".to_string()))],
+        ]);
+
+        assert!(run("SELECT name\nFROM user\nWHERE name ~ \"(\"").is_err());
+        assert!(run("SELECT name\nFROM user\nWHERE name ~ name").is_err());
+    }
+
+    #[test]
+    fn name_errors(){
+        let functions = FunctionRegistry::with_builtins();
+        let tables = vec![TableData {
+            header : vec!["name".to_string(), "age".to_string()],
+            rows : vec![]
+        }];
+        let names = vec!["user".to_string()];
+        let bind = |q : &str| crate::parser::parse_sql(q).map_err(Error::from).and_then(|q| q.to_indexed(&tables, &names, &functions));
+
+        let q = "SELECT u.name, u.height\nFROM user AS u\nWHERE u.age < 30";
+        let err = bind(q).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        match &err {
+            Error::Name { error : NameError::UnknownColumn { table, column }, span : Some(span) } => {
+                assert_eq!(table.as_deref(), Some("u"));
+                assert_eq!(column, "height");
+                assert_eq!(&q[span.start..span.end], "u.height");
+            },
+            e => panic!("unexpected error {:?}", e)
+        }
+        assert_eq!(err.display_with_source(q), concat!(
+            "name error: table u has no column height\n",
+            "  --> line 1, column 16\n",
+            "  |\n",
+            "1 | SELECT u.name, u.height\n",
+            "  |                ^^^^^^^^"));
+
+        //columns are qualified by alias, and only tables in FROM are in scope
+        assert!(matches!(bind("SELECT user.name\nFROM user AS u\nWHERE true"),
+            Err(Error::Name { error : NameError::UnknownTable(_), .. })));
+        assert!(matches!(bind("SELECT *\nFROM users\nWHERE true"),
+            Err(Error::Name { error : NameError::UnknownTable(_), span : Some(Span { start : 14, end : 19 }) })));
+        assert!(matches!(bind("SELECT nope(name)\nFROM user\nWHERE true"),
+            Err(Error::Name { error : NameError::UnknownFunction(_), span : Some(Span { start : 7, end : 17 }) })));
+        assert_eq!(bind("SELECT length(name, age)\nFROM user\nWHERE true").unwrap_err().exit_code(), 4);
+        assert_eq!(bind("SELECT name\nFROM user\nWHERE").unwrap_err().exit_code(), 2);
+    }
+
+    #[test]
+    fn type_errors_are_located(){
+        let functions = FunctionRegistry::with_builtins();
+        let tables = vec![TableData {
+            header : vec!["name".to_string(), "age".to_string()],
+            rows : vec![]
+        }];
+        let names = vec!["user".to_string()];
+        let bind = |q : &str| crate::parser::parse_sql(q).map_err(Error::from).and_then(|q| q.to_indexed(&tables, &names, &functions));
+        let located = |q : &str| match bind(q) {
+            Err(Error::Type { span : Some(span), .. }) => q[span.start..span.end].to_string(),
+            r => panic!("expected a located type error, got {:?}", r)
+        };
+
+        let q = "SELECT name\nFROM user\nWHERE age < 3 && upper(length(name, age)) == \"X\"";
+        assert_eq!(located(q), "length(name, age)");
+        assert_eq!(bind(q).unwrap_err().display_with_source(q), concat!(
+            "type error: Function length expects 1 arguments but was given 2\n",
+            "  --> line 3, column 24\n",
+            "  |\n",
+            "3 | WHERE age < 3 && upper(length(name, age)) == \"X\"\n",
+            "  |                        ^^^^^^^^^^^^^^^^^"));
+        assert_eq!(located("SELECT name\nFROM user\nWHERE age < 3 || name ~ age"), "name ~ age");
+        assert_eq!(located("SELECT name\nFROM user\nWHERE name SIMILAR TO \"(\""), "name SIMILAR TO \"(\"");
+        assert_eq!(located("SELECT count(name), age\nFROM user\nWHERE true"), "age");
+        assert_eq!(located("SELECT regexp_extract(name)\nFROM user\nWHERE true"), "regexp_extract(name)");
+        assert_eq!(located("SELECT max(name, age)\nFROM user\nWHERE true"), "max(name, age)");
+    }
+
+}